@@ -20,9 +20,15 @@ git fetch
 make
 cd -
 
+# Build the first userspace program, which Limine loads as a module.
+cargo build -Z build-std=core,compiler_builtins \
+    -Z build-std-features=compiler-builtins-mem \
+    --target conf/x86_64-sol.json --bin init
+INIT=target/x86_64-sol/debug/init
+
 # Copy the needed files into an ISO image.
 mkdir -p target/iso_root
-cp $KERNEL conf/limine.cfg target/limine/limine{.sys,-cd.bin,-cd-efi.bin} target/iso_root
+cp $KERNEL $INIT conf/limine.cfg target/limine/limine{.sys,-cd.bin,-cd-efi.bin} target/iso_root
 
 xorriso -as mkisofs                                             \
     -b limine-cd.bin                                            \
@@ -27,6 +27,7 @@ mod x86_common;
 pub mod access;
 pub mod interrupts;
 pub mod paging;
+pub mod percpu;
 pub mod task;
 
 fn read_volatile<T>(value: &T) -> T
@@ -0,0 +1,4 @@
+pub use crate::imp::percpu::{
+    init_boot, init_early, install, local_data, local_data_size, Header, HEADER_SIZE, MAX_ALIGN,
+    MAX_CPUS,
+};
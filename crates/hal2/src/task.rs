@@ -1,3 +1,3 @@
 pub use crate::imp::task::{
-    context_switch, context_switch_and_enable_interrupts, hw_thread_id, init_hw_thread, Context,
+    context_switch, context_switch_and_enable_interrupts, hw_thread_id, Context,
 };
@@ -1,6 +1,9 @@
 pub use crate::x86_common::*;
 pub mod gdt;
 pub mod interrupts;
+pub mod percpu;
 pub mod syscall;
 pub mod task;
+pub mod trap;
 pub mod tss;
+pub mod usercopy;
@@ -104,7 +104,8 @@ impl Segment {
         let mut descriptor = Descriptor::zeroed();
         let addr = tss as usize;
         descriptor.set_base(addr as u32);
-        descriptor.set_limit(mem::size_of::<Tss>() as u32);
+        // The limit is inclusive, and must cover the I/O permission bitmap.
+        descriptor.set_limit(mem::size_of::<Tss>() as u32 - 1);
         descriptor.access = 0b10001001;
 
         Self::System(SystemSegment {
@@ -0,0 +1,144 @@
+//! Per-cpu areas, addressed through the GS segment base.
+//!
+//! Every cpu's GS base points at its own area, which starts with a [`Header`] followed by
+//! the cpu's local data. The header records where the area lives, so finding the local
+//! data, or the id of the current cpu, is a single GS-relative load rather than a `rdmsr`.
+//!
+//! While the cpu runs the kernel, `IA32_GS_BASE` points at the area and
+//! `IA32_KERNEL_GS_BASE` holds the user's GS base. Every way into the kernel from ring 3,
+//! the [`syscall`](super::syscall) stub and traps whose saved `cs` is a user selector,
+//! starts with `swapgs`, and every way back ends with one, so the two are swapped exactly
+//! while in ring 3. Entries from ring 0 leave them alone. Both start out pointing at the
+//! area, which user code can't read, as nothing gives it a GS base of its own yet.
+//!
+//! The header also holds the stack pointers the [`syscall`](super::syscall) entry stub
+//! switches between, as it has no free registers to find them with.
+
+use core::{arch::asm, mem, ptr};
+
+use crate::x86_common::instr::wrmsr;
+
+const IA32_GS_BASE: u32 = 0xc0000101;
+const IA32_KERNEL_GS_BASE: u32 = 0xc0000102;
+
+/// The largest alignment local data may require.
+pub const MAX_ALIGN: usize = 64;
+
+/// The start of every per-cpu area. Local data follows it, aligned to [`MAX_ALIGN`].
+#[repr(C, align(64))]
+#[derive(Debug)]
+pub struct Header {
+    this: *mut Header,
+    cpu: usize,
+    size: usize,
+    kernel_rsp: usize,
+    user_rsp: usize,
+}
+
+pub const HEADER_SIZE: usize = mem::size_of::<Header>();
+
+// Offsets of the `Header` fields.
+const THIS: usize = 0;
+const CPU: usize = 8;
+const SIZE: usize = 16;
+pub(super) const KERNEL_RSP: usize = 24;
+pub(super) const USER_RSP: usize = 32;
+
+/// The most cpus there can be areas for. Cpu ids run from zero up to this, exclusive.
+pub const MAX_CPUS: usize = 64;
+
+const EMPTY_HEADER: Header = Header {
+    this: ptr::null_mut(),
+    cpu: 0,
+    size: 0,
+    kernel_rsp: 0,
+    user_rsp: 0,
+};
+
+/// Used by each cpu until its real area has been allocated, so that [`hw_thread_id`]
+/// works from the moment the cpu starts.
+static mut EARLY_HEADERS: [Header; MAX_CPUS] = [EMPTY_HEADER; MAX_CPUS];
+
+/// Install a header with no local data for the bootstrap processor. This needs no memory
+/// allocation, so it can be done before anything else.
+pub unsafe fn init_boot() {
+    init_early(0);
+}
+
+/// Install a header with no local data for cpu `cpu`, which must be below [`MAX_CPUS`].
+/// Like [`init_boot`], this is for application processors to call before anything else.
+pub unsafe fn init_early(cpu: usize) {
+    debug_assert!(cpu < MAX_CPUS);
+    let header = ptr::addr_of_mut!(EARLY_HEADERS[cpu]);
+    install(header.cast(), cpu, 0);
+}
+
+/// Make `area` the current cpu's per-cpu area. It must be at least `HEADER_SIZE + size`
+/// bytes, aligned to [`MAX_ALIGN`], and must live for as long as the cpu runs.
+pub unsafe fn install(area: *mut u8, cpu: usize, size: usize) {
+    debug_assert_eq!(area as usize % MAX_ALIGN, 0);
+
+    let header: *mut Header = area.cast();
+    header.write(Header {
+        this: header,
+        cpu,
+        size,
+        kernel_rsp: 0,
+        user_rsp: 0,
+    });
+
+    wrmsr(IA32_GS_BASE, area as u64);
+    wrmsr(IA32_KERNEL_GS_BASE, area as u64);
+}
+
+#[inline]
+fn read_header_word<const OFFSET: usize>() -> usize {
+    let value: usize;
+    unsafe {
+        asm!(
+            "mov {}, gs:[{}]",
+            out(reg) value,
+            const OFFSET,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+    value
+}
+
+#[inline]
+fn write_header_word<const OFFSET: usize>(value: usize) {
+    unsafe {
+        asm!(
+            "mov gs:[{}], {}",
+            const OFFSET,
+            in(reg) value,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// The current cpu's local data.
+#[inline]
+pub fn local_data() -> *mut u8 {
+    let this = read_header_word::<THIS>();
+    (this + HEADER_SIZE) as *mut u8
+}
+
+/// The size of the current cpu's local data. This is zero until the cpu's area has been
+/// installed.
+#[inline]
+pub fn local_data_size() -> usize {
+    read_header_word::<SIZE>()
+}
+
+/// The id of the current hardware thread.
+#[inline]
+pub(crate) fn hw_thread_id() -> usize {
+    read_header_word::<CPU>()
+}
+
+/// Set the stack the current cpu switches to when entering the kernel through `syscall`.
+#[inline]
+pub(super) fn set_kernel_rsp(top: usize) {
+    write_header_word::<KERNEL_RSP>(top);
+}
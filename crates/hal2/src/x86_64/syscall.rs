@@ -1 +1,220 @@
+//! The `syscall`/`sysretq` fast path into the kernel.
+//!
+//! # Register ABI
+//!
+//! | register                 | on entry        | on return          |
+//! |--------------------------|-----------------|--------------------|
+//! | `rax`                    | syscall number  | return value       |
+//! | `rdi` `rsi` `rdx`        | arguments 0-2   | preserved          |
+//! | `r10` `r8` `r9`          | arguments 3-5   | preserved          |
+//! | `rcx` `r11`              | -               | clobbered          |
+//! | everything else          | -               | preserved          |
+//!
+//! The fourth argument goes in `r10` rather than the C ABI's `rcx`, as `syscall` overwrites
+//! `rcx` with the return address and `r11` with the flags. What the return value means is
+//! up to the handler installed with [`set_handler`].
+//!
+//! # Entry
+//!
+//! The stub swaps to the kernel GS base, stashes the user stack pointer in the per-cpu
+//! header, and switches to the stack set with [`set_kernel_stack`]. It then pushes a
+//! [`SyscallFrame`] and calls the handler with interrupts enabled, so the handler may
+//! sleep. The frame lives on the kernel stack rather than the per-cpu area, which keeps
+//! it safe from whatever else runs on the cpu while the handler sleeps.
 
+use core::arch::asm;
+
+use super::{
+    instr::{rdmsr, wrmsr},
+    percpu::{set_kernel_rsp, KERNEL_RSP, USER_RSP},
+    trap::{self, TrapFrame},
+};
+
+const IA32_EFER: u32 = 0xc0000080;
+const IA32_STAR: u32 = 0xc0000081;
+const IA32_LSTAR: u32 = 0xc0000082;
+const IA32_FMASK: u32 = 0xc0000084;
+
+const EFER_SCE: u64 = 1;
+
+const RFLAGS_TF: usize = 1 << 8;
+pub(super) const RFLAGS_IF: usize = 1 << 9;
+const RFLAGS_DF: usize = 1 << 10;
+const RFLAGS_NT: usize = 1 << 14;
+const RFLAGS_AC: usize = 1 << 18;
+/// The flags userspace may change: the arithmetic flags, direction and alignment check.
+pub(super) const RFLAGS_USER: usize = 0b1000_1101_0101 | RFLAGS_DF | RFLAGS_AC;
+
+/// The end of the lower canonical half. `sysretq` to an address at or above this faults in
+/// ring 0, on the user's stack.
+pub const USER_END: usize = 1 << 47;
+
+/// The user registers saved on entry, in the order the stub pushes them.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallFrame {
+    pub r9: usize,
+    pub r8: usize,
+    pub r10: usize,
+    pub rdx: usize,
+    pub rsi: usize,
+    pub rdi: usize,
+    /// The syscall number on entry, and the return value on exit.
+    pub rax: usize,
+    /// Where to return to, saved by the cpu in `rcx`.
+    pub rip: usize,
+    /// Saved by the cpu in `r11`.
+    pub rflags: usize,
+    pub rsp: usize,
+}
+
+impl SyscallFrame {
+    pub fn number(&self) -> usize {
+        self.rax
+    }
+
+    pub fn args(&self) -> [usize; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    pub fn set_return(&mut self, value: usize) {
+        self.rax = value;
+    }
+
+    /// The frame as it would look had the syscall been a trap from ring 3.
+    unsafe fn to_trap_frame(&self) -> TrapFrame {
+        TrapFrame {
+            r9: self.r9,
+            r8: self.r8,
+            r10: self.r10,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rax: self.rax,
+            // What `syscall` leaves in them.
+            rcx: self.rip,
+            r11: self.rflags,
+            rflags: self.rflags,
+            ..user_frame(self.rip, self.rsp)
+        }
+    }
+}
+
+pub type Handler = fn(&mut SyscallFrame);
+
+static mut HANDLER: Handler = unhandled;
+
+fn unhandled(frame: &mut SyscallFrame) {
+    panic!("syscall {} with no handler installed", frame.number());
+}
+
+/// Install the function every syscall is dispatched to. This must be done before any cpu
+/// runs [`init_hw_thread`].
+pub unsafe fn set_handler(handler: Handler) {
+    HANDLER = handler;
+}
+
+/// Enable `syscall` on the current cpu.
+///
+/// `kernel_code` is followed by the kernel data segment in the GDT, and `user_data` by the
+/// 64-bit user code segment, which is the layout `syscall` and `sysretq` expect.
+pub unsafe fn init_hw_thread(kernel_code: u16, user_data: u16) {
+    // `sysretq` loads SS from the base plus 8 and CS from the base plus 16.
+    let user_base = u64::from(user_data & !0b111) - 8;
+    let star = (user_base << 48) | (u64::from(kernel_code & !0b111) << 32);
+
+    wrmsr(IA32_STAR, star);
+    wrmsr(IA32_LSTAR, syscall_entry as usize as u64);
+    // Enter with interrupts off until the stack is switched, and with the flags that
+    // would confuse kernel code cleared.
+    wrmsr(
+        IA32_FMASK,
+        (RFLAGS_TF | RFLAGS_IF | RFLAGS_DF | RFLAGS_NT | RFLAGS_AC) as u64,
+    );
+    wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_SCE);
+}
+
+/// Set the stack syscalls on the current cpu run on. `top` must be 16 byte aligned.
+pub unsafe fn set_kernel_stack(top: *mut u8) {
+    debug_assert_eq!(top as usize % 16, 0);
+    set_kernel_rsp(top as usize);
+}
+
+/// Drop to ring 3 at `rip` with the stack at `rsp` and `arg` in `rdi`, never to return.
+/// Every other general purpose register is cleared, so no kernel values leak.
+///
+/// If `rip` or `rsp` is outside the user half, the trap handler gets a general protection
+/// fault instead.
+///
+/// # Safety
+/// Syscalls must be set up on this cpu.
+pub unsafe fn enter_user(rip: usize, rsp: usize, arg: usize) -> ! {
+    let mut frame = user_frame(rip, rsp);
+    frame.rdi = arg;
+
+    trap::return_to_user(frame)
+}
+
+/// A trap frame entering ring 3 with the segments `sysretq` would use, from the base set
+/// in `init_hw_thread`.
+unsafe fn user_frame(rip: usize, rsp: usize) -> TrapFrame {
+    let user_base = (rdmsr(IA32_STAR) >> 48) as u16;
+    TrapFrame::user(rip, rsp, user_base + 16, user_base + 8)
+}
+
+extern "C" fn dispatch(frame: &mut SyscallFrame) {
+    unsafe { HANDLER(frame) };
+
+    // Privileged flags must not leak to userspace.
+    frame.rflags = (frame.rflags & RFLAGS_USER) | RFLAGS_IF;
+
+    // `sysretq` to a non-canonical `rip` faults in ring 0, on the user's stack. The handler
+    // may have rewritten the frame, and a syscall in the last bytes of the user half
+    // returns to `USER_END` itself, so in that case leave through `iretq` instead, which
+    // hands userspace the fault. The registers the syscall frame doesn't hold come back
+    // cleared.
+    if frame.rip >= USER_END {
+        unsafe { trap::return_to_user(frame.to_trap_frame()) };
+    }
+}
+
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+    asm!(
+        "swapgs
+        mov gs:[{user_rsp}], rsp
+        mov rsp, gs:[{kernel_rsp}]
+        push qword ptr gs:[{user_rsp}]
+        push r11
+        push rcx
+        push rax
+        push rdi
+        push rsi
+        push rdx
+        push r10
+        push r8
+        push r9
+        sti
+
+        mov rdi, rsp
+        call {dispatch}
+
+        cli
+        pop r9
+        pop r8
+        pop r10
+        pop rdx
+        pop rsi
+        pop rdi
+        pop rax
+        pop rcx
+        pop r11
+        pop rsp
+        swapgs
+        sysretq",
+        user_rsp = const USER_RSP,
+        kernel_rsp = const KERNEL_RSP,
+        dispatch = sym dispatch,
+        options(noreturn)
+    );
+}
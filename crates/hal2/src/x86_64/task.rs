@@ -2,6 +2,13 @@ use core::arch::asm;
 
 pub use crate::x86_common::task::*;
 
+/// The id of the current hardware thread. This is a single load from the per-cpu area, see
+/// [`percpu`](super::percpu).
+#[inline]
+pub unsafe fn hw_thread_id() -> usize {
+    super::percpu::hw_thread_id()
+}
+
 #[naked]
 pub unsafe extern "C" fn context_switch(old: *mut *mut Context, new: *mut Context) {
     asm!(
@@ -25,34 +32,6 @@ pub unsafe extern "C" fn context_switch(old: *mut *mut Context, new: *mut Contex
     );
 }
 
-extern "C" {
-    fn ring3_entry();
-}
-
-#[naked]
-pub unsafe extern "C" fn jump_ring3() {
-    asm!(
-        "
-        mov rcx, 0xc0000082
-        wrmsr
-        mov rcx, 0xc0000080
-        rdmsr
-        or eax, 1
-        wrmsr
-        mov rcx, 0xc0000081
-        rdmsr
-        mov edx, 0x00180008
-        wrmsr
-
-        mov ecx, ring3_entry
-        mov r11, 0x202
-        sysretq",
-        options(noreturn)
-    );
-}
-
-unsafe fn enable_syscall() {}
-
 #[naked]
 pub unsafe extern "C" fn context_switch_and_enable_interrupts(
     old: *mut *mut Context,
@@ -0,0 +1,246 @@
+//! Interrupts and exceptions, from either ring, and the way back to ring 3.
+//!
+//! Every vector gets a stub that pushes its number, and a zero in place of the error code
+//! for vectors the cpu doesn't push one for. The stubs then share a path that swaps to
+//! the kernel GS base if the cpu was in ring 3, saves the general purpose registers to
+//! complete a [`TrapFrame`], and calls the handler installed with [`set_handler`]. The
+//! handler runs with interrupts disabled, and may rewrite the frame to change where the
+//! trap returns to. Leaving restores the frame with `iretq`, swapping GS back first if it
+//! returns to ring 3.
+//!
+//! [`return_to_user`] takes the same way out with a frame built by the kernel, which is
+//! how a task first drops to ring 3.
+//!
+//! `iretq` to an address outside the user half faults in ring 0, after GS has been swapped
+//! back, so a frame returning to ring 3 is checked first. One that fails gets the general
+//! protection fault userspace would have taken, through the handler, which can kill the
+//! task or fix the frame up.
+//!
+//! A trap from ring 3 arrives on the stack in the TSS, which the kernel points at the
+//! stack of the task that is about to run.
+
+use core::arch::{asm, global_asm};
+
+use vm_types::VirtAddr;
+
+use super::{
+    instr::cli,
+    syscall::{RFLAGS_IF, RFLAGS_USER, USER_END},
+};
+
+/// The size of each vector's stub. Stubs are laid out in order, so a vector's entry point
+/// is found by its number.
+const STUB_SIZE: usize = 16;
+
+const GENERAL_PROTECTION: usize = 13;
+
+/// The general purpose registers and the cpu's interrupt frame, in the order the entry
+/// path leaves them on the stack.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrapFrame {
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub r11: usize,
+    pub r10: usize,
+    pub r9: usize,
+    pub r8: usize,
+    pub rbp: usize,
+    pub rdi: usize,
+    pub rsi: usize,
+    pub rdx: usize,
+    pub rcx: usize,
+    pub rbx: usize,
+    pub rax: usize,
+    pub vector: usize,
+    /// Zero for vectors without an error code.
+    pub error_code: usize,
+    pub rip: usize,
+    pub cs: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+    pub ss: usize,
+}
+
+impl TrapFrame {
+    /// A frame that enters ring 3 at `rip`, on the stack at `rsp`, with interrupts
+    /// enabled and every other register cleared.
+    pub fn user(rip: usize, rsp: usize, code_selector: u16, data_selector: u16) -> Self {
+        Self {
+            rip,
+            cs: usize::from(code_selector | 3),
+            rflags: RFLAGS_IF | 1 << 1,
+            rsp,
+            ss: usize::from(data_selector | 3),
+            ..Self::default()
+        }
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.vector as u8
+    }
+
+    /// Whether the trap came from ring 3.
+    pub fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+
+    /// Whether `iretq` can restore the frame in ring 3 without faulting.
+    fn is_user_return(&self) -> bool {
+        self.rip < USER_END && self.rsp < USER_END
+    }
+}
+
+pub type Handler = fn(&mut TrapFrame);
+
+static mut HANDLER: Handler = unhandled;
+
+fn unhandled(frame: &mut TrapFrame) {
+    panic!("trap on vector {} with no handler installed", frame.vector);
+}
+
+/// Install the function every trap is dispatched to. This must be done before the
+/// stubs are put in an IDT.
+pub unsafe fn set_handler(handler: Handler) {
+    HANDLER = handler;
+}
+
+/// The entry point to put in the IDT for `vector`.
+pub fn entry(vector: u8) -> VirtAddr {
+    VirtAddr::from_usize(trap_stubs as usize + usize::from(vector) * STUB_SIZE)
+}
+
+/// Restore `frame`, which must return to ring 3, never to come back. If it points
+/// outside the user half, the handler gets a general protection fault for it first.
+///
+/// # Safety
+/// `frame` must come from [`TrapFrame::user`] or a trap from ring 3.
+pub unsafe fn return_to_user(mut frame: TrapFrame) -> ! {
+    debug_assert!(frame.is_user(), "returning to ring 0 as if it were ring 3");
+
+    // The handler expects interrupts to be disabled, and so does the restore path.
+    cli();
+    prepare_user_return(&mut frame);
+
+    asm!(
+        "mov rsp, {frame}
+        jmp {restore}",
+        frame = in(reg) &frame as *const TrapFrame,
+        restore = sym trap_restore,
+        options(noreturn)
+    );
+}
+
+extern "C" fn dispatch(frame: &mut TrapFrame) {
+    unsafe { HANDLER(frame) };
+
+    if frame.is_user() {
+        prepare_user_return(frame);
+    }
+}
+
+/// Get `frame` ready to be restored in ring 3. Until it can be without faulting, the
+/// handler is given a general protection fault for it, and privileged flags are masked
+/// off so they can't leak to userspace. Must be called with interrupts disabled.
+fn prepare_user_return(frame: &mut TrapFrame) {
+    while !frame.is_user_return() {
+        frame.vector = GENERAL_PROTECTION;
+        frame.error_code = 0;
+        unsafe { HANDLER(frame) };
+    }
+    frame.rflags = (frame.rflags & RFLAGS_USER) | RFLAGS_IF;
+}
+
+extern "C" {
+    fn trap_stubs();
+}
+
+// The vectors the cpu pushes an error code for: #DF, #TS, #NP, #SS, #GP, #PF, #AC, #CP,
+// #VC and #SX.
+global_asm!(
+    "
+    .pushsection .text
+    .balign {stub_size}
+trap_stubs:
+    vector = 0
+    .rept 256
+    .balign {stub_size}
+    .if !((vector == 8) || ((vector >= 10) && (vector <= 14)) || (vector == 17) || (vector == 21) || (vector == 29) || (vector == 30))
+    pushq $0
+    .endif
+    pushq $vector
+    jmp {common}
+    vector = vector + 1
+    .endr
+    .popsection
+    ",
+    stub_size = const STUB_SIZE,
+    common = sym trap_common,
+    options(att_syntax)
+);
+
+#[naked]
+unsafe extern "C" fn trap_common() {
+    asm!(
+        // `cs` is above the vector, the error code and `rip`.
+        "test byte ptr [rsp + 24], 3
+        jz 2f
+        swapgs
+    2:
+        push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15
+        cld
+
+        mov rdi, rsp
+        call {dispatch}
+        jmp {restore}",
+        dispatch = sym dispatch,
+        restore = sym trap_restore,
+        options(noreturn)
+    );
+}
+
+/// Pop a [`TrapFrame`] off the stack and return through it.
+#[naked]
+unsafe extern "C" fn trap_restore() {
+    asm!(
+        "cli
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rbp
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax
+        test byte ptr [rsp + 24], 3
+        jz 2f
+        swapgs
+    2:
+        add rsp, 16
+        iretq",
+        options(noreturn)
+    );
+}
@@ -1,7 +1,15 @@
-use core::{mem, num::NonZeroU8};
+use core::{num::NonZeroU8, ops::RangeInclusive};
 
 use vm_types::VirtAddr;
 
+/// The size of the part of the TSS the cpu defines, after which the I/O permission
+/// bitmap starts.
+const IO_BITMAP_OFFSET: u16 = 104;
+
+/// A 64-bit task state segment, followed by an I/O permission bitmap.
+///
+/// The bitmap controls which ports ring 3 may use `in` and `out` on. It starts out
+/// denying every port.
 #[repr(C, packed(4))]
 #[derive(Debug)]
 pub struct Tss {
@@ -12,14 +20,16 @@ pub struct Tss {
     _reserved3: [u32; 2],
     _reserved4: u16,
     iopb: u16,
+    io_bitmap: IoBitmap,
 }
 
 impl Tss {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             rsp: [VirtAddr::zero(); 3],
             ist: [VirtAddr::zero(); 7],
-            iopb: mem::size_of::<Self>() as u16,
+            iopb: IO_BITMAP_OFFSET,
+            io_bitmap: IoBitmap::new(),
             _reserved4: 0,
             _reserved1: 0,
             _reserved2: [0; 2],
@@ -34,6 +44,19 @@ impl Tss {
     pub fn set_ist(&mut self, index: IstIndex, addr: VirtAddr) {
         self.ist[index.index()] = addr;
     }
+
+    /// Set the stack the cpu switches to when an interrupt arrives in ring 3.
+    pub fn set_privilege_stack(&mut self, addr: VirtAddr) {
+        self.rsp[0] = addr;
+    }
+
+    pub fn io_bitmap(&self) -> &IoBitmap {
+        &self.io_bitmap
+    }
+
+    pub fn io_bitmap_mut(&mut self) -> &mut IoBitmap {
+        &mut self.io_bitmap
+    }
 }
 
 impl Default for Tss {
@@ -50,3 +73,55 @@ impl IstIndex {
         usize::from(self.0.get() - 1)
     }
 }
+
+/// One bit per port, set for the ports ring 3 may not use.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct IoBitmap {
+    bits: [u8; 8192],
+    /// The cpu reads two bytes at a time, so the bitmap must end with a byte of ones.
+    terminator: u8,
+}
+
+impl IoBitmap {
+    /// A bitmap that denies every port.
+    pub const fn new() -> Self {
+        Self {
+            bits: [0xff; 8192],
+            terminator: 0xff,
+        }
+    }
+
+    pub fn is_allowed(&self, port: u16) -> bool {
+        self.bits[usize::from(port / 8)] & (1 << (port % 8)) == 0
+    }
+
+    pub fn allow(&mut self, ports: RangeInclusive<u16>) {
+        self.set(ports, false);
+    }
+
+    pub fn deny(&mut self, ports: RangeInclusive<u16>) {
+        self.set(ports, true);
+    }
+
+    pub fn deny_all(&mut self) {
+        self.bits.fill(0xff);
+    }
+
+    fn set(&mut self, ports: RangeInclusive<u16>, denied: bool) {
+        for port in ports {
+            let byte = &mut self.bits[usize::from(port / 8)];
+            if denied {
+                *byte |= 1 << (port % 8);
+            } else {
+                *byte &= !(1 << (port % 8));
+            }
+        }
+    }
+}
+
+impl Default for IoBitmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,44 @@
+//! Copies to and from user memory that survive a bad user address.
+//!
+//! The copy is a single `rep movsb`. If it faults, the trap handler looks the faulting
+//! instruction up with [`fixup`] and resumes there instead, where the copy returns how
+//! many bytes it didn't get to, which `rep movsb` leaves in `rcx`.
+
+use core::arch::global_asm;
+
+extern "C" {
+    fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn user_copy_insn();
+    fn user_copy_fixup();
+}
+
+global_asm!(
+    "
+    .pushsection .text
+    .global user_copy, user_copy_insn, user_copy_fixup
+user_copy:
+    mov rcx, rdx
+user_copy_insn:
+    rep movsb
+user_copy_fixup:
+    mov rax, rcx
+    ret
+    .popsection
+    "
+);
+
+/// Copy `len` bytes from `src` to `dst`, returning how many were left uncopied because
+/// of a fault.
+///
+/// # Safety
+/// Whichever of `src` and `dst` is kernel memory must be valid for the copy, and the
+/// trap handler must call [`fixup`] for page faults and general protection faults in
+/// ring 0.
+pub unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    user_copy(dst, src, len)
+}
+
+/// Where to resume after a fault in ring 0 at `rip`, if it was in a user copy.
+pub fn fixup(rip: usize) -> Option<usize> {
+    (rip == user_copy_insn as usize).then_some(user_copy_fixup as usize)
+}
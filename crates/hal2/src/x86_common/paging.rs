@@ -30,12 +30,69 @@ impl DirectlyMappedPageTable {
     }
 
     pub unsafe fn active(phys_base: VirtAddr) -> Self {
-        let cr3 = cr3::read() & !0b11;
+        let cr3 = cr3::read() & !0xfff;
 
-        let addr = VirtAddr::from_usize(cr3);
+        let addr = VirtAddr::from_usize(phys_base.as_usize() + cr3);
         Self::from_l4(addr, phys_base)
     }
 
+    /// Drop every mapping in the lower half, such as the identity map the bootloader
+    /// leaves behind, so that it is free for userspace. The tables themselves are leaked.
+    ///
+    /// # Safety
+    /// The table must be active, and nothing may still use a lower half address.
+    pub unsafe fn clear_lower_half(&mut self) {
+        for entry in &mut self.l4.0[..256] {
+            *entry = PageTableEntry(0);
+        }
+        cr3::write(cr3::read());
+    }
+
+    /// The physical address of the top level table, which is what CR3 holds.
+    pub fn root(&self) -> PhysAddr {
+        let addr = VirtAddr::from_ptr(self.l4).as_usize();
+        PhysAddr::from_usize(addr - self.phys_base.as_usize())
+    }
+
+    /// Give every upper half entry of the top level table a table below it, so that
+    /// mapping kernel memory never changes the top level table again. Tables made by
+    /// [`DirectlyMappedPageTable::share_upper_half`] then stay in step with this one.
+    pub fn fill_upper_half<P>(&mut self, frame_allocator: &P) -> Result<(), FrameAllocError>
+    where
+        P: ?Sized + FrameAllocator,
+    {
+        for i in 256..512 {
+            get_subtable(self.l4, i, false, frame_allocator, self.phys_base)?;
+        }
+        Ok(())
+    }
+
+    /// A new table with an empty lower half, and the same upper half as this one.
+    pub fn share_upper_half<P>(&self, frame_allocator: &P) -> Result<Self, PageTableError>
+    where
+        P: ?Sized + FrameAllocator,
+    {
+        let table = Self::new(self.phys_base, frame_allocator)?;
+        table.l4.0[256..].copy_from_slice(&self.l4.0[256..]);
+        Ok(table)
+    }
+
+    /// Free the top level table and the tables below it in the lower half. The frames
+    /// the lower half maps are left to the caller.
+    ///
+    /// # Safety
+    /// The table must have come from [`DirectlyMappedPageTable::share_upper_half`], must
+    /// not be loaded or cached on any cpu, and must not be used again.
+    pub unsafe fn free_lower_half<P>(&mut self, frame_allocator: &P)
+    where
+        P: ?Sized + FrameAllocator,
+    {
+        for entry in self.l4.0[..256].iter().filter(|entry| entry.is_present()) {
+            free_table(entry.frame(), 2, self.phys_base, frame_allocator);
+        }
+        frame_allocator.deallocate_frame(Frame::from_base(self.root()).unwrap());
+    }
+
     pub fn new<P>(phys_base: VirtAddr, frame_allocator: &P) -> Result<Self, PageTableError>
     where
         P: ?Sized + FrameAllocator,
@@ -60,15 +117,18 @@ impl DirectlyMappedPageTable {
     where
         P: ?Sized + FrameAllocator,
     {
-        let entry = self.get_entry(virt, frame_allocator)?;
+        let entry = self.get_entry(virt, false, frame_allocator)?;
         let entry_bits = bits & !1;
         ptr::write(entry, PageTableEntry(entry_bits));
         Ok(())
     }
 
+    /// The entry for `page`, creating any missing tables on the way. If `user`, the
+    /// tables on the way are opened to userspace, leaving the final entry to decide.
     fn get_entry<'a, P>(
         &'a mut self,
         page: Page,
+        user: bool,
         frame_allocator: &P,
     ) -> Result<&'a mut PageTableEntry, FrameAllocError>
     where
@@ -77,9 +137,9 @@ impl DirectlyMappedPageTable {
         let [l3_index, l2_index, l1_index, l0_index] = address_parts(page.addr().as_usize());
 
         let l4 = &mut *self.l4;
-        let l3 = get_subtable(l4, l3_index, frame_allocator, self.phys_base)?;
-        let l2 = get_subtable(l3, l2_index, frame_allocator, self.phys_base)?;
-        let l1 = get_subtable(l2, l1_index, frame_allocator, self.phys_base)?;
+        let l3 = get_subtable(l4, l3_index, user, frame_allocator, self.phys_base)?;
+        let l2 = get_subtable(l3, l2_index, user, frame_allocator, self.phys_base)?;
+        let l1 = get_subtable(l2, l1_index, user, frame_allocator, self.phys_base)?;
 
         Ok(RawPageTable::get_entry(l1, l0_index))
     }
@@ -119,7 +179,7 @@ unsafe impl vm_types::PageTable for DirectlyMappedPageTable {
         bits |= usize::from(user_high5) << 52;
         bits |= frame.addr().as_usize();
 
-        let entry = self.get_entry(page, phys_alloc)?;
+        let entry = self.get_entry(page, user_accessible, phys_alloc)?;
         entry.0 = bits;
 
         if flush_tlb {
@@ -138,11 +198,32 @@ unsafe impl vm_types::PageTable for DirectlyMappedPageTable {
     where
         P: ?Sized + FrameAllocator,
     {
-        let entry = self.get_entry(page, phys_alloc)?;
+        let entry = self.get_entry(page, false, phys_alloc)?;
         entry.0 = bits & !1;
         Ok(())
     }
 
+    unsafe fn unmap(&mut self, page: Page) -> Result<Option<Frame>, PageLookupError> {
+        let [l3_index, l2_index, l1_index, l0_index] = address_parts(page.addr().as_usize());
+
+        let base = self.phys_base;
+
+        let l4 = &mut *self.l4;
+        let l3 = try_get_subtable(l4, l3_index, base)?;
+        let l2 = try_get_subtable(l3, l2_index, base)?;
+        let l1 = try_get_subtable(l2, l1_index, base)?;
+
+        let entry = RawPageTable::get_entry(l1, l0_index);
+        let old = ptr::replace(entry, PageTableEntry(0));
+
+        if old.is_present() {
+            invlpg(page.addr().as_ptr());
+            Ok(Some(old.frame()))
+        } else {
+            Ok(None)
+        }
+    }
+
     unsafe fn load(&'static self) {
         let reg = VirtAddr::from_ptr(self.l4).as_usize();
         cr3::write(reg);
@@ -187,6 +268,7 @@ fn try_get_subtable(
 fn get_subtable<'a, P>(
     parent: &'a mut RawPageTable,
     i: usize,
+    user: bool,
     frame_allocator: &P,
     phys_base: VirtAddr,
 ) -> Result<&'a mut RawPageTable, FrameAllocError>
@@ -198,6 +280,7 @@ where
     let entry = parent.get_entry(i);
 
     if entry.is_present() {
+        entry.0 |= usize::from(user) << USER_BIT;
         let phys = entry.frame().addr().as_usize();
         let virt = unsafe { phys_base.add(phys) };
         return unsafe { Ok(&mut *virt.cast()) };
@@ -208,11 +291,26 @@ where
         let table: *mut RawPageTable = phys_base.add(frame.addr().as_usize()).cast();
         table.write_bytes(0, 1);
         *entry = PageTableEntry::new(frame);
+        entry.0 |= usize::from(user) << USER_BIT;
 
         Ok(&mut *table)
     }
 }
 
+/// Free the table in `frame` and, for `levels` levels, the tables below it.
+unsafe fn free_table<P>(frame: Frame, levels: usize, phys_base: VirtAddr, frame_allocator: &P)
+where
+    P: ?Sized + FrameAllocator,
+{
+    if levels > 0 {
+        let table: &RawPageTable = &*phys_base.as_ptr::<u8>().add(frame.addr().as_usize()).cast();
+        for entry in table.0.iter().filter(|entry| entry.is_present()) {
+            free_table(entry.frame(), levels - 1, phys_base, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
 #[repr(C, align(4096))]
 #[derive(Debug, Clone, Copy)]
 struct RawPageTable([PageTableEntry; 512]);
@@ -0,0 +1,104 @@
+//! The first process, started by the kernel as PID 1.
+//!
+//! The kernel queues every handle init starts with on its bootstrap channel, one per
+//! message, named `kind:name`. For now init only takes stock of them, and checks that the
+//! RTC's interrupt reaches it.
+
+#![no_std]
+#![no_main]
+
+use core::{fmt::Write, str};
+
+use libsol::{
+    io::{inb, outb, IoPorts},
+    irq::Irq,
+    log::Log,
+    rt::Bootstrap,
+    AnyHandle, Error, Handle, Result,
+};
+
+libsol::entry!(main);
+
+fn main(bootstrap: Bootstrap) {
+    let Some(bootstrap) = bootstrap else { return };
+
+    let mut log: Option<Handle<Log>> = None;
+    let mut rtc_ports: Option<Handle<IoPorts>> = None;
+    let mut rtc_irq: Option<Handle<Irq>> = None;
+    let mut bytes = [0; 64];
+    loop {
+        let mut handles: [Option<AnyHandle>; 1] = [None];
+        let len = match bootstrap.read(&mut bytes, &mut handles) {
+            Ok((len, _)) => len,
+            Err(Error::WouldBlock) => match bootstrap.wait() {
+                Ok(()) => continue,
+                Err(_) => break,
+            },
+            Err(_) => break,
+        };
+        let Some(handle) = handles[0].take() else { continue };
+        let name = str::from_utf8(&bytes[..len]).unwrap_or("?");
+
+        if name.starts_with("log:") && log.is_none() {
+            let handle = handle.cast::<Log>();
+            let _ = writeln!(&handle, "init started");
+            log = Some(handle);
+            continue;
+        }
+        if let Some(log) = &log {
+            let _ = writeln!(&*log, "got {}", name);
+        }
+        match name {
+            "io_ports:rtc" => rtc_ports = Some(handle.cast()),
+            "irq:rtc" => rtc_irq = Some(handle.cast()),
+            // Nothing drives the other devices yet, so their handles are simply closed.
+            _ => {}
+        }
+    }
+
+    if let (Some(ports), Some(irq)) = (&rtc_ports, &rtc_irq) {
+        let result = wait_for_rtc(ports, irq);
+        if let Some(log) = &log {
+            let _ = match result {
+                Ok(count) => writeln!(&*log, "rtc interrupt {} arrived", count),
+                Err(err) => writeln!(&*log, "rtc interrupt failed: {}", err),
+            };
+        }
+    }
+
+    if let Some(log) = &log {
+        let _ = writeln!(&*log, "nothing left to do");
+    }
+}
+
+/// Have the RTC interrupt once, at the end of its next update, and wait for it.
+fn wait_for_rtc(ports: &Handle<IoPorts>, irq: &Handle<Irq>) -> Result<u32> {
+    const INDEX: u16 = 0x70;
+    const DATA: u16 = 0x71;
+    const STATUS_B: u8 = 0x0b;
+    const STATUS_C: u8 = 0x0c;
+    const UPDATE_ENDED: u8 = 1 << 4;
+
+    ports.enable()?;
+    // Safety: the ports are enabled, and only the update-ended interrupt is touched.
+    unsafe {
+        outb(INDEX, STATUS_B);
+        let status = inb(DATA);
+        outb(INDEX, STATUS_B);
+        outb(DATA, status | UPDATE_ENDED);
+    }
+
+    let count = irq.wait()?;
+    // Safety: as above. Reading status C tells the RTC the interrupt was seen; until then
+    // it raises no other.
+    unsafe {
+        outb(INDEX, STATUS_C);
+        inb(DATA);
+        outb(INDEX, STATUS_B);
+        let status = inb(DATA);
+        outb(INDEX, STATUS_B);
+        outb(DATA, status & !UPDATE_ENDED);
+    }
+    irq.ack()?;
+    Ok(count)
+}
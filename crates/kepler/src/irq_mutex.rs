@@ -1,28 +1,38 @@
 use hal::interrupts::{self, NoInterrupts};
 use lock_api::RawMutex;
+use lockdep::{LockClass, LockKind};
 
 pub struct IrqMutex<R, T> {
     inner: lock_api::Mutex<R, T>,
+    class: &'static LockClass,
 }
 
 impl<R, T> IrqMutex<R, T>
 where
     R: RawMutex,
 {
-    pub const fn new(value: T) -> Self {
+    pub const fn new(value: T, class: &'static LockClass) -> Self {
         Self {
             inner: lock_api::Mutex::new(value),
+            class,
         }
     }
 
+    #[track_caller]
     pub fn lock<F, U>(&self, f: F) -> U
     where
         F: FnOnce(&mut T, &mut NoInterrupts) -> U,
     {
-        interrupts::without(|token| {
+        let key = (self as *const Self).cast();
+        lockdep::acquire(key, self.class, LockKind::Spin);
+
+        let result = interrupts::without(|token| {
             let mut guard = self.inner.lock();
             f(&mut guard, token)
-        })
+        });
+
+        lockdep::release(key);
+        result
     }
 }
 
@@ -85,6 +85,7 @@ extern "C" fn _start() -> ! {
 
     vm::init(entries);
     allocator::init();
+    lagrange::sync::lockdep_hooks::init();
 
     tracing::info!("tracing initialized");
 
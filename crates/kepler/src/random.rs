@@ -9,7 +9,8 @@ use spin::{mutex::SpinMutex, Lazy};
 
 use crate::irq_mutex::SpinIrqMutex;
 
-static POOL: Lazy<SpinIrqMutex<Blake2bPool>> = Lazy::new(|| SpinIrqMutex::new(Pool::empty()));
+static POOL: Lazy<SpinIrqMutex<Blake2bPool>> =
+    Lazy::new(|| SpinIrqMutex::new(Pool::empty(), lockdep::class!("POOL")));
 
 pub fn mix_entropy(data: &[u8]) {
     POOL.lock(|pool, _| {
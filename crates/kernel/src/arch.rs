@@ -1,3 +1,7 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::{KernErrorKind, KernResult};
+
 pub mod x86_64;
 
 pub use self::x86_64::{init, interrupts, CpuId};
@@ -9,26 +13,90 @@ pub enum IpiTarget {
 }
 
 pub mod cpu {
-    use hal::task::init_hw_thread;
+    pub use super::x86_64::cpu::{allocate_id, get, init, CpuId};
+}
 
-    pub use super::x86_64::cpu::{get, init, CpuId};
+/// A set of cpus, indexed by hardware thread id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CpuMask {
+    bits: u64,
 }
 
-// #[derive(Debug, Clone, Copy)]
-// pub struct CpuMask {
-//     bits: u32,
-// }
+impl CpuMask {
+    pub const MAX_CPUS: usize = u64::BITS as usize;
+
+    pub const fn empty() -> Self {
+        Self { bits: 0 }
+    }
+
+    pub const fn all() -> Self {
+        Self { bits: u64::MAX }
+    }
+
+    /// The mask with only `cpu` in it. Fails if `cpu` is past [`MAX_CPUS`](Self::MAX_CPUS).
+    pub fn single(cpu: usize) -> KernResult<Self> {
+        if cpu >= Self::MAX_CPUS {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+        Ok(Self { bits: 1 << cpu })
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self { bits }
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.bits
+    }
+
+    pub fn insert(&mut self, cpu: usize) {
+        assert!(cpu < Self::MAX_CPUS);
+        self.bits |= 1 << cpu;
+    }
+
+    pub fn remove(&mut self, cpu: usize) {
+        assert!(cpu < Self::MAX_CPUS);
+        self.bits &= !(1 << cpu);
+    }
 
-// impl CpuMask {
-//     pub fn insert(&mut self, cpu: CpuId) {
-//         todo!()
-//     }
+    pub fn contains(&self, cpu: usize) -> bool {
+        cpu < Self::MAX_CPUS && self.bits & (1 << cpu) != 0
+    }
 
-//     pub fn remove(&mut self, cpu: CpuId) {
-//         todo!()
-//     }
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
 
-//     pub fn contains(&self, cpu: CpuId) -> bool {
-//         todo!()
-//     }
-// }
+    pub fn is_all(&self) -> bool {
+        self.bits == u64::MAX
+    }
+
+    /// Iterate over the cpus in the mask, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        let bits = self.bits;
+        (0..Self::MAX_CPUS).filter(move |&cpu| bits & (1 << cpu) != 0)
+    }
+}
+
+impl Default for CpuMask {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+#[derive(Debug)]
+pub struct AtomicCpuMask(AtomicU64);
+
+impl AtomicCpuMask {
+    pub const fn new(mask: CpuMask) -> Self {
+        Self(AtomicU64::new(mask.bits))
+    }
+
+    pub fn load(&self, order: Ordering) -> CpuMask {
+        CpuMask::from_bits(self.0.load(order))
+    }
+
+    pub fn store(&self, mask: CpuMask, order: Ordering) {
+        self.0.store(mask.bits, order);
+    }
+}
@@ -4,7 +4,9 @@ pub mod cpu;
 pub mod gdt;
 pub mod hpet;
 pub mod idt;
+pub mod ioapic;
 pub mod syscall;
+pub mod tlb;
 pub mod tss;
 pub use idt::send_ipi;
 
@@ -1,7 +1,22 @@
-use hal::{interrupts, task::init_hw_thread};
-use x86_64::registers::model_specific::Msr;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 
-use super::idt::LOCAL_APIC;
+use hal::{interrupts, percpu::MAX_CPUS, task::hw_thread_id};
+
+use super::{gdt, idt::LOCAL_APIC, syscall, tlb};
+use crate::{
+    cpu_local,
+    sync::{futex, rcu},
+};
+
+/// The id the next application processor to come up gets. The bootstrap processor is
+/// always 0.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_APIC_ID: AtomicU32 = AtomicU32::new(0);
+
+/// The local APIC id of each cpu, which is what IPIs are addressed by.
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [NO_APIC_ID; MAX_CPUS];
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct CpuId(pub(super) u32);
@@ -10,22 +25,45 @@ impl CpuId {
     pub fn get() -> Self {
         get()
     }
+
+    pub(super) fn apic_id(self) -> u32 {
+        APIC_IDS[self.0 as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// Record the local APIC id of `cpu`, once its APIC is up.
+pub(super) fn set_apic_id(cpu: usize, id: u32) {
+    APIC_IDS[cpu].store(id, Ordering::Relaxed);
 }
 
 pub fn get() -> CpuId {
-    unsafe { CpuId(Msr::new(0xc0000103).read() as u32) }
+    unsafe { CpuId(hw_thread_id() as u32) }
 }
 
-pub unsafe fn set_current_cpu_id(id: u32) {
-    unsafe { Msr::new(0xc0000103).write(id.into()) }
+/// Give an application processor its cpu id. The ids the bootloader reports are ACPI
+/// processor ids, which needn't be dense or small enough to index per-cpu state with, so
+/// cpus are instead numbered in the order they come up. Returns `None` once there are
+/// [`MAX_CPUS`] cpus.
+pub fn allocate_id() -> Option<usize> {
+    NEXT_ID
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| {
+            (id < MAX_CPUS).then_some(id + 1)
+        })
+        .ok()
 }
 
 pub fn init(core: usize) {
     unsafe {
-        init_hw_thread(core);
+        cpu_local::init_cpu(core).expect("failed to set up cpu local storage");
+        rcu::cpu_online(core);
+        tlb::cpu_online(core);
+        futex::cpu_online();
+        gdt::init_ap(core).expect("failed to set up the GDT and TSS");
+        syscall::init_hw_thread();
         interrupts::without(|_| {
             let mut apic = LOCAL_APIC.get().unwrap().lock();
             apic.enable();
+            set_apic_id(core, apic.id());
         });
     }
 }
@@ -1,19 +1,36 @@
+use alloc::boxed::Box;
 use core::{mem, ops::BitAnd};
 
+use hal::x86_64::tss::Tss;
 use spin::lazy::Lazy;
 use x86_64::{
     instructions::tables::{lgdt, load_tss},
     registers::segmentation::{Segment, CS},
-    structures::{gdt::SegmentSelector, tss::TaskStateSegment, DescriptorTablePointer},
+    structures::{gdt::SegmentSelector, DescriptorTablePointer},
 };
 
-use super::tss::TSS;
+use super::tss::{self, BOOT_TSS};
+use crate::error::KernResult;
 
-static GDT: Lazy<(Gdt, Selectors)> = Lazy::new(build_gdt);
+/// The boot cpu's GDT. Every cpu's GDT has the same layout, so they share selectors, but
+/// each points at its own TSS.
+static GDT: Lazy<(Gdt, Selectors)> = Lazy::new(|| build_gdt(tss::tss_ptr(&BOOT_TSS)));
 
 pub unsafe fn init() {
+    tss::init();
     let (gdt, selectors) = Lazy::force(&GDT);
+    load(gdt, selectors);
+}
+
+/// Give application processor `cpu` a GDT and TSS of its own, and load them.
+pub unsafe fn init_ap(cpu: usize) -> KernResult<()> {
+    let tss = tss::init_ap(cpu)?;
+    let (gdt, selectors) = build_gdt(tss::tss_ptr(tss));
+    load(Box::leak(Box::new(gdt)), &selectors);
+    Ok(())
+}
 
+unsafe fn load(gdt: &'static Gdt, selectors: &Selectors) {
     gdt.load();
     CS::set_reg(selectors.code_segment);
     load_tss(selectors.tss_selector);
@@ -31,15 +48,16 @@ pub struct Selectors {
     pub user_data_selector: SegmentSelector,
 }
 
-fn build_gdt() -> (Gdt, Selectors) {
+fn build_gdt(tss: *const Tss) -> (Gdt, Selectors) {
     let mut gdt = Gdt::new();
-    gdt.tss.set_tss(&TSS);
+    gdt.tss.set_tss(tss);
 
     let selectors = Selectors {
         code_segment: SegmentSelector(0x28),
         tss_selector: SegmentSelector(0x48),
-        user_data_selector: SegmentSelector(0x40),
-        user_code_selector: SegmentSelector(0x40 - 8),
+        // Requested privilege level 3, as these are loaded from userspace.
+        user_data_selector: SegmentSelector(0x38 | 3),
+        user_code_selector: SegmentSelector(0x40 | 3),
     };
 
     (gdt, selectors)
@@ -70,10 +88,10 @@ struct TssEntry {
 }
 
 impl TssEntry {
-    fn set_tss(&mut self, tss: &'static TaskStateSegment) {
-        let addr: *const TaskStateSegment = tss;
-        let addr = addr as usize as u64;
-        self.set_address(addr);
+    fn set_tss(&mut self, tss: *const Tss) {
+        // The limit is inclusive, and covers the I/O permission bitmap too.
+        self.len = (mem::size_of::<Tss>() - 1) as u16;
+        self.set_address(tss as usize as u64);
     }
 
     fn set_address(&mut self, addr: u64) {
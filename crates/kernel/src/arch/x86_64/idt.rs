@@ -1,16 +1,31 @@
-use hal::vm_types::{PhysAddr, VirtAddr};
+use core::ops::Range;
+
+use hal::{
+    vm_types::{PhysAddr, VirtAddr},
+    x86_64::{
+        trap::{self, TrapFrame},
+        usercopy,
+    },
+};
 use log::{error, trace};
-use spin::{mutex::SpinMutex, Lazy, Once};
+use spin::{Lazy, Once};
 use x2apic::lapic::{xapic_base, IpiAllShorthand, LocalApic, LocalApicBuilder};
 use x86_64::{
     registers::control::Cr2,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    structures::idt::{
+        Entry, EntryOptions, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+    },
+    PrivilegeLevel,
 };
 
-use super::interrupts;
+use super::{cpu, interrupts, tlb};
 use crate::{
     arch::IpiTarget,
+    error::{KernErrorKind, KernResult},
     memory::{self, map_physical_addr},
+    process::fault::{self, Fault},
+    sync::spinlock::SpinMutex,
+    task,
 };
 
 pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(build_idt);
@@ -29,7 +44,7 @@ pub unsafe fn init() {
                 .timer_vector(InterruptVector::Timer as usize)
                 .set_xapic_base(apic_virtual_address.as_usize() as u64)
                 .build()
-                .map(SpinMutex::new)
+                .map(|apic| SpinMutex::new(apic, lockdep::class!("LOCAL_APIC")))
         })
         .expect("local apic init error")
         .lock();
@@ -39,20 +54,25 @@ pub unsafe fn init() {
     }
 
     trace!("apic id := {}", apic.id());
+    cpu::set_apic_id(0, apic.id());
 
     interrupts::enable();
 }
 
 pub unsafe fn send_ipi(target: IpiTarget) {
+    send_ipi_on(InterruptVector::Ipi, target);
+}
+
+pub(super) unsafe fn send_ipi_on(vector: InterruptVector, target: IpiTarget) {
     let mut apic = LOCAL_APIC.get().unwrap().lock();
-    let vector = InterruptVector::Ipi as u8;
+    let vector = vector as u8;
 
     match target {
         IpiTarget::Others => unsafe {
             apic.send_ipi_all(vector, IpiAllShorthand::AllExcludingSelf)
         },
         IpiTarget::Single(cpu) => {
-            unsafe { apic.send_ipi(vector, cpu.0) };
+            unsafe { apic.send_ipi(vector, cpu.apic_id()) };
         }
     }
 }
@@ -61,52 +81,203 @@ pub static LOCAL_APIC: Once<SpinMutex<LocalApic>> = Once::new();
 
 #[repr(u8)]
 #[derive(Debug)]
-enum InterruptVector {
+pub(super) enum InterruptVector {
     Timer = 32,
     LocalApicError = 40,
     SpuriousInterrupt = 41,
     Ipi = 42,
+    TlbShootdown = 43,
     Syscall = 0x80,
 }
 
+/// The vectors [`allocate_vector`] hands out to devices.
+pub const DEVICE_VECTORS: Range<u8> = 48..112;
+
+pub const DEVICE_VECTOR_COUNT: usize = (DEVICE_VECTORS.end - DEVICE_VECTORS.start) as usize;
+
+/// Called with interrupts disabled when a device interrupts on `vector`. The local APIC
+/// is sent its end of interrupt once this returns, so a level-triggered source must be
+/// masked or quietened first.
+pub type DeviceHandler = fn(vector: u8);
+
+static DEVICE_HANDLERS: SpinMutex<[Option<DeviceHandler>; DEVICE_VECTOR_COUNT]> = SpinMutex::new(
+    [None; DEVICE_VECTOR_COUNT],
+    lockdep::class!("DEVICE_HANDLERS"),
+);
+
+/// Claim a free device vector, and have `handler` called when it fires.
+pub fn allocate_vector(handler: DeviceHandler) -> KernResult<u8> {
+    hal::interrupts::without(|_| {
+        let mut handlers = DEVICE_HANDLERS.lock();
+        let index = handlers
+            .iter()
+            .position(Option::is_none)
+            .ok_or(KernErrorKind::Busy)?;
+        handlers[index] = Some(handler);
+        Ok(DEVICE_VECTORS.start + index as u8)
+    })
+}
+
+/// Give back a vector from [`allocate_vector`]. Its source must no longer be able to fire.
+pub fn free_vector(vector: u8) {
+    let index = usize::from(vector - DEVICE_VECTORS.start);
+    hal::interrupts::without(|_| DEVICE_HANDLERS.lock()[index] = None);
+}
+
+/// The names of the exceptions, by vector.
+const EXCEPTION_NAMES: [&str; 32] = [
+    "divide error",
+    "debug",
+    "non-maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid TSS",
+    "segment not present",
+    "stack segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved exception 15",
+    "x87 floating point exception",
+    "alignment check",
+    "machine check",
+    "SIMD floating point exception",
+    "virtualization exception",
+    "control protection exception",
+    "reserved exception 22",
+    "reserved exception 23",
+    "reserved exception 24",
+    "reserved exception 25",
+    "reserved exception 26",
+    "reserved exception 27",
+    "hypervisor injection exception",
+    "VMM communication exception",
+    "security exception",
+    "reserved exception 31",
+];
+
+const BREAKPOINT: u8 = 3;
+const GENERAL_PROTECTION: u8 = 13;
+const PAGE_FAULT: u8 = 14;
+
 fn build_idt() -> InterruptDescriptorTable {
     let mut idt = InterruptDescriptorTable::new();
 
-    idt.breakpoint.set_handler_fn(breakpoint_handler);
-    idt[32].set_handler_fn(timer_handler);
-
     unsafe {
-        idt.general_protection_fault
-            .set_handler_fn(general_protection_fault_handler)
-            .set_stack_index(0);
+        trap::set_handler(handle_trap);
+
+        // Everything goes through the trap stubs except the double fault, which has a
+        // stack of its own to survive a kernel stack overflow, and the NMI and machine
+        // check, which can arrive in the middle of a swap of the GS base.
+        set_trap(&mut idt.divide_error, 0);
+        set_trap(&mut idt.debug, 1);
+        set_trap(&mut idt.breakpoint, BREAKPOINT).set_privilege_level(PrivilegeLevel::Ring3);
+        set_trap(&mut idt.overflow, 4);
+        set_trap(&mut idt.bound_range_exceeded, 5);
+        set_trap(&mut idt.invalid_opcode, 6);
+        set_trap(&mut idt.device_not_available, 7);
+        set_trap(&mut idt.invalid_tss, 10);
+        set_trap(&mut idt.segment_not_present, 11);
+        set_trap(&mut idt.stack_segment_fault, 12);
+        set_trap(&mut idt.general_protection_fault, 13);
+        set_trap(&mut idt.page_fault, PAGE_FAULT);
+        set_trap(&mut idt.x87_floating_point, 16);
+        set_trap(&mut idt.alignment_check, 17);
+        set_trap(&mut idt.simd_floating_point, 19);
+        set_trap(&mut idt.virtualization, 20);
+        set_trap(&mut idt.cp_protection_exception, 21);
+        set_trap(&mut idt.vmm_communication_exception, 29);
+        set_trap(&mut idt.security_exception, 30);
+
+        for vector in [
+            InterruptVector::Timer as u8,
+            InterruptVector::LocalApicError as u8,
+            InterruptVector::SpuriousInterrupt as u8,
+            InterruptVector::TlbShootdown as u8,
+        ]
+        .into_iter()
+        .chain(DEVICE_VECTORS)
+        {
+            set_trap(&mut idt[usize::from(vector)], vector);
+        }
 
         idt.double_fault
             .set_handler_fn(double_fault_handler)
             .set_stack_index(0);
-
-        idt.page_fault
-            .set_handler_fn(page_fault_handler)
-            .set_stack_index(0);
     };
 
     idt
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error: PageFaultErrorCode,
-) {
+/// Point `entry` at the trap stub for `vector`.
+unsafe fn set_trap<F>(entry: &mut Entry<F>, vector: u8) -> &mut EntryOptions {
+    let addr = trap::entry(vector).as_usize() as u64;
+    entry.set_handler_addr(x86_64::VirtAddr::new(addr))
+}
+
+fn handle_trap(frame: &mut TrapFrame) {
+    const TIMER: u8 = InterruptVector::Timer as u8;
+    const LOCAL_APIC_ERROR: u8 = InterruptVector::LocalApicError as u8;
+    const SPURIOUS_INTERRUPT: u8 = InterruptVector::SpuriousInterrupt as u8;
+    const TLB_SHOOTDOWN: u8 = InterruptVector::TlbShootdown as u8;
+
+    match frame.vector() {
+        vector @ 0..=31 => exception(vector, frame),
+        TIMER => timer_interrupt(),
+        LOCAL_APIC_ERROR => apic_error_interrupt(),
+        SPURIOUS_INTERRUPT => spurious_interrupt(),
+        TLB_SHOOTDOWN => tlb_shootdown_interrupt(),
+        vector if DEVICE_VECTORS.contains(&vector) => device_interrupt(vector),
+        vector => panic!("unexpected interrupt on vector {}", vector),
+    }
+
+    // This is where a task running in ring 3 finds out that its process was terminated.
+    if frame.is_user() {
+        task::exit_if_terminating();
+    }
+}
+
+fn exception(vector: u8, frame: &mut TrapFrame) {
+    let name = EXCEPTION_NAMES[usize::from(vector)];
+
+    // Whatever userspace did wrong is the process's problem, not the kernel's.
+    if frame.is_user() {
+        fault::kill(Fault {
+            name,
+            rip: frame.rip,
+            error_code: frame.error_code,
+            addr: (vector == PAGE_FAULT).then(|| Cr2::read_raw() as usize),
+        });
+    }
+
+    // A copy to or from user memory that hit a bad user address stops short instead.
+    if matches!(vector, GENERAL_PROTECTION | PAGE_FAULT) {
+        if let Some(fixup) = usercopy::fixup(frame.rip) {
+            frame.rip = fixup;
+            return;
+        }
+    }
+
+    match vector {
+        BREAKPOINT => trace!("breakpoint at {:#x}", frame.rip),
+        PAGE_FAULT => kernel_page_fault(frame),
+        _ => panic!("{} at {:#x}: {:#x?}", name, frame.rip, frame),
+    }
+}
+
+fn kernel_page_fault(frame: &TrapFrame) {
+    let error = PageFaultErrorCode::from_bits_truncate(frame.error_code as u64);
     if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
         panic!(
-            "page fault at {:p}: {:?}: {:#?}",
+            "page fault at {:p}: {:?}: {:#x?}",
             Cr2::read(),
             error,
-            stack_frame
+            frame
         );
-    } else if error.contains(PageFaultErrorCode::USER_MODE) {
-        // Check if address is within user's allocated heap region to determine if it's
-        // safe to map.
-        todo!("userspace lazy page mapping")
     }
 
     trace!("page fault, handling as lazily mapped page");
@@ -115,13 +286,6 @@ extern "x86-interrupt" fn page_fault_handler(
     unsafe { memory::handle_page_fault(addr).expect("page fault failure") };
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
-    panic!("general protection fault");
-}
-
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
@@ -129,12 +293,26 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("double fault: {:#?}: {:#?}", stack_frame, error_code);
 }
 
-extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
-    trace!("breakpoint");
+fn timer_interrupt() {
+    trace!("timer");
+
+    unsafe {
+        if let Some(apic) = LOCAL_APIC.get() {
+            apic.lock().end_of_interrupt();
+        }
+    }
+
+    // This may switch away from the interrupted task, so it must come after the EOI.
+    task::tick();
 }
 
-extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
-    trace!("timer");
+fn device_interrupt(vector: u8) {
+    let index = usize::from(vector - DEVICE_VECTORS.start);
+    let handler = DEVICE_HANDLERS.lock()[index];
+    match handler {
+        Some(handler) => handler(vector),
+        None => trace!("unclaimed device interrupt on vector {}", vector),
+    }
 
     unsafe {
         if let Some(apic) = LOCAL_APIC.get() {
@@ -143,7 +321,15 @@ extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
     }
 }
 
-extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn tlb_shootdown_interrupt() {
+    tlb::acknowledge();
+
+    unsafe {
+        LOCAL_APIC.get().unwrap().lock().end_of_interrupt();
+    }
+}
+
+fn spurious_interrupt() {
     trace!("spurious interrupt");
 
     unsafe {
@@ -152,7 +338,7 @@ extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
-extern "x86-interrupt" fn apic_error_handler(_stack_frame: InterruptStackFrame) {
+fn apic_error_interrupt() {
     let mut lapic = LOCAL_APIC.get().unwrap().lock();
 
     error!("apic error: {:?}", unsafe { lapic.error_flags() });
@@ -0,0 +1,64 @@
+//! The I/O APIC, which routes legacy and PCI interrupt lines to vectors.
+
+use hal::{interrupts, vm_types::PhysAddr};
+use spin::Lazy;
+use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
+
+use super::idt::LOCAL_APIC;
+use crate::{
+    error::{KernErrorKind, KernResult},
+    memory::map_physical_addr,
+    sync::spinlock::SpinMutex,
+};
+
+/// Where the firmware puts the I/O APIC. Until the MADT is parsed this is the only one
+/// the kernel knows of, so only its lines can be routed.
+const IO_APIC_BASE: usize = 0xfec0_0000;
+
+static IO_APIC: Lazy<SpinMutex<IoApic>> = Lazy::new(|| unsafe {
+    let addr = map_physical_addr(PhysAddr::from_usize(IO_APIC_BASE));
+    SpinMutex::new(
+        IoApic::new(addr.as_usize() as u64),
+        lockdep::class!("IO_APIC"),
+    )
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// Active high and edge triggered, as ISA lines are.
+    Edge,
+    /// Active low and level triggered, as PCI lines are.
+    Level,
+}
+
+/// Route line `gsi` to `vector` on the current cpu. The line is left masked.
+pub fn route(gsi: u8, vector: u8, trigger: Trigger) -> KernResult<()> {
+    interrupts::without(|_| {
+        let mut io_apic = IO_APIC.lock();
+        if gsi > unsafe { io_apic.max_table_entry() } {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+
+        let dest = unsafe { LOCAL_APIC.get().ok_or(KernErrorKind::Fault)?.lock().id() };
+        let mut flags = IrqFlags::MASKED;
+        if trigger == Trigger::Level {
+            flags |= IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE;
+        }
+
+        let mut entry = RedirectionTableEntry::default();
+        entry.set_vector(vector);
+        entry.set_mode(IrqMode::Fixed);
+        entry.set_flags(flags);
+        entry.set_dest(dest as u8);
+        unsafe { io_apic.set_table_entry(gsi, entry) };
+        Ok(())
+    })
+}
+
+pub fn mask(gsi: u8) {
+    interrupts::without(|_| unsafe { IO_APIC.lock().disable_irq(gsi) });
+}
+
+pub fn unmask(gsi: u8) {
+    interrupts::without(|_| unsafe { IO_APIC.lock().enable_irq(gsi) });
+}
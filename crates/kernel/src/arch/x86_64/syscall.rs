@@ -1,30 +1,19 @@
-use core::arch::asm;
+use hal::{vm_types::VirtAddr, x86_64::syscall};
 
-use hal::vm_types::VirtAddr;
+use super::{gdt, tss};
 
-// pub unsafe fn enable_syscall() {}
-#[naked]
-pub unsafe extern "C" fn sysret(
-    rip: /* edi */ VirtAddr,
-    rsp: /* esi */ VirtAddr,
-    rflags: /* edx */ usize,
-) {
-    asm!(
-        "
-        mov rcx, 0xc0000082
-        wrmsr
-        mov rcx, 0xc0000080
-        rdmsr
-        or eax, 1
-        wrmsr
-        mov rcx, 0xc0000081
-        rdmsr
-        mov edx, 0x00180008
-        wrmsr
-        
-        mov ecx, edi
-        mov r11, 0x202
-        sysret",
-        options(noreturn)
-    );
+/// Enable `syscall` on the current cpu.
+///
+/// Syscalls enter on the kernel stack of the task making them, which
+/// [`set_kernel_stack`] switches to ahead of running each task of a process.
+pub unsafe fn init_hw_thread() {
+    let selectors = gdt::selectors();
+    syscall::init_hw_thread(selectors.code_segment.0, selectors.user_data_selector.0);
+}
+
+/// Have syscalls, interrupts and exceptions from ring 3 on this cpu enter on the stack
+/// ending at `top`. Must be called with interrupts disabled.
+pub unsafe fn set_kernel_stack(top: usize) {
+    syscall::set_kernel_stack(top as *mut u8);
+    tss::set_privilege_stack(VirtAddr::from_usize(top));
 }
@@ -0,0 +1,170 @@
+//! TLB shootdowns.
+//!
+//! Unmapping a page only flushes it from the current cpu's TLB. Any other cpu may still
+//! hold the old translation, so before the frame behind it can be reused every other
+//! online cpu is sent an IPI and flushes the range itself. One shootdown is in flight at
+//! a time, and the initiator spins until everyone has acknowledged it.
+//!
+//! A shootdown can also retire a process's page table, moving any cpu still running on it
+//! to another. Loading a page table flushes everything below the kernel half, and the
+//! kernel maps no global pages, so cpus that switched away earlier have nothing cached.
+
+use core::{
+    hint,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use hal::{
+    interrupts,
+    task::hw_thread_id,
+    vm_types::{PhysAddr, VirtRegion},
+    x86_64::{instr::invlpg, reg::cr3},
+};
+
+use super::idt::{self, InterruptVector};
+use crate::{arch::IpiTarget, sync::spinlock::SpinMutex};
+
+/// Past this many pages it's cheaper to flush the whole TLB than page by page.
+const MAX_INVLPG_PAGES: usize = 32;
+
+/// Cpus that may have translations cached and must take part in shootdowns.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Held by the cpu whose shootdown is in flight.
+static IN_FLIGHT: SpinMutex<()> = SpinMutex::new((), lockdep::class!("IN_FLIGHT"));
+
+/// The range being shot down, as addresses. Only changed while no cpu is pending.
+static START: AtomicUsize = AtomicUsize::new(0);
+static END: AtomicUsize = AtomicUsize::new(0);
+
+/// The page table being retired and the one to move to, or zero if none is. Only changed
+/// while no cpu is pending.
+static OLD_ROOT: AtomicUsize = AtomicUsize::new(0);
+static NEW_ROOT: AtomicUsize = AtomicUsize::new(0);
+
+/// Cpus that have yet to act on the shootdown in flight.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Start taking part in shootdowns. Called on each cpu as it is brought up.
+pub fn cpu_online(cpu: usize) {
+    ONLINE.fetch_or(1 << cpu, Ordering::AcqRel);
+}
+
+/// Flush `region` from the TLB of every cpu, this one included.
+///
+/// This must not be called with a spinlock held that another cpu might be waiting for
+/// with interrupts disabled, as that cpu would never see the IPI.
+pub fn shootdown(region: VirtRegion) {
+    broadcast(Request {
+        start: region.start.addr().as_usize(),
+        end: region.end.addr().as_usize(),
+        old_root: 0,
+        new_root: 0,
+    });
+}
+
+/// Move every cpu running on the page table at `root` to the one at `replacement`. Once
+/// this returns, no cpu walks `root` or has anything it maps cached.
+///
+/// The same restriction on spinlocks applies as for [`shootdown`].
+pub fn retire(root: PhysAddr, replacement: PhysAddr) {
+    broadcast(Request {
+        start: 0,
+        end: 0,
+        old_root: root.as_usize(),
+        new_root: replacement.as_usize(),
+    });
+}
+
+/// What a shootdown asks of each cpu.
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    start: usize,
+    end: usize,
+    old_root: usize,
+    new_root: usize,
+}
+
+impl Request {
+    fn load() -> Self {
+        Self {
+            start: START.load(Ordering::Relaxed),
+            end: END.load(Ordering::Relaxed),
+            old_root: OLD_ROOT.load(Ordering::Relaxed),
+            new_root: NEW_ROOT.load(Ordering::Relaxed),
+        }
+    }
+
+    fn store(self) {
+        START.store(self.start, Ordering::Relaxed);
+        END.store(self.end, Ordering::Relaxed);
+        OLD_ROOT.store(self.old_root, Ordering::Relaxed);
+        NEW_ROOT.store(self.new_root, Ordering::Relaxed);
+    }
+
+    fn apply(self) {
+        flush(self.start, self.end);
+        unsafe {
+            if self.old_root != 0 && cr3::read() & !0xfff == self.old_root {
+                cr3::write(self.new_root);
+            }
+        }
+    }
+}
+
+fn broadcast(request: Request) {
+    interrupts::without(|_| {
+        request.apply();
+
+        let this = 1 << unsafe { hw_thread_id() };
+        let others = ONLINE.load(Ordering::Acquire) & !this;
+        if others == 0 {
+            return;
+        }
+
+        // Two cpus starting shootdowns at once each wait with interrupts disabled, so
+        // keep answering the other's while waiting for a turn.
+        let _in_flight = loop {
+            if let Some(guard) = IN_FLIGHT.try_lock() {
+                break guard;
+            }
+            acknowledge();
+            hint::spin_loop();
+        };
+
+        request.store();
+        PENDING.store(others, Ordering::Release);
+
+        unsafe { idt::send_ipi_on(InterruptVector::TlbShootdown, IpiTarget::Others) };
+
+        while PENDING.load(Ordering::Acquire) != 0 {
+            hint::spin_loop();
+        }
+    });
+}
+
+/// Flush the range in flight if the current cpu hasn't yet. Called from the shootdown
+/// IPI, with interrupts disabled.
+pub(super) fn acknowledge() {
+    let this = 1 << unsafe { hw_thread_id() };
+    if PENDING.load(Ordering::Acquire) & this == 0 {
+        return;
+    }
+
+    Request::load().apply();
+    PENDING.fetch_and(!this, Ordering::AcqRel);
+}
+
+fn flush(start: usize, end: usize) {
+    let pages = (end - start) / 4096;
+    unsafe {
+        if pages > MAX_INVLPG_PAGES {
+            // Global pages survive this, but the kernel doesn't map any.
+            cr3::write(cr3::read());
+        } else {
+            for addr in (start..end).step_by(4096) {
+                invlpg(addr as *const ());
+            }
+        }
+    }
+}
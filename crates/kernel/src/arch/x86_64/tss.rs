@@ -1,19 +1,127 @@
-use core::mem::MaybeUninit;
+use alloc::boxed::Box;
+use core::{
+    mem::MaybeUninit,
+    num::NonZeroU8,
+    ops::RangeInclusive,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 
-use spin::Lazy;
-use x86_64::structures::tss::TaskStateSegment;
+use hal::{
+    percpu::MAX_CPUS,
+    task::hw_thread_id,
+    vm_types::VirtAddr,
+    x86_64::tss::{IstIndex, Tss},
+};
+use lockdep::LockClass;
 
-pub static TSS: Lazy<TaskStateSegment> = Lazy::new(|| unsafe { build_tss() });
+use crate::{
+    error::{KernErrorKind, KernResult},
+    memory::{AddrSpace, AllocOptions},
+    sync::spinlock::SpinMutex,
+};
 
-unsafe fn build_tss() -> TaskStateSegment {
-    static mut EXCEPTION_STACK: [MaybeUninit<u8>; 8192] = MaybeUninit::uninit_array();
-    static mut PRIVILEGE_STACK: [MaybeUninit<u8>; 8192] = MaybeUninit::uninit_array();
+const EXCEPTION_STACK_SIZE: usize = 8192;
 
-    let top = EXCEPTION_STACK.as_mut_ptr_range().end;
+/// A cpu's TSS, and with it the I/O permission bitmap of whatever task it is running.
+#[derive(Debug)]
+pub struct CpuTss {
+    tss: Tss,
+    /// Whether the ports of some process are open in the bitmap, so that switching
+    /// between tasks without any can skip it.
+    ports_open: bool,
+}
+
+impl CpuTss {
+    const fn new() -> Self {
+        Self {
+            tss: Tss::new(),
+            ports_open: false,
+        }
+    }
+}
+
+/// The TSS itself, for a GDT to point at.
+pub fn tss_ptr(tss: &SpinMutex<CpuTss>) -> *const Tss {
+    unsafe { ptr::addr_of!((*tss.as_mut_ptr()).tss) }
+}
+
+static CLASS: LockClass = LockClass::new("TSS");
+
+/// The boot cpu's TSS, which is needed before there is a heap to allocate one from.
+pub static BOOT_TSS: SpinMutex<CpuTss> = SpinMutex::new(CpuTss::new(), &CLASS);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TSS: AtomicPtr<SpinMutex<CpuTss>> = AtomicPtr::new(ptr::null_mut());
+
+/// Each cpu's TSS, once it has been brought up.
+static TSSES: [AtomicPtr<SpinMutex<CpuTss>>; MAX_CPUS] = [NO_TSS; MAX_CPUS];
+
+/// Set up the boot cpu's double fault stack. The stack for entering from ring 3 is left
+/// for [`set_privilege_stack`], as it belongs to whichever task is running.
+pub unsafe fn init() {
+    static mut EXCEPTION_STACK: [MaybeUninit<u8>; EXCEPTION_STACK_SIZE] =
+        MaybeUninit::uninit_array();
+
+    let top = VirtAddr::from_ptr(EXCEPTION_STACK.as_mut_ptr_range().end);
+    BOOT_TSS.lock().tss.set_ist(double_fault_ist(), top);
+    TSSES[0].store(&BOOT_TSS as *const _ as *mut _, Ordering::Release);
+}
+
+/// Give `cpu` a TSS of its own, with its own double fault stack, and return it for the
+/// cpu's GDT to point at.
+pub fn init_ap(cpu: usize) -> KernResult<&'static SpinMutex<CpuTss>> {
+    let slot = TSSES.get(cpu).ok_or(KernErrorKind::InvalidArgument)?;
+
+    let stack = AllocOptions::new(EXCEPTION_STACK_SIZE)
+        .start_guard_pages(1)
+        .allocate_in_address_space(&AddrSpace::Kernel)?;
+    let top = unsafe { VirtAddr::from_ptr(stack.as_mut_ptr().add(stack.len())) };
+
+    let mut tss = CpuTss::new();
+    tss.tss.set_ist(double_fault_ist(), top);
+    let tss: &'static SpinMutex<CpuTss> = Box::leak(Box::new(SpinMutex::new(tss, &CLASS)));
+
+    let previous = slot.swap(tss as *const _ as *mut _, Ordering::AcqRel);
+    debug_assert!(previous.is_null(), "cpu {cpu} given a TSS twice");
+    Ok(tss)
+}
+
+fn double_fault_ist() -> IstIndex {
+    IstIndex(NonZeroU8::new(1).unwrap())
+}
+
+/// The current cpu's TSS. Must be called with interrupts disabled.
+fn current() -> &'static SpinMutex<CpuTss> {
+    let tss = TSSES[unsafe { hw_thread_id() }].load(Ordering::Acquire);
+    assert!(!tss.is_null(), "cpu has no TSS");
+    unsafe { &*tss }
+}
+
+/// Have interrupts and exceptions from ring 3 on this cpu enter on the stack ending at
+/// `top`. Must be called with interrupts disabled.
+pub fn set_privilege_stack(top: VirtAddr) {
+    current().lock().tss.set_privilege_stack(top);
+}
+
+/// Open exactly `ports` to ring 3 on this cpu, closing whatever was open before. Must be
+/// called with interrupts disabled.
+pub fn set_io_ports<I>(ports: I)
+where
+    I: IntoIterator<Item = RangeInclusive<u16>>,
+{
+    let mut ports = ports.into_iter().peekable();
+    let open = ports.peek().is_some();
+
+    let mut tss = current().lock();
+    if !open && !tss.ports_open {
+        return;
+    }
 
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[0] = x86_64::VirtAddr::from_ptr(top);
-    tss.privilege_stack_table[0] =
-        x86_64::VirtAddr::from_ptr(PRIVILEGE_STACK.as_mut_ptr_range().end);
-    tss
+    let bitmap = tss.tss.io_bitmap_mut();
+    bitmap.deny_all();
+    for range in ports {
+        bitmap.allow(range);
+    }
+    tss.ports_open = open;
 }
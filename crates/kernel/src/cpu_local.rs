@@ -1,29 +1,38 @@
+//! Per-cpu storage.
+//!
+//! Statics declared with [`cpu_local!`] are placed in the `.percpu` section, which serves
+//! as a template: each cpu gets its own copy of the section when it is brought up, and its
+//! GS base points at that copy. Finding a cpu local is then a GS-relative load plus an
+//! offset, with no lookup by cpu id.
+
 use alloc::boxed::Box;
 use core::{
-    cell::Cell,
     mem::{self, ManuallyDrop},
     ptr::{self, NonNull},
     sync::atomic::{AtomicPtr, Ordering},
 };
 
+pub use hal::percpu::MAX_ALIGN;
 use hal::{
     interrupts::{self, WithoutInterrupts},
+    percpu::{self, HEADER_SIZE},
     task::hw_thread_id,
 };
 use log::trace;
 use spin::Once;
 
 use crate::{
-    arch::cpu::CpuId,
+    arch::CpuMask,
     error::{KernErrorKind, KernResult},
     memory::{AddrSpace, AllocOptions},
 };
 
 extern "C" {
-    static __percpu_start: [u8; 4];
-    static __percpu_stop: [u8; 4];
+    static __percpu_start: [u8; 0];
+    static __percpu_stop: [u8; 0];
 }
 
+/// A lazily initialized object for each cpu, for values that can't be known statically.
 pub struct CpuLocal<T>
 where
     T: Send,
@@ -35,8 +44,18 @@ impl<T> CpuLocal<T>
 where
     T: Send,
 {
+    pub fn new() -> Self {
+        Self {
+            objects: (0..CpuMask::MAX_CPUS).map(|_| Once::new()).collect(),
+        }
+    }
+
+    fn slot(&self) -> &Once<T> {
+        &self.objects[unsafe { hw_thread_id() }]
+    }
+
     pub fn get<'a>(&'a self, _guard: &'a WithoutInterrupts) -> Option<&'a T> {
-        todo!()
+        self.slot().get()
     }
 
     pub fn get_or_try_init<'a, F, E>(
@@ -47,14 +66,23 @@ where
     where
         F: FnOnce() -> Result<T, E>,
     {
-        todo!()
+        self.slot().try_call_once(init)
     }
 
     pub fn get_or_init<'a, F>(&'a self, init: F, _guard: &'a WithoutInterrupts) -> &'a T
     where
         F: FnOnce() -> T,
     {
-        todo!()
+        self.slot().call_once(init)
+    }
+}
+
+impl<T> Default for CpuLocal<T>
+where
+    T: Send,
+{
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -63,45 +91,68 @@ unsafe impl<T> Sync for CpuLocal<T> where T: Send {}
 static SECTIONS: [AtomicPtr<u8>; 64] = unsafe { mem::transmute([ptr::null_mut::<u8>(); 64]) };
 
 fn percpu_section_size() -> usize {
-    unsafe { __percpu_stop.as_ptr_range().end as usize - __percpu_start.as_ptr() as usize }
+    unsafe { __percpu_stop.as_ptr() as usize - __percpu_start.as_ptr() as usize }
+}
+
+/// Give `cpu` its own copy of the `.percpu` section and install it as the current cpu's
+/// per-cpu area. This must be called on `cpu` itself, once, as it is brought up.
+pub unsafe fn init_cpu(cpu: usize) -> KernResult<()> {
+    let slot = SECTIONS.get(cpu).ok_or(KernErrorKind::Fault)?;
+
+    trace!("allocating cpu local section for cpu {}", cpu);
+
+    let size = percpu_section_size();
+    let area =
+        AllocOptions::new(HEADER_SIZE + size).allocate_in_address_space(&AddrSpace::Kernel)?;
+    let data = area.as_mut_ptr().add(HEADER_SIZE);
+
+    ptr::copy_nonoverlapping(__percpu_start.as_ptr(), data, size);
+
+    let previous = slot.swap(data, Ordering::AcqRel);
+    debug_assert!(previous.is_null(), "cpu {cpu} initialized twice");
+
+    percpu::install(area.as_mut_ptr(), cpu, size);
+    Ok(())
+}
+
+/// Offset of a cpu local from the start of the section.
+fn offset_of<T>(ptr: NonNull<T>) -> usize {
+    ptr.as_ptr() as usize - unsafe { __percpu_start.as_ptr() } as usize
 }
 
 unsafe fn get_percpu_ptr<T>(ptr: NonNull<T>, cpu: usize) -> KernResult<NonNull<T>> {
-    let offset = ptr.as_ptr() as usize - __percpu_start.as_ptr() as usize;
     let section = get_cpu_section(cpu)?;
-    Ok(NonNull::new_unchecked(section.as_ptr().add(offset).cast()))
+    Ok(NonNull::new_unchecked(
+        section.as_ptr().add(offset_of(ptr)).cast(),
+    ))
 }
 
 fn get_cpu_section(cpu: usize) -> KernResult<NonNull<u8>> {
     let atomic = SECTIONS.get(cpu).ok_or(KernErrorKind::Fault)?;
-    let ptr = atomic.load(Ordering::Acquire);
-    if let Some(ptr) = NonNull::new(ptr) {
-        Ok(ptr)
-    } else {
-        trace!("allocating cpu local section for {:?}", CpuId::get());
-
-        let pages = AllocOptions::new(percpu_section_size())
-            .allocate_in_address_space(&AddrSpace::Kernel)?;
-
-        unsafe {
-            ptr::copy_nonoverlapping(
-                __percpu_start.as_ptr(),
-                pages.as_mut_ptr(),
-                percpu_section_size(),
-            )
-        };
-
-        atomic.store(pages.as_mut_ptr(), Ordering::Release);
-        Ok(pages.as_non_null_ptr())
-    }
+    NonNull::new(atomic.load(Ordering::Acquire)).ok_or_else(|| KernErrorKind::Fault.into())
 }
 
+/// Declare one or more cpu local statics, each of which is a [`LocalKey`].
+///
+/// ```ignore
+/// cpu_local! {
+///     static COUNTER: Cell<usize> = Cell::new(0);
+/// }
+/// ```
 macro_rules! cpu_local {
-    ($vis:vis static $name:ident : $t:ty = $init:expr;) => {
-        $vis static $name : $crate::cpu_local::LocalKey<$t> = unsafe { $crate::cpu_local::LocalKey::__new($init) };
-    };
+    ($($(#[$attr:meta])* $vis:vis static $name:ident : $t:ty = $init:expr;)*) => {$(
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        $vis static $name : $crate::cpu_local::LocalKey<$t> = {
+            const _: () = assert!(core::mem::align_of::<$t>() <= $crate::cpu_local::MAX_ALIGN);
+            unsafe { $crate::cpu_local::LocalKey::__new($init) }
+        };
+    )*};
 }
 
+pub(crate) use cpu_local;
+
+/// A cpu local static. The static itself is only a template, never accessed directly.
 #[repr(transparent)]
 #[derive(Debug)]
 pub struct LocalKey<T>(ManuallyDrop<T>);
@@ -116,10 +167,21 @@ impl<T> LocalKey<T> {
         unsafe { self.get_raw().map(|ptr| ptr.as_ref()) }
     }
 
+    /// Get a pointer to the current cpu's copy. This fails if the cpu's area has not been
+    /// set up yet.
     pub unsafe fn get_raw(&self) -> KernResult<NonNull<T>> {
-        let cpu = hw_thread_id();
-        let init = NonNull::from(self).cast::<T>();
-        get_percpu_ptr(init, cpu)
+        let offset = offset_of(NonNull::from(self));
+        if offset + mem::size_of::<T>() > percpu::local_data_size() {
+            return Err(KernErrorKind::Fault.into());
+        }
+        Ok(NonNull::new_unchecked(
+            percpu::local_data().add(offset).cast(),
+        ))
+    }
+
+    /// Get a pointer to another cpu's copy.
+    pub unsafe fn get_raw_on(&self, cpu: usize) -> KernResult<NonNull<T>> {
+        get_percpu_ptr(NonNull::from(self).cast(), cpu)
     }
 
     pub fn with<F, R>(&self, f: F) -> KernResult<R>
@@ -131,13 +193,3 @@ impl<T> LocalKey<T> {
 }
 
 unsafe impl<T> Sync for LocalKey<T> where T: Send {}
-
-cpu_local! {
-    static COUNTER: Cell<usize> = Cell::new(0);
-}
-
-fn use_percpu() {
-    interrupts::without(|guard| {
-        let value = COUNTER.try_get(guard).unwrap();
-    });
-}
@@ -1,34 +1,43 @@
-use alloc::boxed::Box;
+//! Kernel errors.
+//!
+//! A [`KernError`] is a [`KernErrorKind`], which is all userspace ever sees, and
+//! optionally an error object explaining it. The object lives in a global registry so
+//! that `KernError` stays the size of a pointer: an error only holds a handle to its slot,
+//! and frees it when dropped. Context added with [`KernError::context`] is itself such an
+//! object, whose source is the error it wraps.
+//!
+//! `{}` prints the outermost message, `{:#}` the whole chain of causes, and `{:?}` the kind
+//! followed by the chain.
+
+use alloc::{boxed::Box, vec::Vec};
 use core::{
     alloc::AllocError as StdAllocError,
+    cell::UnsafeCell,
     error::Error,
-    fmt::Display,
-    num::{NonZeroU32, NonZeroU8},
-    sync::atomic::AtomicPtr,
+    fmt::{self, Display},
+    iter, mem,
+    num::NonZeroU32,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
 };
 
-use hal::vm_types::{FrameAllocError, PageTableError};
+use hal::{
+    interrupts,
+    vm_types::{FrameAllocError, PageLookupError, PageTableError},
+};
 use spin::mutex::SpinMutex;
 
-/// General error kind. This is what is typically passed to userspace. Internal errors
-/// (`KernError`) holds much more context.
-#[non_exhaustive]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum KernErrorKind {
-    AllocError,
-    /// An internal kernel error.
-    Fault,
-}
-
 /// Kernel error type.
 ///
 /// We need to keep this the same size as a pointer, bonus points if there is a niche
 /// optimization available.
-#[derive(Debug)]
 pub struct KernError {
     inner: ErrorInner,
 }
 
+const _: () = assert!(mem::size_of::<KernError>() == mem::size_of::<usize>());
+const _: () = assert!(mem::size_of::<KernResult<()>>() == mem::size_of::<usize>());
+
 #[derive(Debug)]
 enum ErrorInner {
     Dyn {
@@ -49,26 +58,86 @@ impl ErrorInner {
     }
 }
 
-#[derive(Debug)]
-enum AllocErrorInner {
-    Std,
-}
+impl KernError {
+    /// An error of `kind`, explained by `error`. If the registry is full, only the kind is
+    /// kept.
+    pub fn new<E>(kind: KernErrorKind, error: E) -> Self
+    where
+        E: Error + Send + Sync + 'static,
+    {
+        let handle = Box::try_new(error)
+            .ok()
+            .and_then(|error| REGISTRY.register_boxed(error).ok());
+        Self::with_handle(kind, handle)
+    }
 
-type O = Option<KernError>;
+    /// Wrap this error in `context`, keeping its kind. If the registry or the heap is
+    /// full, only the kind is kept.
+    pub fn context<C>(self, context: C) -> Self
+    where
+        C: Display + Send + Sync + 'static,
+    {
+        let kind = self.kind();
+        let handle = REGISTRY.register_with(|| {
+            Box::try_new(ContextError {
+                context,
+                source: self,
+            })
+            .ok()
+            .map(|error| error as BoxError)
+        });
+        Self::with_handle(kind, handle)
+    }
 
-impl KernError {
     pub fn kind(&self) -> KernErrorKind {
         self.inner.kind()
     }
 
-    pub fn as_error_code(&self) -> u32 {
-        self.kind() as u32
+    /// This error followed by each of its causes, outermost first.
+    pub fn chain(&self) -> impl Iterator<Item = &(dyn Error + 'static)> {
+        let first: &(dyn Error + 'static) = self;
+        iter::successors(Some(first), |&error| error.source())
+    }
+
+    fn with_handle(kind: KernErrorKind, handle: Option<RegistryHandle>) -> Self {
+        let inner = match handle {
+            Some(handle) => ErrorInner::Dyn { kind, handle },
+            None => ErrorInner::Simple(kind),
+        };
+        KernError { inner }
     }
 }
 
 impl Display for KernError {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "kernel error occurred: {:?}", self.kind())
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.inner {
+            ErrorInner::Dyn { handle, .. } => write!(f, "{}", handle.error())?,
+            ErrorInner::Simple(kind) => write!(f, "{}", kind)?,
+        }
+        if f.alternate() {
+            for cause in self.chain().skip(1) {
+                write!(f, ": {}", cause)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for KernError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.inner {
+            ErrorInner::Dyn { kind, .. } => write!(f, "{:?}: {:#}", kind, self),
+            ErrorInner::Simple(kind) => write!(f, "{:?}", kind),
+        }
+    }
+}
+
+impl Error for KernError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.inner {
+            ErrorInner::Dyn { handle, .. } => handle.error().source(),
+            ErrorInner::Simple(_) => None,
+        }
     }
 }
 
@@ -82,55 +151,265 @@ impl From<KernErrorKind> for KernError {
 
 impl From<StdAllocError> for KernError {
     fn from(_: StdAllocError) -> Self {
-        KernErrorKind::AllocError.into()
+        KernError::new(KernErrorKind::AllocError, HeapExhausted)
     }
 }
 
 impl From<PageTableError> for KernError {
     fn from(value: PageTableError) -> Self {
         match value {
-            PageTableError::FrameAllocError => KernErrorKind::AllocError.into(),
+            PageTableError::FrameAllocError => KernError::new(KernErrorKind::AllocError, value),
         }
     }
 }
 
-impl From<FrameAllocError> for KernError {
-    fn from(_: FrameAllocError) -> Self {
-        KernErrorKind::AllocError.into()
+impl From<PageLookupError> for KernError {
+    fn from(value: PageLookupError) -> Self {
+        KernError::new(KernErrorKind::BadAddress, value)
     }
 }
 
-impl Error for KernError {}
+impl From<FrameAllocError> for KernError {
+    fn from(value: FrameAllocError) -> Self {
+        KernError::new(KernErrorKind::AllocError, value)
+    }
+}
 
 pub type KernResult<T> = Result<T, KernError>;
 
-pub enum ErrorContext {
-    None,
-    Code(u16),
+/// General error kind. This is what is typically passed to userspace. Internal errors
+/// (`KernError`) holds much more context.
+///
+/// The kinds and their codes are shared with userspace, see [`sol_types::ErrorKind`].
+pub use sol_types::ErrorKind as KernErrorKind;
+
+/// Adding context to the error of a [`Result`].
+pub trait Context<T> {
+    /// Wrap the error in `context`, as with [`KernError::context`].
+    fn context<C>(self, context: C) -> KernResult<T>
+    where
+        C: Display + Send + Sync + 'static;
+
+    /// Wrap the error in the context `f` returns, only calling it if there is an error.
+    fn with_context<C, F>(self, f: F) -> KernResult<T>
+    where
+        C: Display + Send + Sync + 'static,
+        F: FnOnce() -> C;
 }
 
+impl<T, E: Into<KernError>> Context<T> for Result<T, E> {
+    fn context<C>(self, context: C) -> KernResult<T>
+    where
+        C: Display + Send + Sync + 'static,
+    {
+        self.map_err(|err| err.into().context(context))
+    }
+
+    fn with_context<C, F>(self, f: F) -> KernResult<T>
+    where
+        C: Display + Send + Sync + 'static,
+        F: FnOnce() -> C,
+    {
+        self.map_err(|err| err.into().context(f()))
+    }
+}
+
+/// The kernel heap couldn't satisfy an allocation. This is a ZST so that boxing it never
+/// needs the heap.
 #[derive(Debug)]
-struct ErrorCtx(NonZeroU32);
+struct HeapExhausted;
+
+impl Display for HeapExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("out of heap memory")
+    }
+}
+
+impl Error for HeapExhausted {}
+
+/// An error wrapped by [`KernError::context`].
+struct ContextError<C> {
+    context: C,
+    source: KernError,
+}
 
+impl<C: Display> Display for ContextError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.context)
+    }
+}
+
+impl<C: Display> fmt::Debug for ContextError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextError")
+            .field("context", &format_args!("{}", self.context))
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl<C: Display> Error for ContextError<C> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// The number of slots in the first level. Each level after it is twice as big as the
+/// one before.
+const FIRST_LEVEL_LEN: usize = 64;
+const LEVELS: usize = 8;
+
+static REGISTRY: ErrorRegistry = ErrorRegistry::new(&FIRST_LEVEL);
+
+/// The first level is static, so that the first errors can be registered without the
+/// heap, which may be what failed.
+static FIRST_LEVEL: [Slot; FIRST_LEVEL_LEN] = [Slot::EMPTY; FIRST_LEVEL_LEN];
+
+/// Where the error objects live.
+///
+/// Slots are grouped in levels that are allocated as they are needed and never freed, so
+/// a slot never moves and can be read without a lock by whoever holds its handle.
 struct ErrorRegistry {
-    levels: [AtomicPtr<()>; 8],
+    levels: [AtomicPtr<Slot>; LEVELS],
     grow_lock: SpinMutex<()>,
 }
 
-type BoxError = Box<dyn Error + Send + Sync>;
+struct Slot {
+    used: AtomicBool,
+    /// Written only by whoever claimed the slot, before the handle is handed out, and
+    /// when the handle is dropped.
+    error: UnsafeCell<Option<BoxError>>,
+}
+
+// Safety: the contents of a slot are only reached through its unique handle.
+unsafe impl Sync for Slot {}
+
+impl Slot {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Slot = Slot {
+        used: AtomicBool::new(false),
+        error: UnsafeCell::new(None),
+    };
+}
 
 impl ErrorRegistry {
+    const fn new(first_level: &'static [Slot; FIRST_LEVEL_LEN]) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NONE: AtomicPtr<Slot> = AtomicPtr::new(ptr::null_mut());
+        let mut levels = [NONE; LEVELS];
+        levels[0] = AtomicPtr::new(first_level.as_ptr().cast_mut());
+        Self {
+            levels,
+            grow_lock: SpinMutex::new(()),
+        }
+    }
+
+    /// Store `error`, handing it back if the registry is full.
     pub fn register_boxed(&self, error: BoxError) -> Result<RegistryHandle, BoxError> {
-        todo!()
+        let Some(index) = self.claim() else {
+            return Err(error);
+        };
+        Ok(self.fill(index, error))
     }
 
-    fn register_with(
-        &self,
-        f: &mut dyn FnMut() -> KernResult<BoxError>,
-    ) -> KernResult<Option<RegistryHandle>> {
-        todo!()
+    /// Claim a slot and fill it with what `f` builds. `f` is only called if there is room,
+    /// so nothing is built for an error that would be thrown away.
+    fn register_with(&self, f: impl FnOnce() -> Option<BoxError>) -> Option<RegistryHandle> {
+        let index = self.claim()?;
+        match f() {
+            Some(error) => Some(self.fill(index, error)),
+            None => {
+                self.slot(index).used.store(false, Ordering::Release);
+                None
+            }
+        }
+    }
+
+    fn fill(&self, index: usize, error: BoxError) -> RegistryHandle {
+        // Safety: the slot was just claimed, so no handle to it exists yet.
+        unsafe { *self.slot(index).error.get() = Some(error) };
+        let index = u32::try_from(index + 1).unwrap();
+        RegistryHandle(NonZeroU32::new(index).unwrap())
+    }
+
+    /// Find a free slot and mark it used, growing the registry if every slot is taken.
+    fn claim(&self) -> Option<usize> {
+        let mut start = 0;
+        for level in 0..LEVELS {
+            let slots = self.level(level).or_else(|| self.grow(level))?;
+            for (offset, slot) in slots.iter().enumerate() {
+                if slot
+                    .used
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return Some(start + offset);
+                }
+            }
+            start += slots.len();
+        }
+        None
+    }
+
+    fn level(&self, level: usize) -> Option<&'static [Slot]> {
+        let slots = self.levels[level].load(Ordering::Acquire);
+        if slots.is_null() {
+            return None;
+        }
+        // Safety: levels are never freed once published.
+        Some(unsafe { &*ptr::slice_from_raw_parts(slots, FIRST_LEVEL_LEN << level) })
+    }
+
+    fn grow(&self, level: usize) -> Option<&'static [Slot]> {
+        interrupts::without(|_| {
+            let _guard = self.grow_lock.lock();
+            if let Some(slots) = self.level(level) {
+                return Some(slots);
+            }
+
+            let len = FIRST_LEVEL_LEN << level;
+            let mut slots = Vec::new();
+            slots.try_reserve_exact(len).ok()?;
+            slots.extend(iter::repeat_with(|| Slot::EMPTY).take(len));
+            let slots: &'static [Slot] = Box::leak(slots.into_boxed_slice());
+            self.levels[level].store(slots.as_ptr().cast_mut(), Ordering::Release);
+            Some(slots)
+        })
+    }
+
+    fn slot(&self, index: usize) -> &Slot {
+        let level = (index / FIRST_LEVEL_LEN + 1).ilog2() as usize;
+        let start = FIRST_LEVEL_LEN * ((1 << level) - 1);
+        &self.level(level).expect("slot in a missing level")[index - start]
     }
 }
 
+/// Owns a slot in the registry, and frees it when dropped.
 #[derive(Debug)]
-struct RegistryHandle(NonZeroU8);
+struct RegistryHandle(NonZeroU32);
+
+impl RegistryHandle {
+    fn index(&self) -> usize {
+        self.0.get() as usize - 1
+    }
+
+    fn error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        // Safety: the slot was filled before this handle was made, and only changes once
+        // it is dropped.
+        let error = unsafe { &*REGISTRY.slot(self.index()).error.get() };
+        error.as_deref().expect("registered slot is empty")
+    }
+}
+
+impl Drop for RegistryHandle {
+    fn drop(&mut self) {
+        let slot = REGISTRY.slot(self.index());
+        // Safety: this is the only handle to the slot.
+        let error = unsafe { (*slot.error.get()).take() };
+        slot.used.store(false, Ordering::Release);
+        // Dropping the error may free the slots of its causes.
+        drop(error);
+    }
+}
@@ -21,20 +21,17 @@
 extern crate alloc;
 
 use alloc::{sync::Arc, vec::Vec};
-use core::{arch::asm, mem, panic::PanicInfo, ptr};
+use core::{mem, panic::PanicInfo};
 
 use arch::cpu;
-use error::KernResult;
-use hal::{interrupts, vm_types::MapOptions, x86_64::instr::int3};
+use error::{Context, KernResult};
+use hal::{interrupts, vm_types::MapOptions};
 use limine::{LimineSmpInfo, LimineSmpRequest};
 use log::{error, info, set_logger, set_max_level, trace, LevelFilter};
 use stdio::StdoutLogger;
 use tracing::instrument;
-use x86_64::registers::model_specific::Msr;
 
 use crate::{
-    arch::x86_64::gdt,
-    memory::allocate_user,
     sync::{barrier::Barrier, Mutex},
     task::{spawn, yield_now},
 };
@@ -43,6 +40,7 @@ mod arch;
 mod cpu_local;
 mod error;
 mod memory;
+mod object;
 mod process;
 mod random;
 mod stdio;
@@ -63,6 +61,9 @@ pub extern "C" fn _start() -> ! {
 }
 
 fn kernel_main() -> KernResult<()> {
+    // The logger prints the current cpu id, which lives in the per-cpu area.
+    unsafe { hal::percpu::init_boot() };
+
     set_logger(&StdoutLogger).expect("no other logger is set");
     set_max_level(LevelFilter::Trace);
 
@@ -76,8 +77,14 @@ fn kernel_main() -> KernResult<()> {
 
         arch::init();
         // interrupt_table::init();
-        memory::init()?;
-        hal::task::init_hw_thread(0);
+        memory::init().context("initializing memory")?;
+        cpu_local::init_cpu(0)?;
+        sync::rcu::cpu_online(0);
+        arch::x86_64::tlb::cpu_online(0);
+        sync::futex::cpu_online();
+        sync::lockdep_hooks::init();
+        syscall::init();
+        arch::x86_64::syscall::init_hw_thread();
     }
 
     info!("finished initialization");
@@ -89,53 +96,8 @@ fn kernel_main() -> KernResult<()> {
     // tracing::subscriber::set_global_default(KernelSubscriber::default()).unwrap();
 
     // tracing::trace!("Hello tracing!");
-    let stack = allocate_user(8192).unwrap();
-    let top = (stack.as_ptr() as *mut u8).wrapping_add(stack.len());
-
-    let user_memory = allocate_user(8192).unwrap();
-    unsafe { user_memory.as_mut_ptr().write(10) };
-    unsafe {
-        ptr::copy(
-            ring3_entry as usize as *mut u8,
-            user_memory.as_mut_ptr(),
-            4096,
-        )
-    };
-    let addr = user_memory.as_ptr() as *mut u8;
-    info!("user stack start: {:p}", stack);
-    info!("user code start: {:p}", user_memory);
-    trace!("addr = {:p}", addr);
-
-    let mut efer = Msr::new(0xc0000080);
-    let mut star = Msr::new(0xc0000081);
-    let mut lstar = Msr::new(0xc0000082);
-    let mut cstar = Msr::new(0xc0000083);
-    let mut sfmask = Msr::new(0xc0000084);
-
-    unsafe {
-        lstar.write(0x0);
-
-        let enable = efer.read() | 1;
-        efer.write(enable);
-
-        let cs: u64 = gdt::selectors().user_code_selector.0.into();
-        let ss: u64 = gdt::selectors().user_data_selector.0.into();
-
-        star.write((cs << 48) | (ss << 46));
-    }
-
-    unsafe {
-        asm!(
-            "
-            mov rsp, {}
-            mov rcx, {}
-            mov r11, 0x202
-            sysret",
-            in(reg) top,
-            in(reg) addr,
-            options(noreturn)
-        );
-    }
+    task::init_naive_scheduler();
+    process::init::spawn().context("starting init")?;
 
     // foo(5);
     // spawn(|| {
@@ -202,7 +164,7 @@ fn kernel_main() -> KernResult<()> {
     //     thread::park_or_wait();
     // }
     // Ok(())
-    Ok(())
+    unsafe { task::enter() }
 }
 
 #[track_caller]
@@ -219,20 +181,21 @@ fn foo(val: i32) {
     tracing::info!("hello again");
 }
 
-#[no_mangle]
-pub extern "C" fn ring3_entry() -> ! {
-    // Should fault
-    unsafe { interrupts::disable() };
-    loop {
-        unsafe { int3() };
-    }
-}
-
 extern "C" fn apu_start(info: *const LimineSmpInfo) -> ! {
+    // A cpu past the ones we have room for can't be given a per-cpu area or appear in a
+    // cpu mask, so it is left parked.
+    let Some(id) = cpu::allocate_id() else {
+        loop {
+            unsafe { interrupts::wait() };
+        }
+    };
+
     unsafe {
-        let id = (*info).processor_id as usize;
+        // Logging reads the cpu id from the per-cpu area, so one has to be installed before
+        // anything else.
+        hal::percpu::init_early(id);
         cpu::init(id);
-        info!("apu start: {}", (*info).processor_id);
+        info!("apu start: cpu {} (processor {})", id, (*info).processor_id);
     };
 
     unsafe { task::enter() };
@@ -1,21 +1,25 @@
 use alloc::sync::Arc;
-use core::ptr::{self, NonNull};
+use core::{ops::Range, ptr::NonNull};
 
 use bitflags::bitflags;
 use hal::{
     interrupts,
     paging::DirectlyMappedPageTable,
     vm_types::{
-        FrameAllocator, MapOptions, Page, PageTable, PageTableError, PhysAddr, VirtAddr, VirtRegion,
+        Frame, FrameAllocator, MapOptions, Page, PageTable, PageTableError, PhysAddr, VirtAddr,
     },
+    x86_64::reg::cr3,
 };
-use limine::{LimineHhdmRequest, LimineMemmapRequest};
+use limine::{LimineHhdmRequest, LimineMemmapRequest, LimineMemoryMapEntryType};
 use log::trace;
-use spin::{mutex::SpinMutex, Lazy};
+use spin::Lazy;
 
-pub use self::process::ProcAddrSpace;
-use self::{kernel::KERNEL_ADDRESS_SPACE, user::UserAddressSpace};
-use crate::error::KernResult;
+use self::kernel::KERNEL_ADDRESS_SPACE;
+pub use self::{
+    kernel::kernel_root,
+    process::{ProcAddrSpace, Protection},
+};
+use crate::error::{KernErrorKind, KernResult};
 
 mod allocator;
 mod frame_allocator;
@@ -37,13 +41,78 @@ pub unsafe fn map_physical_addr(phys: PhysAddr) -> VirtAddr {
     VirtAddr::from_ptr(hhdm_start().add(phys.as_usize()))
 }
 
+/// Run the current cpu on the page table whose top level table is at `root`, unless it
+/// already is. Must be called with interrupts disabled.
+pub unsafe fn load_page_table(root: PhysAddr) {
+    if cr3::read() & !0xfff != root.as_usize() {
+        cr3::write(root.as_usize());
+    }
+}
+
+/// Whether any of `frames` is memory the kernel, or the bootloader before it, hands out.
+pub fn is_ram(frames: Range<Frame>) -> bool {
+    let mmap_response = MMAP_REQUEST
+        .get_response()
+        .get()
+        .expect("memory map request failed");
+
+    let (start, end) = (frames.start.addr().as_usize(), frames.end.addr().as_usize());
+    mmap_response.memmap().iter().any(|entry| {
+        let ram = matches!(
+            entry.typ,
+            LimineMemoryMapEntryType::Usable
+                | LimineMemoryMapEntryType::BootloaderReclaimable
+                | LimineMemoryMapEntryType::AcpiReclaimable
+                | LimineMemoryMapEntryType::KernelAndModules
+        );
+        let (base, len) = (entry.base as usize, entry.len as usize);
+        ram && base < end && start < base + len
+    })
+}
+
 #[derive(Debug, Clone)]
 pub enum AddrSpace {
     Kernel,
-    User(Arc<UserAddressSpace>),
+    User(Arc<ProcAddrSpace>),
 }
 
 impl AddrSpace {
+    /// A number identifying the address space, unique among live address spaces.
+    pub fn id(&self) -> usize {
+        match self {
+            AddrSpace::Kernel => 0,
+            AddrSpace::User(space) => Arc::as_ptr(space) as usize,
+        }
+    }
+
+    /// Look up the physical address that `addr` is mapped to.
+    pub fn translate(&self, addr: VirtAddr) -> KernResult<PhysAddr> {
+        if let AddrSpace::User(space) = self {
+            return space.translate(addr);
+        }
+
+        // The direct map uses huge pages, which the page table walk doesn't handle, but
+        // it is a fixed offset anyway.
+        let hhdm = hhdm_start() as usize..frame_allocator::hhdm_end().as_usize();
+        if hhdm.contains(&addr.as_usize()) {
+            return Ok(PhysAddr::from_usize(addr.as_usize() - hhdm.start));
+        }
+
+        let page = Page::containing(addr);
+        let frame = interrupts::without(|_| KERNEL_ADDRESS_SPACE.lock().page_table().lookup(page))?;
+        let offset = addr.as_usize() - page.addr().as_usize();
+        Ok(PhysAddr::from_usize(frame.addr().as_usize() + offset))
+    }
+
+    /// Check that ring 3 may access `addr` in the ways `prot` asks for, on top of reading
+    /// it. Nothing in the kernel's address space belongs to ring 3.
+    pub fn check_user(&self, addr: VirtAddr, prot: Protection) -> KernResult<()> {
+        match self {
+            AddrSpace::Kernel => Err(KernErrorKind::BadAddress.into()),
+            AddrSpace::User(space) => space.check_user(addr, prot),
+        }
+    }
+
     pub fn allocate(&self, options: &AllocOptions) -> KernResult<NonNull<[u8]>> {
         match self {
             AddrSpace::Kernel => {
@@ -52,6 +121,17 @@ impl AddrSpace {
             AddrSpace::User(_) => todo!("userspace allocations"),
         }
     }
+
+    /// # Safety
+    /// 1. `ptr` must have been returned by [`AddrSpace::allocate`] on this address space
+    /// with identical options.
+    /// 2. The region must no longer be in use.
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, options: &AllocOptions) {
+        match self {
+            AddrSpace::Kernel => kernel::deallocate(ptr, options),
+            AddrSpace::User(_) => todo!("userspace allocations"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -92,6 +172,12 @@ impl AllocOptions {
     pub fn allocate_in_address_space(&self, addr_space: &AddrSpace) -> KernResult<NonNull<[u8]>> {
         addr_space.allocate(self)
     }
+
+    /// # Safety
+    /// See [`AddrSpace::deallocate`].
+    pub unsafe fn deallocate_in_address_space(&self, ptr: NonNull<u8>, addr_space: &AddrSpace) {
+        addr_space.deallocate(ptr, self)
+    }
 }
 
 fn map_guard<P>(page: Page, page_table: &mut P) -> Result<(), PageTableError>
@@ -182,45 +268,3 @@ unsafe fn get_active_page_table() -> DirectlyMappedPageTable {
 
     DirectlyMappedPageTable::active(phys_base)
 }
-
-static USERSPACE: Lazy<SpinMutex<UserAddressSpace>> = Lazy::new(|| {
-    let base = VirtAddr::from_usize(1 << 22);
-    let top = base.as_usize() + (1 << 26);
-    let top = VirtAddr::from_usize(top);
-    let region = VirtRegion {
-        end: Page::from_base(base).unwrap(),
-        start: Page::from_base(top).unwrap(),
-    };
-
-    SpinMutex::new(UserAddressSpace::new(region))
-});
-
-pub fn allocate_user(size: usize) -> KernResult<NonNull<[u8]>> {
-    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
-
-    let mut kern_address_space = KERNEL_ADDRESS_SPACE.lock();
-    let page_table = kern_address_space.page_table();
-
-    let region = USERSPACE.lock().allocate_stack(pages).unwrap();
-    let phys_alloc = &mut frame_allocator::Global;
-    for page in region {
-        let frame = phys_alloc.allocate_frame()?;
-
-        unsafe {
-            MapOptions::new(frame, page)
-                .execute()
-                .write()
-                .user_accessible()
-                .present()
-                .map(page_table, phys_alloc)
-                .unwrap();
-        }
-    }
-
-    let start = region.start.addr().as_ptr();
-    let len = region.len();
-    trace!("len := {}", len);
-
-    let ptr = ptr::slice_from_raw_parts_mut(start, len);
-    Ok(NonNull::new(ptr).unwrap())
-}
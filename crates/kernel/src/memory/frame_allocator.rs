@@ -7,9 +7,10 @@ use hal::{
 };
 use limine::LimineMemoryMapEntryType;
 use log::trace;
-use spin::{mutex::SpinMutex, Once};
+use spin::Once;
 
 use super::{HHDM_REQUEST, MMAP_REQUEST};
+use crate::sync::spinlock::SpinMutex;
 
 #[derive(Debug)]
 pub struct Global;
@@ -39,7 +40,7 @@ unsafe impl FrameAllocator for Global {
 
 pub fn init() {
     trace!("beginning initialization");
-    GLOBAL.call_once(|| SpinMutex::new(build_global()));
+    GLOBAL.call_once(|| SpinMutex::new(build_global(), lockdep::class!("frame allocator")));
     trace!("finished initialization");
 }
 
@@ -1,24 +1,50 @@
 use core::{
     alloc::AllocError,
+    cmp,
     iter::Step,
     ptr::{self, NonNull},
 };
 
 use hal::{
+    interrupts,
     paging::DirectlyMappedPageTable,
-    vm_types::{Page, VirtAddr, VirtRegion},
+    vm_types::{FrameAllocator, Page, PageTable, PhysAddr, VirtAddr, VirtRegion},
 };
 use log::trace;
-use spin::{mutex::SpinMutex, Lazy};
+use spin::{Lazy, Once};
 
 use super::{map_guard, map_lazy, map_normal, AllocOptions, PAGE_SIZE};
 use crate::{
-    error::{KernErrorKind, KernResult},
-    memory::{frame_allocator::hhdm_end, get_active_page_table},
+    arch::x86_64::tlb,
+    error::KernResult,
+    memory::{
+        frame_allocator::{self, hhdm_end},
+        get_active_page_table,
+    },
+    sync::spinlock::SpinMutex,
 };
 
-pub static KERNEL_ADDRESS_SPACE: Lazy<SpinMutex<KernelAddressSpace>> =
-    Lazy::new(|| unsafe { SpinMutex::new(make_kernel_addrspace()) });
+pub static KERNEL_ADDRESS_SPACE: Lazy<SpinMutex<KernelAddressSpace>> = Lazy::new(|| unsafe {
+    SpinMutex::new(
+        make_kernel_addrspace(),
+        lockdep::class!("KERNEL_ADDRESS_SPACE"),
+    )
+});
+
+/// Where the kernel's top level page table is, for kernel tasks to run on.
+static KERNEL_ROOT: Once<PhysAddr> = Once::new();
+
+/// The physical address of the kernel's top level page table.
+pub fn kernel_root() -> PhysAddr {
+    *KERNEL_ROOT.get().expect("kernel address space not set up")
+}
+
+/// The number of freed regions remembered for reuse. The cache lives inline so that
+/// freeing never touches the heap while the address space lock is held.
+const FREE_REGION_CACHE_SIZE: usize = 32;
+
+/// The most pages unmapped between TLB shootdowns when releasing a region.
+const UNMAP_BATCH: usize = 16;
 
 #[derive(Debug)]
 pub struct KernelAddressSpace {
@@ -26,6 +52,7 @@ pub struct KernelAddressSpace {
     kernel_heap_end: VirtAddr,
     kernel_heap_ptr: VirtAddr,
     page_table: DirectlyMappedPageTable,
+    free_regions: [Option<VirtRegion>; FREE_REGION_CACHE_SIZE],
 }
 
 impl KernelAddressSpace {
@@ -39,21 +66,10 @@ impl KernelAddressSpace {
         let num_usable_pages = options.num_pages;
         let num_pages = num_usable_pages + options.start_guard_pages + options.end_guard_pages;
 
-        // let region = self.allocate_unmapped_region(num_pages)?;
-        let start: *mut u8 = self.kernel_heap_ptr.as_ptr();
-        let end = start.wrapping_add(num_pages * PAGE_SIZE);
-
-        let start = self.kernel_heap_ptr;
-        let end = VirtAddr::from_ptr(end);
-
-        if !(start..self.kernel_heap_end).contains(&end) {
-            return Err(KernErrorKind::AllocError.into());
-        }
-        self.kernel_heap_ptr = end;
-
+        let region = self.allocate_unmapped_region(num_pages)?;
         let page_table = &mut self.page_table;
 
-        let mut page = Page::from_base(start).unwrap();
+        let mut page = region.start;
 
         for _ in 0..options.start_guard_pages {
             map_guard(page, page_table)?;
@@ -87,9 +103,48 @@ impl KernelAddressSpace {
         }
     }
 
+    fn free_region(&mut self, region: VirtRegion) {
+        // If the cache is full the virtual range is simply abandoned. The frames
+        // backing it have already been returned, and the kernel half is large enough
+        // that this is of little consequence.
+        if let Some(slot) = self.free_regions.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(region);
+        }
+    }
+
+    /// Find the smallest cached free region able to hold `num_pages`, splitting off and
+    /// keeping any remainder.
+    fn reuse_free_region(&mut self, num_pages: usize) -> Option<VirtRegion> {
+        let pages = |r: VirtRegion| Step::steps_between(&r.start, &r.end).unwrap_or(0);
+
+        let slot = self
+            .free_regions
+            .iter_mut()
+            .filter(|slot| slot.map_or(false, |r| pages(r) >= num_pages))
+            .min_by_key(|slot| slot.map_or(usize::MAX, pages))?;
+
+        let region = slot.take()?;
+        let split = Step::forward(region.start, num_pages);
+        if split != region.end {
+            *slot = Some(VirtRegion {
+                start: split,
+                end: region.end,
+            });
+        }
+
+        Some(VirtRegion {
+            start: region.start,
+            end: split,
+        })
+    }
+
     /// Allocate a region of the kernel address space, but does not perform any mapping
     /// or other operations.
     fn allocate_unmapped_region(&mut self, num_pages: usize) -> KernResult<VirtRegion> {
+        if let Some(region) = self.reuse_free_region(num_pages) {
+            return Ok(region);
+        }
+
         let start = Page::from_base(self.kernel_heap_ptr).unwrap();
 
         let end = Step::forward_checked(start, num_pages).ok_or(AllocError)?;
@@ -102,8 +157,63 @@ impl KernelAddressSpace {
     }
 }
 
+/// Release a region returned by [`KernelAddressSpace::allocate`]. Every page, guard pages
+/// included, is unmapped and any committed frames are returned to the frame allocator.
+///
+/// Frames are only freed once every cpu has flushed the pages from its TLB. The address
+/// space lock is dropped for the shootdown, since the other cpus may be waiting for it
+/// with interrupts disabled, so pages are unmapped in batches whose frames are held here
+/// in the meantime.
+///
+/// # Safety
+/// 1. `ptr` and `options` must match a previous call to `allocate`.
+/// 2. Nothing may access the region after this call.
+pub unsafe fn deallocate(ptr: NonNull<u8>, options: &AllocOptions) {
+    let num_pages = options.num_pages + options.start_guard_pages + options.end_guard_pages;
+
+    let first = Page::containing(VirtAddr::from_ptr(ptr.as_ptr()));
+    let start = Step::backward(first, options.start_guard_pages);
+    let end = Step::forward(start, num_pages);
+    let region = VirtRegion { start, end };
+
+    let mut page = region.start;
+    while page != region.end {
+        let batch = VirtRegion {
+            start: page,
+            end: cmp::min(Step::forward(page, UNMAP_BATCH), region.end),
+        };
+
+        let mut frames = [None; UNMAP_BATCH];
+        interrupts::without(|_| {
+            let mut space = KERNEL_ADDRESS_SPACE.lock();
+            for (page, frame) in batch.into_iter().zip(&mut frames) {
+                match space.page_table.unmap(page) {
+                    Ok(unmapped) => *frame = unmapped,
+                    Err(err) => trace!("page {:p} was never mapped: {:?}", page, err),
+                }
+            }
+        });
+
+        tlb::shootdown(batch);
+        for frame in frames.into_iter().flatten() {
+            frame_allocator::Global.deallocate_frame(frame);
+        }
+        page = batch.end;
+    }
+
+    interrupts::without(|_| KERNEL_ADDRESS_SPACE.lock().free_region(region));
+}
+
 unsafe fn make_kernel_addrspace() -> KernelAddressSpace {
-    let page_table = get_active_page_table();
+    let mut page_table = get_active_page_table();
+    // The bootloader's identity map would hide user mappings, and walking its huge pages
+    // as tables would corrupt memory.
+    page_table.clear_lower_half();
+    // Process page tables copy the upper half of this one, so it must never change.
+    page_table
+        .fill_upper_half(&frame_allocator::Global)
+        .expect("failed to fill in the kernel half of the page table");
+    KERNEL_ROOT.call_once(|| page_table.root());
 
     let kernel_heap_start = hhdm_end();
     let kernel_heap_end = VirtAddr::from_usize(usize::MAX);
@@ -121,5 +231,6 @@ unsafe fn make_kernel_addrspace() -> KernelAddressSpace {
         kernel_heap_ptr,
         kernel_heap_start,
         page_table,
+        free_regions: [None; FREE_REGION_CACHE_SIZE],
     }
 }
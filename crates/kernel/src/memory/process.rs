@@ -1,2 +1,265 @@
+//! The memory a process owns.
+//!
+//! Each process has a page table of its own, whose lower half holds its pages and whose
+//! upper half is the kernel's. The kernel never changes its top level table once set up,
+//! so sharing those entries keeps every process in step with it. Most pages are backed by
+//! a frame the address space owns, and both go away with it. Device memory is only
+//! borrowed.
+
+use alloc::vec::Vec;
+use core::{iter::Step, ops::Range, ptr};
+
+use bitflags::bitflags;
+use hal::{
+    interrupts,
+    paging::DirectlyMappedPageTable,
+    vm_types::{
+        Caching, Frame, FrameAllocator, MapOptions, Page, PageTable, PhysAddr, VirtAddr, VirtRegion,
+    },
+    x86_64::syscall::USER_END,
+};
+
+use super::{
+    frame_allocator,
+    kernel::{kernel_root, KERNEL_ADDRESS_SPACE},
+    map_physical_addr,
+    user::UserAddressSpace,
+    PAGE_SIZE,
+};
+use crate::{
+    arch::x86_64::tlb,
+    error::{KernError, KernErrorKind, KernResult},
+    sync::{mutex, spinlock::SpinMutex, Mutex},
+};
+
+bitflags! {
+    /// What ring 3 may do with a page, beyond reading it.
+    pub struct Protection: u8 {
+        const WRITE = 1 << 0;
+        const EXECUTE = 1 << 1;
+    }
+}
+
+/// A process's pages and the page table they are mapped in. Dropping it waits for every
+/// cpu to move off the page table, so it must not be dropped with a spinlock held.
 #[derive(Debug)]
-pub struct ProcAddrSpace {}
+pub struct ProcAddrSpace {
+    page_table: SpinMutex<DirectlyMappedPageTable>,
+    /// Where the top level table is, for loading the page table.
+    root: PhysAddr,
+    mappings: Mutex<Vec<Mapping>>,
+    /// Hands out the addresses of stacks, rings and device mappings.
+    regions: Mutex<UserAddressSpace>,
+}
+
+/// Where [`ProcAddrSpace::map_region`] and [`ProcAddrSpace::map_device`] put things, well
+/// clear of where executables are linked.
+const REGIONS: Range<usize> = 1 << 32..1 << 46;
+
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    page: Page,
+    frame: Frame,
+    prot: Protection,
+    /// Whether the frame is freed with the mapping.
+    owned: bool,
+}
+
+impl ProcAddrSpace {
+    /// An address space with nothing mapped below the kernel half.
+    pub fn new() -> KernResult<Self> {
+        let page_table = interrupts::without(|_| {
+            let mut kernel = KERNEL_ADDRESS_SPACE.lock();
+            kernel
+                .page_table()
+                .share_upper_half(&frame_allocator::Global)
+        })?;
+        let root = page_table.root();
+
+        Ok(Self {
+            page_table: SpinMutex::new(page_table, lockdep::class!("ProcAddrSpace::page_table")),
+            root,
+            mappings: mutex::with_class(Vec::new(), lockdep::class!("ProcAddrSpace::mappings")),
+            regions: mutex::with_class(
+                UserAddressSpace::new(VirtRegion {
+                    start: Page::from_base(VirtAddr::from_usize(REGIONS.start)).unwrap(),
+                    end: Page::from_base(VirtAddr::from_usize(REGIONS.end)).unwrap(),
+                }),
+                lockdep::class!("ProcAddrSpace::regions"),
+            ),
+        })
+    }
+
+    /// The physical address of the top level page table.
+    pub fn root(&self) -> PhysAddr {
+        self.root
+    }
+
+    /// Map a zeroed frame at `page`. Fails with [`KernErrorKind::Busy`] if something is
+    /// already mapped there.
+    pub fn map_zeroed(&self, page: Page, prot: Protection) -> KernResult<()> {
+        let frame = frame_allocator::Global.allocate_frame()?;
+        unsafe { ptr::write_bytes(map_physical_addr(frame.addr()).as_ptr::<u8>(), 0, PAGE_SIZE) };
+
+        let mapping = Mapping {
+            page,
+            frame,
+            prot,
+            owned: true,
+        };
+        if let Err(err) = self.map(mapping, Caching::WriteBack) {
+            unsafe { frame_allocator::Global.deallocate_frame(frame) };
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Take `count` pages from the address space's region for stacks, rings and devices,
+    /// and map them zeroed and writable, below a guard page that is left unmapped.
+    ///
+    /// The addresses are only given back with the address space, even when the pages
+    /// are.
+    pub fn map_region(&self, count: usize) -> KernResult<VirtRegion> {
+        let region = self.reserve(count)?;
+        for page in region {
+            self.map_zeroed(page, Protection::WRITE)?;
+        }
+        Ok(region)
+    }
+
+    /// Map the device memory in `frames` into a region taken like
+    /// [`ProcAddrSpace::map_region`]. The frames are left alone when it is unmapped.
+    pub fn map_device(
+        &self,
+        frames: Range<Frame>,
+        prot: Protection,
+        caching: Caching,
+    ) -> KernResult<VirtRegion> {
+        let count = Step::steps_between(&frames.start, &frames.end).unwrap_or(0);
+        let region = self.reserve(count)?;
+        for (page, frame) in region.into_iter().zip(frames) {
+            let mapping = Mapping {
+                page,
+                frame,
+                prot,
+                owned: false,
+            };
+            self.map(mapping, caching)?;
+        }
+        Ok(region)
+    }
+
+    /// Copy `bytes` to `addr`, which must have been mapped by this address space. The
+    /// copy goes through the frames rather than the mapping, so it works on read-only
+    /// pages too.
+    pub fn write(&self, addr: VirtAddr, bytes: &[u8]) -> KernResult<()> {
+        let mappings = self.mappings.lock();
+        let mut addr = addr.as_usize();
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let page = Page::containing(VirtAddr::from_usize(addr));
+            let mapping = mappings
+                .iter()
+                .find(|mapping| mapping.page == page && mapping.owned)
+                .ok_or(KernErrorKind::BadAddress)?;
+
+            let offset = addr - page.addr().as_usize();
+            let len = bytes.len().min(PAGE_SIZE - offset);
+            unsafe {
+                let dst = map_physical_addr(mapping.frame.addr()).as_ptr::<u8>();
+                ptr::copy_nonoverlapping(bytes.as_ptr(), dst.add(offset), len);
+            }
+
+            addr += len;
+            bytes = &bytes[len..];
+        }
+        Ok(())
+    }
+
+    /// Look up the physical address that `addr` is mapped to. Only this address space's
+    /// own mappings count, so this fails for another process's addresses.
+    pub fn translate(&self, addr: VirtAddr) -> KernResult<PhysAddr> {
+        let page = Page::containing(addr);
+        let mappings = self.mappings.lock();
+        let mapping = mappings
+            .iter()
+            .find(|mapping| mapping.page == page)
+            .ok_or(KernErrorKind::BadAddress)?;
+
+        let offset = addr.as_usize() - page.addr().as_usize();
+        let frame = mapping.frame.addr().as_usize();
+        Ok(PhysAddr::from_usize(frame + offset))
+    }
+
+    /// Check that ring 3 may access `addr` in the ways `prot` asks for, on top of reading
+    /// it.
+    pub fn check_user(&self, addr: VirtAddr, prot: Protection) -> KernResult<()> {
+        let page = Page::containing(addr);
+        let mappings = self.mappings.lock();
+        mappings
+            .iter()
+            .find(|mapping| mapping.page == page)
+            .filter(|mapping| mapping.prot.contains(prot))
+            .ok_or(KernErrorKind::BadAddress)?;
+        Ok(())
+    }
+
+    /// Take `count` pages from the region, leaving a guard page below them.
+    fn reserve(&self, count: usize) -> KernResult<VirtRegion> {
+        let region = self.regions.lock().allocate_stack(count + 1)?;
+        Ok(VirtRegion {
+            start: Step::forward(region.start, 1),
+            end: region.end,
+        })
+    }
+
+    fn map(&self, mapping: Mapping, caching: Caching) -> KernResult<()> {
+        let page = mapping.page;
+        if page.addr().as_usize() == 0 || page.addr().as_usize() >= USER_END {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+
+        let mut mappings = self.mappings.lock();
+        mappings
+            .try_reserve(1)
+            .map_err(|_| KernErrorKind::AllocError)?;
+
+        interrupts::without(|_| {
+            let mut page_table = self.page_table.lock();
+            if page_table.lookup(page).is_ok() {
+                return Err(KernErrorKind::Busy.into());
+            }
+
+            let mut options = MapOptions::new(mapping.frame, page);
+            options.user_accessible().present().caching(caching);
+            if mapping.prot.contains(Protection::WRITE) {
+                options.write();
+            }
+            if mapping.prot.contains(Protection::EXECUTE) {
+                options.execute();
+            }
+            unsafe { options.map(&mut *page_table, &frame_allocator::Global) }
+                .map_err(KernError::from)
+        })?;
+
+        mappings.push(mapping);
+        Ok(())
+    }
+}
+
+impl Drop for ProcAddrSpace {
+    fn drop(&mut self) {
+        // No cpu may walk the tables, or have what they map cached, once they are freed.
+        tlb::retire(self.root, kernel_root());
+
+        unsafe {
+            self.page_table
+                .get_mut()
+                .free_lower_half(&frame_allocator::Global)
+        };
+        let mappings = self.mappings.get_mut();
+        for mapping in mappings.iter().filter(|mapping| mapping.owned) {
+            unsafe { frame_allocator::Global.deallocate_frame(mapping.frame) };
+        }
+    }
+}
@@ -12,18 +12,15 @@ pub struct UserAddressSpace {
 }
 
 impl UserAddressSpace {
-    pub fn allocate_stack(&self, count: usize) -> KernResult<VirtRegion> {
+    /// Take `count` pages from the top of the region, below anything taken before.
+    pub fn allocate_stack(&mut self, count: usize) -> KernResult<VirtRegion> {
         let end = self.stack_ptr;
         let start = Step::backward_checked(end, count)
-            .ok_or(KernErrorKind::AllocError)
-            .unwrap();
+            .filter(|&start| start >= self.heap_ptr)
+            .ok_or(KernErrorKind::AllocError)?;
 
-        // if start <= self.heap_ptr {
-        //     return Err(KernErrorKind::AllocError.into());
-        // }
-
-        let region = VirtRegion { end, start };
-        Ok(region)
+        self.stack_ptr = start;
+        Ok(VirtRegion { end, start })
     }
 
     pub(crate) fn new(region: VirtRegion) -> UserAddressSpace {
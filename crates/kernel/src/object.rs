@@ -0,0 +1,52 @@
+//! Kernel objects, and the handles processes refer to them by.
+//!
+//! Every resource or capability a process holds is a kernel object, reached through a
+//! [`Handle`] in the process's [`HandleTable`]. A handle carries [`Rights`] that limit
+//! what may be done through it. An object lives for as long as any handle, or the
+//! kernel, holds on to it.
+
+use alloc::sync::Arc;
+use core::{
+    any::{Any, TypeId},
+    fmt::Debug,
+};
+
+use bitflags::bitflags;
+
+pub use self::handle::{Handle, HandleTable};
+
+pub mod channel;
+mod handle;
+pub mod io_port;
+pub mod irq;
+pub mod log;
+pub mod phys_mem;
+
+/// An object that can be held through a handle.
+pub trait KernelObject: Any + Send + Sync + Debug {
+    /// A short name for the kind of object, for logging.
+    fn kind(&self) -> &'static str;
+}
+
+bitflags! {
+    /// What may be done with an object through a handle.
+    pub struct Rights: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const MAP = 1 << 2;
+        /// The handle may be sent to another process.
+        const TRANSFER = 1 << 3;
+        /// The handle may be duplicated, with the same or fewer rights.
+        const DUPLICATE = 1 << 4;
+    }
+}
+
+/// Convert a type erased object back into what it really is.
+fn downcast<T: KernelObject>(object: Arc<dyn KernelObject>) -> Option<Arc<T>> {
+    // Through the vtable, so this is the id of the object rather than of the `Arc`.
+    if (*object).type_id() != TypeId::of::<T>() {
+        return None;
+    }
+    // Safety: the object is a `T`, so this only changes the pointer's metadata.
+    Some(unsafe { Arc::from_raw(Arc::into_raw(object).cast::<T>()) })
+}
@@ -0,0 +1,199 @@
+//! Channels: pairs of endpoints that carry messages of bytes and handles between
+//! processes.
+//!
+//! Each endpoint has a queue of the messages waiting to be read from it. Writing to one
+//! endpoint pushes onto its peer's queue. Handles in a message belong to no process while
+//! they are in flight: they leave the sender's table when the message is written, and
+//! enter the receiver's when it is read.
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use super::{KernelObject, Rights};
+use crate::{
+    error::{KernError, KernErrorKind, KernResult},
+    sync::{
+        futex::{wait_interruptible, wake_all},
+        mutex, Mutex,
+    },
+};
+
+/// The largest message body, in bytes.
+pub const MAX_MESSAGE_BYTES: usize = 65536;
+/// The most handles one message can carry.
+pub const MAX_MESSAGE_HANDLES: usize = 64;
+/// The most messages that can wait to be read from an endpoint.
+pub const MAX_QUEUED_MESSAGES: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct Message {
+    pub bytes: Vec<u8>,
+    pub handles: Vec<(Arc<dyn KernelObject>, Rights)>,
+}
+
+/// Create a channel, returning its two endpoints.
+pub fn create() -> (Endpoint, Endpoint) {
+    let pair = Arc::new(Pair {
+        sides: [Side::default(), Side::default()],
+    });
+
+    let a = Endpoint {
+        side: 0,
+        pair: Arc::clone(&pair),
+    };
+    (a, Endpoint { side: 1, pair })
+}
+
+#[derive(Debug)]
+pub struct Endpoint {
+    side: usize,
+    pair: Arc<Pair>,
+}
+
+#[derive(Debug)]
+struct Pair {
+    sides: [Side; 2],
+}
+
+#[derive(Debug)]
+struct Side {
+    /// The messages waiting to be read from this side.
+    queue: Mutex<VecDeque<Message>>,
+    closed: AtomicBool,
+    /// Bumped whenever a message arrives at this side or its peer closes, and waited on
+    /// by readers.
+    seq: AtomicU32,
+}
+
+impl Default for Side {
+    fn default() -> Self {
+        Self {
+            queue: mutex::with_class(VecDeque::new(), lockdep::class!("channel queue")),
+            closed: AtomicBool::new(false),
+            seq: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Endpoint {
+    /// Send a message to the peer.
+    ///
+    /// The handles are only taken, by calling `take_handles`, once the message is certain
+    /// to be delivered, and there must be no more than [`MAX_MESSAGE_HANDLES`] of them. If
+    /// taking them fails, nothing is sent.
+    pub fn write<F>(&self, bytes: Vec<u8>, take_handles: F) -> KernResult<()>
+    where
+        F: FnOnce() -> KernResult<Vec<(Arc<dyn KernelObject>, Rights)>>,
+    {
+        if bytes.len() > MAX_MESSAGE_BYTES {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+
+        let peer = self.peer();
+        {
+            let mut queue = peer.queue.lock();
+            // Checked under the lock, which the peer takes to close, so a message can't
+            // slip in behind the close and be leaked.
+            if peer.closed.load(Ordering::Acquire) {
+                return Err(KernErrorKind::PeerClosed.into());
+            }
+            if queue.len() >= MAX_QUEUED_MESSAGES {
+                return Err(KernErrorKind::WouldBlock.into());
+            }
+
+            let handles = take_handles()?;
+            debug_assert!(handles.len() <= MAX_MESSAGE_HANDLES);
+            queue.push_back(Message { bytes, handles });
+        }
+
+        peer.seq.fetch_add(1, Ordering::Release);
+        wake_all(&peer.seq);
+        Ok(())
+    }
+
+    /// Take the next message, if it fits in `max_bytes` and `max_handles`. Fails with
+    /// [`KernErrorKind::WouldBlock`] if there is none, and [`KernErrorKind::PeerClosed`]
+    /// if there never will be.
+    pub fn read(&self, max_bytes: usize, max_handles: usize) -> KernResult<Message> {
+        let side = self.side();
+        let mut queue = side.queue.lock();
+
+        let Some(message) = queue.front() else {
+            return Err(self.empty_error());
+        };
+        if message.bytes.len() > max_bytes || message.handles.len() > max_handles {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+        Ok(queue.pop_front().unwrap())
+    }
+
+    /// Put back a message that was read but couldn't be delivered, so that it's the next
+    /// one read.
+    pub fn unread(&self, message: Message) {
+        self.side().queue.lock().push_front(message);
+    }
+
+    /// Sleep until there is a message to read or the peer has closed. The wait is cut short
+    /// with [`KernErrorKind::Interrupted`] if the caller's process is terminated.
+    pub fn wait_readable(&self) -> KernResult<()> {
+        let side = self.side();
+        loop {
+            let seq = side.seq.load(Ordering::Acquire);
+            if !side.queue.lock().is_empty() {
+                return Ok(());
+            }
+            if self.peer().closed.load(Ordering::Acquire) {
+                return Err(KernErrorKind::PeerClosed.into());
+            }
+            wait_interruptible(&side.seq, seq)?;
+        }
+    }
+
+    fn empty_error(&self) -> KernError {
+        if self.peer().closed.load(Ordering::Acquire) {
+            KernErrorKind::PeerClosed.into()
+        } else {
+            KernErrorKind::WouldBlock.into()
+        }
+    }
+
+    /// Whether `other` is this endpoint or its peer.
+    pub fn same_channel(&self, other: &Endpoint) -> bool {
+        Arc::ptr_eq(&self.pair, &other.pair)
+    }
+
+    fn side(&self) -> &Side {
+        &self.pair.sides[self.side]
+    }
+
+    fn peer(&self) -> &Side {
+        &self.pair.sides[1 - self.side]
+    }
+}
+
+impl KernelObject for Endpoint {
+    fn kind(&self) -> &'static str {
+        "channel"
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let side = self.side();
+        // Nobody can read what is left, and the handles in it may hold other endpoints,
+        // so drop it outside the lock.
+        let unread = {
+            let mut queue = side.queue.lock();
+            side.closed.store(true, Ordering::Release);
+            mem::take(&mut *queue)
+        };
+        drop(unread);
+
+        let peer = self.peer();
+        peer.seq.fetch_add(1, Ordering::Release);
+        wake_all(&peer.seq);
+    }
+}
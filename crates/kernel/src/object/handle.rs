@@ -0,0 +1,209 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{downcast, KernelObject, Rights};
+use crate::error::{KernErrorKind, KernResult};
+
+const INDEX_BITS: u32 = 20;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+const GENERATION_MASK: u32 = u32::MAX >> INDEX_BITS;
+
+/// The most handles a process can hold at once.
+pub const MAX_HANDLES: usize = 1 << INDEX_BITS;
+
+/// Names an object in a [`HandleTable`].
+///
+/// The low bits pick a slot in the table and the high bits count how many times the
+/// slot has been reused, so a stale handle doesn't silently name whatever took its place.
+/// Generations start at one, so zero is never a valid handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Handle(u32);
+
+impl Handle {
+    pub const fn from_raw(raw: u32) -> Self {
+        Self(raw)
+    }
+
+    pub const fn into_raw(self) -> u32 {
+        self.0
+    }
+
+    fn new(index: usize, generation: u32) -> Self {
+        Self((generation << INDEX_BITS) | index as u32)
+    }
+
+    fn index(self) -> usize {
+        (self.0 & INDEX_MASK) as usize
+    }
+
+    fn generation(self) -> u32 {
+        self.0 >> INDEX_BITS
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    object: Arc<dyn KernelObject>,
+    rights: Rights,
+}
+
+#[derive(Debug)]
+struct Slot {
+    generation: u32,
+    entry: Option<Entry>,
+}
+
+/// The objects a process holds, and the rights it holds them with.
+#[derive(Debug, Default)]
+pub struct HandleTable {
+    slots: Vec<Slot>,
+    /// Indices of the empty slots.
+    free: Vec<usize>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a handle to `object` with `rights`.
+    pub fn insert(&mut self, object: Arc<dyn KernelObject>, rights: Rights) -> KernResult<Handle> {
+        let entry = Some(Entry { object, rights });
+
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index];
+            slot.entry = entry;
+            return Ok(Handle::new(index, slot.generation));
+        }
+
+        let index = self.slots.len();
+        if index >= MAX_HANDLES {
+            return Err(KernErrorKind::AllocError.into());
+        }
+        self.slots
+            .try_reserve(1)
+            .map_err(|_| KernErrorKind::AllocError)?;
+        self.slots.push(Slot {
+            generation: 1,
+            entry,
+        });
+        Ok(Handle::new(index, 1))
+    }
+
+    /// Close `handle`, handing back the object and the rights it was held with.
+    pub fn remove(&mut self, handle: Handle) -> KernResult<(Arc<dyn KernelObject>, Rights)> {
+        let taken = self.take(handle)?;
+        self.release(handle);
+        Ok(taken)
+    }
+
+    /// Take the object `handle` names out of the table, along with its rights, but keep
+    /// the slot, so that it can be [put back](Self::put_back) under the same handle.
+    /// Until then `handle` names nothing. [`release`](Self::release) gives the slot up.
+    pub fn take(&mut self, handle: Handle) -> KernResult<(Arc<dyn KernelObject>, Rights)> {
+        self.entry(handle)?;
+
+        let entry = self.slots[handle.index()].entry.take().unwrap();
+        Ok((entry.object, entry.rights))
+    }
+
+    /// Put back what [`take`](Self::take) took out under `handle`. Like
+    /// [`release`](Self::release), this does nothing if the slot is gone, as when the table
+    /// has been emptied because the process is exiting.
+    pub fn put_back(&mut self, handle: Handle, (object, rights): (Arc<dyn KernelObject>, Rights)) {
+        if let Some(slot) = self.taken_slot(handle) {
+            slot.entry = Some(Entry { object, rights });
+        }
+    }
+
+    /// Give up the slot of a handle that has been [taken](Self::take) out.
+    pub fn release(&mut self, handle: Handle) {
+        let index = handle.index();
+        let Some(slot) = self.taken_slot(handle) else { return };
+        // Wrap past zero, which no handle may use.
+        slot.generation = match (slot.generation + 1) & GENERATION_MASK {
+            0 => 1,
+            generation => generation,
+        };
+        self.free.push(index);
+    }
+
+    /// Add another handle to the object `handle` names, with no more than its rights.
+    /// The handle must have [`Rights::DUPLICATE`].
+    pub fn duplicate(&mut self, handle: Handle, rights: Rights) -> KernResult<Handle> {
+        let entry = self.entry(handle)?;
+        if !entry.rights.contains(Rights::DUPLICATE) || !entry.rights.contains(rights) {
+            return Err(KernErrorKind::AccessDenied.into());
+        }
+
+        let object = Arc::clone(&entry.object);
+        self.insert(object, rights)
+    }
+
+    /// The object `handle` names, which must be a `T` held with at least `rights`.
+    pub fn get<T: KernelObject>(&self, handle: Handle, rights: Rights) -> KernResult<Arc<T>> {
+        let entry = self.entry(handle)?;
+        if !entry.rights.contains(rights) {
+            return Err(KernErrorKind::AccessDenied.into());
+        }
+
+        downcast(Arc::clone(&entry.object)).ok_or_else(|| KernErrorKind::InvalidHandle.into())
+    }
+
+    /// The object `handle` names, whatever it is.
+    pub fn object(&self, handle: Handle) -> KernResult<&Arc<dyn KernelObject>> {
+        Ok(&self.entry(handle)?.object)
+    }
+
+    /// The rights `handle` is held with.
+    pub fn rights(&self, handle: Handle) -> KernResult<Rights> {
+        Ok(self.entry(handle)?.rights)
+    }
+
+    /// Close all of `handles` at once, which must be distinct and have at least `rights`.
+    /// If any of them can't be taken, none are.
+    pub fn remove_all(
+        &mut self,
+        handles: &[Handle],
+        rights: Rights,
+    ) -> KernResult<Vec<(Arc<dyn KernelObject>, Rights)>> {
+        for (i, &handle) in handles.iter().enumerate() {
+            if !self.entry(handle)?.rights.contains(rights) {
+                return Err(KernErrorKind::AccessDenied.into());
+            }
+            if handles[..i].contains(&handle) {
+                return Err(KernErrorKind::InvalidArgument.into());
+            }
+        }
+
+        let mut taken = Vec::new();
+        taken
+            .try_reserve_exact(handles.len())
+            .map_err(|_| KernErrorKind::AllocError)?;
+        for &handle in handles {
+            taken.push(self.remove(handle)?);
+        }
+        Ok(taken)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn taken_slot(&mut self, handle: Handle) -> Option<&mut Slot> {
+        self.slots
+            .get_mut(handle.index())
+            .filter(|slot| slot.generation == handle.generation() && slot.entry.is_none())
+    }
+
+    fn entry(&self, handle: Handle) -> KernResult<&Entry> {
+        self.slots
+            .get(handle.index())
+            .filter(|slot| slot.generation == handle.generation())
+            .and_then(|slot| slot.entry.as_ref())
+            .ok_or_else(|| KernErrorKind::InvalidHandle.into())
+    }
+}
@@ -0,0 +1,89 @@
+//! Ranges of I/O ports, for drivers that run in userspace.
+//!
+//! A process with a handle to an [`IoPorts`] object can enable it, after which its tasks
+//! may use `in` and `out` on exactly those ports, through the I/O permission bitmap in
+//! the TSS. Each port belongs to at most one object at a time, and the ports the kernel
+//! drives itself can't be claimed at all.
+
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use hal::interrupts;
+
+use super::KernelObject;
+use crate::{
+    error::{KernErrorKind, KernResult},
+    sync::spinlock::SpinMutex,
+};
+
+/// Ports the kernel drives itself.
+const RESERVED: &[RangeInclusive<u16>] = &[
+    // The legacy PICs, which are masked but must stay that way.
+    0x20..=0x21,
+    0xa0..=0xa1,
+    // The PIT.
+    0x40..=0x43,
+    // The serial port the kernel logs to.
+    0x3f8..=0x3ff,
+    // PCI configuration space.
+    0xcf8..=0xcff,
+];
+
+/// The ranges that are currently claimed.
+static CLAIMED: SpinMutex<Vec<RangeInclusive<u16>>> =
+    SpinMutex::new(Vec::new(), lockdep::class!("io_port::CLAIMED"));
+
+#[derive(Debug)]
+pub struct IoPorts {
+    range: RangeInclusive<u16>,
+}
+
+impl IoPorts {
+    /// Claim the ports in `range`. Fails with [`KernErrorKind::AccessDenied`] if any of
+    /// them are the kernel's, and [`KernErrorKind::Busy`] if any are already claimed.
+    pub fn claim(range: RangeInclusive<u16>) -> KernResult<Self> {
+        if range.is_empty() {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+        if RESERVED.iter().any(|reserved| overlaps(reserved, &range)) {
+            return Err(KernErrorKind::AccessDenied.into());
+        }
+
+        interrupts::without(|_| {
+            let mut claimed = CLAIMED.lock();
+            if claimed.iter().any(|other| overlaps(other, &range)) {
+                return Err(KernErrorKind::Busy.into());
+            }
+            claimed
+                .try_reserve(1)
+                .map_err(|_| KernErrorKind::AllocError)?;
+            claimed.push(range.clone());
+            Ok(Self { range })
+        })
+    }
+
+    pub fn range(&self) -> RangeInclusive<u16> {
+        self.range.clone()
+    }
+}
+
+impl KernelObject for IoPorts {
+    fn kind(&self) -> &'static str {
+        "io_ports"
+    }
+}
+
+impl Drop for IoPorts {
+    fn drop(&mut self) {
+        interrupts::without(|_| {
+            let mut claimed = CLAIMED.lock();
+            if let Some(index) = claimed.iter().position(|other| *other == self.range) {
+                claimed.swap_remove(index);
+            }
+        });
+    }
+}
+
+fn overlaps(a: &RangeInclusive<u16>, b: &RangeInclusive<u16>) -> bool {
+    a.start() <= b.end() && b.start() <= a.end()
+}
@@ -0,0 +1,177 @@
+//! Interrupts, for drivers that run in userspace.
+//!
+//! An [`Irq`] binds a device vector to whoever holds it. When the interrupt fires, the
+//! kernel masks it and wakes the tasks waiting on the object. It stays masked until the
+//! driver has dealt with the device and acks it, so a level-triggered line can't storm
+//! the cpu in the meantime.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use hal::interrupts;
+
+use super::KernelObject;
+use crate::{
+    arch::x86_64::{
+        idt::{self, DEVICE_VECTORS, DEVICE_VECTOR_COUNT, LOCAL_APIC},
+        ioapic::{self, Trigger},
+    },
+    error::{KernErrorKind, KernResult},
+    sync::{
+        futex::{wait_interruptible, wake_all},
+        spinlock::SpinMutex,
+    },
+};
+
+/// The base of the local APIC's MSI address window.
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+const UNBOUND: Option<Line> = None;
+const IDLE: State = State::new();
+
+/// The line bound to each device vector, read by [`handle`].
+static LINES: SpinMutex<[Option<Line>; DEVICE_VECTOR_COUNT]> =
+    SpinMutex::new([UNBOUND; DEVICE_VECTOR_COUNT], lockdep::class!("LINES"));
+/// Kept apart from the objects, so that the interrupt handler never drops one.
+static STATES: [State; DEVICE_VECTOR_COUNT] = [IDLE; DEVICE_VECTOR_COUNT];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line {
+    /// An I/O APIC line, which can be masked.
+    Legacy(u8),
+    /// A message signalled interrupt, which the kernel can't mask at the device. One
+    /// that arrives before the ack is counted but otherwise ignored.
+    Msi,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Bumped every time the interrupt fires, and waited on by [`Irq::wait`].
+    count: AtomicU32,
+    /// Whether the interrupt has fired and not been acked.
+    pending: AtomicBool,
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU32::new(0),
+            pending: AtomicBool::new(false),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Irq {
+    vector: u8,
+    line: Line,
+}
+
+impl Irq {
+    /// Bind I/O APIC line `gsi`. Fails with [`KernErrorKind::Busy`] if the line is already
+    /// bound, or there are no vectors left.
+    pub fn bind_legacy(gsi: u8, trigger: Trigger) -> KernResult<Self> {
+        let irq = Self::bind(Line::Legacy(gsi))?;
+        ioapic::route(gsi, irq.vector, trigger)?;
+        ioapic::unmask(gsi);
+        Ok(irq)
+    }
+
+    /// Bind a fresh vector for a device to signal with MSI, programmed with
+    /// [`Irq::msi_message`].
+    pub fn bind_msi() -> KernResult<Self> {
+        Self::bind(Line::Msi)
+    }
+
+    fn bind(line: Line) -> KernResult<Self> {
+        let vector = idt::allocate_vector(handle)?;
+        let state = &STATES[index(vector)];
+        state.count.store(0, Ordering::Relaxed);
+        state.pending.store(false, Ordering::Relaxed);
+
+        let bound = interrupts::without(|_| {
+            let mut lines = LINES.lock();
+            if line != Line::Msi && lines.contains(&Some(line)) {
+                return false;
+            }
+            lines[index(vector)] = Some(line);
+            true
+        });
+        if !bound {
+            idt::free_vector(vector);
+            return Err(KernErrorKind::Busy.into());
+        }
+        Ok(Self { vector, line })
+    }
+
+    /// The address and data a device must write to raise this interrupt, if it is an MSI.
+    pub fn msi_message(&self) -> Option<(u64, u32)> {
+        if self.line != Line::Msi {
+            return None;
+        }
+
+        let dest = unsafe { LOCAL_APIC.get()?.lock().id() };
+        Some((
+            MSI_ADDRESS_BASE | u64::from(dest) << 12,
+            u32::from(self.vector),
+        ))
+    }
+
+    /// Sleep until the interrupt has fired and not been acked, returning how many times
+    /// it has fired in all. Fails with [`KernErrorKind::Interrupted`] if the caller's
+    /// process is terminated first.
+    pub fn wait(&self) -> KernResult<u32> {
+        let state = self.state();
+        loop {
+            let count = state.count.load(Ordering::Acquire);
+            if state.pending.load(Ordering::Acquire) {
+                return Ok(count);
+            }
+            wait_interruptible(&state.count, count)?;
+        }
+    }
+
+    /// Unmask the interrupt once the device has been dealt with.
+    pub fn ack(&self) {
+        if self.state().pending.swap(false, Ordering::AcqRel) {
+            if let Line::Legacy(gsi) = self.line {
+                ioapic::unmask(gsi);
+            }
+        }
+    }
+
+    fn state(&self) -> &'static State {
+        &STATES[index(self.vector)]
+    }
+}
+
+impl KernelObject for Irq {
+    fn kind(&self) -> &'static str {
+        "irq"
+    }
+}
+
+impl Drop for Irq {
+    fn drop(&mut self) {
+        if let Line::Legacy(gsi) = self.line {
+            ioapic::mask(gsi);
+        }
+        interrupts::without(|_| LINES.lock()[index(self.vector)] = None);
+        idt::free_vector(self.vector);
+    }
+}
+
+fn handle(vector: u8) {
+    let Some(line) = LINES.lock()[index(vector)] else { return };
+    if let Line::Legacy(gsi) = line {
+        ioapic::mask(gsi);
+    }
+
+    let state = &STATES[index(vector)];
+    state.pending.store(true, Ordering::Release);
+    state.count.fetch_add(1, Ordering::Release);
+    wake_all(&state.count);
+}
+
+fn index(vector: u8) -> usize {
+    usize::from(vector - DEVICE_VECTORS.start)
+}
@@ -0,0 +1,45 @@
+//! The kernel log, for processes to write to.
+//!
+//! Until there is a userspace logger, a process's output goes to the same place as the
+//! kernel's, each line tagged with the name the [`Log`] was created with.
+
+use alloc::string::String;
+
+use log::info;
+
+use super::KernelObject;
+use crate::error::{KernErrorKind, KernResult};
+
+/// The longest message a single write may carry, in bytes.
+pub const MAX_LOG_BYTES: usize = 1024;
+
+#[derive(Debug)]
+pub struct Log {
+    tag: &'static str,
+}
+
+impl Log {
+    pub fn new(tag: &'static str) -> Self {
+        Self { tag }
+    }
+
+    /// Log `bytes`, which should be UTF-8. Invalid sequences are replaced rather than
+    /// rejected, so that a confused process still gets its message out.
+    pub fn write(&self, bytes: &[u8]) -> KernResult<()> {
+        if bytes.len() > MAX_LOG_BYTES {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+
+        let message = String::from_utf8_lossy(bytes);
+        for line in message.trim_end_matches('\n').lines() {
+            info!("[{}] {}", self.tag, line);
+        }
+        Ok(())
+    }
+}
+
+impl KernelObject for Log {
+    fn kind(&self) -> &'static str {
+        "log"
+    }
+}
@@ -0,0 +1,86 @@
+//! Ranges of physical memory, for drivers that run in userspace.
+//!
+//! A [`PhysMem`] names device memory, such as a framebuffer or a device's registers,
+//! that a process holding it may map. Like [I/O ports](super::io_port), each frame
+//! belongs to at most one object at a time, and RAM can't be claimed at all: handing it
+//! out would let a process read whatever the kernel keeps there.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use hal::{
+    interrupts,
+    vm_types::{Caching, Frame},
+};
+
+use super::KernelObject;
+use crate::{
+    error::{KernErrorKind, KernResult},
+    memory,
+    sync::spinlock::SpinMutex,
+};
+
+/// The ranges that are currently claimed.
+static CLAIMED: SpinMutex<Vec<Range<Frame>>> =
+    SpinMutex::new(Vec::new(), lockdep::class!("phys_mem::CLAIMED"));
+
+#[derive(Debug)]
+pub struct PhysMem {
+    frames: Range<Frame>,
+    caching: Caching,
+}
+
+impl PhysMem {
+    /// Claim the device memory in `frames`, to be mapped with `caching`. Fails with
+    /// [`KernErrorKind::AccessDenied`] if any of it is RAM, and [`KernErrorKind::Busy`] if
+    /// any is already claimed.
+    pub fn claim(frames: Range<Frame>, caching: Caching) -> KernResult<Self> {
+        if frames.is_empty() {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+        if memory::is_ram(frames.clone()) {
+            return Err(KernErrorKind::AccessDenied.into());
+        }
+
+        interrupts::without(|_| {
+            let mut claimed = CLAIMED.lock();
+            if claimed.iter().any(|other| overlaps(other, &frames)) {
+                return Err(KernErrorKind::Busy.into());
+            }
+            claimed
+                .try_reserve(1)
+                .map_err(|_| KernErrorKind::AllocError)?;
+            claimed.push(frames.clone());
+            Ok(Self { frames, caching })
+        })
+    }
+
+    pub fn frames(&self) -> Range<Frame> {
+        self.frames.clone()
+    }
+
+    pub fn caching(&self) -> Caching {
+        self.caching
+    }
+}
+
+impl KernelObject for PhysMem {
+    fn kind(&self) -> &'static str {
+        "phys_mem"
+    }
+}
+
+impl Drop for PhysMem {
+    fn drop(&mut self) {
+        interrupts::without(|_| {
+            let mut claimed = CLAIMED.lock();
+            if let Some(index) = claimed.iter().position(|other| *other == self.frames) {
+                claimed.swap_remove(index);
+            }
+        });
+    }
+}
+
+fn overlaps(a: &Range<Frame>, b: &Range<Frame>) -> bool {
+    a.start < b.end && b.start < a.end
+}
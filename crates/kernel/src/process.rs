@@ -6,6 +6,10 @@ use self::capability::Capabilities;
 use crate::error::KernResult;
 
 pub mod capability;
+pub mod elf;
+pub mod exec;
+pub mod fault;
+pub mod init;
 
 pub struct Process {
     capabilities: Capabilities,
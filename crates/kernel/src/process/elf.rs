@@ -0,0 +1,284 @@
+//! Parsing ELF64 executables.
+//!
+//! Only what the loader needs is read: the file header and the program headers. Anything
+//! the loader couldn't map faithfully is rejected here, so that loading never has to
+//! second-guess the file.
+
+use core::{error::Error, fmt, ops::Range};
+
+use hal::{
+    vm_types::{PageSize, Size4KiB},
+    x86_64::syscall::USER_END,
+};
+
+use crate::memory::Protection;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3e;
+
+const HEADER_SIZE: usize = 64;
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    BadMagic,
+    /// Not a 64-bit little endian file of the current version.
+    BadIdent,
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeaders,
+    /// The file needs a dynamic linker.
+    Dynamic,
+    NoSegments,
+    /// A segment claims more file bytes than it has memory, or more than the file has.
+    BadSegment,
+    /// A segment lies outside the user half, or on page zero.
+    BadAddress,
+    WritableAndExecutable,
+    Overlapping,
+    BadEntry,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ElfError::BadMagic => "not an ELF file",
+            ElfError::BadIdent => "not a 64-bit little endian ELF file",
+            ElfError::NotExecutable => "not a static executable",
+            ElfError::WrongMachine => "not built for x86_64",
+            ElfError::BadProgramHeaders => "program headers are malformed or truncated",
+            ElfError::Dynamic => "needs a dynamic linker",
+            ElfError::NoSegments => "has nothing to load",
+            ElfError::BadSegment => "a segment's sizes don't fit the file",
+            ElfError::BadAddress => "a segment lies outside user memory",
+            ElfError::WritableAndExecutable => "a segment is both writable and executable",
+            ElfError::Overlapping => "two segments share a page",
+            ElfError::BadEntry => "the entry point isn't in an executable segment",
+        };
+        f.write_str(message)
+    }
+}
+
+impl Error for ElfError {}
+
+/// An executable that has passed every check.
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    entry: usize,
+    program_headers: &'a [u8],
+}
+
+/// A `PT_LOAD` segment.
+#[derive(Debug, Clone)]
+pub struct Segment<'a> {
+    /// Where the segment is mapped.
+    pub vaddr: usize,
+    /// The bytes at the start of the segment. The rest, up to `mem_size`, is zeroed.
+    pub data: &'a [u8],
+    pub mem_size: usize,
+    pub prot: Protection,
+    /// The range of the file the segment is loaded from.
+    offset: Range<usize>,
+}
+
+impl Segment<'_> {
+    /// The pages the segment covers.
+    pub fn pages(&self) -> Range<usize> {
+        let start = self.vaddr & !(Size4KiB::SIZE - 1);
+        let end = (self.vaddr + self.mem_size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+        start..end
+    }
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ElfError> {
+        if bytes.get(..4) != Some(&MAGIC) {
+            return Err(ElfError::BadMagic);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(ElfError::BadIdent);
+        }
+        if bytes[4] != CLASS_64 || bytes[5] != DATA_LSB || bytes[6] != VERSION_CURRENT {
+            return Err(ElfError::BadIdent);
+        }
+        if read_u16(bytes, 16) != TYPE_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(bytes, 18) != MACHINE_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let entry = read_u64(bytes, 24) as usize;
+        let phoff = read_u64(bytes, 32) as usize;
+        let phentsize = usize::from(read_u16(bytes, 54));
+        let phnum = usize::from(read_u16(bytes, 56));
+        if phentsize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaders);
+        }
+        let program_headers = phnum
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|len| bytes.get(phoff..phoff.checked_add(len)?))
+            .ok_or(ElfError::BadProgramHeaders)?;
+
+        let elf = Self {
+            bytes,
+            entry,
+            program_headers,
+        };
+        elf.check_segments()?;
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// The number of program headers.
+    pub fn program_header_count(&self) -> usize {
+        self.program_headers.len() / PROGRAM_HEADER_SIZE
+    }
+
+    /// Where the program headers end up in memory, if they are loaded at all.
+    pub fn program_headers_addr(&self) -> Option<usize> {
+        let phoff = self.program_headers.as_ptr() as usize - self.bytes.as_ptr() as usize;
+        let phdr = self
+            .program_headers()
+            .find(|header| header.kind == PT_PHDR)
+            .map(|header| header.vaddr);
+        phdr.or_else(|| {
+            self.segments()
+                .find(|segment| segment.offset.contains(&phoff))
+                .map(|segment| segment.vaddr + (phoff - segment.offset.start))
+        })
+    }
+
+    /// The segments to load, which have all been checked by [`Elf::parse`].
+    pub fn segments(&self) -> impl Iterator<Item = Segment<'a>> + '_ {
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD)
+            .map(|header| {
+                let offset = header.offset..header.offset + header.file_size;
+                Segment {
+                    vaddr: header.vaddr,
+                    data: &self.bytes[offset.clone()],
+                    mem_size: header.mem_size,
+                    prot: header.prot(),
+                    offset,
+                }
+            })
+    }
+
+    fn check_segments(&self) -> Result<(), ElfError> {
+        for header in self.program_headers() {
+            match header.kind {
+                PT_INTERP | PT_DYNAMIC => return Err(ElfError::Dynamic),
+                PT_LOAD => header.check(self.bytes.len())?,
+                _ => {}
+            }
+        }
+
+        let mut segments = self.segments().peekable();
+        if segments.peek().is_none() {
+            return Err(ElfError::NoSegments);
+        }
+        for (i, segment) in segments.enumerate() {
+            let pages = segment.pages();
+            if self
+                .segments()
+                .take(i)
+                .any(|other| other.pages().start < pages.end && pages.start < other.pages().end)
+            {
+                return Err(ElfError::Overlapping);
+            }
+        }
+
+        let entry_ok = self.segments().any(|segment| {
+            segment.prot.contains(Protection::EXECUTE)
+                && (segment.vaddr..segment.vaddr + segment.mem_size).contains(&self.entry)
+        });
+        if !entry_ok {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(())
+    }
+
+    fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers
+            .chunks_exact(PROGRAM_HEADER_SIZE)
+            .map(ProgramHeader::read)
+    }
+}
+
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    file_size: usize,
+    mem_size: usize,
+}
+
+impl ProgramHeader {
+    fn read(bytes: &[u8]) -> Self {
+        Self {
+            kind: read_u32(bytes, 0),
+            flags: read_u32(bytes, 4),
+            offset: read_u64(bytes, 8) as usize,
+            vaddr: read_u64(bytes, 16) as usize,
+            file_size: read_u64(bytes, 32) as usize,
+            mem_size: read_u64(bytes, 40) as usize,
+        }
+    }
+
+    fn prot(&self) -> Protection {
+        let mut prot = Protection::empty();
+        prot.set(Protection::WRITE, self.flags & PF_W != 0);
+        prot.set(Protection::EXECUTE, self.flags & PF_X != 0);
+        prot
+    }
+
+    fn check(&self, file_len: usize) -> Result<(), ElfError> {
+        let file_end = self.offset.checked_add(self.file_size);
+        if self.file_size > self.mem_size || file_end.map_or(true, |end| end > file_len) {
+            return Err(ElfError::BadSegment);
+        }
+
+        let end = self.vaddr.checked_add(self.mem_size);
+        if self.vaddr < Size4KiB::SIZE || end.map_or(true, |end| end > USER_END) {
+            return Err(ElfError::BadAddress);
+        }
+
+        if self
+            .prot()
+            .contains(Protection::WRITE | Protection::EXECUTE)
+        {
+            return Err(ElfError::WritableAndExecutable);
+        }
+        Ok(())
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,166 @@
+//! Loading executables into new processes.
+//!
+//! A [`Program`] is a process with its image mapped and its initial stack built, but
+//! no tasks yet. Starting it spawns the first task, which drops to ring 3 at the entry
+//! point.
+//!
+//! # Initial stack
+//!
+//! The stack follows the System V ABI, so that `rsp` points at `argc` on entry:
+//!
+//! | address      | contents                                         |
+//! |--------------|--------------------------------------------------|
+//! | `rsp`        | `argc`                                           |
+//! | `rsp + 8`    | `argv[0]` .. `argv[argc - 1]`, then a null       |
+//! |              | `envp[0]` .. `envp[envc - 1]`, then a null       |
+//! |              | the auxiliary vector, ending in `AT_NULL`        |
+//! | ...          | padding, then the strings                        |
+//!
+//! `rdi` holds the argument given to [`Program::start`].
+
+use alloc::{format, sync::Arc, vec, vec::Vec};
+use core::mem;
+
+use hal::{
+    vm_types::{Page, PageSize, Size4KiB, VirtAddr},
+    x86_64::syscall::enter_user,
+};
+use log::trace;
+
+use super::elf::{Elf, PROGRAM_HEADER_SIZE};
+use crate::{
+    error::{Context, KernError, KernErrorKind, KernResult},
+    memory::ProcAddrSpace,
+    task::{self, Process, Task},
+};
+
+/// The number of pages in the initial stack, not counting its guard page.
+const STACK_PAGES: usize = 16;
+
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+
+#[derive(Debug)]
+pub struct Program {
+    process: Arc<Process>,
+    entry: usize,
+    stack_ptr: usize,
+}
+
+impl Program {
+    /// Load the executable in `image` into a new process, with `args` and `env` on its
+    /// stack. Fails with [`KernErrorKind::InvalidArgument`] if the image is malformed.
+    pub fn load(image: &[u8], args: &[&str], env: &[&str]) -> KernResult<Self> {
+        let elf =
+            Elf::parse(image).map_err(|err| KernError::new(KernErrorKind::InvalidArgument, err))?;
+
+        let space = ProcAddrSpace::new()?;
+        for segment in elf.segments() {
+            trace!(
+                "loading segment at {:#x}, {:#x} bytes",
+                segment.vaddr,
+                segment.mem_size
+            );
+            for addr in segment.pages().step_by(Size4KiB::SIZE) {
+                let page = Page::from_base(VirtAddr::from_usize(addr)).unwrap();
+                space
+                    .map_zeroed(page, segment.prot)
+                    .with_context(|| format!("mapping the segment at {:#x}", segment.vaddr))?;
+            }
+            space.write(VirtAddr::from_usize(segment.vaddr), segment.data)?;
+        }
+
+        let mut auxv = vec![
+            (AT_PHENT, PROGRAM_HEADER_SIZE),
+            (AT_PHNUM, elf.program_header_count()),
+            (AT_PAGESZ, Size4KiB::SIZE),
+            (AT_ENTRY, elf.entry()),
+        ];
+        if let Some(addr) = elf.program_headers_addr() {
+            auxv.push((AT_PHDR, addr));
+        }
+
+        let stack = space
+            .map_region(STACK_PAGES)
+            .context("mapping the initial stack")?;
+        let stack_ptr = build_stack(&space, stack.end.addr().as_usize(), args, env, &auxv)
+            .context("building the initial stack")?;
+
+        Ok(Self {
+            process: Arc::new(Process::new(Arc::new(space))),
+            entry: elf.entry(),
+            stack_ptr,
+        })
+    }
+
+    /// The process the program runs in, to give it handles before it starts.
+    pub fn process(&self) -> &Arc<Process> {
+        &self.process
+    }
+
+    /// Spawn the program's first task, with `arg` in `rdi`.
+    pub fn start(self, arg: usize) -> KernResult<Task> {
+        let Self {
+            process,
+            entry,
+            stack_ptr,
+        } = self;
+        task::Builder::new()
+            .process(process)
+            .spawn(move || unsafe { enter_user(entry, stack_ptr, arg) })
+    }
+}
+
+/// Write the initial stack below `top`, returning the stack pointer to enter with.
+fn build_stack(
+    space: &ProcAddrSpace,
+    top: usize,
+    args: &[&str],
+    env: &[&str],
+    auxv: &[(usize, usize)],
+) -> KernResult<usize> {
+    let strings_len: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+    let words = 1 + (args.len() + 1) + (env.len() + 1) + (auxv.len() + 1) * 2;
+    let strings = top
+        .checked_sub(strings_len)
+        .ok_or(KernErrorKind::InvalidArgument)?;
+    let sp = strings
+        .checked_sub(words * mem::size_of::<usize>())
+        .ok_or(KernErrorKind::InvalidArgument)?
+        & !0xf;
+    if top - sp > STACK_PAGES * Size4KiB::SIZE {
+        return Err(KernErrorKind::InvalidArgument.into());
+    }
+
+    let mut vector = Vec::new();
+    vector
+        .try_reserve_exact(words)
+        .map_err(|_| KernErrorKind::AllocError)?;
+    let mut string_bytes = Vec::new();
+    string_bytes
+        .try_reserve_exact(strings_len)
+        .map_err(|_| KernErrorKind::AllocError)?;
+
+    vector.push(args.len());
+    for strs in [args, env] {
+        for s in strs {
+            vector.push(strings + string_bytes.len());
+            string_bytes.extend_from_slice(s.as_bytes());
+            string_bytes.push(0);
+        }
+        vector.push(0);
+    }
+    for &(kind, value) in auxv {
+        vector.extend([kind, value]);
+    }
+    vector.extend([AT_NULL, 0]);
+
+    let vector: Vec<u8> = vector.iter().flat_map(|word| word.to_ne_bytes()).collect();
+    space.write(VirtAddr::from_usize(sp), &vector)?;
+    space.write(VirtAddr::from_usize(strings), &string_bytes)?;
+    Ok(sp)
+}
@@ -0,0 +1,54 @@
+//! Exceptions raised by userspace.
+//!
+//! An exception from ring 3 is the fault of the process that raised it, so rather than
+//! treating it as a kernel bug the whole process is terminated, with
+//! [`ExitStatus::Faulted`] for whoever waits on it.
+
+use core::fmt;
+
+use hal::interrupts;
+use log::warn;
+use sol_types::ExitStatus;
+
+use crate::task;
+
+/// An exception raised by a task running in ring 3.
+#[derive(Debug, Clone, Copy)]
+pub struct Fault {
+    pub name: &'static str,
+    /// The address of the faulting instruction.
+    pub rip: usize,
+    /// Zero for exceptions without an error code.
+    pub error_code: usize,
+    /// The address that was accessed, for page faults.
+    pub addr: Option<usize>,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {:#x}", self.name, self.rip)?;
+        if let Some(addr) = self.addr {
+            write!(f, " accessing {:#x}", addr)?;
+        }
+        if self.error_code != 0 {
+            write!(f, ", error code {:#x}", self.error_code)?;
+        }
+        Ok(())
+    }
+}
+
+/// Terminate the current task's process for `fault`, and exit the task. Called from the
+/// trap handler, on the task's own kernel stack.
+pub fn kill(fault: Fault) -> ! {
+    // The trap came from ring 3, so the task holds nothing in the kernel, and tearing the
+    // process down may sleep.
+    unsafe { interrupts::enable() };
+
+    let task = task::current();
+    warn!("task {} killed by {}", task, fault);
+    if let Some(process) = task.process() {
+        process.terminate(ExitStatus::Faulted);
+    }
+    drop(task);
+    task::exit()
+}
@@ -0,0 +1,157 @@
+//! The first process.
+//!
+//! Init is the first module the bootloader loads alongside the kernel, named by
+//! `MODULE_PATH` in `conf/limine.cfg`. It is started as PID 1 with its bootstrap channel
+//! in `rdi`, on which the kernel has queued one message per handle it starts with. Each
+//! message carries a single handle, and its bytes say what it is, as `kind:name`:
+//!
+//! | message                | object                | rights                 |
+//! |------------------------|-----------------------|------------------------|
+//! | `log:init`             | the kernel log        | `WRITE`                |
+//! | `phys_mem:framebuffer` | each framebuffer      | `MAP`, `WRITE`         |
+//! | `io_ports:<device>`    | ports of a device     | `READ`, `WRITE`        |
+//! | `irq:<device>`         | interrupt of a device | `READ`, `WRITE`        |
+//!
+//! Every handle can also be transferred and duplicated, so that init can hand them on to
+//! drivers. The kernel's end of the channel is closed once the messages are queued.
+
+use alloc::{format, sync::Arc, vec};
+use core::{ops::RangeInclusive, slice};
+
+use hal::vm_types::{Caching, Frame, PageSize, PhysAddr, Size4KiB, VirtAddr};
+use limine::{LimineFramebufferRequest, LimineModuleRequest};
+use log::{info, warn};
+
+use super::exec::Program;
+use crate::{
+    arch::x86_64::ioapic::Trigger,
+    error::{Context, KernErrorKind, KernResult},
+    memory::AddrSpace,
+    object::{
+        channel::{self, Endpoint},
+        io_port::IoPorts,
+        irq::Irq,
+        log::Log,
+        phys_mem::PhysMem,
+        KernelObject, Rights,
+    },
+    task::Task,
+};
+
+/// Ports init may drive, for devices the kernel leaves alone.
+const DEVICE_PORTS: &[(&str, RangeInclusive<u16>)] = &[
+    // The PS/2 controller, and the speaker control port in the middle of it.
+    ("ps2", 0x60..=0x64),
+    // The CMOS real time clock.
+    ("rtc", 0x70..=0x71),
+];
+
+/// The ISA interrupt lines of the same devices. Without the MADT's overrides, each is
+/// taken to be the I/O APIC line of the same number.
+const DEVICE_IRQS: &[(&str, u8)] = &[("ps2", 1), ("rtc", 8)];
+
+/// Rights added to every handle init starts with.
+const PASS_ON: Rights =
+    Rights::from_bits_truncate(Rights::TRANSFER.bits() | Rights::DUPLICATE.bits());
+
+static MODULE_REQUEST: LimineModuleRequest = LimineModuleRequest::new(0);
+static FRAMEBUFFER_REQUEST: LimineFramebufferRequest = LimineFramebufferRequest::new(0);
+
+/// Load init from the first module and start it. Fails with
+/// [`KernErrorKind::InvalidArgument`] if there is no module, or it isn't an executable.
+pub fn spawn() -> KernResult<Task> {
+    let Some(module) = MODULE_REQUEST
+        .get_response()
+        .get()
+        .and_then(|response| response.modules().first())
+    else {
+        warn!("no init module was loaded");
+        return Err(KernErrorKind::InvalidArgument.into());
+    };
+    let base = module.base.as_ptr().ok_or(KernErrorKind::InvalidArgument)?;
+    // Safety: the bootloader loaded the module there, and the memory is never reused.
+    let image = unsafe { slice::from_raw_parts(base, module.length as usize) };
+
+    let program = Program::load(image, &["init"], &[]).context("loading the init module")?;
+    let (kernel_end, init_end) = channel::create();
+
+    send(
+        &kernel_end,
+        "init",
+        Arc::new(Log::new("init")),
+        Rights::WRITE,
+    )?;
+
+    let framebuffers = FRAMEBUFFER_REQUEST
+        .get_response()
+        .get()
+        .map_or(&[][..], |response| response.framebuffers());
+    for framebuffer in framebuffers {
+        let Some(addr) = framebuffer.address.as_ptr() else { continue };
+        let len = framebuffer.pitch as usize * framebuffer.height as usize;
+        match claim_framebuffer(VirtAddr::from_ptr(addr), len) {
+            Ok(mem) => send(
+                &kernel_end,
+                "framebuffer",
+                Arc::new(mem),
+                Rights::MAP | Rights::WRITE,
+            )?,
+            Err(err) => warn!("not passing on the framebuffer: {:#}", err),
+        }
+    }
+
+    for (name, ports) in DEVICE_PORTS {
+        match IoPorts::claim(ports.clone()) {
+            Ok(ports) => send(
+                &kernel_end,
+                name,
+                Arc::new(ports),
+                Rights::READ | Rights::WRITE,
+            )?,
+            Err(err) => warn!("not passing on the {} ports: {:#}", name, err),
+        }
+    }
+
+    for &(name, gsi) in DEVICE_IRQS {
+        match Irq::bind_legacy(gsi, Trigger::Edge) {
+            Ok(irq) => send(
+                &kernel_end,
+                name,
+                Arc::new(irq),
+                Rights::READ | Rights::WRITE,
+            )?,
+            Err(err) => warn!("not passing on the {} interrupt: {:#}", name, err),
+        }
+    }
+
+    let bootstrap = program
+        .process()
+        .handles()
+        .lock()
+        .insert(Arc::new(init_end), Rights::READ | Rights::WRITE | PASS_ON)?;
+    drop(kernel_end);
+
+    info!("starting init");
+    program.start(bootstrap.into_raw() as usize)
+}
+
+/// Queue `object` for init, with `rights` and the rights to pass it on.
+fn send<T: KernelObject>(
+    endpoint: &Endpoint,
+    name: &str,
+    object: Arc<T>,
+    rights: Rights,
+) -> KernResult<()> {
+    let message = format!("{}:{}", object.kind(), name).into_bytes();
+    endpoint.write(message, || {
+        Ok(vec![(object as Arc<dyn KernelObject>, rights | PASS_ON)])
+    })
+}
+
+/// Claim the framebuffer the bootloader mapped at `addr`.
+fn claim_framebuffer(addr: VirtAddr, len: usize) -> KernResult<PhysMem> {
+    let start = AddrSpace::Kernel.translate(addr)?;
+    let end = PhysAddr::from_usize(start.as_usize() + len).align_up(Size4KiB::SIZE);
+    let frames = Frame::containing(start)..Frame::from_base(end).unwrap();
+    PhysMem::claim(frames, Caching::WriteThrough)
+}
@@ -3,9 +3,12 @@ use core::iter::repeat_with;
 use entropy::Blake2bPool;
 use hal::{interrupts, x86_64::random::RdSeed};
 use rand_core::{RngCore, SeedableRng};
-use spin::{mutex::SpinMutex, Lazy};
+use spin::Lazy;
 
-static POOL: Lazy<SpinMutex<Blake2bPool>> = Lazy::new(Default::default);
+use crate::sync::spinlock::SpinMutex;
+
+static POOL: Lazy<SpinMutex<Blake2bPool>> =
+    Lazy::new(|| SpinMutex::new(Blake2bPool::default(), lockdep::class!("POOL")));
 
 pub fn init() {
     write_cpu_randomness();
@@ -1,14 +1,20 @@
 pub mod barrier;
+pub mod condvar;
 pub mod futex;
 pub mod lazy;
+pub mod lockdep_hooks;
 pub mod mutex;
 pub mod once;
 pub mod once_cell;
+pub mod rcu;
+pub mod rwlock;
 pub mod spinlock;
 
 pub use self::{
+    condvar::Condvar,
     lazy::Lazy,
     mutex::{Mutex, MutexGuard},
     once::Once,
     once_cell::OnceCell,
+    rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
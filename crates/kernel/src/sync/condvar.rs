@@ -0,0 +1,57 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{
+    futex::{wait, wake_all, wake_one},
+    MutexGuard,
+};
+
+/// A condition variable for use with [`Mutex`](super::Mutex).
+///
+/// Waiters sleep on a sequence number that every notification bumps, so a notification
+/// that lands between unlocking the mutex and going to sleep is never lost. As with any
+/// condition variable, wakeups may be spurious; [`wait_while`](Condvar::wait_while)
+/// deals with that for you.
+#[derive(Debug, Default)]
+pub struct Condvar {
+    seq: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+        }
+    }
+
+    /// Unlock the mutex and sleep until notified, then lock it again.
+    #[track_caller]
+    pub fn wait<T>(&self, guard: &mut MutexGuard<'_, T>) {
+        lockdep::might_sleep();
+
+        let seq = self.seq.load(Ordering::Relaxed);
+        MutexGuard::unlocked(guard, || wait(&self.seq, seq));
+    }
+
+    /// Wait for as long as `condition` holds.
+    #[track_caller]
+    pub fn wait_while<T, F>(&self, guard: &mut MutexGuard<'_, T>, mut condition: F)
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            self.wait(guard);
+        }
+    }
+
+    /// Wake one waiter, returning whether there was one.
+    pub fn notify_one(&self) -> bool {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        wake_one(&self.seq)
+    }
+
+    /// Wake every waiter, returning how many there were.
+    pub fn notify_all(&self) -> usize {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        wake_all(&self.seq)
+    }
+}
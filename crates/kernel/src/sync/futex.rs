@@ -4,13 +4,18 @@
 use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
 use core::{
     cell::Cell,
+    cmp::Reverse,
     hash::{BuildHasher, Hash, Hasher},
-    ptr::NonNull,
-    sync::atomic::{AtomicU32, Ordering},
+    mem,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
 };
 
 use ahash::RandomState;
-use hal::interrupts;
+use hal::{
+    interrupts,
+    vm_types::{Frame, VirtAddr},
+};
 use meteor::{
     tail_list::{self, TailList},
     Node,
@@ -18,28 +23,475 @@ use meteor::{
 use spin::{mutex::SpinMutex, Lazy};
 use tracing::trace;
 
-use crate::task::{self, Task};
+use super::{
+    rcu::{rcu_read_lock, RcuCell},
+    spinlock,
+};
+use crate::{
+    error::{KernErrorKind, KernResult},
+    memory::AddrSpace,
+    task::{self, Process, Task},
+};
+
+/// A bitset that matches every waiter.
+pub const BITSET_MATCH_ANY: u32 = u32::MAX;
+
+/// The table grows as cpus come online, keeping this many buckets per cpu so that
+/// contention on the bucket locks doesn't grow with the machine.
+const BUCKETS_PER_CPU: usize = 64;
 
 pub fn wait(atomic: &AtomicU32, value: u32) {
-    tracing::trace!("futex.wait({:?})", FutexKey::from_atomic(atomic));
-    let bucket = TABLE.bucket(atomic);
-    bucket.wait(atomic, value);
+    let key = FutexKey::from_atomic(atomic);
+    tracing::trace!("futex.wait({:?})", key);
+    // Only interruptible waits fail.
+    _ = wait_inner(key, atomic, value, BITSET_MATCH_ANY, false);
+}
+
+/// Like [`wait`], but fails with [`KernErrorKind::Interrupted`] once the current task's
+/// process is terminating, instead of keeping the task from exiting. Waits on behalf of
+/// userspace use this.
+pub fn wait_interruptible(atomic: &AtomicU32, value: u32) -> KernResult<()> {
+    let key = FutexKey::from_atomic(atomic);
+    tracing::trace!("futex.wait_interruptible({:?})", key);
+    wait_inner(key, atomic, value, BITSET_MATCH_ANY, true)
+}
+
+/// Like [`wait`], but only wakes that share a bit with `bitset` can wake us.
+pub fn wait_bitset(atomic: &AtomicU32, value: u32, bitset: u32) -> KernResult<()> {
+    tracing::trace!(
+        "futex.wait_bitset({:?}, {:#x})",
+        FutexKey::from_atomic(atomic),
+        bitset
+    );
+    if bitset == 0 {
+        return Err(KernErrorKind::InvalidArgument.into());
+    }
+    wait_inner(FutexKey::from_atomic(atomic), atomic, value, bitset, false)
+}
+
+/// Wait on the futex identified by `key`, of which `atomic` must be a mapping. This is
+/// how process shared futexes are waited on, so the wait is interruptible like
+/// [`wait_interruptible`].
+pub fn wait_keyed(key: FutexKey, atomic: &AtomicU32, value: u32, bitset: u32) -> KernResult<()> {
+    if bitset == 0 {
+        return Err(KernErrorKind::InvalidArgument.into());
+    }
+    wait_inner(key, atomic, value, bitset, true)
 }
 
 pub fn wake_one(atomic: *const AtomicU32) -> bool {
-    tracing::trace!("futex.wake_one({:?})", FutexKey::from_atomic(atomic));
-    let bucket = TABLE.bucket(atomic);
-    bucket.wake_one(atomic)
+    let key = FutexKey::from_atomic(atomic);
+    tracing::trace!("futex.wake_one({:?})", key);
+    wake_inner(key, 1, BITSET_MATCH_ANY) == 1
 }
 
 pub fn wake_all(atomic: *const AtomicU32) -> usize {
-    tracing::trace!("futex.wake_all({:?})", FutexKey::from_atomic(atomic));
-    let bucket = TABLE.bucket(atomic);
-    bucket.wake_all(atomic)
+    let key = FutexKey::from_atomic(atomic);
+    tracing::trace!("futex.wake_all({:?})", key);
+    wake_inner(key, usize::MAX, BITSET_MATCH_ANY)
+}
+
+/// Wake up to `count` of the waiters on `atomic` whose bitset shares a bit with `bitset`,
+/// returning how many were woken.
+pub fn wake_bitset(atomic: *const AtomicU32, count: usize, bitset: u32) -> KernResult<usize> {
+    tracing::trace!(
+        "futex.wake_bitset({:?}, {}, {:#x})",
+        FutexKey::from_atomic(atomic),
+        count,
+        bitset
+    );
+    wake_keyed(FutexKey::from_atomic(atomic), count, bitset)
+}
+
+/// Wake up to `count` waiters on the futex identified by `key` whose bitset shares a bit
+/// with `bitset`.
+pub fn wake_keyed(key: FutexKey, count: usize, bitset: u32) -> KernResult<usize> {
+    if bitset == 0 {
+        return Err(KernErrorKind::InvalidArgument.into());
+    }
+    Ok(wake_inner(key, count, bitset))
+}
+
+/// Wake up to `wake` waiters on `from`, and move up to `requeue` of the rest over to wait
+/// on `to` without waking them. Returns how many waiters were woken or moved.
+///
+/// This lets a condition variable broadcast wake a single waiter and hand the rest
+/// straight to the mutex, rather than waking them all only for them to pile up on it.
+///
+/// If `expected` is given and `from` no longer holds it, nothing is done and this fails
+/// with [`KernErrorKind::WouldBlock`].
+pub fn requeue(
+    from: &AtomicU32,
+    to: *const AtomicU32,
+    wake: usize,
+    requeue: usize,
+    expected: Option<u32>,
+) -> KernResult<usize> {
+    requeue_keyed(
+        FutexKey::from_atomic(from),
+        from,
+        FutexKey::from_atomic(to),
+        wake,
+        requeue,
+        expected,
+    )
+}
+
+/// [`requeue`] for futexes identified by key. `from` must be a mapping of `from_key`.
+pub fn requeue_keyed(
+    from_key: FutexKey,
+    from: &AtomicU32,
+    to_key: FutexKey,
+    wake: usize,
+    requeue: usize,
+    expected: Option<u32>,
+) -> KernResult<usize> {
+    tracing::trace!("futex.requeue({:?} -> {:?})", from_key, to_key);
+
+    interrupts::without(|_| {
+        with_buckets(from_key, to_key, |from_queue, to_queue| {
+            if let Some(expected) = expected {
+                if from.load(Ordering::Acquire) != expected {
+                    return Err(KernErrorKind::WouldBlock.into());
+                }
+            }
+
+            let woken = wake_waiters(from_queue, from_key, wake, BITSET_MATCH_ANY);
+            if from_key == to_key {
+                return Ok(woken);
+            }
+
+            let mut moved = 0;
+            let mut i = 0;
+            while moved < requeue && i < from_queue.len() {
+                if from_queue[i].key != from_key {
+                    i += 1;
+                    continue;
+                }
+
+                match to_queue.as_deref_mut() {
+                    Some(to_queue) => {
+                        let Some(mut waiter) = from_queue.remove(i) else { break };
+                        waiter.key = to_key;
+                        to_queue.push_back(waiter);
+                    }
+                    // Both keys hash to the same bucket, so the waiter can stay put.
+                    None => {
+                        from_queue[i].key = to_key;
+                        i += 1;
+                    }
+                }
+                moved += 1;
+            }
+
+            Ok(woken + moved)
+        })
+    })
+}
+
+/// The operation [`wake_op`] applies to its second futex word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeOp {
+    Set(u32),
+    Add(u32),
+    Or(u32),
+    AndNot(u32),
+    Xor(u32),
+}
+
+impl WakeOp {
+    /// Apply the operation, returning the old value.
+    fn apply(self, atomic: &AtomicU32) -> u32 {
+        match self {
+            Self::Set(arg) => atomic.swap(arg, Ordering::AcqRel),
+            Self::Add(arg) => atomic.fetch_add(arg, Ordering::AcqRel),
+            Self::Or(arg) => atomic.fetch_or(arg, Ordering::AcqRel),
+            Self::AndNot(arg) => atomic.fetch_and(!arg, Ordering::AcqRel),
+            Self::Xor(arg) => atomic.fetch_xor(arg, Ordering::AcqRel),
+        }
+    }
 }
 
-static TABLE: Lazy<Table> = Lazy::new(Table::new);
+/// The comparison [`wake_op`] makes against the old value of its second futex word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeCmp {
+    Eq(u32),
+    Ne(u32),
+    Lt(u32),
+    Le(u32),
+    Gt(u32),
+    Ge(u32),
+}
 
+impl WakeCmp {
+    fn matches(self, old: u32) -> bool {
+        match self {
+            Self::Eq(arg) => old == arg,
+            Self::Ne(arg) => old != arg,
+            Self::Lt(arg) => old < arg,
+            Self::Le(arg) => old <= arg,
+            Self::Gt(arg) => old > arg,
+            Self::Ge(arg) => old >= arg,
+        }
+    }
+}
+
+/// Apply `op` to `atomic2`, then wake up to `wake1` waiters on `atomic1` and, if the old
+/// value of `atomic2` satisfies `cmp`, up to `wake2` waiters on `atomic2`. Returns how
+/// many waiters were woken in total.
+///
+/// Both buckets are locked throughout, so nobody can start waiting on `atomic2` between
+/// the update and the wakeups.
+pub fn wake_op(
+    atomic1: *const AtomicU32,
+    wake1: usize,
+    atomic2: &AtomicU32,
+    op: WakeOp,
+    cmp: WakeCmp,
+    wake2: usize,
+) -> usize {
+    wake_op_keyed(
+        FutexKey::from_atomic(atomic1),
+        wake1,
+        FutexKey::from_atomic(atomic2),
+        atomic2,
+        op,
+        cmp,
+        wake2,
+    )
+}
+
+/// [`wake_op`] for futexes identified by key. `atomic2` must be a mapping of `key2`.
+pub fn wake_op_keyed(
+    key1: FutexKey,
+    wake1: usize,
+    key2: FutexKey,
+    atomic2: &AtomicU32,
+    op: WakeOp,
+    cmp: WakeCmp,
+    wake2: usize,
+) -> usize {
+    tracing::trace!("futex.wake_op({:?}, {:?}, {:?}, {:?})", key1, key2, op, cmp);
+
+    interrupts::without(|_| {
+        with_buckets(key1, key2, |queue1, queue2| {
+            let old = op.apply(atomic2);
+
+            let mut woken = wake_waiters(queue1, key1, wake1, BITSET_MATCH_ANY);
+            if cmp.matches(old) {
+                let queue2 = match queue2 {
+                    Some(queue2) => queue2,
+                    None => queue1,
+                };
+                woken += wake_waiters(queue2, key2, wake2, BITSET_MATCH_ANY);
+            }
+            woken
+        })
+    })
+}
+
+/// Wake the threads of `process` from their interruptible waits, which then fail with
+/// [`KernErrorKind::Interrupted`]. Returns how many were woken.
+///
+/// The process must already be terminating. Waiters check that under their bucket lock,
+/// which this takes afterwards, so none can go to sleep unseen.
+pub fn interrupt(process: &Process) -> usize {
+    let belongs = |waiter: &Waiter| {
+        waiter.interruptible
+            && waiter
+                .thread
+                .process()
+                .map_or(false, |owner| ptr::eq(&**owner, process))
+    };
+
+    let mut woken = 0;
+    interrupts::without(|_| loop {
+        let guard = rcu_read_lock();
+        let scanned = TABLE.read(&guard).buckets.iter().all(|bucket| {
+            let mut queue = bucket.queue.lock();
+            // A resize moved the rest of the waiters, so go again on the new table.
+            let Some(queue) = queue.as_mut() else { return false };
+            let mut i = 0;
+            while i < queue.len() {
+                if !belongs(&queue[i]) {
+                    i += 1;
+                } else if let Some(waiter) = queue.remove(i) {
+                    waiter.thread.unpark();
+                    woken += 1;
+                }
+            }
+            true
+        });
+        if scanned {
+            break;
+        }
+        drop(guard);
+        core::hint::spin_loop();
+    });
+    woken
+}
+
+/// Grow the table for one more cpu. Called on each cpu as it is brought up.
+pub fn cpu_online() {
+    let cpus = ONLINE_CPUS.fetch_add(1, Ordering::Relaxed) + 1;
+    let wanted = (cpus * BUCKETS_PER_CPU).next_power_of_two();
+
+    let _resizing = RESIZING.lock();
+    let hash_builder = {
+        let guard = rcu_read_lock();
+        let table = TABLE.read(&guard);
+        (table.buckets.len() < wanted).then(|| table.hash_builder.clone())
+    };
+    let Some(hash_builder) = hash_builder else { return };
+
+    // Nobody else can see the new table yet, so its buckets don't need locking. Each old
+    // bucket is left marked as moved, and anyone who finds it so tries again once the new
+    // table is published.
+    let mut new = Table::new(wanted, hash_builder);
+    interrupts::without(|_| {
+        let guard = rcu_read_lock();
+        for bucket in TABLE.read(&guard).buckets.iter() {
+            let waiters = bucket.queue.lock().take().unwrap_or_default();
+            // Waiters on any one key all come from the same old bucket, so this keeps
+            // them in order.
+            for waiter in waiters {
+                let index = new.index(waiter.key);
+                let queue = new.buckets[index].queue.get_mut();
+                queue.get_or_insert_with(VecDeque::new).push_back(waiter);
+            }
+        }
+        TABLE.store(new);
+    });
+
+    trace!("futex table resized to {} buckets", wanted);
+}
+
+/// Run `f` on the buckets of `a` and `b`, as [`Table::with_buckets`] does, in whichever
+/// table is current.
+fn with_buckets<F, R>(a: FutexKey, b: FutexKey, f: F) -> R
+where
+    F: FnOnce(&mut VecDeque<Waiter>, Option<&mut VecDeque<Waiter>>) -> R,
+{
+    let mut f = Some(f);
+    loop {
+        let guard = rcu_read_lock();
+        let table = TABLE.read(&guard);
+        let result = table.with_buckets(a, b, |first, second| {
+            let f = f.take().expect("bucket callback already run");
+            f(first, second)
+        });
+        match result {
+            Some(result) => return result,
+            // A resize is moving the waiters; spin until the new table is published.
+            None => core::hint::spin_loop(),
+        }
+    }
+}
+
+fn wait_inner(
+    key: FutexKey,
+    atomic: &AtomicU32,
+    value: u32,
+    bitset: u32,
+    interruptible: bool,
+) -> KernResult<()> {
+    let interrupted = || {
+        interruptible
+            && task::current()
+                .process()
+                .map_or(false, |process| process.is_terminating())
+    };
+
+    interrupts::without(|_| {
+        // Checked under the bucket lock, which [`interrupt`] takes after the process is
+        // marked as terminating.
+        let queued = with_buckets(key, key, |queue, _| -> KernResult<bool> {
+            if interrupted() {
+                return Err(KernErrorKind::Interrupted.into());
+            }
+            if atomic.load(Ordering::Acquire) != value {
+                return Ok(false);
+            }
+
+            queue.push_back(Waiter {
+                key,
+                bitset,
+                interruptible,
+                thread: task::current(),
+            });
+            Ok(true)
+        })?;
+
+        if queued {
+            task::park();
+        }
+        Ok(())
+    })?;
+
+    // Whatever woke the task, a process on its way out has no use for it.
+    if interrupted() {
+        return Err(KernErrorKind::Interrupted.into());
+    }
+    Ok(())
+}
+
+fn wake_inner(key: FutexKey, count: usize, bitset: u32) -> usize {
+    interrupts::without(|_| {
+        with_buckets(key, key, |queue, _| wake_waiters(queue, key, count, bitset))
+    })
+}
+
+/// Wake up to `count` waiters on `key` whose bitset shares a bit with `bitset`: the
+/// highest effective policy first, in FIFO order among equals.
+fn wake_waiters(queue: &mut VecDeque<Waiter>, key: FutexKey, count: usize, bitset: u32) -> usize {
+    let matches = |waiter: &Waiter| waiter.key == key && waiter.bitset & bitset != 0;
+    let mut woken = 0;
+
+    if count >= queue.len() {
+        // Everyone who matches is woken, so the order doesn't matter.
+        let mut i = 0;
+        while i < queue.len() {
+            if matches(&queue[i]) {
+                if let Some(waiter) = queue.remove(i) {
+                    trace!("futex.wake");
+                    waiter.thread.unpark();
+                    woken += 1;
+                }
+            } else {
+                i += 1;
+            }
+        }
+        return woken;
+    }
+
+    while woken < count {
+        let next = queue
+            .iter()
+            .enumerate()
+            .filter(|(_, waiter)| matches(waiter))
+            .max_by_key(|&(i, waiter)| (waiter.thread.policy(), Reverse(i)))
+            .map(|(i, _)| i);
+
+        let Some(waiter) = next.and_then(|i| queue.remove(i)) else { break };
+        trace!("futex.wake");
+        waiter.thread.unpark();
+        woken += 1;
+    }
+    woken
+}
+
+static TABLE: Lazy<RcuCell<Table>> =
+    Lazy::new(|| RcuCell::new(Table::new(BUCKETS_PER_CPU, RandomState::new())));
+
+/// Serializes resizes, which are the only writers of [`TABLE`].
+static RESIZING: spinlock::SpinMutex<()> =
+    spinlock::SpinMutex::new((), lockdep::class!("RESIZING"));
+
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// The futex hash table. Bucket operations find it under RCU, so they never wait for a
+/// resize; a resize builds a new table, moves every waiter over to it and publishes it,
+/// and the old one is freed after a grace period.
 #[derive(Debug)]
 struct Table {
     buckets: Box<[Bucket]>,
@@ -48,103 +500,109 @@ struct Table {
 }
 
 impl Table {
-    pub fn new() -> Self {
-        let num_buckets = 64;
+    pub fn new(num_buckets: usize, hash_builder: RandomState) -> Self {
+        debug_assert!(num_buckets.is_power_of_two());
+
         let mut buckets = Vec::with_capacity(num_buckets);
         buckets.resize_with(num_buckets, Default::default);
         let buckets = buckets.into_boxed_slice();
 
         Self {
             buckets,
-            hash_builder: RandomState::new(),
+            hash_builder,
             mask: (num_buckets as u64 - 1),
         }
     }
 
-    pub fn bucket(&self, atomic: *const AtomicU32) -> &Bucket {
+    pub fn index(&self, key: FutexKey) -> usize {
         let mut hasher = self.hash_builder.build_hasher();
-        (atomic as usize).hash(&mut hasher);
+        key.hash(&mut hasher);
         let hash = hasher.finish();
-        let bucket = hash & self.mask;
-        &self.buckets[bucket as usize]
+        (hash & self.mask) as usize
     }
-}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct FutexKey(usize);
+    /// Lock the buckets of two keys, in a consistent order so that two callers can't
+    /// deadlock. If both keys hash to the same bucket, it is only locked once and `f`
+    /// gets `None` for the second. Returns `None` without calling `f` if either bucket
+    /// has been moved to a newer table.
+    pub fn with_buckets<F, R>(&self, a: FutexKey, b: FutexKey, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut VecDeque<Waiter>, Option<&mut VecDeque<Waiter>>) -> R,
+    {
+        let (i, j) = (self.index(a), self.index(b));
+        if i == j {
+            return Some(f(self.buckets[i].queue.lock().as_mut()?, None));
+        }
 
-impl FutexKey {
-    pub fn from_atomic(atomic: *const AtomicU32) -> Self {
-        Self(atomic as usize)
+        let mut first = self.buckets[i.min(j)].queue.lock();
+        let mut second = self.buckets[i.max(j)].queue.lock();
+        let (first, second) = (first.as_mut()?, second.as_mut()?);
+        if i < j {
+            Some(f(first, Some(second)))
+        } else {
+            Some(f(second, Some(first)))
+        }
     }
 }
 
-#[derive(Debug, Default)]
-struct Bucket {
-    /// Todo: Consider using a priority queue/btreemap here
-    queue: SpinMutex<VecDeque<Waiter>>,
+/// Identifies a futex. Waiters and wakers only meet if they agree on the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FutexKey {
+    /// A futex private to one address space, keyed by the space and virtual address.
+    Private { space: usize, addr: usize },
+    /// A futex in memory that may be mapped more than once, keyed by the frame backing it
+    /// and the offset into that frame, so that every mapping finds the same waiters.
+    Shared { frame: usize, offset: usize },
 }
 
-impl Bucket {
-    pub fn wait(&self, atomic: &AtomicU32, value: u32) {
-        interrupts::without(|_| {
-            let key = FutexKey::from_atomic(atomic);
-
-            let mut queue = self.queue.lock();
-            if atomic.load(Ordering::Acquire) != value {
-                return;
-            }
-
-            queue.push_back(Waiter {
-                key,
-                thread: task::current(),
-            });
-
-            drop(queue);
+impl FutexKey {
+    /// The key of a futex in kernel memory that only the kernel uses.
+    pub fn from_atomic(atomic: *const AtomicU32) -> Self {
+        Self::Private {
+            space: AddrSpace::Kernel.id(),
+            addr: atomic as usize,
+        }
+    }
 
-            task::park();
-        })
+    /// The key of a futex private to `space`. Nothing needs resolving, so this is the
+    /// fast path, but only waiters in the same address space will match.
+    pub fn private(space: &AddrSpace, addr: VirtAddr) -> Self {
+        Self::Private {
+            space: space.id(),
+            addr: addr.as_usize(),
+        }
     }
 
-    pub fn wake_one(&self, atomic: *const AtomicU32) -> bool {
-        interrupts::without(|_| {
-            let mut queue = self.queue.lock();
-            let key = FutexKey::from_atomic(atomic);
+    /// The key of a futex that may be shared between address spaces, or with the kernel,
+    /// through shared memory. `addr` is resolved through `space`'s page table, and must
+    /// be mapped.
+    pub fn shared(space: &AddrSpace, addr: VirtAddr) -> KernResult<Self> {
+        if !addr.is_aligned(mem::align_of::<AtomicU32>()) {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
 
-            for i in 0..queue.len() {
-                if queue[i].key == key {
-                    if let Some(waiter) = queue.remove(i) {
-                        trace!("futex.wake_one");
-                        waiter.thread.unpark();
-                        return true;
-                    }
-                }
-            }
-            false
+        let phys = space.translate(addr)?;
+        let frame = Frame::containing(phys).addr();
+        Ok(Self::Shared {
+            frame: frame.as_usize(),
+            offset: phys.as_usize() - frame.as_usize(),
         })
     }
+}
 
-    pub fn wake_all(&self, atomic: *const AtomicU32) -> usize {
-        interrupts::without(|_| {
-            let mut queue = self.queue.lock();
-            let mut woken = 0;
-            let key = FutexKey::from_atomic(atomic);
-
-            let mut i = 0;
-            while i < queue.len() {
-                if queue[i].key == key {
-                    if let Some(waiter) = queue.remove(i) {
-                        trace!("futex.wake_all");
-                        waiter.thread.unpark();
-                        woken += 1;
-                    }
-                } else {
-                    i += 1;
-                }
-            }
+#[derive(Debug)]
+struct Bucket {
+    /// Todo: Consider using a priority queue/btreemap here
+    ///
+    /// `None` once a resize has moved the waiters to a newer table.
+    queue: SpinMutex<Option<VecDeque<Waiter>>>,
+}
 
-            woken
-        })
+impl Default for Bucket {
+    fn default() -> Self {
+        Self {
+            queue: SpinMutex::new(Some(VecDeque::new())),
+        }
     }
 }
 
@@ -227,6 +685,10 @@ impl Bucket2 {
 #[derive(Debug)]
 struct Waiter {
     key: FutexKey,
+    /// Only wakes sharing a bit with this wake the waiter.
+    bitset: u32,
+    /// Whether [`interrupt`] wakes the waiter.
+    interruptible: bool,
     thread: Task,
     // link: tail_queue::Link,
 }
@@ -0,0 +1,33 @@
+//! Glue between the `lockdep` lock validator and the kernel's tasks.
+
+use hal::interrupts;
+use lockdep::{HeldLocks, Hooks};
+
+use crate::task;
+
+static HOOKS: Hooks = Hooks {
+    held,
+    interrupts_enabled,
+    without_interrupts,
+};
+
+/// Start validating locks. This does nothing unless the `lockdep` feature is enabled.
+pub fn init() {
+    lockdep::init(&HOOKS);
+}
+
+fn held() -> Option<&'static HeldLocks> {
+    let task = task::try_current().ok()?;
+    // The running task outlives any lock operation it performs, so the reference stays
+    // valid for as long as lockdep uses it.
+    let held: *const HeldLocks = &task.head().held_locks;
+    Some(unsafe { &*held })
+}
+
+fn interrupts_enabled() -> bool {
+    unsafe { interrupts::are_enabled() }
+}
+
+fn without_interrupts(f: &mut dyn FnMut()) {
+    interrupts::without(|_| f());
+}
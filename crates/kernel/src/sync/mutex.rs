@@ -1,49 +1,131 @@
+use alloc::vec::Vec;
 use core::{
-    hint,
-    sync::atomic::{AtomicU32, Ordering},
+    hint, ptr,
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering},
 };
 
 use lock_api::GuardSend;
+use lockdep::{LockClass, LockKind};
 use log::trace;
+use spin::mutex::SpinMutex;
 
-use super::futex::{wait, wake_all, wake_one};
+use self::pi::{PiState, Waiting};
+use super::futex::{wait, wake_one};
+use crate::task::{self, Head};
+
+mod pi;
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// Locked, with tasks waiting or about to wait.
+const CONTENDED: u32 = 2;
 
 pub type Mutex<T> = lock_api::Mutex<RawMutex, T>;
 pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, RawMutex, T>;
 
+/// The lockdep class of mutexes made with `Mutex::new`.
+static CLASS: LockClass = LockClass::new("Mutex");
+
+/// Create a mutex in lockdep class `class`. Mutexes made with `Mutex::new` all share one
+/// class, so any that nest inside others should be given their own.
+pub const fn with_class<T>(value: T, class: &'static LockClass) -> Mutex<T> {
+    Mutex::const_new(RawMutex::with_class(class), value)
+}
+
 #[derive(Debug)]
 pub struct RawMutex {
     state: AtomicU32,
+    /// The task holding the mutex, or null if it's unlocked or the holder has yet to
+    /// record itself. No reference is held: the `pi` module says when it may be followed.
+    owner: AtomicPtr<Head>,
+    /// Waiter tracking for priority inheritance.
+    pi: SpinMutex<PiState>,
+    class: Option<&'static LockClass>,
 }
 
 impl RawMutex {
+    pub const fn with_class(class: &'static LockClass) -> Self {
+        Self {
+            class: Some(class),
+            ..<Self as lock_api::RawMutex>::INIT
+        }
+    }
+
+    fn class(&self) -> &'static LockClass {
+        self.class.unwrap_or(&CLASS)
+    }
+
     #[cold]
     fn lock_contended(&self) {
         trace!("mutex.lock_contended()");
         let mut state = self.spin();
 
-        if state == 0 {
-            match self
-                .state
-                .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
-            {
-                Ok(_) => return,
+        if state == UNLOCKED {
+            match self.state.compare_exchange(
+                UNLOCKED,
+                LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return self.acquired(),
                 Err(s) => state = s,
             }
         }
 
+        // Before the scheduler is running there is nobody to inherit from or lend to.
+        let waiting = task::try_current()
+            .ok()
+            .map(|task| Waiting::new(self, task));
+
         loop {
-            if state != 2 && self.state.swap(2, Ordering::Acquire) == 0 {
-                return;
+            if state != CONTENDED && self.state.swap(CONTENDED, Ordering::SeqCst) == UNLOCKED {
+                break;
+            }
+            if let Some(waiting) = &waiting {
+                // The owner may have changed since we last slept, so lend our policy
+                // again every time round.
+                waiting.boost_owner();
             }
-            wait(&self.state, 2);
+            wait(&self.state, CONTENDED);
             state = self.spin();
         }
+
+        drop(waiting);
+        self.acquired();
+    }
+
+    /// Record the current task as the owner. This is all the fast path does for priority
+    /// inheritance: the rest is left to whoever finds the mutex contended.
+    fn acquired(&self) {
+        self.owner.store(task::current_ptr(), Ordering::SeqCst);
+
+        // A waiter that saw the mutex locked before the store above had nobody to lend its
+        // policy to, so pick it up now.
+        if self.state.load(Ordering::SeqCst) == CONTENDED {
+            pi::inherit_waiters(self);
+        }
+    }
+
+    fn try_lock_uninstrumented(&self) -> bool {
+        let locked = self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        if locked {
+            self.acquired();
+        }
+        locked
+    }
+
+    fn key(&self) -> *const () {
+        (self as *const Self).cast()
     }
 
     #[cold]
-    fn wake(&self) {
+    fn unlock_contended(&self) {
         trace!("mutex.wake");
+        pi::release(self);
         wake_one(&self.state);
     }
 
@@ -51,7 +133,7 @@ impl RawMutex {
         let mut spin = 100;
         loop {
             let state = self.state.load(Ordering::Relaxed);
-            if state != 2 || spin == 0 {
+            if state != CONTENDED || spin == 0 {
                 return state;
             }
             hint::spin_loop();
@@ -65,27 +147,44 @@ unsafe impl lock_api::RawMutex for RawMutex {
 
     #[allow(clippy::declare_interior_mutable_const)]
     const INIT: Self = Self {
-        state: AtomicU32::new(0),
+        state: AtomicU32::new(UNLOCKED),
+        owner: AtomicPtr::new(ptr::null_mut()),
+        pi: SpinMutex::new(PiState {
+            waiters: Vec::new(),
+        }),
+        class: None,
     };
 
     #[inline]
+    #[track_caller]
     fn try_lock(&self) -> bool {
-        self.state
-            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
+        let locked = self.try_lock_uninstrumented();
+        if locked {
+            lockdep::try_acquired(self.key(), self.class(), LockKind::Sleep);
+        }
+        locked
     }
 
     #[inline]
+    #[track_caller]
     fn lock(&self) {
-        if !self.try_lock() {
+        lockdep::might_sleep();
+        lockdep::acquire(self.key(), self.class(), LockKind::Sleep);
+
+        if !self.try_lock_uninstrumented() {
             self.lock_contended();
         }
     }
 
     #[inline]
     unsafe fn unlock(&self) {
-        if self.state.swap(0, Ordering::Release) == 2 {
-            self.wake();
+        lockdep::release(self.key());
+        // A waiter may have taken the owner out to boost it; wait for it to be put back.
+        if self.owner.swap(ptr::null_mut(), Ordering::SeqCst).is_null() {
+            pi::clear_owner(self);
+        }
+        if self.state.swap(UNLOCKED, Ordering::SeqCst) == CONTENDED {
+            self.unlock_contended();
         }
     }
 }
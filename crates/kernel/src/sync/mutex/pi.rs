@@ -0,0 +1,160 @@
+//! Priority inheritance for [`RawMutex`].
+//!
+//! A task that blocks on a mutex lends its effective policy to the owner. If the owner is
+//! itself blocked, the policy is passed along the chain of owners. Each boost is recorded
+//! on the owner against the mutex it came through, so releasing a mutex drops exactly the
+//! policy that was inherited through it.
+//!
+//! None of this happens unless the mutex is contended. The owner is recorded with a plain
+//! store of its head pointer, without a reference, and the state here is only touched by
+//! waiters and by an owner that finds the mutex contended. To follow the pointer, a waiter
+//! holding `pi` takes it out of the mutex and puts it back when done. An owner whose
+//! unlock finds it gone waits in [`clear_owner`] for `pi`, so it is still alive while the
+//! waiter takes its reference, and cannot let anyone else in and out of the mutex first.
+
+use alloc::vec::Vec;
+use core::{
+    ptr::{self, NonNull},
+    sync::atomic::Ordering,
+};
+
+use hal::interrupts;
+
+use super::{RawMutex, CONTENDED};
+use crate::task::{self, Policy, Task, TaskId};
+
+/// Bound on how far a boost is propagated, so that a deadlock cycle cannot spin forever.
+const MAX_CHAIN_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub struct PiState {
+    pub waiters: Vec<(TaskId, Policy)>,
+}
+
+impl PiState {
+    fn max_waiter(&self) -> Option<Policy> {
+        self.waiters.iter().map(|&(_, policy)| policy).max()
+    }
+}
+
+/// A task blocked on a mutex. Dropping this removes the task from the mutex's waiters.
+pub struct Waiting<'a> {
+    mutex: &'a RawMutex,
+    task: Task,
+}
+
+impl<'a> Waiting<'a> {
+    pub fn new(mutex: &'a RawMutex, task: Task) -> Self {
+        let entry = (task.id(), task.policy());
+        with_pi(mutex, |pi| pi.waiters.push(entry));
+
+        let blocked_on = mutex as *const RawMutex as *mut RawMutex;
+        task.head()
+            .inheritance
+            .blocked_on
+            .store(blocked_on, Ordering::Release);
+
+        Self { mutex, task }
+    }
+
+    pub fn boost_owner(&self) {
+        propagate(self.mutex);
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.task
+            .head()
+            .inheritance
+            .blocked_on
+            .store(ptr::null_mut(), Ordering::Release);
+
+        let id = self.task.id();
+        with_pi(self.mutex, |pi| pi.waiters.retain(|&(waiter, _)| waiter != id));
+    }
+}
+
+/// Called by a new owner that found the mutex contended. A waiter that got in before the
+/// owner recorded itself couldn't lend it anything, so the owner takes it here.
+pub fn inherit_waiters(mutex: &RawMutex) {
+    let Ok(owner) = task::try_current() else { return };
+    with_pi(mutex, |pi| {
+        if owner.inherit(key(mutex), pi.max_waiter()) {
+            task::reprioritize(&owner);
+        }
+    });
+}
+
+/// Drop whatever the current task inherited through `mutex`. Called by a contended unlock,
+/// after the owner has been cleared.
+pub fn release(mutex: &RawMutex) {
+    let Ok(owner) = task::try_current() else { return };
+    with_pi(mutex, |_| {
+        if owner.inherit(key(mutex), None) {
+            task::reprioritize(&owner);
+        }
+    });
+}
+
+/// Clear the owner of `mutex`, for an unlock that found the pointer taken out. It is put
+/// back before `pi` is released.
+pub fn clear_owner(mutex: &RawMutex) {
+    with_pi(mutex, |_| {
+        mutex.owner.store(ptr::null_mut(), Ordering::SeqCst)
+    });
+}
+
+/// Lend the highest policy waiting on `mutex` to its owner, following the chain of owners
+/// that are themselves blocked.
+fn propagate(mut mutex: &RawMutex) {
+    for _ in 0..MAX_CHAIN_DEPTH {
+        let boosted = with_pi(mutex, |pi| {
+            let owner = owner(mutex)?;
+            if !owner.inherit(key(mutex), pi.max_waiter()) {
+                return None;
+            }
+            task::reprioritize(&owner);
+            Some(owner)
+        });
+        let Some(owner) = boosted else { return };
+
+        // The owner's policy went up, so its entry in whatever it is blocked on is stale.
+        // A mutex cannot be destroyed while a task is blocked on it, so the pointer is
+        // valid for as long as the owner stays blocked.
+        let next = owner.head().inheritance.blocked_on.load(Ordering::Acquire);
+        let Some(next) = (unsafe { next.as_ref() }) else { return };
+
+        let (id, policy) = (owner.id(), owner.policy());
+        with_pi(next, |pi| {
+            if let Some(entry) = pi.waiters.iter_mut().find(|(waiter, _)| *waiter == id) {
+                entry.1 = policy;
+            }
+        });
+
+        mutex = next;
+    }
+}
+
+/// Take a reference to the owner of `mutex`, if it has recorded itself and will come
+/// through [`release`] when it unlocks. Must be called with `pi` held.
+fn owner(mutex: &RawMutex) -> Option<Task> {
+    let owner = NonNull::new(mutex.owner.swap(ptr::null_mut(), Ordering::SeqCst))?;
+    // Until the pointer is put back the owner cannot get past unlocking, so the lock word
+    // cannot leave `CONTENDED` under us either.
+    let task = (mutex.state.load(Ordering::SeqCst) == CONTENDED)
+        .then(|| unsafe { Task::clone_from_raw(owner) });
+    mutex.owner.store(owner.as_ptr(), Ordering::SeqCst);
+    task
+}
+
+fn with_pi<F, T>(mutex: &RawMutex, f: F) -> T
+where
+    F: FnOnce(&mut PiState) -> T,
+{
+    interrupts::without(|_| f(&mut mutex.pi.lock()))
+}
+
+fn key(mutex: &RawMutex) -> usize {
+    mutex as *const RawMutex as usize
+}
@@ -0,0 +1,261 @@
+//! Read-copy-update.
+//!
+//! Readers enter a read-side critical section with [`rcu_read_lock`], which just disables
+//! interrupts on the current cpu, so a reader is never preempted or switched away from.
+//! Writers publish a new version of the data and wait for a *grace period* before freeing
+//! the old one: once every online cpu has passed through a quiescent state (a context
+//! switch, a timer tick that interrupted code with interrupts enabled, or the idle loop),
+//! no reader can still be looking at the old version.
+//!
+//! The scheduler reports quiescent states through [`quiescent_state`]. Writers either
+//! block with [`synchronize_rcu`] or defer the cleanup with [`call_rcu`], whose callbacks
+//! run from the idle loop or the next time someone waits for a grace period.
+
+use alloc::{boxed::Box, collections::VecDeque};
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering},
+};
+
+use hal::{interrupts, task::hw_thread_id};
+use log::trace;
+
+use super::{futex, spinlock::SpinMutex};
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+struct GpState {
+    /// The most recently started grace period.
+    current: u32,
+    /// The latest grace period anyone is waiting for.
+    requested: u32,
+    callbacks: VecDeque<(u32, Callback)>,
+}
+
+static GP: SpinMutex<GpState> = SpinMutex::new(
+    GpState {
+        current: 0,
+        requested: 0,
+        callbacks: VecDeque::new(),
+    },
+    lockdep::class!("GP"),
+);
+
+/// The most recently completed grace period. Waiters sleep on this with the futex.
+static COMPLETED: AtomicU32 = AtomicU32::new(0);
+/// Cpus that have yet to pass through a quiescent state in the current grace period.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+/// Cpus that take part in grace periods.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Grace period numbers wrap, so compare them the way TCP compares sequence numbers.
+fn reached(gp: u32, target: u32) -> bool {
+    gp.wrapping_sub(target) as i32 >= 0
+}
+
+/// Guard for a read-side critical section. Sections nest, and the outermost one ends
+/// when its guard is dropped.
+#[derive(Debug)]
+pub struct RcuReadGuard {
+    were_enabled: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Enter a read-side critical section. The section must not sleep.
+pub fn rcu_read_lock() -> RcuReadGuard {
+    let were_enabled = unsafe { interrupts::are_enabled() };
+    unsafe { interrupts::disable() };
+    RcuReadGuard {
+        were_enabled,
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for RcuReadGuard {
+    fn drop(&mut self) {
+        if self.were_enabled {
+            unsafe { interrupts::enable() };
+        }
+    }
+}
+
+/// Start taking part in grace periods. Called on each cpu as it is brought up.
+pub fn cpu_online(cpu: usize) {
+    ONLINE.fetch_or(1 << cpu, Ordering::AcqRel);
+}
+
+/// Report that the current cpu is not inside a read-side critical section.
+pub fn quiescent_state() {
+    let bit = 1 << unsafe { hw_thread_id() };
+    if PENDING.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+
+    let completed = interrupts::without(|_| {
+        let mut gp = GP.lock();
+        if PENDING.fetch_and(!bit, Ordering::AcqRel) != bit {
+            return false;
+        }
+
+        trace!("rcu: grace period {} completed", gp.current);
+        COMPLETED.store(gp.current, Ordering::Release);
+        if !reached(gp.current, gp.requested) {
+            start_gp(&mut gp);
+        }
+        true
+    });
+
+    if completed {
+        futex::wake_all(&COMPLETED);
+    }
+}
+
+fn start_gp(gp: &mut GpState) {
+    gp.current = gp.current.wrapping_add(1);
+    let online = ONLINE.load(Ordering::Acquire);
+    PENDING.store(online, Ordering::Release);
+    if online == 0 {
+        // Nobody can be reading, so the grace period is over as soon as it starts.
+        COMPLETED.store(gp.current, Ordering::Release);
+    }
+}
+
+/// Ask for a grace period that starts after this call, returning its number.
+fn request_gp(gp: &mut GpState) -> u32 {
+    let target = if PENDING.load(Ordering::Acquire) != 0 {
+        // One is already under way, but a reader may have started before we were called,
+        // so it has to be the next one.
+        gp.current.wrapping_add(1)
+    } else {
+        start_gp(gp);
+        gp.current
+    };
+
+    if reached(target, gp.requested) {
+        gp.requested = target;
+    }
+    target
+}
+
+/// Block until every read-side critical section that was running when this was called
+/// has finished.
+#[track_caller]
+pub fn synchronize_rcu() {
+    lockdep::might_sleep();
+
+    let target = interrupts::without(|_| request_gp(&mut GP.lock()));
+    // We're not a reader, so we can vouch for our own cpu.
+    quiescent_state();
+
+    loop {
+        let completed = COMPLETED.load(Ordering::Acquire);
+        if reached(completed, target) {
+            break;
+        }
+        futex::wait(&COMPLETED, completed);
+    }
+
+    run_callbacks();
+}
+
+/// Run `f` once every read-side critical section that was running when this was called
+/// has finished. Unlike [`synchronize_rcu`] this never blocks, so it can be called from
+/// inside a read-side critical section or with spinlocks held. It does box `f` up, though,
+/// so it can't be called where allocating isn't allowed, such as from an interrupt handler.
+pub fn call_rcu<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let f: Callback = Box::new(f);
+    interrupts::without(|_| {
+        let mut gp = GP.lock();
+        let target = request_gp(&mut gp);
+        gp.callbacks.push_back((target, f));
+    });
+}
+
+/// Run the callbacks whose grace period has completed, returning how many there were.
+pub fn run_callbacks() -> usize {
+    let mut count = 0;
+
+    loop {
+        let next = interrupts::without(|_| {
+            let mut gp = GP.lock();
+            let completed = COMPLETED.load(Ordering::Acquire);
+            match gp.callbacks.front() {
+                Some(&(target, _)) if reached(completed, target) => gp.callbacks.pop_front(),
+                _ => None,
+            }
+        });
+        let Some((_, f)) = next else { break };
+        f();
+        count += 1;
+    }
+
+    count
+}
+
+/// A pointer to a heap allocated `T` that readers can follow without taking a lock.
+///
+/// Updates replace the whole value; the old one is freed once no reader can see it.
+/// Writers are expected to serialize among themselves.
+pub struct RcuCell<T> {
+    ptr: AtomicPtr<T>,
+}
+
+unsafe impl<T: Send + Sync> Send for RcuCell<T> {}
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+
+impl<T> RcuCell<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(value))),
+        }
+    }
+
+    /// Read the current value. It stays valid for as long as the read-side critical
+    /// section.
+    pub fn read<'a>(&'a self, _guard: &'a RcuReadGuard) -> &'a T {
+        unsafe { &*self.ptr.load(Ordering::Acquire) }
+    }
+
+    /// Publish `value`, waiting for a grace period and handing back the old value.
+    pub fn swap(&self, value: T) -> Box<T> {
+        let old = self.replace(value);
+        synchronize_rcu();
+        unsafe { Box::from_raw(old) }
+    }
+
+    fn replace(&self, value: T) -> *mut T {
+        self.ptr
+            .swap(Box::into_raw(Box::new(value)), Ordering::AcqRel)
+    }
+}
+
+impl<T: Send + 'static> RcuCell<T> {
+    /// Publish `value`, freeing the old value after a grace period without waiting for it.
+    pub fn store(&self, value: T) {
+        let old = self.replace(value) as usize;
+        call_rcu(move || unsafe { drop(Box::from_raw(old as *mut T)) });
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        // Readers borrow the cell, so there can't be any left.
+        unsafe { drop(Box::from_raw(*self.ptr.get_mut())) };
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for RcuCell<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let guard = rcu_read_lock();
+        f.debug_tuple("RcuCell").field(self.read(&guard)).finish()
+    }
+}
+
+impl<T: Default> Default for RcuCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
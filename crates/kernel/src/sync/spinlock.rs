@@ -1,48 +1,108 @@
 use core::ops::{Deref, DerefMut};
 
 use hal::interrupts::{self, WithoutInterrupts};
-use spin::mutex::{SpinMutex, SpinMutexGuard};
+use lockdep::{LockClass, LockKind};
 
+/// A spinlock checked by lockdep. This is [`spin::mutex::SpinMutex`] with the same
+/// interface, except that each one is created in a [`LockClass`], usually declared with
+/// [`lockdep::class!`] right where the lock is.
 #[derive(Debug)]
-pub struct SpinLock<T>(SpinMutex<T>);
+pub struct SpinMutex<T> {
+    inner: spin::mutex::SpinMutex<T>,
+    class: &'static LockClass,
+}
 
-impl<T> SpinLock<T> {
-    pub const fn new(value: T) -> Self {
-        Self(SpinMutex::new(value))
+impl<T> SpinMutex<T> {
+    pub const fn new(value: T, class: &'static LockClass) -> Self {
+        Self {
+            inner: spin::mutex::SpinMutex::new(value),
+            class,
+        }
     }
 
-    pub fn lock<'a>(&'a self, _g: &'a WithoutInterrupts) -> SpinLockGuard<'a, T> {
-        SpinLockGuard(self.0.lock())
+    #[track_caller]
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        lockdep::acquire(self.key(), self.class, LockKind::Spin);
+        SpinMutexGuard {
+            lock: self,
+            guard: self.inner.lock(),
+        }
     }
 
-    pub fn try_lock<'a>(&'a self, _g: &'a WithoutInterrupts) -> Option<SpinLockGuard<'a, T>> {
-        self.0.try_lock().map(SpinLockGuard)
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        lockdep::try_acquired(self.key(), self.class, LockKind::Spin);
+        Some(SpinMutexGuard { lock: self, guard })
     }
 
-    pub fn with<F, R>(&self, f: F) -> R
-    where
-        F: FnOnce(&mut T) -> R,
-    {
-        interrupts::without(|g| {
-            let mut guard = self.lock(g);
-            f(&mut guard)
-        })
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.inner.as_mut_ptr()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    fn key(&self) -> *const () {
+        (self as *const Self).cast()
     }
 }
 
 #[derive(Debug)]
-pub struct SpinLockGuard<'a, T>(SpinMutexGuard<'a, T>);
+pub struct SpinMutexGuard<'a, T> {
+    lock: &'a SpinMutex<T>,
+    guard: spin::mutex::SpinMutexGuard<'a, T>,
+}
 
-impl<'a, T> Deref for SpinLockGuard<'a, T> {
+impl<'a, T> Deref for SpinMutexGuard<'a, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.guard
     }
 }
 
-impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+impl<'a, T> DerefMut for SpinMutexGuard<'a, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for SpinMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.key());
+    }
+}
+
+/// A [`SpinMutex`] that can only be taken with interrupts disabled.
+#[derive(Debug)]
+pub struct SpinLock<T>(SpinMutex<T>);
+
+pub type SpinLockGuard<'a, T> = SpinMutexGuard<'a, T>;
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T, class: &'static LockClass) -> Self {
+        Self(SpinMutex::new(value, class))
+    }
+
+    #[track_caller]
+    pub fn lock<'a>(&'a self, _g: &'a WithoutInterrupts) -> SpinLockGuard<'a, T> {
+        self.0.lock()
+    }
+
+    #[track_caller]
+    pub fn try_lock<'a>(&'a self, _g: &'a WithoutInterrupts) -> Option<SpinLockGuard<'a, T>> {
+        self.0.try_lock()
+    }
+
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        interrupts::without(|g| {
+            let mut guard = self.lock(g);
+            f(&mut guard)
+        })
     }
 }
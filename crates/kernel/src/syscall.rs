@@ -16,7 +16,474 @@
 //!
 //! The mem system call is used to interact with memory mappings. It takes a similar
 //! role as mmap/unmap on linux.
+//!
+//! # ABI
+//!
+//! The number goes in `rax` and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8`
+//! and `r9`, see [`hal::x86_64::syscall`]. The result comes back in `rax`: a non-negative
+//! value on success, or `-1 - code` on failure, where `code` is the error code of a
+//! [`KernErrorKind`]. Unknown numbers fail with [`KernErrorKind::Unsupported`].
+//!
+//! Numbers are the index of the syscall in [`SYSCALLS`], and are never reused. Most of
+//! them may also be queued on a [ring](ring) rather than called directly.
+
+use alloc::sync::Arc;
+use core::{mem, sync::atomic::AtomicU32};
+
+use hal::{
+    vm_types::VirtAddr,
+    x86_64::syscall::{self, SyscallFrame, USER_END},
+};
+use log::trace;
+
+use crate::{
+    error::{KernError, KernErrorKind, KernResult},
+    memory::{AddrSpace, Protection},
+    object::{
+        io_port::IoPorts,
+        irq::Irq,
+        log::{Log, MAX_LOG_BYTES},
+        phys_mem::PhysMem,
+        Handle, KernelObject, Rights,
+    },
+    sync::futex::{self, FutexKey},
+    task::{self, Process},
+};
+
+mod channel;
+mod process;
+pub mod ring;
+mod user;
+
+pub const SYS_FUTEX: usize = 0;
+pub const SYS_RING_SETUP: usize = 1;
+pub const SYS_RING_ENTER: usize = 2;
+pub const SYS_HANDLE_DROP: usize = 3;
+pub const SYS_HANDLE_DUPLICATE: usize = 4;
+pub const SYS_CHANNEL_CREATE: usize = 5;
+pub const SYS_CHANNEL_WRITE: usize = 6;
+pub const SYS_CHANNEL_READ: usize = 7;
+pub const SYS_CHANNEL_WAIT: usize = 8;
+pub const SYS_IO_PORTS_ENABLE: usize = 9;
+pub const SYS_IRQ_WAIT: usize = 10;
+pub const SYS_IRQ_ACK: usize = 11;
+pub const SYS_IRQ_MSI_MESSAGE: usize = 12;
+pub const SYS_LOG_WRITE: usize = 13;
+pub const SYS_PHYS_MEM_MAP: usize = 14;
+pub const SYS_PROCESS_EXIT: usize = 15;
+pub const SYS_PROCESS_SPAWN: usize = 16;
+pub const SYS_PROCESS_WAIT: usize = 17;
+pub const SYS_PROCESS_KILL: usize = 18;
+pub const SYS_PROCESS_ID: usize = 19;
+
+struct Syscall {
+    name: &'static str,
+    handler: fn(Args) -> KernResult<usize>,
+    /// Whether the syscall may be submitted through a ring.
+    queueable: bool,
+}
+
+const SYSCALLS: &[Syscall] = &[
+    Syscall {
+        name: "futex",
+        handler: sys_futex,
+        queueable: true,
+    },
+    Syscall {
+        name: "ring_setup",
+        handler: sys_ring_setup,
+        queueable: false,
+    },
+    Syscall {
+        name: "ring_enter",
+        handler: sys_ring_enter,
+        queueable: false,
+    },
+    Syscall {
+        name: "handle_drop",
+        handler: sys_handle_drop,
+        queueable: true,
+    },
+    Syscall {
+        name: "handle_duplicate",
+        handler: sys_handle_duplicate,
+        queueable: true,
+    },
+    Syscall {
+        name: "channel_create",
+        handler: channel::sys_channel_create,
+        queueable: true,
+    },
+    Syscall {
+        name: "channel_write",
+        handler: channel::sys_channel_write,
+        queueable: true,
+    },
+    Syscall {
+        name: "channel_read",
+        handler: channel::sys_channel_read,
+        queueable: true,
+    },
+    Syscall {
+        name: "channel_wait",
+        handler: channel::sys_channel_wait,
+        queueable: true,
+    },
+    Syscall {
+        name: "io_ports_enable",
+        handler: sys_io_ports_enable,
+        queueable: true,
+    },
+    Syscall {
+        name: "irq_wait",
+        handler: sys_irq_wait,
+        queueable: true,
+    },
+    Syscall {
+        name: "irq_ack",
+        handler: sys_irq_ack,
+        queueable: true,
+    },
+    Syscall {
+        name: "irq_msi_message",
+        handler: sys_irq_msi_message,
+        queueable: true,
+    },
+    Syscall {
+        name: "log_write",
+        handler: sys_log_write,
+        queueable: true,
+    },
+    Syscall {
+        name: "phys_mem_map",
+        handler: sys_phys_mem_map,
+        queueable: true,
+    },
+    Syscall {
+        name: "process_exit",
+        handler: process::sys_process_exit,
+        queueable: false,
+    },
+    Syscall {
+        name: "process_spawn",
+        handler: process::sys_process_spawn,
+        queueable: true,
+    },
+    Syscall {
+        name: "process_wait",
+        handler: process::sys_process_wait,
+        queueable: true,
+    },
+    Syscall {
+        name: "process_kill",
+        handler: process::sys_process_kill,
+        queueable: true,
+    },
+    Syscall {
+        name: "process_id",
+        handler: process::sys_process_id,
+        queueable: true,
+    },
+];
+
+/// Route syscalls from every cpu to [`SYSCALLS`]. Each cpu still has to enable them with
+/// [`arch::x86_64::syscall::init_hw_thread`](crate::arch::x86_64::syscall::init_hw_thread).
+pub unsafe fn init() {
+    syscall::set_handler(dispatch);
+}
+
+fn dispatch(frame: &mut SyscallFrame) {
+    let result = run(frame.number(), Args(frame.args()), false);
+    frame.set_return(encode(result));
+
+    // The process may have been terminated while the syscall ran.
+    task::exit_if_terminating();
+}
+
+/// Run syscall `number`, either called directly or, if `queued`, submitted on a ring.
+fn run(number: usize, args: Args, queued: bool) -> KernResult<usize> {
+    match SYSCALLS.get(number) {
+        Some(syscall) if !queued || syscall.queueable => {
+            trace!("syscall {}{:x?}", syscall.name, args.0);
+            let result = (syscall.handler)(args);
+            if let Err(err) = &result {
+                trace!("syscall {} failed: {:?}", syscall.name, err);
+            }
+            result
+        }
+        _ => Err(KernErrorKind::Unsupported.into()),
+    }
+}
+
+fn encode(result: KernResult<usize>) -> usize {
+    match result {
+        Ok(value) => {
+            debug_assert!(
+                value <= isize::MAX as usize,
+                "syscall result looks like an error"
+            );
+            value
+        }
+        Err(err) => error_code(&err),
+    }
+}
+
+fn error_code(err: &KernError) -> usize {
+    err.kind().encode()
+}
+
+/// The raw arguments of a syscall, with accessors that check them before the kernel
+/// acts on them.
+#[derive(Debug, Clone, Copy)]
+struct Args([usize; 6]);
+
+impl Args {
+    fn get(&self, index: usize) -> usize {
+        self.0[index]
+    }
+
+    /// An argument that must fit in 32 bits. Anything set in the high bits is rejected
+    /// rather than silently dropped.
+    fn u32(&self, index: usize) -> KernResult<u32> {
+        self.get(index)
+            .try_into()
+            .map_err(|_| KernErrorKind::InvalidArgument.into())
+    }
+
+    /// A pointer to a `T` in user memory. It must be non-null, aligned, and lie entirely
+    /// below the kernel's half of the address space. Whether it is mapped is up to the
+    /// syscall to check.
+    fn user_addr<T>(&self, index: usize) -> KernResult<VirtAddr> {
+        let addr = self.get(index);
+        let end = addr.checked_add(mem::size_of::<T>());
+        if addr == 0 || addr % mem::align_of::<T>() != 0 || end.map_or(true, |end| end > USER_END) {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+        Ok(VirtAddr::from_usize(addr))
+    }
+
+    fn handle(&self, index: usize) -> KernResult<Handle> {
+        Ok(Handle::from_raw(self.u32(index)?))
+    }
+
+    /// A set of rights, none of which may be unknown.
+    fn rights(&self, index: usize) -> KernResult<Rights> {
+        Rights::from_bits(self.u32(index)?).ok_or_else(|| KernErrorKind::InvalidArgument.into())
+    }
+}
+
+/// Wait until woken, if the futex still holds the expected value.
+pub const FUTEX_WAIT: u32 = 0;
+/// Wake up to the given number of waiters, returning how many were woken.
+pub const FUTEX_WAKE: u32 = 1;
+const FUTEX_OP_MASK: u32 = 0xff;
+/// The futex may be mapped by more than one address space. Without this the futex is
+/// private to the calling process, which is cheaper.
+pub const FUTEX_SHARED: u32 = 1 << 8;
+
+/// `futex(addr, op, value, bitset)`
+///
+/// For [`FUTEX_WAIT`], `value` is the value `addr` must still hold for the caller to go
+/// to sleep. For [`FUTEX_WAKE`] it is the most waiters to wake. Only waits and wakes whose
+/// `bitset`s share a bit meet, so pass [`futex::BITSET_MATCH_ANY`] for the usual
+/// behaviour.
+fn sys_futex(args: Args) -> KernResult<usize> {
+    let addr = args.user_addr::<AtomicU32>(0)?;
+    let op = args.u32(1)?;
+    let bitset = args.u32(3)?;
+
+    let task = task::try_current()?;
+    let space = task.address_space()?;
+    let key = futex_key(&space, addr, op & FUTEX_SHARED != 0)?;
+
+    match op & !FUTEX_SHARED {
+        FUTEX_WAIT => {
+            let value = args.u32(2)?;
+            // Safety: the address was translated above, so it is mapped.
+            let atomic = unsafe { &*addr.as_ptr::<AtomicU32>() };
+            futex::wait_keyed(key, atomic, value, bitset)?;
+            Ok(0)
+        }
+        FUTEX_WAKE => futex::wake_keyed(key, args.get(2), bitset),
+        op if op & !FUTEX_OP_MASK != 0 => Err(KernErrorKind::InvalidArgument.into()),
+        _ => Err(KernErrorKind::Unsupported.into()),
+    }
+}
+
+fn futex_key(space: &AddrSpace, addr: VirtAddr, shared: bool) -> KernResult<FutexKey> {
+    if shared {
+        FutexKey::shared(space, addr)
+    } else {
+        // Private keys don't need the mapping, but the wait still reads through it.
+        space.translate(addr)?;
+        Ok(FutexKey::private(space, addr))
+    }
+}
+
+/// `ring_setup(entries)`
+///
+/// Map a new [ring](ring) with `entries` submission entries into the calling process,
+/// returning its address, which also names it to `ring_enter`. Fails with `Busy` once the
+/// process has [`MAX_RINGS`](ring::MAX_RINGS) rings.
+fn sys_ring_setup(args: Args) -> KernResult<usize> {
+    let entries = args.u32(0)?;
+
+    let task = task::try_current()?;
+    current_process(&task)?.add_ring(&task.address_space()?, entries)
+}
+
+/// `ring_enter(ring, to_submit, min_complete)`
+///
+/// Run up to `to_submit` of the entries queued on `ring`, then wait until at least
+/// `min_complete` completions are waiting to be reaped. Returns how many entries were run.
+fn sys_ring_enter(args: Args) -> KernResult<usize> {
+    let to_submit = args.u32(1)?;
+    let min_complete = args.u32(2)?;
+
+    let task = task::try_current()?;
+    let ring = current_process(&task)?.ring(args.get(0))?;
+    let submitted = ring.submit(to_submit)?;
+    if min_complete > 0 {
+        ring.wait_completions(min_complete)?;
+    }
+    Ok(submitted as usize)
+}
+
+/// `handle_drop(handle)`
+///
+/// Close `handle`. The object goes away once nothing else holds it.
+fn sys_handle_drop(args: Args) -> KernResult<usize> {
+    let handle = args.handle(0)?;
+
+    let task = task::try_current()?;
+    let (object, _) = current_process(&task)?.handles().lock().remove(handle)?;
+    // Dropped outside the lock, as the object's teardown may be arbitrarily involved.
+    drop(object);
+    Ok(0)
+}
+
+/// `handle_duplicate(handle, rights)`
+///
+/// Add another handle to the object `handle` names, with `rights`, which must be a
+/// subset of the rights of `handle`. `handle` must have [`Rights::DUPLICATE`]. Returns the
+/// new handle.
+fn sys_handle_duplicate(args: Args) -> KernResult<usize> {
+    let handle = args.handle(0)?;
+    let rights = args.rights(1)?;
+
+    let task = task::try_current()?;
+    let new = current_process(&task)?
+        .handles()
+        .lock()
+        .duplicate(handle, rights)?;
+    Ok(new.into_raw() as usize)
+}
+
+/// `io_ports_enable(handle)`
+///
+/// Let the calling process use `in` and `out` on the ports of the [`IoPorts`] `handle`
+/// names, for as long as it lives. `handle` needs [`Rights::READ`] and [`Rights::WRITE`].
+fn sys_io_ports_enable(args: Args) -> KernResult<usize> {
+    let handle = args.handle(0)?;
+
+    let task = task::try_current()?;
+    let process = current_process(&task)?;
+    let ports = process
+        .handles()
+        .lock()
+        .get::<IoPorts>(handle, Rights::READ | Rights::WRITE)?;
+    process.enable_io_ports(ports)?;
+    Ok(0)
+}
+
+/// `irq_wait(handle)`
+///
+/// Sleep until the [`Irq`] `handle` names has fired and not been acked. The interrupt
+/// stays masked until then. Returns how many times it has fired in all, which wraps.
+/// `handle` needs [`Rights::READ`].
+fn sys_irq_wait(args: Args) -> KernResult<usize> {
+    let irq = current_object::<Irq>(args.handle(0)?, Rights::READ)?;
+    Ok(irq.wait()? as usize)
+}
+
+/// `irq_ack(handle)`
+///
+/// Unmask the [`Irq`] `handle` names, once the device has been dealt with. `handle` needs
+/// [`Rights::WRITE`].
+fn sys_irq_ack(args: Args) -> KernResult<usize> {
+    let irq = current_object::<Irq>(args.handle(0)?, Rights::WRITE)?;
+    irq.ack();
+    Ok(0)
+}
+
+/// `irq_msi_message(handle)`
+///
+/// The message a device must write to raise the [`Irq`] `handle` names: the address in
+/// the low 32 bits and the data in the high 32 bits. Fails with `InvalidArgument` if the
+/// interrupt isn't message signalled. `handle` needs [`Rights::READ`].
+fn sys_irq_msi_message(args: Args) -> KernResult<usize> {
+    let irq = current_object::<Irq>(args.handle(0)?, Rights::READ)?;
+    let (addr, data) = irq.msi_message().ok_or(KernErrorKind::InvalidArgument)?;
+    Ok(addr as usize | (data as usize) << 32)
+}
+
+/// `log_write(handle, bytes, len)`
+///
+/// Write the `len` bytes at `bytes` to the [`Log`] `handle` names. `handle` needs
+/// [`Rights::WRITE`].
+fn sys_log_write(args: Args) -> KernResult<usize> {
+    let (addr, len) = (args.get(1), args.get(2));
+    if len > MAX_LOG_BYTES {
+        return Err(KernErrorKind::InvalidArgument.into());
+    }
+
+    let log = current_object::<Log>(args.handle(0)?, Rights::WRITE)?;
+    let task = task::try_current()?;
+    let bytes = user::copy_from_user(&task.address_space()?, addr, len)?;
+    log.write(&bytes)?;
+    Ok(0)
+}
+
+/// `phys_mem_map(handle)`
+///
+/// Map the [`PhysMem`] `handle` names into the calling process, returning its address.
+/// `handle` needs [`Rights::MAP`], and the mapping is only writable if it also has
+/// [`Rights::WRITE`]. Device memory is never executable.
+fn sys_phys_mem_map(args: Args) -> KernResult<usize> {
+    let handle = args.handle(0)?;
+
+    let task = task::try_current()?;
+    let process = current_process(&task)?;
+    let (mem, rights) = {
+        let handles = process.handles().lock();
+        (
+            handles.get::<PhysMem>(handle, Rights::MAP)?,
+            handles.rights(handle)?,
+        )
+    };
+
+    let mut prot = Protection::empty();
+    prot.set(Protection::WRITE, rights.contains(Rights::WRITE));
+    let region = process
+        .address_space()?
+        .map_device(mem.frames(), prot, mem.caching())?;
+    Ok(region.start.addr().as_usize())
+}
 
-const SYSCALLS: &[extern "C" fn()] = &[];
+/// The object `handle` names in the calling process, which must be a `T` held with at
+/// least `rights`.
+fn current_object<T: KernelObject>(handle: Handle, rights: Rights) -> KernResult<Arc<T>> {
+    let task = task::try_current()?;
+    // Bound first, so that the guard is dropped before `task`.
+    let object = current_process(&task)?
+        .handles()
+        .lock()
+        .get::<T>(handle, rights);
+    object
+}
 
-extern "C" fn sys_futex() {}
+/// Kernel threads have no process to make syscalls on behalf of.
+fn current_process(task: &task::Task) -> KernResult<&Arc<Process>> {
+    task.process()
+        .ok_or_else(|| KernErrorKind::InvalidArgument.into())
+}
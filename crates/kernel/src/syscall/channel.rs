@@ -0,0 +1,148 @@
+//! Syscalls on [channels](crate::object::channel).
+
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{current_process, user, Args};
+use crate::{
+    error::{KernErrorKind, KernResult},
+    memory::Protection,
+    object::{
+        channel::{self, Endpoint, MAX_MESSAGE_BYTES, MAX_MESSAGE_HANDLES},
+        Handle, Rights,
+    },
+    task,
+};
+
+/// The rights both ends of a new channel come with.
+const ENDPOINT_RIGHTS: Rights = Rights::from_bits_truncate(
+    Rights::READ.bits() | Rights::WRITE.bits() | Rights::TRANSFER.bits() | Rights::DUPLICATE.bits(),
+);
+
+/// `channel_create()`
+///
+/// Create a channel, returning a handle to one end in the low 32 bits and to the other
+/// in the high 32 bits.
+pub(super) fn sys_channel_create(_: Args) -> KernResult<usize> {
+    let task = task::try_current()?;
+    let mut handles = current_process(&task)?.handles().lock();
+
+    let (a, b) = channel::create();
+    let a = handles.insert(Arc::new(a), ENDPOINT_RIGHTS)?;
+    let b = match handles.insert(Arc::new(b), ENDPOINT_RIGHTS) {
+        Ok(b) => b,
+        Err(err) => {
+            handles.remove(a)?;
+            return Err(err);
+        }
+    };
+    Ok(a.into_raw() as usize | (b.into_raw() as usize) << 32)
+}
+
+/// `channel_write(handle, bytes, len, handles, count)`
+///
+/// Send `len` bytes and `count` handles to the other end. `handle` needs
+/// [`Rights::WRITE`], and every handle sent needs [`Rights::TRANSFER`]. On success the
+/// handles are gone from the caller's table; on failure they are all still there. Either
+/// end of the channel itself can't be sent through it.
+pub(super) fn sys_channel_write(args: Args) -> KernResult<usize> {
+    let handle = args.handle(0)?;
+    let (bytes_addr, len) = (args.get(1), args.get(2));
+    let (handles_addr, count) = (args.get(3), args.get(4));
+    if len > MAX_MESSAGE_BYTES || count > MAX_MESSAGE_HANDLES {
+        return Err(KernErrorKind::InvalidArgument.into());
+    }
+
+    let task = task::try_current()?;
+    let space = &task.address_space()?;
+    let bytes = user::copy_from_user(space, bytes_addr, len)?;
+    let to_send: Vec<Handle> = user::copy_u32s_from_user(space, handles_addr, count)?
+        .into_iter()
+        .map(Handle::from_raw)
+        .collect();
+
+    let mut handles = current_process(&task)?.handles().lock();
+    let endpoint = handles.get::<Endpoint>(handle, Rights::WRITE)?;
+
+    // An endpoint sent through its own channel, from either end, would keep the channel
+    // alive forever. Longer cycles, like two channels each sent through the other, aren't
+    // detected, and leak every channel in them.
+    for &sent in &to_send {
+        let Ok(sent) = handles.get::<Endpoint>(sent, Rights::empty()) else { continue };
+        if sent.same_channel(&endpoint) {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+    }
+
+    endpoint.write(bytes, || handles.remove_all(&to_send, Rights::TRANSFER))?;
+    Ok(0)
+}
+
+/// `channel_read(handle, bytes, len, handles, count)`
+///
+/// Receive the next message into the buffers, which must be large enough for all of
+/// it. Returns the number of bytes in the low 32 bits and the number of handles in the
+/// high 32 bits. `handle` needs [`Rights::READ`]. Fails with `WouldBlock` if there is no
+/// message, and `PeerClosed` if there never will be.
+pub(super) fn sys_channel_read(args: Args) -> KernResult<usize> {
+    let handle = args.handle(0)?;
+    let (bytes_addr, len) = (args.get(1), args.get(2));
+    let (handles_addr, count) = (args.get(3), args.get(4));
+
+    let task = task::try_current()?;
+    let space = &task.address_space()?;
+    let process = current_process(&task)?;
+
+    // Check the buffers up front, so that a bad one doesn't lose the message.
+    let len = len.min(MAX_MESSAGE_BYTES);
+    let count = count.min(MAX_MESSAGE_HANDLES);
+    user::check_range(space, bytes_addr, len, Protection::WRITE)?;
+    user::check_range(space, handles_addr, count * 4, Protection::WRITE)?;
+
+    let endpoint = process
+        .handles()
+        .lock()
+        .get::<Endpoint>(handle, Rights::READ)?;
+    let message = endpoint.read(len, count)?;
+
+    let mut received = Vec::with_capacity(message.handles.len());
+    let inserted = {
+        let mut handles = process.handles().lock();
+        let inserted = message
+            .handles
+            .iter()
+            .try_for_each(|(object, rights)| -> KernResult<()> {
+                received.push(handles.insert(Arc::clone(object), *rights)?.into_raw());
+                Ok(())
+            });
+        if inserted.is_err() {
+            for &new in &received {
+                handles.remove(Handle::from_raw(new))?;
+            }
+        }
+        inserted
+    };
+    if let Err(err) = inserted {
+        endpoint.unread(message);
+        return Err(err);
+    }
+
+    user::copy_to_user(space, bytes_addr, &message.bytes)?;
+    user::copy_u32s_to_user(space, handles_addr, &received)?;
+    Ok(message.bytes.len() | received.len() << 32)
+}
+
+/// `channel_wait(handle)`
+///
+/// Sleep until there is a message to read from `handle`, or fail with `PeerClosed` once
+/// there never will be. `handle` needs [`Rights::READ`].
+pub(super) fn sys_channel_wait(args: Args) -> KernResult<usize> {
+    let handle = args.handle(0)?;
+
+    let task = task::try_current()?;
+    let endpoint = current_process(&task)?
+        .handles()
+        .lock()
+        .get::<Endpoint>(handle, Rights::READ)?;
+    endpoint.wait_readable()?;
+    Ok(0)
+}
@@ -0,0 +1,122 @@
+//! Syscalls on [processes](crate::task::Process).
+
+use alloc::sync::Arc;
+
+use sol_types::ExitStatus;
+
+use super::{current_object, current_process, user, Args};
+use crate::{
+    error::{Context, KernErrorKind, KernResult},
+    object::{channel::Endpoint, KernelObject, Rights},
+    process::exec::Program,
+    task::{self, Process},
+};
+
+/// The largest executable `process_spawn` takes, in bytes.
+pub const MAX_IMAGE_BYTES: usize = 16 << 20;
+
+/// The rights a handle to a new process comes with.
+const PROCESS_RIGHTS: Rights = Rights::from_bits_truncate(
+    Rights::READ.bits() | Rights::WRITE.bits() | Rights::TRANSFER.bits() | Rights::DUPLICATE.bits(),
+);
+
+/// `process_exit(code)`
+///
+/// End the calling process with exit code `code`, which must fit in 32 bits. The other
+/// threads of the process exit too. Never returns.
+pub(super) fn sys_process_exit(args: Args) -> KernResult<usize> {
+    let code = args.u32(0)?;
+
+    let task = task::try_current()?;
+    current_process(&task)?.terminate(ExitStatus::Exited(code));
+    drop(task);
+    task::exit()
+}
+
+/// `process_spawn(image, len, bootstrap)`
+///
+/// Start the executable in the `len` bytes at `image` in a new process, returning a
+/// handle to it with [`Rights::READ`] and [`Rights::WRITE`]. If `bootstrap` isn't zero it
+/// must be a channel handle with [`Rights::TRANSFER`], which moves to the new process and
+/// is passed to it in `rdi`.
+///
+/// On failure `bootstrap` is left alone.
+pub(super) fn sys_process_spawn(args: Args) -> KernResult<usize> {
+    let (image_addr, len) = (args.get(0), args.get(1));
+    let bootstrap = Some(args.handle(2)?).filter(|handle| handle.into_raw() != 0);
+    if len > MAX_IMAGE_BYTES {
+        return Err(KernErrorKind::InvalidArgument.into());
+    }
+
+    let task = task::try_current()?;
+    let image = user::copy_from_user(&task.address_space()?, image_addr, len)?;
+    let program = Program::load(&image, &[], &[]).context("loading a spawned image")?;
+    drop(image);
+    let child = Arc::clone(program.process());
+
+    // The bootstrap channel is taken out of the caller's table in the same go as the
+    // handle to the child goes in, so that nothing else can use or close it in the
+    // meantime. Its slot is kept until the child has started, which happens with the table
+    // unlocked as starting may reap and sleep, so that it can go back if that fails.
+    let caller = current_process(&task)?;
+    let mut handles = caller.handles().lock();
+    let arg = match bootstrap {
+        Some(bootstrap) => {
+            let endpoint: Arc<dyn KernelObject> =
+                handles.get::<Endpoint>(bootstrap, Rights::TRANSFER)?;
+            let rights = handles.rights(bootstrap)?;
+            child.handles().lock().insert(endpoint, rights)?.into_raw() as usize
+        }
+        None => 0,
+    };
+    let object: Arc<dyn KernelObject> = Arc::clone(&child);
+    let handle = handles.insert(object, PROCESS_RIGHTS)?;
+    let taken = match bootstrap {
+        Some(bootstrap) => Some((bootstrap, handles.take(bootstrap)?)),
+        None => None,
+    };
+    drop(handles);
+
+    if let Err(err) = program.start(arg) {
+        let mut handles = caller.handles().lock();
+        // Another thread may have closed it already.
+        let _ = handles.remove(handle);
+        if let Some((bootstrap, endpoint)) = taken {
+            handles.put_back(bootstrap, endpoint);
+        }
+        drop(handles);
+        child.terminate(ExitStatus::Killed);
+        return Err(err);
+    }
+    if let Some((bootstrap, _)) = &taken {
+        caller.handles().lock().release(*bootstrap);
+    }
+    Ok(handle.into_raw() as usize)
+}
+
+/// `process_wait(handle)`
+///
+/// Sleep until the process `handle` names has exited and released everything it held,
+/// returning how it ended as an [`ExitStatus`]. `handle` needs [`Rights::READ`].
+pub(super) fn sys_process_wait(args: Args) -> KernResult<usize> {
+    let process = current_object::<Process>(args.handle(0)?, Rights::READ)?;
+    Ok(process.wait()?.encode())
+}
+
+/// `process_kill(handle)`
+///
+/// Terminate the process `handle` names, unless it is already ending. It is gone once
+/// `process_wait` returns. `handle` needs [`Rights::WRITE`].
+pub(super) fn sys_process_kill(args: Args) -> KernResult<usize> {
+    let process = current_object::<Process>(args.handle(0)?, Rights::WRITE)?;
+    process.terminate(ExitStatus::Killed);
+    Ok(0)
+}
+
+/// `process_id(handle)`
+///
+/// The id of the process `handle` names. `handle` needs [`Rights::READ`].
+pub(super) fn sys_process_id(args: Args) -> KernResult<usize> {
+    let process = current_object::<Process>(args.handle(0)?, Rights::READ)?;
+    Ok(process.id().as_u64() as usize)
+}
@@ -0,0 +1,266 @@
+//! Submission and completion rings in memory shared with a process.
+//!
+//! A ring is a single mapping laid out as a [`RingHeader`], then the submission queue
+//! at `sq_offset` and the completion queue at `cq_offset`. Both queues are powers of two
+//! long and indexed by their counters modulo their length. Counters only ever increase,
+//! wrapping at `u32::MAX`.
+//!
+//! The process fills in [`Sqe`]s and bumps `sq_tail`, then calls `ring_enter`. The
+//! kernel runs each entry in order and posts a [`Cqe`] carrying the entry's `user_data`
+//! and its result, encoded like a syscall return value. `sq_tail` and `cq_head` belong to
+//! the process; `sq_head` and `cq_tail` to the kernel, which keeps its own copies and
+//! never trusts what it finds in shared memory.
+//!
+//! `cq_tail` doubles as a futex: after posting, the kernel wakes anyone waiting on it
+//! with a private futex, so a process can sleep until completions arrive without another
+//! `ring_enter`.
+
+use core::{
+    mem,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use hal::vm_types::{PageSize, Size4KiB, VirtAddr};
+
+use super::{encode, run, Args};
+use crate::{
+    error::{KernErrorKind, KernResult},
+    memory::AddrSpace,
+    sync::{
+        futex::{self, FutexKey, BITSET_MATCH_ANY},
+        mutex, Mutex,
+    },
+};
+
+/// The most submission entries a ring may have.
+pub const MAX_ENTRIES: u32 = 4096;
+/// The most rings a process may set up.
+pub const MAX_RINGS: usize = 16;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct RingHeader {
+    pub sq_head: AtomicU32,
+    pub sq_tail: AtomicU32,
+    pub cq_head: AtomicU32,
+    pub cq_tail: AtomicU32,
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    /// Byte offsets of the queues from the start of the ring.
+    pub sq_offset: u32,
+    pub cq_offset: u32,
+}
+
+/// A submission entry: run syscall `opcode` with `args`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Sqe {
+    pub opcode: u32,
+    /// Reserved, must be zero.
+    pub flags: u32,
+    pub user_data: u64,
+    pub args: [u64; 6],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Cqe {
+    pub user_data: u64,
+    pub result: i64,
+}
+
+/// The kernel's side of a ring.
+#[derive(Debug)]
+pub struct Ring {
+    memory: NonNull<[u8]>,
+    space: AddrSpace,
+    sq: NonNull<Sqe>,
+    cq: NonNull<Cqe>,
+    sq_entries: u32,
+    cq_entries: u32,
+    cursors: Mutex<Cursors>,
+}
+
+#[derive(Debug, Default)]
+struct Cursors {
+    sq_head: u32,
+    cq_tail: u32,
+    /// Entries taken off the submission queue whose completions are yet to be posted.
+    /// Each has a completion slot set aside.
+    running: u32,
+}
+
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    /// Map a ring with `entries` submission entries, and twice that many completion
+    /// entries so that completions can pile up while the process gets around to them.
+    pub fn new(space: &AddrSpace, entries: u32) -> KernResult<Self> {
+        if !entries.is_power_of_two() || entries > MAX_ENTRIES {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+        let cq_entries = entries * 2;
+
+        // The header is a multiple of the entries' alignment, so the queues pack in after it.
+        let sq_offset = mem::size_of::<RingHeader>();
+        let cq_offset = sq_offset + entries as usize * mem::size_of::<Sqe>();
+        let size = cq_offset + cq_entries as usize * mem::size_of::<Cqe>();
+
+        // The ring belongs to the process, so that addresses in it resolve through the
+        // process's address space like any others. It comes zeroed.
+        let AddrSpace::User(process_space) = space else {
+            return Err(KernErrorKind::Unsupported.into());
+        };
+        let region = process_space.map_region((size + Size4KiB::SIZE - 1) / Size4KiB::SIZE)?;
+        let memory = ptr::slice_from_raw_parts_mut(region.start.addr().as_ptr(), region.len());
+        let memory = NonNull::new(memory).ok_or(KernErrorKind::BadAddress)?;
+        unsafe {
+            memory.as_mut_ptr().cast::<RingHeader>().write(RingHeader {
+                sq_head: AtomicU32::new(0),
+                sq_tail: AtomicU32::new(0),
+                cq_head: AtomicU32::new(0),
+                cq_tail: AtomicU32::new(0),
+                sq_entries: entries,
+                cq_entries,
+                sq_offset: sq_offset as u32,
+                cq_offset: cq_offset as u32,
+            });
+        }
+
+        let base = memory.as_mut_ptr();
+        Ok(Self {
+            memory,
+            space: space.clone(),
+            sq: unsafe { NonNull::new_unchecked(base.add(sq_offset).cast()) },
+            cq: unsafe { NonNull::new_unchecked(base.add(cq_offset).cast()) },
+            sq_entries: entries,
+            cq_entries,
+            cursors: mutex::with_class(Cursors::default(), lockdep::class!("Ring::cursors")),
+        })
+    }
+
+    /// The user address the ring is mapped at.
+    pub fn addr(&self) -> usize {
+        self.memory.as_mut_ptr() as usize
+    }
+
+    /// Run up to `max` submitted entries, returning how many were run.
+    ///
+    /// Entries run one at a time, in order, on the calling thread, so one that blocks holds
+    /// up the rest. The ring isn't locked while an entry runs, so other threads can submit
+    /// in the meantime, and completions are posted in the order entries finish. This stops
+    /// early when the completion queue is full.
+    pub fn submit(&self, max: u32) -> KernResult<u32> {
+        let mut submitted = 0;
+        while submitted < max {
+            let Some(sqe) = self.next_entry()? else { break };
+
+            let result = if sqe.flags != 0 {
+                Err(KernErrorKind::InvalidArgument.into())
+            } else {
+                run(
+                    sqe.opcode as usize,
+                    Args(sqe.args.map(|arg| arg as usize)),
+                    true,
+                )
+            };
+
+            self.complete(Cqe {
+                user_data: sqe.user_data,
+                result: encode(result) as i64,
+            })?;
+            submitted += 1;
+        }
+
+        Ok(submitted)
+    }
+
+    /// Take the next entry off the submission queue and set aside a completion slot for
+    /// it, or return `None` if the submission queue is empty or the completion queue full.
+    fn next_entry(&self) -> KernResult<Option<Sqe>> {
+        let mut cursors = self.cursors.lock();
+
+        let sq_tail = self.header().sq_tail.load(Ordering::Acquire);
+        let queued = sq_tail.wrapping_sub(cursors.sq_head);
+        if queued > self.sq_entries {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+        if queued == 0 {
+            return Ok(None);
+        }
+
+        let cq_head = self.header().cq_head.load(Ordering::Acquire);
+        // A garbage head reads as a full queue, which only stalls the process itself.
+        let pending = cursors.cq_tail.wrapping_sub(cq_head);
+        if pending.saturating_add(cursors.running) >= self.cq_entries {
+            return Ok(None);
+        }
+
+        // Copy the entry out in one go, so the process can't change it under us.
+        let sqe = unsafe { ptr::read_volatile(self.sqe(cursors.sq_head)) };
+        cursors.sq_head = cursors.sq_head.wrapping_add(1);
+        cursors.running += 1;
+        self.header()
+            .sq_head
+            .store(cursors.sq_head, Ordering::Release);
+        Ok(Some(sqe))
+    }
+
+    /// Post the completion of an entry taken by [`next_entry`](Self::next_entry), in the
+    /// slot set aside for it.
+    fn complete(&self, cqe: Cqe) -> KernResult<()> {
+        let mut cursors = self.cursors.lock();
+        unsafe { ptr::write_volatile(self.cqe(cursors.cq_tail), cqe) };
+        cursors.cq_tail = cursors.cq_tail.wrapping_add(1);
+        cursors.running -= 1;
+        self.header()
+            .cq_tail
+            .store(cursors.cq_tail, Ordering::Release);
+        drop(cursors);
+
+        futex::wake_keyed(self.cq_tail_key(), usize::MAX, BITSET_MATCH_ANY)?;
+        Ok(())
+    }
+
+    /// Sleep until at least `min` completions are waiting for the process.
+    pub fn wait_completions(&self, min: u32) -> KernResult<()> {
+        if min > self.cq_entries {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+
+        let header = self.header();
+        loop {
+            let cq_tail = header.cq_tail.load(Ordering::Acquire);
+            let cq_head = header.cq_head.load(Ordering::Acquire);
+            if cq_tail.wrapping_sub(cq_head) >= min {
+                return Ok(());
+            }
+            futex::wait_keyed(
+                self.cq_tail_key(),
+                &header.cq_tail,
+                cq_tail,
+                BITSET_MATCH_ANY,
+            )?;
+        }
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*self.memory.as_mut_ptr().cast() }
+    }
+
+    fn sqe(&self, index: u32) -> *const Sqe {
+        let index = (index & (self.sq_entries - 1)) as usize;
+        unsafe { self.sq.as_ptr().add(index) }
+    }
+
+    fn cqe(&self, index: u32) -> *mut Cqe {
+        let index = (index & (self.cq_entries - 1)) as usize;
+        unsafe { self.cq.as_ptr().add(index) }
+    }
+
+    fn cq_tail_key(&self) -> FutexKey {
+        FutexKey::private(&self.space, VirtAddr::from_ptr(&self.header().cq_tail))
+    }
+}
@@ -0,0 +1,82 @@
+//! Copying to and from user memory.
+//!
+//! Syscalls never hold on to references into user memory: the process can change it at
+//! any moment, so arguments are copied in once and results copied out once.
+//!
+//! Ranges are checked page by page before a copy, but another thread of the process can
+//! still pull a page out from under it, so the copies themselves go through
+//! [`usercopy`], which turns a fault into [`KernErrorKind::BadAddress`].
+
+use alloc::vec::Vec;
+
+use hal::{
+    vm_types::{PageSize, Size4KiB, VirtAddr},
+    x86_64::{syscall::USER_END, usercopy},
+};
+
+use crate::{
+    error::{KernErrorKind, KernResult},
+    memory::{AddrSpace, Protection},
+};
+
+/// Check that the `len` bytes at `addr` lie below the kernel's half of the address space
+/// and are mapped in `space` for ring 3 to access as `prot` asks, on top of reading.
+pub fn check_range(space: &AddrSpace, addr: usize, len: usize, prot: Protection) -> KernResult<()> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let end = addr
+        .checked_add(len)
+        .filter(|&end| addr != 0 && end <= USER_END)
+        .ok_or(KernErrorKind::BadAddress)?;
+
+    let mut page = VirtAddr::from_usize(addr).align_down(Size4KiB::SIZE);
+    while page.as_usize() < end {
+        space.check_user(page, prot)?;
+        page = VirtAddr::from_usize(page.as_usize() + Size4KiB::SIZE);
+    }
+    Ok(())
+}
+
+pub fn copy_from_user(space: &AddrSpace, addr: usize, len: usize) -> KernResult<Vec<u8>> {
+    check_range(space, addr, len, Protection::empty())?;
+
+    let mut bytes = Vec::new();
+    bytes
+        .try_reserve_exact(len)
+        .map_err(|_| KernErrorKind::AllocError)?;
+    unsafe {
+        if usercopy::copy(bytes.as_mut_ptr(), addr as *const u8, len) != 0 {
+            return Err(KernErrorKind::BadAddress.into());
+        }
+        bytes.set_len(len);
+    }
+    Ok(bytes)
+}
+
+pub fn copy_to_user(space: &AddrSpace, addr: usize, bytes: &[u8]) -> KernResult<()> {
+    check_range(space, addr, bytes.len(), Protection::WRITE)?;
+
+    if unsafe { usercopy::copy(addr as *mut u8, bytes.as_ptr(), bytes.len()) } != 0 {
+        return Err(KernErrorKind::BadAddress.into());
+    }
+    Ok(())
+}
+
+pub fn copy_u32s_from_user(space: &AddrSpace, addr: usize, count: usize) -> KernResult<Vec<u32>> {
+    let len = count.checked_mul(4).ok_or(KernErrorKind::InvalidArgument)?;
+    let bytes = copy_from_user(space, addr, len)?;
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_ne_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+pub fn copy_u32s_to_user(space: &AddrSpace, addr: usize, values: &[u32]) -> KernResult<()> {
+    let bytes: Vec<u8> = values
+        .iter()
+        .flat_map(|value| value.to_ne_bytes())
+        .collect();
+    copy_to_user(space, addr, &bytes)
+}
@@ -1,21 +1,39 @@
 use alloc::boxed::Box;
-use core::sync::atomic::Ordering;
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 
-use hal::task::context_switch;
+use hal::task::{context_switch, hw_thread_id};
 use log::trace;
 use spin::Once;
 
-pub use self::task_types::{Task, TaskId};
+pub use self::{
+    deadline::DeadlineParams,
+    process::{exit_if_terminating, Process, ProcessId},
+    reaper::reap,
+    task_types::{Head, Policy, Task, TaskId},
+    thread::Builder,
+};
 use self::{
     naive_scheduler::NaiveScheduler, naive_smp_scheduler::NaiveSmpScheduler, sched::Scheduler,
     work_stealing::WorkStealingScheduler,
 };
-use crate::error::{KernErrorKind, KernResult};
+use crate::{
+    arch::{self, CpuMask},
+    cpu_local::cpu_local,
+    error::{KernErrorKind, KernResult},
+    memory,
+    sync::rcu,
+};
 
+mod deadline;
 mod idle;
 mod naive_scheduler;
 mod naive_smp_scheduler;
 mod process;
+mod reaper;
+mod run_queue;
 mod sched;
 mod stack;
 mod task_types;
@@ -26,6 +44,11 @@ mod work_stealing;
 #[derive(Debug)]
 pub struct SchedError;
 
+cpu_local! {
+    /// The task running on this cpu, without a reference held. Set on every switch.
+    static CURRENT: AtomicPtr<Head> = AtomicPtr::new(ptr::null_mut());
+}
+
 pub fn spawn<F, T>(f: F) -> KernResult<Task>
 where
     F: FnOnce() -> T + 'static + Send,
@@ -52,12 +75,32 @@ pub fn exit() -> ! {
     try_exit().unwrap()
 }
 
+/// Restrict `task` to the cpus in `mask`. If the task is the current one and may no
+/// longer run on this cpu, the scheduler moves it before this returns. If it can't, the
+/// old mask is put back.
+pub fn set_affinity(task: &Task, mask: CpuMask) -> KernResult<()> {
+    let old = task.affinity();
+    task.set_affinity(mask)?;
+
+    let on_disallowed_cpu = !mask.contains(unsafe { hw_thread_id() });
+    if on_disallowed_cpu && try_current()?.id() == task.id() {
+        rcu::quiescent_state();
+        if let Err(err) = scheduler()?.migrate() {
+            task.set_affinity(old)?;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
 pub fn try_yield_now() -> KernResult<()> {
+    rcu::quiescent_state();
     scheduler()?.yield_now()
 }
 
 pub fn try_park() -> KernResult<()> {
     trace!("scheduler.park: {:?}", current());
+    rcu::quiescent_state();
     scheduler()?.park()
 }
 
@@ -65,7 +108,20 @@ pub fn try_current() -> KernResult<Task> {
     scheduler()?.current()
 }
 
+/// The task running on this cpu, for code that only needs to tell tasks apart and can't
+/// afford to take a reference. Null until the cpu first switches tasks.
+pub fn current_ptr() -> *mut Head {
+    CURRENT
+        .with(|current| current.load(Ordering::Relaxed))
+        .unwrap_or(ptr::null_mut())
+}
+
 pub fn try_exit() -> KernResult<!> {
+    let task = try_current()?;
+    if let Some(process) = task.process() {
+        process.remove_thread(task.id());
+    }
+    drop(task);
     scheduler()?.exit()
 }
 
@@ -73,6 +129,14 @@ pub fn try_unpark(task: Task) -> KernResult<()> {
     scheduler()?.unpark(task)
 }
 
+/// Tell the scheduler that the effective policy of `task` changed, so that a queued task
+/// is moved to its new band.
+pub fn reprioritize(task: &Task) {
+    if let Ok(scheduler) = scheduler() {
+        scheduler.reprioritize(task).unwrap();
+    }
+}
+
 pub unsafe fn try_enter() -> KernResult<!> {
     scheduler()?.enter()
 }
@@ -81,6 +145,25 @@ pub unsafe fn enter() -> ! {
     try_enter().unwrap()
 }
 
+/// Called from the preemption timer. Ticks arriving before the scheduler is initialized
+/// are ignored.
+pub fn tick() {
+    // The tick interrupted code with interrupts enabled, which can't have been an RCU
+    // reader.
+    rcu::quiescent_state();
+    if let Ok(scheduler) = scheduler() {
+        scheduler.tick().unwrap();
+    }
+}
+
+pub unsafe fn finish_switch() {
+    try_finish_switch().unwrap()
+}
+
+pub unsafe fn try_finish_switch() -> KernResult<()> {
+    scheduler()?.finish_switch()
+}
+
 fn scheduler() -> KernResult<&'static dyn Scheduler> {
     Ok(*SCHEDULER.get().ok_or(KernErrorKind::Fault)?)
 }
@@ -106,6 +189,27 @@ where
     }
 }
 
+/// Get the cpu ready to run `new`. Called by the schedulers, with interrupts disabled,
+/// just before they switch to it.
+fn prepare_switch(new: &Task) {
+    _ = CURRENT.with(|current| current.store(new.0.as_ptr(), Ordering::Relaxed));
+
+    // Kernel tasks, and threads of a process that has exited, run on the kernel's page
+    // table, so that nothing is left on a process's once it is freed.
+    let root = new
+        .process()
+        .and_then(|process| process.page_table_root())
+        .unwrap_or_else(memory::kernel_root);
+    unsafe { memory::load_page_table(root) };
+
+    // Kernel tasks run in ring 0, which the I/O permission bitmap doesn't apply to, and
+    // never enter the kernel from ring 3.
+    if let Some(process) = new.process() {
+        process.open_io_ports();
+        unsafe { arch::x86_64::syscall::set_kernel_stack(new.head().stack_top) };
+    }
+}
+
 unsafe fn task_switch(old: &Task, new: &Task) {
     let old = old.head().stack_ptr.as_ptr();
     let new = new.head().stack_ptr.load(Ordering::Relaxed);
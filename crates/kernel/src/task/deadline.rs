@@ -0,0 +1,325 @@
+//! Earliest-deadline-first real-time scheduling.
+//!
+//! Deadline tasks sit alongside the fixed [`Policy`](super::Policy) bands and always run in
+//! preference to them. Each one reserves `runtime` ticks of cpu time out of every `period`
+//! ticks, and that runtime must be delivered within `deadline` ticks of the start of each
+//! period.
+//!
+//! Tasks are partitioned: admission control places each task on a single cpu, and only
+//! admits it if the total density (`runtime / deadline`) on that cpu stays at or below one.
+//! For a task whose deadline is its period this is just its bandwidth, and for one with a
+//! shorter deadline it's the stricter test that still guarantees the deadline is met.
+//! Within a cpu, the ready task with the earliest absolute deadline runs first. The
+//! preemption timer charges the running task one tick at a time. Once its budget is spent
+//! it is throttled until its next period begins, so a misbehaving task cannot steal time
+//! reserved by others: if nothing else is ready, the cpu idles instead.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use hal::interrupts;
+use log::{trace, warn};
+use spin::{mutex::SpinMutex, Once};
+
+use super::Task;
+use crate::{
+    arch::CpuMask,
+    error::{KernErrorKind, KernResult},
+};
+
+/// Fixed-point scale used for bandwidth accounting. A full cpu is `BW_UNIT`.
+const BW_UNIT: u64 = 1 << 20;
+
+/// The longest period accepted, in ticks. This keeps the bandwidth calculation and the
+/// absolute deadlines well clear of overflow.
+const MAX_PERIOD: u64 = u32::MAX as u64;
+
+/// The parameters of a deadline task, in timer ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineParams {
+    runtime: u64,
+    deadline: u64,
+    period: u64,
+}
+
+impl DeadlineParams {
+    /// Validate a set of parameters. They must satisfy
+    /// `0 < runtime <= deadline <= period <= MAX_PERIOD`.
+    pub fn new(runtime: u64, deadline: u64, period: u64) -> KernResult<Self> {
+        if runtime == 0 || runtime > deadline || deadline > period || period > MAX_PERIOD {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+
+        Ok(Self {
+            runtime,
+            deadline,
+            period,
+        })
+    }
+
+    /// The share of a cpu the task needs, as its density: the runtime has to fit inside
+    /// the deadline, not just the period.
+    fn bandwidth(&self) -> u64 {
+        // Round up so that admission errs on the side of caution. `new` bounds the period,
+        // so this can't overflow.
+        (self.runtime * BW_UNIT + self.deadline - 1) / self.deadline
+    }
+}
+
+/// The per-task state of a deadline task.
+#[derive(Debug)]
+pub struct DeadlineEntity {
+    params: DeadlineParams,
+    cpu: usize,
+    /// The absolute deadline of the current period.
+    deadline: AtomicU64,
+    /// The start of the next period, when the budget is replenished.
+    next_period: AtomicU64,
+    /// The runtime left in the current period.
+    budget: AtomicU64,
+    released: AtomicBool,
+}
+
+impl DeadlineEntity {
+    /// Run admission control, reserving bandwidth on the first cpu in `affinity` with room
+    /// for it.
+    pub fn admit(params: DeadlineParams, affinity: CpuMask) -> KernResult<Self> {
+        let queues = queues()?;
+
+        let cpu = affinity
+            .iter()
+            .take_while(|&cpu| cpu < queues.len())
+            .find(|&cpu| queues[cpu].try_reserve(&params))
+            .ok_or(KernErrorKind::Busy)?;
+
+        trace!("admitted deadline task {:?} on cpu {}", params, cpu);
+
+        let now = now();
+        Ok(Self {
+            params,
+            cpu,
+            deadline: AtomicU64::new(now + params.deadline),
+            next_period: AtomicU64::new(now + params.period),
+            budget: AtomicU64::new(params.runtime),
+            released: AtomicBool::new(false),
+        })
+    }
+
+    pub fn params(&self) -> &DeadlineParams {
+        &self.params
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    pub fn deadline(&self) -> u64 {
+        self.deadline.load(Ordering::Relaxed)
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.budget.load(Ordering::Relaxed) == 0
+    }
+
+    /// Start a fresh period at `start`.
+    fn renew(&self, start: u64) {
+        self.deadline
+            .store(start + self.params.deadline, Ordering::Relaxed);
+        self.next_period
+            .store(start + self.params.period, Ordering::Relaxed);
+        self.budget.store(self.params.runtime, Ordering::Relaxed);
+    }
+
+    /// A task waking up after its deadline has passed gets a new period, otherwise its
+    /// stale deadline would let it starve everybody else.
+    fn wake(&self, now: u64) {
+        if now >= self.deadline() {
+            self.renew(now);
+        }
+    }
+
+    fn replenish(&self, now: u64) -> bool {
+        let next = self.next_period.load(Ordering::Relaxed);
+        if now < next {
+            return false;
+        }
+        // Carry on from the scheduled period boundary, unless we have fallen more than a
+        // whole period behind, in which case start afresh.
+        let start = if now - next < self.params.period {
+            next
+        } else {
+            now
+        };
+        self.renew(start);
+        true
+    }
+
+    /// Give the bandwidth reserved by this task back to its cpu. This happens when the
+    /// task is reaped, or when the entity is dropped if it never ran.
+    pub fn release(&self) {
+        if self.released.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if let Ok(queues) = queues() {
+            queues[self.cpu].unreserve(&self.params);
+        }
+    }
+}
+
+impl Drop for DeadlineEntity {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// The deadline runqueue of a single cpu.
+#[derive(Debug, Default)]
+pub struct DeadlineQueue {
+    inner: SpinMutex<QueueInner>,
+}
+
+#[derive(Debug, Default)]
+struct QueueInner {
+    bandwidth: u64,
+    ready: Vec<Task>,
+    throttled: Vec<Task>,
+}
+
+impl DeadlineQueue {
+    fn with<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut QueueInner) -> T,
+    {
+        interrupts::without(|_| f(&mut self.inner.lock()))
+    }
+
+    fn try_reserve(&self, params: &DeadlineParams) -> bool {
+        self.with(|inner| {
+            let total = inner.bandwidth + params.bandwidth();
+            if total > BW_UNIT {
+                return false;
+            }
+            inner.bandwidth = total;
+            true
+        })
+    }
+
+    fn unreserve(&self, params: &DeadlineParams) {
+        self.with(|inner| inner.bandwidth -= params.bandwidth());
+    }
+
+    fn push(&self, task: Task, now: u64) {
+        let entity = entity(&task);
+        entity.wake(now);
+
+        self.with(|inner| {
+            if entity.is_throttled() {
+                inner.throttled.push(task);
+            } else {
+                inner.ready.push(task);
+            }
+        });
+    }
+
+    fn pop(&self) -> Option<Task> {
+        self.with(|inner| {
+            let (i, _) = inner
+                .ready
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, task)| entity(task).deadline())?;
+            Some(inner.ready.swap_remove(i))
+        })
+    }
+
+    fn earliest(&self) -> Option<u64> {
+        self.with(|inner| inner.ready.iter().map(|task| entity(task).deadline()).min())
+    }
+
+    fn replenish(&self, now: u64) {
+        self.with(|inner| {
+            let mut i = 0;
+            while i < inner.throttled.len() {
+                if entity(&inner.throttled[i]).replenish(now) {
+                    let task = inner.throttled.swap_remove(i);
+                    inner.ready.push(task);
+                } else {
+                    i += 1;
+                }
+            }
+        });
+    }
+}
+
+static QUEUES: Once<Box<[DeadlineQueue]>> = Once::new();
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Set up the per-cpu deadline runqueues. Schedulers which support deadline tasks call
+/// this when they are created; until then, admission fails.
+pub fn init(cpus: usize) {
+    QUEUES.call_once(|| {
+        let mut queues = Vec::with_capacity(cpus);
+        queues.resize_with(cpus, DeadlineQueue::default);
+        queues.into_boxed_slice()
+    });
+}
+
+fn queues() -> KernResult<&'static [DeadlineQueue]> {
+    QUEUES
+        .get()
+        .map(|queues| &**queues)
+        .ok_or_else(|| KernErrorKind::Unsupported.into())
+}
+
+/// The current time, in timer ticks.
+pub fn now() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn entity(task: &Task) -> &DeadlineEntity {
+    task.deadline().expect("not a deadline task")
+}
+
+/// Queue a runnable deadline task on the cpu it was admitted to.
+pub fn push(task: Task) {
+    let queues = queues().expect("deadline task without deadline queues");
+    let cpu = entity(&task).cpu;
+    queues[cpu].push(task, now());
+}
+
+/// Take the ready deadline task with the earliest deadline on `cpu`.
+pub fn pop(cpu: usize) -> Option<Task> {
+    queues().ok()?.get(cpu)?.pop()
+}
+
+/// Account for a timer tick on `cpu`, while `current` is running. Returns true if
+/// `current` should be preempted, either because it has exhausted its budget or because a
+/// task with an earlier deadline is ready.
+pub fn tick(cpu: usize, current: &Task) -> bool {
+    // The bootstrap processor keeps the clock so that it advances at the timer rate
+    // regardless of how many cpus are running.
+    if cpu == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let Ok(queues) = queues() else { return false };
+    let Some(queue) = queues.get(cpu) else { return false };
+
+    let now = now();
+    queue.replenish(now);
+
+    let earliest = queue.earliest();
+
+    let Some(entity) = current.deadline() else {
+        return earliest.is_some();
+    };
+
+    let budget = entity.budget.load(Ordering::Relaxed).saturating_sub(1);
+    entity.budget.store(budget, Ordering::Relaxed);
+
+    if now > entity.deadline() {
+        warn!("deadline task {} missed its deadline", current);
+    }
+
+    budget == 0 || earliest.map_or(false, |deadline| deadline < entity.deadline())
+}
@@ -4,17 +4,31 @@ use core::{
     sync::atomic::{AtomicBool, AtomicUsize},
 };
 
-use super::task_types::{allocate_id, AtomicState, Head, Policy, State, Task, TaskVTable};
+use hal::task::hw_thread_id;
+use lockdep::HeldLocks;
+
+use super::task_types::{
+    allocate_id, AtomicPolicy, AtomicState, Head, Inheritance, Policy, State, Task, TaskVTable,
+};
+use crate::arch::{AtomicCpuMask, CpuMask};
 
 /// Create a task that refers to the current task.
 pub unsafe fn allocate_bootstrap_task() -> Task {
     let head = Head {
         id: allocate_id(),
         link: Default::default(),
-        policy: Policy::Low(0),
+        policy: AtomicPolicy::new(Policy::Low(0)),
+        base_policy: Policy::Low(0),
+        inheritance: Inheritance::new(),
+        deadline: None,
+        // The bootstrap task runs on the cpu's boot stack, so it must never migrate.
+        affinity: AtomicCpuMask::new(CpuMask::single(hw_thread_id()).expect("cpu id out of range")),
         preemptible: AtomicBool::new(true),
+        held_locks: HeldLocks::new(),
+        process: None,
         refs: AtomicUsize::new(1),
         stack_ptr: Default::default(),
+        stack_top: 0,
         state: AtomicState::new(State::Active),
         vtable: &VTABLE,
     };
@@ -29,4 +43,5 @@ const VTABLE: TaskVTable = TaskVTable {
         _ = Box::from_raw(head.as_ptr());
     },
     drop_in_place: |_head| {},
+    reap: |_head| {},
 };
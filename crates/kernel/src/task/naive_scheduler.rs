@@ -8,7 +8,7 @@ mod queue;
 mod soul;
 
 use self::{queue::TaskQueue, soul::Soul};
-use super::{sched::Scheduler, task_types::Task};
+use super::{deadline, sched::Scheduler, task_types::Task};
 use crate::error::{KernErrorKind, KernResult};
 
 /// A dumb as rocks single-soul scheduler.
@@ -19,6 +19,8 @@ pub struct NaiveScheduler {
 
 impl NaiveScheduler {
     pub fn new() -> Self {
+        deadline::init(1);
+
         Self {
             soul: Once::new(),
             queue: Default::default(),
@@ -41,6 +43,8 @@ impl Scheduler for NaiveScheduler {
         interrupts::without(|_| unsafe {
             if let Some(soul) = self.soul.get() {
                 (*soul.get()).unpark(task);
+            } else if task.deadline().is_some() {
+                deadline::push(task);
             } else {
                 self.queue.lock().push(task);
             }
@@ -81,4 +85,32 @@ impl Scheduler for NaiveScheduler {
             s.enter();
         })
     }
+
+    unsafe fn finish_switch(&self) -> KernResult<()> {
+        self.with_soul(|s| {
+            s.finish_switch();
+            Ok(())
+        })
+    }
+
+    /// Ticks arriving before the soul is set up are ignored.
+    fn tick(&self) -> KernResult<()> {
+        interrupts::without(|_| unsafe {
+            if let Some(soul) = self.soul.get() {
+                (*soul.get()).tick();
+            }
+        });
+        Ok(())
+    }
+
+    fn reprioritize(&self, task: &Task) -> KernResult<()> {
+        interrupts::without(|_| unsafe {
+            if let Some(soul) = self.soul.get() {
+                (*soul.get()).requeue(task);
+            } else {
+                self.queue.lock().requeue(task);
+            }
+        });
+        Ok(())
+    }
 }
@@ -2,9 +2,36 @@ use alloc::collections::VecDeque;
 
 use meteor::tail_list::TailList;
 
-use crate::task::Task;
+use crate::task::{run_queue::Bands, Task};
 
-pub type TaskQueue = LinkedQueue;
+/// Runnable tasks, highest policy band first.
+#[derive(Debug, Default)]
+pub struct TaskQueue {
+    bands: Bands<LinkedQueue>,
+}
+
+impl TaskQueue {
+    pub fn pop(&mut self) -> Option<Task> {
+        self.bands.iter_mut().find_map(LinkedQueue::pop)
+    }
+
+    pub fn push(&mut self, task: Task) {
+        self.bands.get_mut(task.policy()).push(task);
+    }
+
+    /// Move `task` to the band for its current policy, if it is queued here.
+    pub fn requeue(&mut self, task: &Task) {
+        let id = task.id();
+        let found = self
+            .bands
+            .iter_mut()
+            .find_map(|band| band.inner.drain_filter(|queued| queued.id() == id).next());
+
+        if let Some(task) = found {
+            self.push(task);
+        }
+    }
+}
 
 // #[derive(Debug, Default)]
 // pub struct TaskQueue {
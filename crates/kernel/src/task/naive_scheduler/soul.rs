@@ -7,40 +7,90 @@ use hal::{
 use log::{debug, trace, warn};
 
 use super::queue::TaskQueue;
-use crate::task::{
-    idle::allocate_bootstrap_task,
-    task_types::{State, Task},
+use crate::{
+    sync::rcu,
+    task::{
+        deadline::{self, DeadlineEntity},
+        idle::allocate_bootstrap_task,
+        prepare_switch, reaper,
+        task_types::{State, Task},
+    },
 };
 
+/// The cpu the soul runs on, as far as deadline accounting is concerned.
+const CPU: usize = 0;
+
 #[derive(Debug)]
 pub struct Soul {
     active: Task,
+    /// The task running the loop in `enter`. It is never queued, and runs whenever there
+    /// is nothing else to.
+    idle: Task,
     // local_queue: TailList<Task>,
     local_queue: TaskQueue,
-    exited: Option<Task>,
+    /// The task switched away from, and the state it was left in.
+    previous: Option<(Task, State)>,
 }
 
 impl Soul {
     pub unsafe fn new(queue: TaskQueue) -> Self {
         let active = allocate_bootstrap_task();
         Self {
+            idle: active.clone(),
             active,
             local_queue: queue,
-            exited: None,
+            previous: None,
+        }
+    }
+
+    fn get_next(&mut self) -> Option<Task> {
+        deadline::pop(CPU).or_else(|| self.local_queue.pop())
+    }
+
+    fn get_next_or_idle(&mut self) -> Task {
+        self.get_next().unwrap_or_else(|| self.idle.clone())
+    }
+
+    fn push(&mut self, task: Task) {
+        if task.deadline().is_some() {
+            deadline::push(task);
+        } else {
+            self.local_queue.push(task);
         }
     }
 
     pub fn exit(&mut self) -> ! {
         loop {
-            let new = self.local_queue.pop().expect("no waiting tasks");
-            self.switch(new, false);
+            let new = self.get_next_or_idle();
+            self.switch(new, State::Exited);
             unreachable!();
         }
     }
 
     pub fn yield_now(&mut self) {
-        let Some(new ) = self.local_queue.pop() else { return };
-        self.switch(new, true);
+        let Some(new ) = self.get_next() else { return };
+        self.switch(new, State::Queued);
+    }
+
+    /// Account for a timer tick, preempting the current task if the deadline scheduler
+    /// says so.
+    pub fn tick(&mut self) {
+        let current = &self.active;
+        if !deadline::tick(CPU, current) || !current.head().preemptible.load(Ordering::Relaxed) {
+            return;
+        }
+
+        // A task that has used up its budget has to stop even if there is nothing else to
+        // run.
+        let throttled = current
+            .deadline()
+            .map_or(false, DeadlineEntity::is_throttled);
+        let new = match self.get_next() {
+            Some(new) => new,
+            None if throttled => self.idle.clone(),
+            None => return,
+        };
+        self.switch(new, State::Queued);
     }
 
     pub fn unpark(&mut self, task: Task) {
@@ -56,42 +106,47 @@ impl Soul {
             .is_ok();
 
         if was_parked {
-            self.local_queue.push(task);
+            self.push(task);
         }
     }
 
+    /// Move `task` to the band for its current policy, if it is queued.
+    pub fn requeue(&mut self, task: &Task) {
+        self.local_queue.requeue(task);
+    }
+
     pub fn park(&mut self) {
-        let new = self.local_queue.pop().expect("no waiting tasks");
-        self.switch(new, false);
+        let new = self.get_next_or_idle();
+        self.switch(new, State::Parked);
     }
 
     pub fn enter(&mut self) -> ! {
         loop {
             unsafe { interrupts::disable() };
             debug!("scheduler.enter.loop()");
-            if let Some(new) = self.local_queue.pop() {
-                self.switch(new, true);
-            } else {
+            rcu::quiescent_state();
+            if let Some(new) = self.get_next() {
+                self.switch(new, State::Parked);
+            } else if reaper::reap() + rcu::run_callbacks() == 0 {
                 warn!("halt");
                 unsafe { enable_and_wait() };
             }
         }
     }
 
-    fn switch(&mut self, new: Task, requeue: bool) {
+    fn switch(&mut self, new: Task, old_state: State) {
         let old = mem::replace(&mut self.active, new);
+        // The idle task is only ever run when there's nothing else, never from the queue.
+        let old_state = if old.id() == self.idle.id() {
+            State::Parked
+        } else {
+            old_state
+        };
 
         trace!("switch {} -> {}", old, self.active);
 
         self.active.change_state_to_active();
 
-        let old_state = if requeue {
-            self.local_queue.push(old.clone());
-            State::Queued
-        } else {
-            State::Parked
-        };
-
         old.change_state(State::Active, old_state)
             .expect("invalid task state transition");
 
@@ -100,15 +155,28 @@ impl Soul {
 
         trace!("num_refs = {}", old.head().refs.load(Ordering::Relaxed));
 
-        if requeue {
-            mem::drop(old)
-        } else {
-            self.exited = Some(old);
-        }
+        let previous = self.previous.replace((old, old_state));
+        debug_assert!(previous.is_none());
+
+        prepare_switch(&self.active);
 
         unsafe {
             context_switch(old_ctx, new_ctx);
         }
+
+        self.finish_switch();
+    }
+
+    /// Deal with the task switched away from. This goes by the state it was left in: a
+    /// parked task may already have been unparked and queued again.
+    pub fn finish_switch(&mut self) {
+        let Some((old, old_state)) = self.previous.take() else { return };
+
+        match old_state {
+            State::Queued => self.push(old),
+            State::Exited => reaper::push(old),
+            _ => {}
+        }
     }
 
     pub fn current(&self) -> Task {
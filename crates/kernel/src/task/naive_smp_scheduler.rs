@@ -1,8 +1,8 @@
 use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use core::{
     cell::{Cell, SyncUnsafeCell},
-    mem, ptr,
-    sync::atomic::Ordering,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use hal::{
@@ -14,18 +14,28 @@ use meteor::mpsc_queue::{Link, MpscQueue};
 use nanorand::{Rng, WyRand};
 use spin::{mutex::SpinMutex, Once};
 
-use super::{sched::Scheduler, Task};
+use super::{
+    run_queue::{self, Bands},
+    sched::Scheduler,
+    Task,
+};
 use crate::{
-    arch::interrupts::enable_and_wait,
+    arch::{interrupts::enable_and_wait, CpuMask},
     error::{KernErrorKind, KernResult},
-    task::{idle::allocate_bootstrap_task, task_types::State},
+    sync::rcu,
+    task::{
+        deadline::{self, DeadlineEntity},
+        idle::allocate_bootstrap_task,
+        prepare_switch, reaper,
+        task_types::State,
+    },
 };
 
 static STUB: Link = Link::new();
 
 pub struct NaiveSmpScheduler {
     workers: Box<[Worker]>,
-    queue: Arc<MpmcQueue>,
+    queue: Arc<Bands<MpmcQueue>>,
 }
 
 unsafe impl Sync for NaiveSmpScheduler {}
@@ -35,19 +45,24 @@ impl NaiveSmpScheduler {
         let worker_count = cores;
         let mut workers = Vec::with_capacity(worker_count);
 
-        let queue = Arc::new(MpmcQueue::new());
+        let queue = Arc::new(Bands::new(MpmcQueue::new));
 
         let mut seed = 0;
         workers.resize_with(worker_count, || {
             seed += 1;
             Worker {
+                cpu: seed - 1,
                 current: Once::new(),
-                exited: Cell::new(None),
+                idle: Once::new(),
+                previous: Cell::new(None),
                 queue: queue.clone(),
+                pinned: Bands::new(MpmcQueue::new),
             }
         });
         let workers = workers.into_boxed_slice();
 
+        deadline::init(worker_count);
+
         Self { workers, queue }
     }
 
@@ -69,18 +84,90 @@ impl NaiveSmpScheduler {
         let cpu = hw_thread_id();
         &self.workers[cpu]
     }
+
+    fn switch(&self, worker: &Worker, new: Task, old_state: State) {
+        worker.switch(new, old_state);
+        // We may have been resumed on a different cpu, so look the worker up again rather
+        // than reusing `worker`.
+        self.finish_switch_on(unsafe { self.worker_unchecked() });
+    }
+
+    fn get_next_or_idle(&self, worker: &Worker) -> Task {
+        self.get_next(worker).unwrap_or_else(|| worker.idle())
+    }
+
+    fn finish_switch_on(&self, worker: &Worker) {
+        if let Some(task) = worker.finish_switch() {
+            self.push(task);
+        }
+    }
+
+    /// The cpu a task restricted to `mask` should be queued on, or `None` if it may go on
+    /// the shared queue. Of the cpus in the mask, the one with the fewest pinned tasks is
+    /// picked, so that restricted tasks spread out as they are requeued. If the mask names
+    /// no cpu that we have a worker for, it is ignored.
+    fn placement(&self, mask: CpuMask) -> Option<usize> {
+        if mask.is_all() {
+            return None;
+        }
+        mask.iter()
+            .take_while(|&cpu| cpu < self.workers.len())
+            .min_by_key(|&cpu| self.workers[cpu].pinned_len())
+    }
+
+    fn push(&self, task: Task) {
+        if task.deadline().is_some() {
+            return deadline::push(task);
+        }
+
+        let policy = task.policy();
+        match self.placement(task.affinity()) {
+            Some(cpu) => self.workers[cpu].pinned.get(policy).push(task),
+            None => self.queue.get(policy).push(task),
+        }
+    }
+
+    fn get_next(&self, worker: &Worker) -> Option<Task> {
+        if let Some(task) = deadline::pop(worker.cpu) {
+            return Some(task);
+        }
+
+        run_queue::bands().find_map(|band| self.get_next_in(worker, band))
+    }
+
+    fn get_next_in(&self, worker: &Worker, band: usize) -> Option<Task> {
+        if let Some(task) = worker.pinned.band(band).try_pop() {
+            if task.affinity().contains(worker.cpu) {
+                return Some(task);
+            }
+            // The mask changed while the task was queued.
+            trace!("migrating {}", task);
+            self.push(task);
+        }
+
+        loop {
+            let task = self.queue.band(band).pop()?;
+            match self.placement(task.affinity()) {
+                Some(cpu) if !task.affinity().contains(worker.cpu) => {
+                    trace!("migrating {}", task);
+                    self.workers[cpu].pinned.get(task.policy()).push(task);
+                }
+                _ => return Some(task),
+            }
+        }
+    }
 }
 
 impl Scheduler for NaiveSmpScheduler {
     fn unpark(&self, task: Task) -> KernResult<()> {
-        self.queue.push(task);
+        self.push(task);
         Ok(())
     }
 
     fn park(&self) -> KernResult<()> {
         self.with_worker(|worker| {
-            let new = worker.queue.pop().expect("no tasks in queue");
-            worker.switch(new, false);
+            let new = self.get_next_or_idle(worker);
+            self.switch(worker, new, State::Parked);
             Ok(())
         })
     }
@@ -95,52 +182,142 @@ impl Scheduler for NaiveSmpScheduler {
 
     fn yield_now(&self) -> KernResult<()> {
         self.with_worker(|worker| {
-            let Some(new) = self.queue.pop() else { return Ok(()) };
-            worker.switch(new, true);
+            let Some(new) = self.get_next(worker) else { return Ok(()) };
+            self.switch(worker, new, State::Queued);
+            Ok(())
+        })
+    }
+
+    fn migrate(&self) -> KernResult<()> {
+        self.with_worker(|worker| {
+            let cell = worker.current.get().ok_or(KernErrorKind::Fault)?;
+            let mask = unsafe { (*cell.get()).affinity() };
+            if mask.contains(worker.cpu) {
+                return Ok(());
+            }
+            if self.placement(mask).is_none() {
+                return Err(KernErrorKind::InvalidArgument.into());
+            }
+
+            // Requeueing goes through `placement`, which puts the task on the pinned queue
+            // of a cpu it is allowed on.
+            let new = self.get_next_or_idle(worker);
+            self.switch(worker, new, State::Queued);
             Ok(())
         })
     }
 
     fn exit(&self) -> KernResult<!> {
         self.with_worker(|worker| {
-            let new = self.queue.pop().expect("no waiting tasks");
-            worker.switch(new, false);
+            let new = self.get_next_or_idle(worker);
+            self.switch(worker, new, State::Exited);
             unreachable!();
         })
     }
 
     unsafe fn enter(&self) -> KernResult<!> {
         self.with_worker(|worker| {
-            worker
+            let current = worker
                 .current
                 .call_once(|| SyncUnsafeCell::new(allocate_bootstrap_task()));
+            worker
+                .idle
+                .call_once(|| unsafe { (*current.get()).clone() });
 
             Ok(())
         })?;
 
         loop {
             interrupts::disable();
+            rcu::quiescent_state();
 
-            if let Some(new) = self.queue.pop() {
-                self.worker_unchecked().switch(new, true);
-            } else {
+            let worker = self.worker_unchecked();
+            if let Some(new) = self.get_next(worker) {
+                self.switch(worker, new, State::Parked);
+            } else if reaper::reap() + rcu::run_callbacks() == 0 {
                 warn!("halt");
                 unsafe { enable_and_wait() };
             }
         }
     }
+
+    unsafe fn finish_switch(&self) -> KernResult<()> {
+        self.with_worker(|worker| {
+            self.finish_switch_on(worker);
+            Ok(())
+        })
+    }
+
+    fn tick(&self) -> KernResult<()> {
+        self.with_worker(|worker| {
+            let Some(cell) = worker.current.get() else { return Ok(()) };
+
+            // A task that has used up its budget has to stop even if there is nothing else
+            // to run.
+            let (preempt, throttled) = {
+                let current = unsafe { &*cell.get() };
+                let preempt = deadline::tick(worker.cpu, current)
+                    && current.head().preemptible.load(Ordering::Relaxed);
+                let throttled = current
+                    .deadline()
+                    .map_or(false, DeadlineEntity::is_throttled);
+                (preempt, throttled)
+            };
+
+            if !preempt {
+                return Ok(());
+            }
+
+            let new = match self.get_next(worker) {
+                Some(new) => new,
+                None if throttled => worker.idle(),
+                None => return Ok(()),
+            };
+            self.switch(worker, new, State::Queued);
+            Ok(())
+        })
+    }
 }
 
 struct Worker {
+    cpu: usize,
     current: Once<SyncUnsafeCell<Task>>,
-    exited: Cell<Option<Task>>,
-    queue: Arc<MpmcQueue>,
+    /// The task running the loop in `enter`. It is never queued, and runs whenever there
+    /// is nothing else to.
+    idle: Once<Task>,
+    /// The task switched away from, and the state it was left in.
+    previous: Cell<Option<(Task, State)>>,
+    queue: Arc<Bands<MpmcQueue>>,
+    /// Tasks restricted to a subset of cpus which includes this one.
+    pinned: Bands<MpmcQueue>,
 }
 
 impl Worker {
-    fn switch(&self, new: Task, requeue: bool) {
+    fn idle(&self) -> Task {
+        self.idle.get().expect("uninitialized worker").clone()
+    }
+
+    fn is_idle(&self, task: &Task) -> bool {
+        self.idle.get().map_or(false, |idle| idle.id() == task.id())
+    }
+
+    /// Roughly how many tasks are pinned to this cpu, for placement.
+    fn pinned_len(&self) -> usize {
+        self.pinned.iter().map(MpmcQueue::len).sum()
+    }
+
+    /// Switch to `new`, leaving the current task in `old_state`. The outgoing task is
+    /// stashed in `previous` and dealt with by `finish_switch` once its context has been
+    /// saved.
+    fn switch(&self, new: Task, old_state: State) {
         let slot = self.current.get().expect("uninitialized worker");
         let old = unsafe { ptr::replace(slot.get(), new) };
+        // The idle task is only ever run when there's nothing else, never from a queue.
+        let old_state = if self.is_idle(&old) {
+            State::Parked
+        } else {
+            old_state
+        };
 
         let active = unsafe { &*slot.get() };
 
@@ -148,13 +325,6 @@ impl Worker {
 
         active.change_state_to_active();
 
-        let old_state = if requeue {
-            self.queue.push(old.clone());
-            State::Queued
-        } else {
-            State::Parked
-        };
-
         old.change_state(State::Active, old_state)
             .expect("invalid task state transition");
 
@@ -163,21 +333,37 @@ impl Worker {
 
         trace!("num_refs = {}", old.head().refs.load(Ordering::Relaxed));
 
-        if requeue {
-            mem::drop(old)
-        } else {
-            self.exited.set(Some(old));
-        }
+        let previous = self.previous.replace(Some((old, old_state)));
+        debug_assert!(previous.is_none());
+
+        prepare_switch(active);
 
         unsafe {
             context_switch(old_ctx, new_ctx);
         }
     }
+
+    /// Deal with the task switched away from, returning it if it needs to be requeued.
+    /// This goes by the state it was left in: a parked task may already have been
+    /// unparked and queued again.
+    fn finish_switch(&self) -> Option<Task> {
+        let (old, old_state) = self.previous.take()?;
+
+        match old_state {
+            State::Queued => return Some(old),
+            State::Exited => reaper::push(old),
+            _ => {}
+        }
+        None
+    }
 }
 
 struct MpmcQueue {
     inner: MpscQueue<Task>,
     pop_lock: SpinMutex<()>,
+    /// The number of queued tasks. It can briefly lag behind the queue itself, which is
+    /// fine for the placement heuristics it's used for.
+    len: AtomicUsize,
 }
 
 impl MpmcQueue {
@@ -187,20 +373,33 @@ impl MpmcQueue {
         Self {
             inner: MpscQueue::with_static_stub(Box::leak(stub)),
             pop_lock: SpinMutex::new(()),
+            len: AtomicUsize::new(0),
         }
     }
 
     pub fn pop(&self) -> Option<Task> {
         let _guard = self.pop_lock.lock();
-        unsafe { self.inner.pop_unsync() }
+        self.popped(unsafe { self.inner.pop_unsync() })
     }
 
     pub fn try_pop(&self) -> Option<Task> {
         let _guard = self.pop_lock.try_lock()?;
-        unsafe { self.inner.pop_unsync() }
+        self.popped(unsafe { self.inner.pop_unsync() })
     }
 
     pub fn push(&self, task: Task) {
+        self.len.fetch_add(1, Ordering::Relaxed);
         self.inner.push(task);
     }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn popped(&self, task: Option<Task>) -> Option<Task> {
+        if task.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        task
+    }
 }
@@ -1,8 +1,288 @@
-use alloc::sync::Arc;
+//! Processes: the address space, objects and threads that make up a running program.
+//!
+//! A process runs until it exits, is killed, or one of its threads faults, which all
+//! [terminate](Process::terminate) it. Termination releases everything the process holds
+//! at once, apart from its address space, which its threads may still be using. Each
+//! thread exits the next time it would return to ring 3, and the last one out frees the
+//! address space and wakes whoever [waits](Process::wait) on the process.
 
-use crate::memory::ProcAddrSpace;
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    fmt, mem,
+    num::NonZeroU64,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use hal::{interrupts, vm_types::PhysAddr};
+use log::info;
+use sol_types::ExitStatus;
+use spin::{mutex::SpinMutex, Once};
+
+use super::{current, exit, TaskId};
+use crate::{
+    arch::x86_64::tss,
+    error::{KernErrorKind, KernResult},
+    memory::{AddrSpace, ProcAddrSpace},
+    object::{io_port::IoPorts, HandleTable, KernelObject},
+    sync::{futex, mutex, Mutex},
+    syscall::ring::{Ring, MAX_RINGS},
+};
+
+/// Names a process. Ids are never reused, and init is always 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProcessId(NonZeroU64);
+
+impl ProcessId {
+    fn allocate() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        NonZeroU64::new(NEXT.fetch_add(1, Ordering::Relaxed))
+            .map(Self)
+            .expect("process id overflow")
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl fmt::Display for ProcessId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 #[derive(Debug)]
 pub struct Process {
-    address_space: Arc<ProcAddrSpace>,
+    id: ProcessId,
+    /// Taken once the last thread has exited.
+    address_space: SpinMutex<Option<Arc<ProcAddrSpace>>>,
+    handles: Mutex<HandleTable>,
+    /// The submission/completion rings the process has set up.
+    rings: Mutex<Vec<Arc<Ring>>>,
+    /// The I/O ports the process's tasks may use. Read on every switch to one of them,
+    /// so behind a spinlock.
+    io_ports: SpinMutex<Vec<Arc<IoPorts>>>,
+    /// The tasks running on behalf of the process.
+    threads: SpinMutex<Vec<TaskId>>,
+    /// How the process ended, set by whatever terminated it first.
+    status: Once<ExitStatus>,
+    /// Set to one once the last thread has exited and the process holds nothing, and
+    /// waited on by [`Process::wait`].
+    exited: AtomicU32,
+}
+
+impl Process {
+    pub fn new(address_space: Arc<ProcAddrSpace>) -> Self {
+        Self {
+            id: ProcessId::allocate(),
+            address_space: SpinMutex::new(Some(address_space)),
+            handles: mutex::with_class(HandleTable::new(), lockdep::class!("Process::handles")),
+            rings: mutex::with_class(Vec::new(), lockdep::class!("Process::rings")),
+            io_ports: SpinMutex::new(Vec::new()),
+            threads: SpinMutex::new(Vec::new()),
+            status: Once::new(),
+            exited: AtomicU32::new(0),
+        }
+    }
+
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    /// The address space, until the process has exited.
+    pub fn address_space(&self) -> KernResult<Arc<ProcAddrSpace>> {
+        interrupts::without(|_| self.address_space.lock().clone())
+            .ok_or_else(|| KernErrorKind::NotFound.into())
+    }
+
+    /// Where the top level page table of the address space is, until the process has
+    /// exited. Unlike [`Process::address_space`] this takes no reference, so it can't end
+    /// up dropping the address space.
+    pub fn page_table_root(&self) -> Option<PhysAddr> {
+        interrupts::without(|_| self.address_space.lock().as_ref().map(|space| space.root()))
+    }
+
+    /// The kernel objects the process holds.
+    pub fn handles(&self) -> &Mutex<HandleTable> {
+        &self.handles
+    }
+
+    /// Map a new ring with `entries` submission entries into `space`, returning its
+    /// address. A process can have at most [`MAX_RINGS`] rings.
+    pub fn add_ring(&self, space: &AddrSpace, entries: u32) -> KernResult<usize> {
+        let mut rings = self.rings.lock();
+        if rings.len() >= MAX_RINGS {
+            return Err(KernErrorKind::Busy.into());
+        }
+        let ring = Arc::new(Ring::new(space, entries)?);
+        let addr = ring.addr();
+        rings.push(ring);
+        Ok(addr)
+    }
+
+    /// The ring mapped at `addr`.
+    pub fn ring(&self, addr: usize) -> KernResult<Arc<Ring>> {
+        self.rings
+            .lock()
+            .iter()
+            .find(|ring| ring.addr() == addr)
+            .cloned()
+            .ok_or_else(|| KernErrorKind::InvalidArgument.into())
+    }
+
+    /// Let the process's tasks use `ports` until it exits, starting with the current
+    /// task.
+    pub fn enable_io_ports(&self, ports: Arc<IoPorts>) -> KernResult<()> {
+        interrupts::without(|_| {
+            let mut enabled = self.io_ports.lock();
+            if !enabled.iter().any(|other| Arc::ptr_eq(other, &ports)) {
+                enabled
+                    .try_reserve(1)
+                    .map_err(|_| KernErrorKind::AllocError)?;
+                enabled.push(ports);
+            }
+            drop(enabled);
+
+            self.open_io_ports();
+            Ok(())
+        })
+    }
+
+    /// Open the process's ports, and only those, on this cpu, ahead of running one of its
+    /// tasks. Must be called with interrupts disabled.
+    pub fn open_io_ports(&self) {
+        let enabled = self.io_ports.lock();
+        tss::set_io_ports(enabled.iter().map(|ports| ports.range()));
+    }
+
+    /// End the process with `status`, unless it is already ending. Everything it holds
+    /// apart from its address space is released now.
+    ///
+    /// Threads in ring 3 exit on their next interrupt, and threads in the kernel once
+    /// their syscall returns. Threads asleep on behalf of userspace are woken, and their
+    /// waits fail with [`KernErrorKind::Interrupted`].
+    pub fn terminate(&self, status: ExitStatus) {
+        let mut first = false;
+        self.status.call_once(|| {
+            first = true;
+            status
+        });
+        if !first {
+            return;
+        }
+
+        info!("process {} {}", self.id, status);
+        self.release();
+        futex::interrupt(self);
+        // Nothing would be left to finish the exit of a process without threads.
+        if interrupts::without(|_| self.threads.lock().is_empty()) {
+            self.finish_exit();
+        }
+    }
+
+    /// Whether the process has been terminated, though its threads may still be on their
+    /// way out.
+    pub fn is_terminating(&self) -> bool {
+        self.status.is_completed()
+    }
+
+    /// How the process ended, once it has fully exited.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        if self.exited.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        self.status.get().copied()
+    }
+
+    /// Sleep until the process has fully exited, returning how it ended. Fails with
+    /// [`KernErrorKind::Interrupted`] if the caller's own process is terminated first.
+    pub fn wait(&self) -> KernResult<ExitStatus> {
+        loop {
+            if let Some(status) = self.exit_status() {
+                return Ok(status);
+            }
+            futex::wait_interruptible(&self.exited, 0)?;
+        }
+    }
+
+    /// Count `task` among the process's threads. Fails with [`KernErrorKind::NotFound`]
+    /// once the process is terminating.
+    pub(super) fn add_thread(&self, task: TaskId) -> KernResult<()> {
+        interrupts::without(|_| {
+            let mut threads = self.threads.lock();
+            // Checked under the lock, which `terminate` takes after setting the status,
+            // so a thread can't join a process nobody will finish exiting.
+            if self.is_terminating() {
+                return Err(KernErrorKind::NotFound.into());
+            }
+            threads
+                .try_reserve(1)
+                .map_err(|_| KernErrorKind::AllocError)?;
+            threads.push(task);
+            Ok(())
+        })
+    }
+
+    /// Forget `task`, which is exiting. The last thread out finishes the process's exit.
+    pub(super) fn remove_thread(&self, task: TaskId) {
+        let last = interrupts::without(|_| {
+            let mut threads = self.threads.lock();
+            threads.retain(|&id| id != task);
+            threads.is_empty()
+        });
+        if !last {
+            return;
+        }
+
+        if self.is_terminating() {
+            self.finish_exit();
+        } else {
+            // A process whose threads all end without it exiting exits cleanly, and
+            // terminating a process without threads finishes its exit.
+            self.terminate(ExitStatus::Exited(0));
+        }
+    }
+
+    /// Release what is left once no thread can be using it, and wake the waiters.
+    ///
+    /// Both `terminate` and the last thread out may get here if they race, so whichever
+    /// takes the address space does the rest.
+    fn finish_exit(&self) {
+        let address_space = interrupts::without(|_| self.address_space.lock().take());
+        let Some(address_space) = address_space else { return };
+        // Again, for anything a thread gained between `terminate` and exiting.
+        self.release();
+        drop(address_space);
+
+        self.exited.store(1, Ordering::Release);
+        futex::wake_all(&self.exited);
+    }
+
+    /// Drop the objects, rings and ports the process holds.
+    fn release(&self) {
+        let handles = mem::take(&mut *self.handles.lock());
+        let rings = mem::take(&mut *self.rings.lock());
+        let io_ports = interrupts::without(|_| mem::take(&mut *self.io_ports.lock()));
+        // Dropped outside the locks, as the objects' teardown may be arbitrarily involved.
+        drop((handles, rings, io_ports));
+    }
+}
+
+impl KernelObject for Process {
+    fn kind(&self) -> &'static str {
+        "process"
+    }
+}
+
+/// Exit the current task if its process is terminating. Called on the way back to
+/// ring 3, where the task holds nothing in the kernel, so interrupts may be disabled and
+/// are enabled to exit.
+pub fn exit_if_terminating() {
+    let terminating = current()
+        .process()
+        .map_or(false, |process| process.is_terminating());
+    if terminating {
+        unsafe { interrupts::enable() };
+        exit();
+    }
 }
@@ -0,0 +1,44 @@
+//! Deferred cleanup of exited tasks.
+//!
+//! A task cannot free its own stack: it is still running on it when it calls `exit`. Instead
+//! the scheduler hands the task to the reaper once it has been switched away from, at which
+//! point nothing can be executing on it. The task is then parked on the zombie list until
+//! [`reap`] runs from a safe context (the idle loop, or spawn), where its stack is released
+//! and the scheduler's reference is dropped. The head itself lives on until the last
+//! outstanding [`Task`] handle goes away.
+
+use core::sync::atomic::Ordering;
+
+use hal::interrupts;
+use log::trace;
+use meteor::tail_list::TailList;
+
+use super::task_types::{State, Task};
+use crate::sync::spinlock::SpinMutex;
+
+static ZOMBIES: SpinMutex<TailList<Task>> =
+    SpinMutex::new(TailList::new(), lockdep::class!("ZOMBIES"));
+
+/// Queue an exited task for reaping.
+///
+/// The caller must ensure that the task has been fully switched away from.
+pub fn push(task: Task) {
+    debug_assert_eq!(task.head().state.load(Ordering::Relaxed), State::Exited);
+    interrupts::without(|_| ZOMBIES.lock().push_back(task));
+}
+
+/// Release the resources of every task on the zombie list, returning how many were reaped.
+pub fn reap() -> usize {
+    let mut count = 0;
+
+    while let Some(task) = interrupts::without(|_| ZOMBIES.lock().pop_front()) {
+        trace!("reaping {}", task);
+        if let Some(deadline) = task.deadline() {
+            deadline.release();
+        }
+        unsafe { (task.vtable().reap)(task.0) };
+        count += 1;
+    }
+
+    count
+}
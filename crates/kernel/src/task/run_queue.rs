@@ -0,0 +1,52 @@
+//! Run queues split by policy band.
+//!
+//! Schedulers keep one queue per band and always take from the highest band with a task
+//! in it, so a task boosted by priority inheritance runs ahead of everything below its new
+//! band. Within a band tasks run in the order they were queued: the level inside the band
+//! is not looked at.
+
+use super::task_types::Policy;
+
+#[derive(Debug, Default)]
+pub struct Bands<Q> {
+    queues: [Q; Policy::BANDS],
+}
+
+impl<Q> Bands<Q> {
+    pub fn new<F>(mut f: F) -> Self
+    where
+        F: FnMut() -> Q,
+    {
+        Self {
+            queues: [(); Policy::BANDS].map(|_| f()),
+        }
+    }
+
+    /// The queue for tasks with `policy`.
+    pub fn get(&self, policy: Policy) -> &Q {
+        &self.queues[policy.band()]
+    }
+
+    pub fn get_mut(&mut self, policy: Policy) -> &mut Q {
+        &mut self.queues[policy.band()]
+    }
+
+    /// The queue for band number `band`, as given by [`Policy::band`].
+    pub fn band(&self, band: usize) -> &Q {
+        &self.queues[band]
+    }
+
+    /// The queues, highest band first.
+    pub fn iter(&self) -> impl Iterator<Item = &Q> {
+        self.queues.iter().rev()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Q> {
+        self.queues.iter_mut().rev()
+    }
+}
+
+/// The band numbers, highest first.
+pub fn bands() -> impl Iterator<Item = usize> {
+    (0..Policy::BANDS).rev()
+}
@@ -1,5 +1,5 @@
 use super::task_types::Task;
-use crate::error::KernResult;
+use crate::error::{KernErrorKind, KernResult};
 
 pub trait Scheduler: Send + Sync + 'static {
     fn unpark(&self, task: Task) -> KernResult<()>;
@@ -8,4 +8,25 @@ pub trait Scheduler: Send + Sync + 'static {
     fn yield_now(&self) -> KernResult<()>;
     fn exit(&self) -> KernResult<!>;
     unsafe fn enter(&self) -> KernResult<!>;
+    /// Complete a context switch on behalf of the incoming task. This requeues, releases or
+    /// hands the previous task off to the reaper, and must run with interrupts disabled
+    /// before the incoming task does anything else.
+    unsafe fn finish_switch(&self) -> KernResult<()>;
+    /// Called from the preemption timer on every cpu, with interrupts disabled. Schedulers
+    /// use this for time accounting, and may switch tasks from within it.
+    fn tick(&self) -> KernResult<()> {
+        Ok(())
+    }
+    /// Called when the effective policy of `task` changes. Schedulers that can move a
+    /// queued task to its new band do so here; the rest pick the change up the next time
+    /// the task is queued.
+    fn reprioritize(&self, _task: &Task) -> KernResult<()> {
+        Ok(())
+    }
+    /// Move the current task off this cpu, which its affinity no longer allows. Unlike
+    /// `yield_now` this always switches away, running the idle task if there is nothing
+    /// else. Schedulers that only ever run on one cpu have nowhere to send it.
+    fn migrate(&self) -> KernResult<()> {
+        Err(KernErrorKind::Unsupported.into())
+    }
 }
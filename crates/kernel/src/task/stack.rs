@@ -2,7 +2,12 @@ use core::{cell::UnsafeCell, ptr::NonNull};
 
 use hal::task::context_switch;
 
-use crate::error::KernResult;
+use crate::{
+    error::KernResult,
+    memory::{AddrSpace, AllocOptions},
+};
+
+const KERNEL_STACK_SIZE: usize = 16384;
 
 pub struct Stack {
     ptr: NonNull<[u8]>,
@@ -12,8 +17,28 @@ pub struct Stack {
 unsafe impl Send for Stack {}
 unsafe impl Sync for Stack {}
 
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe {
+            stack_options().deallocate_in_address_space(self.ptr.cast(), &AddrSpace::Kernel);
+        }
+    }
+}
+
 pub fn allocate_kernel_stack() -> KernResult<Stack> {
-    todo!()
+    let ptr = stack_options().allocate_in_address_space(&AddrSpace::Kernel)?;
+    let top = unsafe { NonNull::new_unchecked(ptr.as_mut_ptr().add(ptr.len())) };
+
+    Ok(Stack {
+        ptr,
+        top: UnsafeCell::new(top),
+    })
+}
+
+fn stack_options() -> AllocOptions {
+    *AllocOptions::new(KERNEL_STACK_SIZE)
+        .start_guard_pages(1)
+        .end_guard_pages(1)
 }
 
 pub unsafe fn stack_switch(old: &Stack, new: &Stack) {
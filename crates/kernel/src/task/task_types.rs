@@ -1,18 +1,28 @@
+use alloc::{sync::Arc, vec::Vec};
 use core::{
     fmt::{Debug, Display},
     hint::unreachable_unchecked,
     mem::ManuallyDrop,
     num::NonZeroU64,
-    ptr::NonNull,
-    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    ptr::{self, NonNull},
+    sync::atomic::{
+        fence, AtomicBool, AtomicPtr, AtomicU16, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+    },
 };
 
-use hal::task::Context;
+use hal::{interrupts, task::Context};
+use lockdep::HeldLocks;
 use log::info;
 use meteor::{DynSinglePtrLink, Node};
-
-use super::unpark;
-use crate::memory::AddrSpace;
+use spin::mutex::SpinMutex;
+
+use super::{deadline::DeadlineEntity, process::Process, unpark};
+use crate::{
+    arch::{AtomicCpuMask, CpuMask},
+    error::{KernErrorKind, KernResult},
+    memory::AddrSpace,
+    sync::mutex::RawMutex,
+};
 
 #[derive(Debug)]
 pub struct Head {
@@ -22,8 +32,23 @@ pub struct Head {
     pub id: TaskId,
     pub vtable: &'static TaskVTable,
     pub stack_ptr: AtomicPtr<Context>,
-    pub policy: Policy,
+    /// The top of the task's kernel stack, where it enters the kernel from ring 3. Zero
+    /// for tasks running on a stack they don't own.
+    pub stack_top: usize,
+    /// The effective policy, which may be raised above `base_policy` by priority
+    /// inheritance.
+    pub policy: AtomicPolicy,
+    pub base_policy: Policy,
+    pub inheritance: Inheritance,
+    /// Present for tasks in the deadline scheduling class.
+    pub deadline: Option<DeadlineEntity>,
+    /// The cpus this task may run on.
+    pub affinity: AtomicCpuMask,
     pub preemptible: AtomicBool,
+    /// The locks this task holds, when lock validation is enabled.
+    pub held_locks: HeldLocks,
+    /// The process the task runs on behalf of. Kernel threads have none.
+    pub process: Option<Arc<Process>>,
 }
 
 impl Drop for Head {
@@ -113,12 +138,85 @@ pub enum Policy {
 }
 
 impl Policy {
+    /// The number of bands, which is what the run queues order tasks by.
+    pub const BANDS: usize = 3;
+
+    /// The band the policy falls in, from 0 for `Low` up.
+    pub const fn band(&self) -> usize {
+        match self {
+            Policy::Low(_) => 0,
+            Policy::Normal(_) => 1,
+            Policy::High(_) => 2,
+        }
+    }
+
     pub fn should_preempt(&self, policy: Policy) -> bool {
         match (self, policy) {
             (Policy::Low(_), _) => false,
             (this, other) => *this > other,
         }
     }
+
+    /// Pack the policy into an integer with the same ordering as the policy itself.
+    const fn to_bits(self) -> u16 {
+        match self {
+            Policy::Low(p) => p as u16,
+            Policy::Normal(p) => 0x100 | p as u16,
+            Policy::High(p) => 0x200 | p as u16,
+        }
+    }
+
+    const fn from_bits(bits: u16) -> Self {
+        let p = bits as u8;
+        match bits >> 8 {
+            0 => Policy::Low(p),
+            1 => Policy::Normal(p),
+            _ => Policy::High(p),
+        }
+    }
+}
+
+pub struct AtomicPolicy(AtomicU16);
+
+impl AtomicPolicy {
+    pub const fn new(policy: Policy) -> Self {
+        Self(AtomicU16::new(policy.to_bits()))
+    }
+
+    pub fn load(&self, order: Ordering) -> Policy {
+        Policy::from_bits(self.0.load(order))
+    }
+
+    pub fn store(&self, policy: Policy, order: Ordering) {
+        self.0.store(policy.to_bits(), order);
+    }
+}
+
+impl Debug for AtomicPolicy {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("AtomicPolicy")
+            .field(&self.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// Priority inheritance state for a task.
+#[derive(Debug, Default)]
+pub struct Inheritance {
+    /// The mutex this task is currently blocked on, if any.
+    pub blocked_on: AtomicPtr<RawMutex>,
+    /// The policies lent to this task through each contended mutex it holds, keyed by the
+    /// mutex address.
+    boosts: SpinMutex<Vec<(usize, Policy)>>,
+}
+
+impl Inheritance {
+    pub const fn new() -> Self {
+        Self {
+            blocked_on: AtomicPtr::new(ptr::null_mut()),
+            boosts: SpinMutex::new(Vec::new()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -135,6 +233,14 @@ impl Task {
         ManuallyDrop::new(self).0
     }
 
+    /// Take a new reference to the task at `raw`, leaving any existing one alone.
+    ///
+    /// # Safety
+    /// The task must be kept alive by someone else for the duration of the call.
+    pub unsafe fn clone_from_raw(raw: NonNull<Head>) -> Self {
+        (*ManuallyDrop::new(Self::from_raw(raw))).clone()
+    }
+
     pub fn unpark(self) {
         unpark(self);
     }
@@ -173,12 +279,73 @@ impl Task {
         self.head().stack_ptr.load(Ordering::Acquire)
     }
 
-    pub fn address_space(&self) -> &AddrSpace {
-        &AddrSpace::Kernel
+    /// The address space the task's user addresses refer to: its process's, or the
+    /// kernel's for a kernel task. Fails once the process has fully exited.
+    pub fn address_space(&self) -> KernResult<AddrSpace> {
+        match self.process() {
+            Some(process) => Ok(AddrSpace::User(process.address_space()?)),
+            None => Ok(AddrSpace::Kernel),
+        }
+    }
+
+    pub fn process(&self) -> Option<&Arc<Process>> {
+        self.head().process.as_ref()
     }
 
+    /// The effective policy of the task, including anything it has inherited.
     pub fn policy(&self) -> Policy {
-        self.head().policy
+        self.head().policy.load(Ordering::Acquire)
+    }
+
+    pub fn base_policy(&self) -> Policy {
+        self.head().base_policy
+    }
+
+    pub fn deadline(&self) -> Option<&DeadlineEntity> {
+        self.head().deadline.as_ref()
+    }
+
+    pub fn affinity(&self) -> CpuMask {
+        self.head().affinity.load(Ordering::Acquire)
+    }
+
+    /// Change the set of cpus this task may run on. This only updates the mask: the
+    /// scheduler moves the task the next time it is queued.
+    ///
+    /// Deadline tasks hold a bandwidth reservation on a single cpu, so their mask must
+    /// keep that cpu.
+    pub fn set_affinity(&self, mask: CpuMask) -> KernResult<()> {
+        if mask.is_empty() {
+            return Err(KernErrorKind::InvalidArgument.into());
+        }
+        if let Some(deadline) = self.deadline() {
+            if !mask.contains(deadline.cpu()) {
+                return Err(KernErrorKind::InvalidArgument.into());
+            }
+        }
+        self.head().affinity.store(mask, Ordering::Release);
+        Ok(())
+    }
+
+    /// Record that this task inherits `policy` through the mutex at `key`, replacing any
+    /// previous boost through it. Returns true if the effective policy changed.
+    pub fn inherit(&self, key: usize, policy: Option<Policy>) -> bool {
+        interrupts::without(|_| {
+            let mut boosts = self.head().inheritance.boosts.lock();
+            boosts.retain(|&(k, _)| k != key);
+            if let Some(policy) = policy {
+                boosts.push((key, policy));
+            }
+
+            let effective = boosts
+                .iter()
+                .map(|&(_, p)| p)
+                .fold(self.base_policy(), Policy::max);
+
+            let old = self.head().policy.load(Ordering::Relaxed);
+            self.head().policy.store(effective, Ordering::Release);
+            old != effective
+        })
     }
 }
 
@@ -194,13 +361,17 @@ impl Clone for Task {
 
 impl Drop for Task {
     fn drop(&mut self) {
-        let n = self.head().refs.fetch_sub(1, Ordering::Relaxed);
+        let n = self.head().refs.fetch_sub(1, Ordering::Release);
 
         if 1 != n {
             info!("task.drop refs = {}", n);
             return;
         }
 
+        // Tasks are released by whichever cpu drops the final reference, which is
+        // frequently not the one they last ran on.
+        fence(Ordering::Acquire);
+
         let vtable = self.vtable();
         unsafe {
             (vtable.drop_in_place)(self.0);
@@ -238,6 +409,10 @@ impl Node<DynSinglePtrLink> for Task {
 pub struct TaskVTable {
     pub drop_in_place: unsafe fn(NonNull<Head>),
     pub deallocate: unsafe fn(NonNull<u8>),
+    /// Release any resources that are only needed while the task can run, such as its
+    /// stack. Called once by the reaper after the task has exited and been switched away
+    /// from. The head must remain valid, as outstanding references may still exist.
+    pub reap: unsafe fn(NonNull<Head>),
 }
 
 impl Debug for TaskVTable {
@@ -1,9 +1,9 @@
-use alloc::{alloc::Global, boxed::Box};
+use alloc::{alloc::Global, boxed::Box, sync::Arc};
 use core::{
     alloc::{AllocError, Allocator, Layout},
     cell::{Cell, SyncUnsafeCell},
-    mem::{ManuallyDrop, MaybeUninit},
-    ptr::{self, addr_of_mut, NonNull},
+    mem::MaybeUninit,
+    ptr::{self, NonNull},
     sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize},
 };
 
@@ -11,12 +11,20 @@ use hal::{
     interrupts::{self, enable},
     task::Context,
 };
+use lockdep::HeldLocks;
 
 use super::{
-    current, exit,
-    task_types::{allocate_id, AtomicState, Head, Policy, Task, TaskVTable},
+    current,
+    deadline::{DeadlineEntity, DeadlineParams},
+    exit, finish_switch,
+    process::Process,
+    reaper,
+    task_types::{
+        allocate_id, AtomicPolicy, AtomicState, Head, Inheritance, Policy, Task, TaskVTable,
+    },
 };
 use crate::{
+    arch::{AtomicCpuMask, CpuMask},
     error::{KernErrorKind, KernResult},
     memory::{self, AllocOptions},
 };
@@ -32,6 +40,9 @@ where
 pub struct Builder {
     stack_size: usize,
     policy: Policy,
+    deadline: Option<DeadlineParams>,
+    affinity: CpuMask,
+    process: Option<Arc<Process>>,
 }
 
 impl Builder {
@@ -39,9 +50,37 @@ impl Builder {
         Self {
             stack_size: 16384,
             policy: Policy::Normal(127),
+            deadline: None,
+            affinity: CpuMask::all(),
+            process: None,
         }
     }
 
+    /// Run the thread on behalf of `process`.
+    pub fn process(mut self, process: Arc<Process>) -> Self {
+        self.process = Some(process);
+        self
+    }
+
+    /// Schedule the thread with `policy` rather than the default `Normal(127)`.
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Restrict the thread to the given set of cpus.
+    pub fn affinity(mut self, mask: CpuMask) -> Self {
+        self.affinity = mask;
+        self
+    }
+
+    /// Run the thread in the deadline scheduling class. Spawning fails if the scheduler
+    /// cannot guarantee the requested bandwidth.
+    pub fn deadline(mut self, params: DeadlineParams) -> Self {
+        self.deadline = Some(params);
+        self
+    }
+
     pub fn spawn<F, T>(self, f: F) -> KernResult<Task>
     where
         F: FnOnce() -> T + 'static + Send,
@@ -51,10 +90,15 @@ impl Builder {
 
     pub fn spawn_in<F, T, A>(self, f: F, allocator: A) -> KernResult<Task>
     where
-        A: Allocator + Clone,
+        A: Allocator,
         F: FnOnce() -> T + 'static + Send,
     {
+        reaper::reap();
+
         let thread = allocate_thread_in(self, f, allocator)?;
+        if let Some(process) = thread.process() {
+            process.add_thread(thread.id())?;
+        }
         thread.clone().unpark();
         Ok(thread)
     }
@@ -81,18 +125,32 @@ impl Default for Builder {
 
 fn allocate_thread_in<F, T, A>(builder: Builder, f: F, allocator: A) -> KernResult<Task>
 where
-    A: Allocator + Clone,
+    A: Allocator,
     F: FnOnce() -> T + 'static + Send,
 {
     let layout =
         Layout::from_size_align(builder.stack_size, 4096).map_err(|_| KernErrorKind::Fault)?;
 
+    if builder.affinity.is_empty() {
+        return Err(KernErrorKind::InvalidArgument.into());
+    }
+
+    // Admission comes first, so a rejected thread costs nothing. Should a later allocation
+    // fail, dropping the entity gives the bandwidth back.
+    let deadline = builder
+        .deadline
+        .map(|params| DeadlineEntity::admit(params, builder.affinity))
+        .transpose()?;
+
     let stack = allocator.allocate(layout)?;
-    let mut stack = unsafe { Box::from_raw_in(stack.as_ptr() as *mut _, allocator.clone()) };
+    let mut stack = unsafe { Box::from_raw_in(stack.as_ptr() as *mut _, allocator) };
 
+    let stack_top = stack.as_mut_ptr_range().end as usize;
     let sp = init_stack(entry::<F, T, A>, &mut stack);
-    let allocation = Box::new_uninit_in(allocator);
-    let (ptr, allocator) = Box::into_raw_with_allocator(allocation);
+    // Only the stack comes from `allocator`. The rest is freed wherever the last reference
+    // is dropped, possibly with interrupts disabled, so it comes from the heap rather than
+    // a mapping of its own that would need a TLB shootdown to undo.
+    let ptr = Box::into_raw(Box::<ThreadInner<F, T, A>>::new_uninit());
 
     let inner = ThreadInner {
         head: Head {
@@ -102,11 +160,17 @@ where
             link: Default::default(),
             vtable: &ThreadInner::<F, T, A>::VTABLE,
             stack_ptr: AtomicPtr::new(sp.as_ptr()),
-            policy: builder.policy,
+            stack_top,
+            policy: AtomicPolicy::new(builder.policy),
+            base_policy: builder.policy,
+            inheritance: Inheritance::new(),
+            deadline,
+            affinity: AtomicCpuMask::new(builder.affinity),
             preemptible: AtomicBool::new(true),
+            held_locks: HeldLocks::new(),
+            process: builder.process,
         },
-        stack: SyncUnsafeCell::new(stack),
-        allocator: ManuallyDrop::new(allocator),
+        stack: SyncUnsafeCell::new(Some(stack)),
         func: Cell::new(Some(f)),
         result: Cell::new(None::<T>),
         finished: AtomicU32::new(0),
@@ -121,12 +185,13 @@ where
 
 extern "C" fn entry<F, T, A>(_: *mut ()) -> !
 where
-    A: Allocator + Clone,
+    A: Allocator,
     F: FnOnce() -> T + 'static + Send,
 {
     let f = unsafe {
         let were_enabled = interrupts::are_enabled();
         assert!(!were_enabled);
+        finish_switch();
         enable();
 
         let task = current();
@@ -158,9 +223,8 @@ where
     head: Head,
     result: Cell<Option<T>>,
     finished: AtomicU32,
-    stack: SyncUnsafeCell<Box<[MaybeUninit<u8>], A>>,
+    stack: SyncUnsafeCell<Option<Box<[MaybeUninit<u8>], A>>>,
     func: Cell<Option<F>>,
-    allocator: ManuallyDrop<A>,
 }
 
 impl<F, T, A> ThreadInner<F, T, A>
@@ -170,9 +234,19 @@ where
     const VTABLE: TaskVTable = TaskVTable {
         deallocate: deallocate::<F, T, A>,
         drop_in_place: drop_in_place::<F, T, A>,
+        reap: reap::<F, T, A>,
     };
 }
 
+unsafe fn reap<F, T, A>(head: NonNull<Head>)
+where
+    A: Allocator,
+{
+    let inner: NonNull<ThreadInner<F, T, A>> = head.cast();
+    let stack = (*inner.as_ref().stack.get()).take();
+    drop(stack);
+}
+
 unsafe fn drop_in_place<F, T, A>(head: NonNull<Head>)
 where
     A: Allocator,
@@ -185,15 +259,8 @@ unsafe fn deallocate<F, T, A>(head: NonNull<u8>)
 where
     A: Allocator,
 {
-    let inner: NonNull<ThreadInner<F, T, A>> = head.cast();
     let layout = Layout::new::<ThreadInner<F, T, A>>();
-
-    // Deallocate will be called after drop_in_place, so we use a ManuallyDrop to
-    // prevent the allocator from being dropped, then use addr_of_mut!() to access the
-    // location of the allocator, take it out of the object, then deallocate.
-    let slot = addr_of_mut!((*inner.as_ptr()).allocator);
-    let allocator = ManuallyDrop::take(&mut *slot);
-    allocator.deallocate(head.cast(), layout);
+    Global.deallocate(head, layout);
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -208,5 +275,10 @@ unsafe impl Allocator for KAlloc {
             .map_err(|_| AllocError)
     }
 
-    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {}
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        AllocOptions::new(layout.size())
+            .start_guard_pages(1)
+            .end_guard_pages(1)
+            .deallocate_in_address_space(ptr, &memory::AddrSpace::Kernel);
+    }
 }
@@ -1,8 +1,8 @@
 use alloc::{boxed::Box, vec::Vec};
 use core::{
     cell::{Cell, SyncUnsafeCell},
-    mem, ptr,
-    sync::atomic::Ordering,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use hal::{
@@ -15,11 +15,21 @@ use nanorand::{Rng, WyRand};
 use spin::{mutex::SpinMutex, Once};
 
 use self::spmc::UnsafeQueue;
-use super::{sched::Scheduler, Task};
+use super::{
+    run_queue::{self, Bands},
+    sched::Scheduler,
+    Policy, Task,
+};
 use crate::{
-    arch::interrupts::enable_and_wait,
+    arch::{interrupts::enable_and_wait, CpuMask},
     error::{KernErrorKind, KernResult},
-    task::{idle::allocate_bootstrap_task, task_types::State},
+    sync::rcu,
+    task::{
+        deadline::{self, DeadlineEntity},
+        idle::allocate_bootstrap_task,
+        prepare_switch, reaper,
+        task_types::State,
+    },
 };
 
 mod spmc;
@@ -41,15 +51,20 @@ impl WorkStealingScheduler {
         workers.resize_with(worker_count, || {
             seed += 1;
             Worker {
+                cpu: seed as usize - 1,
                 buffer: UnsafeQueue::new(),
                 current: Once::new(),
-                queue: MpmcQueue::new(),
+                queue: Bands::new(MpmcQueue::new),
+                pinned: Bands::new(MpmcQueue::new),
                 rng: SpinMutex::new(WyRand::new_seed(seed)),
-                exited: Cell::new(None),
+                idle: Once::new(),
+                previous: Cell::new(None),
             }
         });
         let workers = workers.into_boxed_slice();
 
+        deadline::init(worker_count);
+
         Self { workers }
     }
 
@@ -71,20 +86,83 @@ impl WorkStealingScheduler {
         let cpu = hw_thread_id();
         &self.workers[cpu]
     }
+
+    fn switch(&self, worker: &Worker, new: Task, old_state: State) {
+        worker.switch(new, old_state);
+        // We may have been resumed on a different cpu, so look the worker up again rather
+        // than reusing `worker`.
+        self.finish_switch_on(unsafe { self.worker_unchecked() });
+    }
+
+    fn get_next_or_idle(&self, worker: &Worker) -> Task {
+        self.get_next(worker).unwrap_or_else(|| worker.idle())
+    }
+
+    fn finish_switch_on(&self, worker: &Worker) {
+        if let Some(task) = worker.finish_switch() {
+            self.push(worker, task);
+        }
+    }
+
+    /// The cpu a task restricted to `mask` should be queued on. Of the cpus in the mask,
+    /// the one with the fewest pinned tasks is picked, preferring `cpu` on a tie, so that
+    /// restricted tasks spread out as they are requeued. Returns `None` if the mask names
+    /// no cpu that we have a worker for.
+    fn placement(&self, mask: CpuMask, cpu: usize) -> Option<usize> {
+        mask.iter()
+            .take_while(|&other| other < self.workers.len())
+            .min_by_key(|&other| (self.workers[other].pinned_len(), other != cpu))
+    }
+
+    /// Whether a task restricted to `mask` may run on `cpu`. A mask that names no cpu
+    /// that we have a worker for is ignored.
+    fn allowed(&self, mask: CpuMask, cpu: usize) -> bool {
+        mask.contains(cpu) || self.placement(mask, cpu).is_none()
+    }
+
+    fn push(&self, worker: &Worker, task: Task) {
+        if task.deadline().is_some() {
+            return deadline::push(task);
+        }
+
+        let mask = task.affinity();
+        if mask.is_all() {
+            return worker.push(task);
+        }
+
+        // Restricted tasks go on the pinned queue of a cpu they may run on, which other
+        // workers never steal from.
+        let cpu = self.placement(mask, worker.cpu).unwrap_or(worker.cpu);
+        self.workers[cpu].pinned.get(task.policy()).push(task);
+    }
+
+    fn get_next(&self, worker: &Worker) -> Option<Task> {
+        loop {
+            let task = worker.get_next(&self.workers)?;
+
+            // The mask may have changed while the task was queued. If so, send it on to
+            // a cpu it is allowed on. It never comes back here, so this terminates.
+            if self.allowed(task.affinity(), worker.cpu) {
+                return Some(task);
+            }
+            trace!("migrating {}", task);
+            self.push(worker, task);
+        }
+    }
 }
 
 impl Scheduler for WorkStealingScheduler {
     fn unpark(&self, task: Task) -> KernResult<()> {
         self.with_worker(|worker| {
-            worker.push(task);
+            self.push(worker, task);
             Ok(())
         })
     }
 
     fn park(&self) -> KernResult<()> {
         self.with_worker(|worker| {
-            let new = worker.get_next(&self.workers).expect("no tasks in queue");
-            worker.switch(new, false);
+            let new = self.get_next_or_idle(worker);
+            self.switch(worker, new, State::Parked);
             Ok(())
         })
     }
@@ -99,16 +177,35 @@ impl Scheduler for WorkStealingScheduler {
 
     fn yield_now(&self) -> KernResult<()> {
         self.with_worker(|worker| {
-            let Some(new) = worker.get_next(&self.workers) else { return Ok(()) };
-            worker.switch(new, true);
+            let Some(new) = self.get_next(worker) else { return Ok(()) };
+            self.switch(worker, new, State::Queued);
+            Ok(())
+        })
+    }
+
+    fn migrate(&self) -> KernResult<()> {
+        self.with_worker(|worker| {
+            let cell = worker.current.get().ok_or(KernErrorKind::Fault)?;
+            let mask = unsafe { (*cell.get()).affinity() };
+            if mask.contains(worker.cpu) {
+                return Ok(());
+            }
+            if self.placement(mask, worker.cpu).is_none() {
+                return Err(KernErrorKind::InvalidArgument.into());
+            }
+
+            // Requeueing goes through `placement`, which puts the task on the pinned queue
+            // of a cpu it is allowed on.
+            let new = self.get_next_or_idle(worker);
+            self.switch(worker, new, State::Queued);
             Ok(())
         })
     }
 
     fn exit(&self) -> KernResult<!> {
         self.with_worker(|worker| {
-            let new = worker.get_next(&self.workers).expect("no waiting tasks");
-            worker.switch(new, false);
+            let new = self.get_next_or_idle(worker);
+            self.switch(worker, new, State::Exited);
             unreachable!();
         })
     }
@@ -117,71 +214,167 @@ impl Scheduler for WorkStealingScheduler {
         let worker = self.worker_unchecked();
         debug_assert!(!worker.current.is_completed());
 
-        worker
+        let current = worker
             .current
             .call_once(|| SyncUnsafeCell::new(allocate_bootstrap_task()));
+        worker.idle.call_once(|| (*current.get()).clone());
 
         loop {
             interrupts::disable();
+            rcu::quiescent_state();
 
-            if let Some(new) = worker.get_next(&self.workers) {
-                worker.switch(new, true);
-            } else {
+            let worker = self.worker_unchecked();
+            if let Some(new) = self.get_next(worker) {
+                self.switch(worker, new, State::Parked);
+            } else if reaper::reap() + rcu::run_callbacks() == 0 {
                 warn!("halt");
                 unsafe { enable_and_wait() };
             }
         }
     }
+
+    unsafe fn finish_switch(&self) -> KernResult<()> {
+        self.with_worker(|worker| {
+            self.finish_switch_on(worker);
+            Ok(())
+        })
+    }
+
+    fn tick(&self) -> KernResult<()> {
+        self.with_worker(|worker| {
+            let Some(cell) = worker.current.get() else { return Ok(()) };
+
+            // A task that has used up its budget has to stop even if there is nothing else
+            // to run.
+            let (preempt, throttled) = {
+                let current = unsafe { &*cell.get() };
+                let preempt = deadline::tick(worker.cpu, current)
+                    && current.head().preemptible.load(Ordering::Relaxed);
+                let throttled = current
+                    .deadline()
+                    .map_or(false, DeadlineEntity::is_throttled);
+                (preempt, throttled)
+            };
+
+            if !preempt {
+                return Ok(());
+            }
+
+            let new = match self.get_next(worker) {
+                Some(new) => new,
+                None if throttled => worker.idle(),
+                None => return Ok(()),
+            };
+            self.switch(worker, new, State::Queued);
+            Ok(())
+        })
+    }
 }
 
 struct Worker {
+    cpu: usize,
     current: Once<SyncUnsafeCell<Task>>,
+    /// The task running the loop in `enter`. It is never queued, and runs whenever there
+    /// is nothing else to.
+    idle: Once<Task>,
+    /// `Normal` tasks, which is nearly all of them. Tasks in the other bands skip the
+    /// buffer and go straight on the queue.
     buffer: UnsafeQueue,
-    queue: MpmcQueue,
+    queue: Bands<MpmcQueue>,
+    /// Tasks restricted to a subset of cpus. Never stolen from.
+    pinned: Bands<MpmcQueue>,
     rng: SpinMutex<WyRand>,
-    exited: Cell<Option<Task>>,
+    /// The task switched away from, and the state it was left in.
+    previous: Cell<Option<(Task, State)>>,
 }
 
 impl Worker {
-    fn get_next(&self, workers: &[Worker]) -> Option<Task> {
-        if let Some(task) = self.buffer.pop() {
-            return Some(task);
-        }
-        trace!("local buffer empty, trying local queue");
+    fn idle(&self) -> Task {
+        self.idle.get().expect("uninitialized worker").clone()
+    }
 
-        if let Some(task) = self.queue.try_pop() {
+    fn is_idle(&self, task: &Task) -> bool {
+        self.idle.get().map_or(false, |idle| idle.id() == task.id())
+    }
+
+    /// Roughly how many tasks are pinned to this cpu, for placement.
+    fn pinned_len(&self) -> usize {
+        self.pinned.iter().map(MpmcQueue::len).sum()
+    }
+
+    /// Take the next task, going through the bands from the highest down. Within a band
+    /// the local queues come first, then other workers' are stolen from.
+    fn get_next(&self, workers: &[Worker]) -> Option<Task> {
+        // Deadline tasks are pinned to the cpu they were admitted on and are never stolen.
+        if let Some(task) = deadline::pop(self.cpu) {
             return Some(task);
         }
 
-        let start = self.rng.lock().generate_range(..workers.len());
-        let workers = workers[start..].iter().chain(&workers[..start]);
+        let buffered = Policy::Normal(0).band();
 
-        trace!("local queue empty, trying remove queue");
+        for band in run_queue::bands() {
+            if let Some(task) = self.pinned.band(band).try_pop() {
+                return Some(task);
+            }
 
-        for worker in workers {
-            if ptr::eq(worker, self) {
-                continue;
+            if band == buffered {
+                if let Some(task) = self.buffer.pop() {
+                    return Some(task);
+                }
+                trace!("local buffer empty, trying local queue");
             }
-            if let Some(task) = worker.queue.try_pop() {
+
+            if let Some(task) = self.queue.band(band).try_pop() {
                 return Some(task);
             }
-            if let Some(task) = unsafe { worker.buffer.steal_into(&self.buffer) } {
-                warn!("steal");
-                return Some(task);
+
+            let start = self.rng.lock().generate_range(..workers.len());
+            let workers = workers[start..].iter().chain(&workers[..start]);
+
+            trace!("local queue empty, trying remove queue");
+
+            for worker in workers {
+                if ptr::eq(worker, self) {
+                    continue;
+                }
+                if let Some(task) = worker.queue.band(band).try_pop() {
+                    return Some(task);
+                }
+                if band != buffered {
+                    continue;
+                }
+                if let Some(task) = unsafe { worker.buffer.steal_into(&self.buffer) } {
+                    warn!("steal");
+                    return Some(task);
+                }
             }
         }
         None
     }
 
     fn push(&self, task: Task) {
+        let policy = task.policy();
+        if policy.band() != Policy::Normal(0).band() {
+            return self.queue.get(policy).push(task);
+        }
+
         if let Err(task) = unsafe { self.buffer.push(task) } {
-            self.queue.push(task);
+            self.queue.get(policy).push(task);
         }
     }
 
-    fn switch(&self, new: Task, requeue: bool) {
+    /// Switch to `new`, leaving the current task in `old_state`. The outgoing task is
+    /// stashed in `previous` and dealt with by `finish_switch` once its context has been
+    /// saved.
+    fn switch(&self, new: Task, old_state: State) {
         let slot = self.current.get().expect("uninitialized worker");
         let old = unsafe { ptr::replace(slot.get(), new) };
+        // The idle task is only ever run when there's nothing else, never from a queue.
+        let old_state = if self.is_idle(&old) {
+            State::Parked
+        } else {
+            old_state
+        };
 
         let active = unsafe { &*slot.get() };
 
@@ -189,13 +382,6 @@ impl Worker {
 
         active.change_state_to_active();
 
-        let old_state = if requeue {
-            self.push(old.clone());
-            State::Queued
-        } else {
-            State::Parked
-        };
-
         old.change_state(State::Active, old_state)
             .expect("invalid task state transition");
 
@@ -204,21 +390,38 @@ impl Worker {
 
         trace!("num_refs = {}", old.head().refs.load(Ordering::Relaxed));
 
-        if requeue {
-            mem::drop(old)
-        } else {
-            self.exited.set(Some(old));
-        }
+        let previous = self.previous.replace(Some((old, old_state)));
+        debug_assert!(previous.is_none());
+
+        prepare_switch(active);
 
         unsafe {
             context_switch(old_ctx, new_ctx);
         }
     }
+
+    /// Deal with the task switched away from, returning it if it needs to be requeued.
+    ///
+    /// This goes by the state the task was left in rather than its current one: a parked
+    /// task may already have been unparked and queued by someone else.
+    fn finish_switch(&self) -> Option<Task> {
+        let (old, old_state) = self.previous.take()?;
+
+        match old_state {
+            State::Queued => return Some(old),
+            State::Exited => reaper::push(old),
+            _ => {}
+        }
+        None
+    }
 }
 
 struct MpmcQueue {
     inner: MpscQueue<Task>,
     pop_lock: SpinMutex<()>,
+    /// The number of queued tasks. It can briefly lag behind the queue itself, which is
+    /// fine for the placement heuristics it's used for.
+    len: AtomicUsize,
 }
 
 impl MpmcQueue {
@@ -228,20 +431,33 @@ impl MpmcQueue {
         Self {
             inner: MpscQueue::with_static_stub(Box::leak(stub)),
             pop_lock: SpinMutex::new(()),
+            len: AtomicUsize::new(0),
         }
     }
 
     pub fn pop(&self) -> Option<Task> {
         let _guard = self.pop_lock.lock();
-        unsafe { self.inner.pop_unsync() }
+        self.popped(unsafe { self.inner.pop_unsync() })
     }
 
     pub fn try_pop(&self) -> Option<Task> {
         let _guard = self.pop_lock.try_lock()?;
-        unsafe { self.inner.pop_unsync() }
+        self.popped(unsafe { self.inner.pop_unsync() })
     }
 
     pub fn push(&self, task: Task) {
+        self.len.fetch_add(1, Ordering::Relaxed);
         self.inner.push(task);
     }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn popped(&self, task: Option<Task>) -> Option<Task> {
+        if task.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        task
+    }
 }
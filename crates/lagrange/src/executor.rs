@@ -0,0 +1,161 @@
+//! Async tasks.
+//!
+//! An async task is a [`Future`] that sits on the scheduler's run queue alongside threads,
+//! but has no stack of its own. When the scheduler pops one it polls it in place, with
+//! interrupts disabled, on the stack of whichever thread is dispatching, and carries on
+//! looking for a thread to run. The task's waker puts it back on the queue.
+//!
+//! Because of this, async tasks must never block: use the async primitives in
+//! [`crate::sync`] rather than the blocking ones, and never call [`block_on`] from inside
+//! one.
+
+use alloc::{alloc::dealloc, boxed::Box, sync::Arc, task::Wake};
+use core::{
+    alloc::Layout,
+    cell::UnsafeCell,
+    future::Future,
+    mem::{self, ManuallyDrop},
+    pin::{pin, Pin},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{
+    scheduler::global,
+    task::{
+        header::{Header, TaskVTable},
+        raw_task::RawTask,
+        JoinHandle,
+    },
+    thread::{self, Thread},
+};
+
+/// Spawn a future as an async task. The returned handle can either be awaited or joined
+/// from a thread.
+pub fn spawn_async<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: 'static + Send + Future,
+    F::Output: 'static + Send,
+{
+    let task = allocate_task(future);
+    let join_handle = unsafe { JoinHandle::from_raw(task.clone()) };
+    global().schedule(task);
+    join_handle
+}
+
+/// Run a future to completion on the current thread, parking it whenever the future is
+/// pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+
+    let signal = Arc::new(Signal {
+        thread: thread::current(),
+        notified: AtomicBool::new(false),
+    });
+    let waker = Waker::from(Arc::clone(&signal));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+            return value;
+        }
+        thread::park_if(|| !signal.notified.swap(false, Ordering::Acquire));
+    }
+}
+
+struct Signal {
+    thread: Thread,
+    notified: AtomicBool,
+}
+
+impl Wake for Signal {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.notified.swap(true, Ordering::Release) {
+            self.thread.clone().unpark();
+        }
+    }
+}
+
+fn allocate_task<F>(future: F) -> RawTask
+where
+    F: Future + Send,
+{
+    let state = Box::new(AsyncTaskState {
+        header: Header::new(&AsyncTaskState::<F>::VTABLE),
+        polling: AtomicBool::new(false),
+        stage: UnsafeCell::new(Stage::Running(future)),
+    });
+
+    unsafe { RawTask::from_raw(NonNull::new(Box::into_raw(state)).unwrap().cast()) }
+}
+
+#[repr(C)]
+struct AsyncTaskState<F: Future> {
+    header: Header,
+    polling: AtomicBool,
+    stage: UnsafeCell<Stage<F>>,
+}
+
+enum Stage<F: Future> {
+    Running(F),
+    Finished(F::Output),
+    Consumed,
+}
+
+impl<F: Future> AsyncTaskState<F> {
+    const VTABLE: TaskVTable = TaskVTable {
+        drop_in_place: drop_in_place::<F>,
+        deallocate: deallocate::<F>,
+        read_value_into: read_value_into::<F>,
+        poll: Some(poll::<F>),
+    };
+}
+
+unsafe fn poll<F: Future>(ptr: NonNull<Header>) {
+    let state = ptr.cast::<AsyncTaskState<F>>().as_ref();
+    let task = ManuallyDrop::new(RawTask::from_raw(ptr));
+
+    // A wakeup that arrives while another cpu is polling us puts us straight back on the
+    // queue. Leave the future to that poll and try again later.
+    if state.polling.swap(true, Ordering::Acquire) {
+        RawTask::clone(&task).schedule();
+        return;
+    }
+
+    let stage = &mut *state.stage.get();
+    if let Stage::Running(future) = stage {
+        let waker = RawTask::clone(&task).into_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        if let Poll::Ready(value) = Pin::new_unchecked(future).poll(&mut cx) {
+            *stage = Stage::Finished(value);
+            state.header.finished.notify();
+        }
+    }
+
+    state.polling.store(false, Ordering::Release);
+}
+
+unsafe fn drop_in_place<F: Future>(ptr: NonNull<Header>) {
+    let state: NonNull<AsyncTaskState<F>> = ptr.cast();
+    ptr::drop_in_place(state.as_ptr());
+}
+
+unsafe fn deallocate<F: Future>(ptr: *mut u8) {
+    let layout = Layout::new::<AsyncTaskState<F>>();
+    dealloc(ptr, layout);
+}
+
+unsafe fn read_value_into<F: Future>(hdr: NonNull<Header>, ptr: *mut u8) {
+    let state = hdr.cast::<AsyncTaskState<F>>().as_ref();
+    let Stage::Finished(value) = mem::replace(&mut *state.stage.get(), Stage::Consumed) else {
+        panic!("no return value available");
+    };
+
+    ptr::write(ptr.cast(), value);
+}
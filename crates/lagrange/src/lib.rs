@@ -11,8 +11,14 @@
 
 extern crate alloc;
 
+pub mod executor;
 pub mod futex;
 mod scheduler;
 pub mod sync;
 mod task;
 pub mod thread;
+
+pub use self::{
+    executor::{block_on, spawn_async},
+    task::JoinHandle,
+};
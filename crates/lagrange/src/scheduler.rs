@@ -65,14 +65,30 @@ impl Scheduler {
     }
 
     fn pop_task(&self) -> Option<RawTask> {
-        let task = self.queue.pop()?;
-        task.mark_not_scheduled();
-        Some(task)
+        loop {
+            let task = self.queue.pop()?;
+            task.mark_not_scheduled();
+            if !task.is_async() {
+                return Some(task);
+            }
+            // Async tasks have no context to switch to, so run them up to their next
+            // suspension point right here and keep looking.
+            unsafe { task.poll() };
+        }
     }
 
     unsafe fn switch_to(&self, task: RawTask, interrupts_were_enabled: bool) {
         debug_assert!(!interrupts::are_enabled());
 
+        if self.current.lock().ptr_eq(&task) {
+            // We were woken while looking for something else to run, possibly by an async
+            // task we just polled. Our context was never saved, so just carry on.
+            if interrupts_were_enabled {
+                interrupts::enable();
+            }
+            return;
+        }
+
         let cur = mem::replace(&mut *self.current.lock(), task.clone());
         cur.header()
             .interrupts_enabled
@@ -199,6 +215,7 @@ const BASE_VTABLE: TaskVTable = TaskVTable {
     drop_in_place,
     read_value_into,
     deallocate,
+    poll: None,
 };
 
 unsafe fn drop_in_place(_ptr: NonNull<Header>) {
@@ -1,13 +1,28 @@
+pub mod async_mutex;
 pub mod atomic;
 pub mod barrier;
+pub mod condvar;
 pub mod event;
 pub mod lazy;
+pub mod lockdep_hooks;
+pub mod mpmc;
 pub mod mutex;
 pub mod once;
 pub mod once_cell;
 pub mod oneshot;
 pub mod parking_lot;
 pub mod queue_mutex;
+pub mod rwlock;
 pub mod spin_mutex;
 
-pub use self::{lazy::Lazy, mutex::Mutex, once::Once, once_cell::OnceCell, queue_mutex::IqMutex};
+pub use self::{
+    async_mutex::AsyncMutex,
+    barrier::Barrier,
+    condvar::Condvar,
+    lazy::Lazy,
+    mutex::{Mutex, MutexGuard},
+    once::Once,
+    once_cell::OnceCell,
+    queue_mutex::IqMutex,
+    rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
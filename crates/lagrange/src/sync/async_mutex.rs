@@ -0,0 +1,211 @@
+use alloc::collections::VecDeque;
+use core::{
+    cell::UnsafeCell,
+    fmt::Debug,
+    future::Future,
+    mem,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use spin::mutex::SpinMutex;
+
+/// A mutex for async tasks. Rather than parking the thread, a contended [`lock`] returns
+/// a future that is woken when the mutex is released.
+///
+/// Waiters are woken one at a time in the order they started waiting. A waiter that is
+/// woken but dropped before taking the lock passes its wakeup on to the next one.
+///
+/// [`lock`]: AsyncMutex::lock
+pub struct AsyncMutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: SpinMutex<Waiters>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AsyncMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: SpinMutex::new(Waiters {
+                next_key: 0,
+                queue: VecDeque::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> AsyncMutex<T> {
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            key: None,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        self.try_acquire()
+            .then_some(AsyncMutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        let waiter = self.waiters.lock().queue.pop_front();
+        if let Some((_, waker)) = waiter {
+            waker.wake();
+        }
+    }
+}
+
+impl<T: ?Sized> Debug for AsyncMutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AsyncMutex")
+            .field("locked", &self.locked.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Default> Default for AsyncMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+struct Waiters {
+    next_key: u64,
+    queue: VecDeque<(u64, Waker)>,
+}
+
+impl Waiters {
+    fn push(&mut self, waker: Waker) -> u64 {
+        let key = self.next_key;
+        self.next_key += 1;
+        self.queue.push_back((key, waker));
+        key
+    }
+
+    fn get_mut(&mut self, key: u64) -> Option<&mut Waker> {
+        self.queue
+            .iter_mut()
+            .find(|(k, _)| *k == key)
+            .map(|(_, waker)| waker)
+    }
+
+    /// Remove a waiter, returning false if it had already been woken.
+    fn remove(&mut self, key: u64) -> bool {
+        let Some(i) = self.queue.iter().position(|(k, _)| *k == key) else { return false };
+        self.queue.remove(i);
+        true
+    }
+}
+
+/// Future returned by [`AsyncMutex::lock`].
+pub struct Lock<'a, T: ?Sized> {
+    mutex: &'a AsyncMutex<T>,
+    key: Option<u64>,
+}
+
+impl<'a, T: ?Sized> Lock<'a, T> {
+    fn acquired(&mut self) -> AsyncMutexGuard<'a, T> {
+        if let Some(key) = self.key.take() {
+            self.mutex.waiters.lock().remove(key);
+        }
+        AsyncMutexGuard { mutex: self.mutex }
+    }
+}
+
+impl<'a, T: ?Sized> Future for Lock<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        if this.mutex.try_acquire() {
+            return Poll::Ready(this.acquired());
+        }
+
+        let mut waiters = this.mutex.waiters.lock();
+
+        // Try again under the waiter lock, so that an unlock can't slip in between and
+        // find nobody to wake.
+        if this.mutex.try_acquire() {
+            mem::drop(waiters);
+            return Poll::Ready(this.acquired());
+        }
+
+        match this.key.and_then(|key| waiters.get_mut(key)) {
+            Some(waker) => {
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => this.key = Some(waiters.push(cx.waker().clone())),
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<T: ?Sized> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else { return };
+
+        let mut waiters = self.mutex.waiters.lock();
+        if waiters.remove(key) {
+            return;
+        }
+
+        // We were woken but never took the lock, so hand the wakeup on.
+        let next = waiters.queue.pop_front();
+        mem::drop(waiters);
+        if let Some((_, waker)) = next {
+            waker.wake();
+        }
+    }
+}
+
+pub struct AsyncMutexGuard<'a, T: ?Sized> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for AsyncMutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
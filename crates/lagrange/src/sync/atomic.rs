@@ -37,12 +37,16 @@ struct ParkingLot {
 
 impl ParkingLot {
     pub fn new() -> Self {
-        let mask = 0;
-        let buckets = Default::default();
+        let num_buckets = 64;
+        let buckets = (0..num_buckets)
+            .map(|_| Bucket {
+                queue: IqMutex::new(Vec::new()),
+            })
+            .collect();
         Self {
             hash_builder: RandomState::new(),
             buckets,
-            mask,
+            mask: num_buckets - 1,
         }
     }
 
@@ -85,6 +89,11 @@ impl Bucket {
 
     pub fn wait(&self, atomic: &AtomicU32, value: u32) {
         let mut queue = self.queue.lock();
+        // Check under the bucket lock, so that a wake can't slip in between the check and
+        // queueing up, and so that we don't leave a stale waiter behind.
+        if atomic.load(Ordering::Acquire) != value {
+            return;
+        }
         let key = atomic as *const AtomicU32 as usize;
         let waiter = Waiter {
             key,
@@ -92,9 +101,9 @@ impl Bucket {
         };
         queue.push(waiter);
         mem::drop(queue);
-        if atomic.load(Ordering::Acquire) == value {
-            park();
-        }
+        // If we're woken before we get to park, we're already back on the run queue and
+        // parking only gives up the rest of our turn.
+        park();
     }
 }
 
@@ -0,0 +1,108 @@
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use spin::mutex::SpinMutex;
+
+use crate::executor::block_on;
+
+/// A barrier that releases its waiters once `n` of them have arrived. It can be used by
+/// threads and async tasks alike, and is reusable once released.
+#[derive(Debug)]
+pub struct Barrier {
+    n: usize,
+    state: SpinMutex<BarrierState>,
+}
+
+#[derive(Debug)]
+struct BarrierState {
+    arrived: usize,
+    generation: u64,
+    waiters: Vec<Waker>,
+}
+
+impl Barrier {
+    pub const fn new(n: usize) -> Self {
+        Self {
+            n,
+            state: SpinMutex::new(BarrierState {
+                arrived: 0,
+                generation: 0,
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    /// Block the current thread until all parties have arrived.
+    pub fn wait(&self) -> BarrierWaitResult {
+        block_on(self.wait_async())
+    }
+
+    /// Wait for all parties to arrive from an async task.
+    ///
+    /// The task counts as arrived once the future is first polled, so dropping it
+    /// afterwards still counts towards releasing the barrier.
+    pub fn wait_async(&self) -> Wait<'_> {
+        Wait {
+            barrier: self,
+            generation: None,
+        }
+    }
+}
+
+/// Returned from waiting on a [`Barrier`]. Exactly one waiter per generation, the last to
+/// arrive, is the leader.
+#[derive(Debug, Clone, Copy)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+/// Future returned by [`Barrier::wait_async`].
+#[derive(Debug)]
+pub struct Wait<'a> {
+    barrier: &'a Barrier,
+    generation: Option<u64>,
+}
+
+impl Future for Wait<'_> {
+    type Output = BarrierWaitResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<BarrierWaitResult> {
+        let mut state = self.barrier.state.lock();
+
+        if let Some(generation) = self.generation {
+            if state.generation != generation {
+                return Poll::Ready(BarrierWaitResult(false));
+            }
+            if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                state.waiters.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        }
+
+        state.arrived += 1;
+        if state.arrived < self.barrier.n {
+            self.generation = Some(state.generation);
+            state.waiters.push(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        state.arrived = 0;
+        state.generation += 1;
+        let waiters = mem::take(&mut state.waiters);
+        mem::drop(state);
+
+        for waker in waiters {
+            waker.wake();
+        }
+        Poll::Ready(BarrierWaitResult(true))
+    }
+}
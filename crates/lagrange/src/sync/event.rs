@@ -1,17 +1,19 @@
 use alloc::vec::Vec;
 use core::{
+    future::Future,
     mem,
+    pin::Pin,
     sync::atomic::{AtomicBool, Ordering},
-    task::Waker,
+    task::{Context, Poll, Waker},
 };
 
 use spin::mutex::SpinMutex;
 
-use crate::thread::{self, park, Thread};
+use crate::thread::{self, park};
 
 #[derive(Debug)]
 pub struct OneShotEvent {
-    waiters: SpinMutex<Vec<Thread>>,
+    waiters: SpinMutex<Vec<Waker>>,
     is_set: AtomicBool,
 }
 
@@ -33,13 +35,38 @@ impl OneShotEvent {
 
     #[cold]
     fn wait_slow(&self) {
-        self.waiters.lock().push(thread::current());
+        self.waiters.lock().push(thread::current().into_waker());
 
         while !self.is_set() {
             park();
         }
     }
 
+    /// Wait for the event from an async task.
+    #[inline]
+    pub fn wait_async(&self) -> Wait<'_> {
+        Wait { event: self }
+    }
+
+    /// Arrange for `waker` to be woken when the event is set. Returns true, without
+    /// registering anything, if it already is.
+    pub fn register(&self, waker: &Waker) -> bool {
+        if self.is_set() {
+            return true;
+        }
+
+        let mut waiters = self.waiters.lock();
+        // `notify` sets the flag before taking the waiters, so checking again under the
+        // lock means we can't miss it.
+        if self.is_set() {
+            return true;
+        }
+        if !waiters.iter().any(|w| w.will_wake(waker)) {
+            waiters.push(waker.clone());
+        }
+        false
+    }
+
     #[inline]
     pub fn is_set(&self) -> bool {
         self.is_set.load(Ordering::Acquire)
@@ -49,7 +76,7 @@ impl OneShotEvent {
         self.is_set.store(true, Ordering::Release);
         let queue = mem::take(&mut *self.waiters.lock());
         for waker in queue {
-            waker.unpark();
+            waker.wake();
         }
     }
 }
@@ -60,3 +87,21 @@ impl Default for OneShotEvent {
         Self::new()
     }
 }
+
+/// Future returned by [`OneShotEvent::wait_async`].
+#[derive(Debug)]
+pub struct Wait<'a> {
+    event: &'a OneShotEvent,
+}
+
+impl Future for Wait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.event.register(cx.waker()) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
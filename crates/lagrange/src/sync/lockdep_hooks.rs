@@ -0,0 +1,29 @@
+//! Glue between the `lockdep` lock validator and lagrange's tasks.
+
+use hal::interrupts;
+use lockdep::{HeldLocks, Hooks};
+
+use crate::scheduler::global;
+
+static HOOKS: Hooks = Hooks {
+    held,
+    interrupts_enabled: interrupts::are_enabled,
+    without_interrupts,
+};
+
+/// Start validating locks. This does nothing unless the `lockdep` feature is enabled.
+pub fn init() {
+    lockdep::init(&HOOKS);
+}
+
+fn held() -> Option<&'static HeldLocks> {
+    let task = global().current();
+    // The running task outlives any lock operation it performs, so the reference stays
+    // valid for as long as lockdep uses it.
+    let held: *const HeldLocks = &task.header().held_locks;
+    Some(unsafe { &*held })
+}
+
+fn without_interrupts(f: &mut dyn FnMut()) {
+    interrupts::without(|_| f());
+}
@@ -0,0 +1,368 @@
+//! Multi-producer multi-consumer channels.
+//!
+//! Messages travel through a [`meteor`] MPSC queue: senders push onto it without taking a
+//! lock, and receivers take turns popping from it. Blocked senders and receivers sleep on
+//! futex sequence numbers that are bumped whenever there is something new for them to
+//! look at, whether that is a message, a free slot or a disconnect.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    fmt::{self, Debug, Display},
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use meteor::{
+    mpsc_queue::{Link, MpscQueue},
+    DynSinglePtrLink, Node,
+};
+use spin::mutex::SpinMutex;
+
+use super::atomic::{wait, wake_all, wake_one};
+use crate::thread;
+
+mod select;
+
+pub use self::select::{select, Select};
+
+/// Create a channel that holds any number of messages. Sending never blocks.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    channel(None)
+}
+
+/// Create a channel that holds at most `cap` messages. Sending blocks while it is full.
+///
+/// # Panics
+/// If `cap` is zero.
+pub fn bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(cap > 0, "channel capacity must be non-zero");
+    channel(Some(cap))
+}
+
+fn channel<T>(cap: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let stub = NonNull::from(Box::leak(Box::new(Link::new())));
+    let inner = Arc::new(Inner {
+        queue: unsafe { MpscQueue::with_stub(stub) },
+        pop_lock: SpinMutex::new(()),
+        stub,
+        cap,
+        len: AtomicUsize::new(0),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+        recv_seq: AtomicU32::new(0),
+        send_seq: AtomicU32::new(0),
+        selectors: SpinMutex::new(Vec::new()),
+    });
+
+    let sender = Sender {
+        inner: Arc::clone(&inner),
+    };
+    (sender, Receiver { inner })
+}
+
+/// The message could not be sent because every receiver is gone.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is bounded and full.
+    Full(T),
+    Disconnected(T),
+}
+
+/// Every sender is gone and the channel is empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+// Like the standard library, don't require `T: Debug` to debug print an error.
+impl<T> Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("Full(..)"),
+            Self::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sending on a disconnected channel")
+    }
+}
+
+impl<T> Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("sending on a full channel"),
+            Self::Disconnected(_) => f.write_str("sending on a disconnected channel"),
+        }
+    }
+}
+
+impl Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("receiving on an empty and disconnected channel")
+    }
+}
+
+impl Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("receiving on an empty channel"),
+            Self::Disconnected => f.write_str("receiving on an empty and disconnected channel"),
+        }
+    }
+}
+
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a message, blocking while the channel is full.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = value;
+        loop {
+            let seq = self.inner.send_seq.load(Ordering::Acquire);
+            match self.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(v)) => return Err(SendError(v)),
+                Err(TrySendError::Full(v)) => value = v,
+            }
+            wait(&self.inner.send_seq, seq);
+        }
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.inner.try_send(value)
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.inner.receivers.load(Ordering::Acquire) == 0
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.inner.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.inner.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.inner.notify_receivers(true);
+        }
+    }
+}
+
+impl<T> Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive a message, blocking until there is one. Fails once the channel is empty
+    /// and every sender is gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            let seq = self.inner.recv_seq.load(Ordering::Acquire);
+            match self.try_recv() {
+                Ok(value) => return Ok(value),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                // A send is halfway through, so the queue looks empty even though there
+                // may be messages behind it, and we may have been woken for one of those.
+                // Sleeping now would swallow that wakeup, so wait for the send instead.
+                Err(TryRecvError::Empty) if self.inner.len.load(Ordering::Relaxed) > 0 => {
+                    thread::yield_now();
+                    continue;
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+            wait(&self.inner.recv_seq, seq);
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.inner.try_recv()
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.inner.senders.load(Ordering::Acquire) == 0
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.inner.receivers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.inner.receivers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.inner.notify_senders(true);
+        }
+    }
+}
+
+impl<T> Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+#[repr(C)]
+struct Message<T> {
+    link: DynSinglePtrLink,
+    value: T,
+}
+
+impl<T> Node<DynSinglePtrLink> for Box<Message<T>> {
+    fn into_link(node: Self) -> NonNull<DynSinglePtrLink> {
+        NonNull::from(Box::leak(node)).cast()
+    }
+
+    unsafe fn from_link(link: NonNull<DynSinglePtrLink>) -> Self {
+        Box::from_raw(link.as_ptr().cast())
+    }
+}
+
+struct Inner<T> {
+    queue: MpscQueue<Box<Message<T>>>,
+    /// Popping from the queue is single consumer, so receivers take turns.
+    pop_lock: SpinMutex<()>,
+    stub: NonNull<Link>,
+    cap: Option<usize>,
+    /// Messages sent but not yet received, counting senders that have claimed a slot
+    /// but not yet pushed.
+    len: AtomicUsize,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    /// Bumped when a message is sent or the last sender goes away.
+    recv_seq: AtomicU32,
+    /// Bumped when a slot frees up in a bounded channel or the last receiver goes away.
+    send_seq: AtomicU32,
+    /// Tokens of the [`Select`]s waiting on this channel.
+    selectors: SpinMutex<Vec<Arc<AtomicU32>>>,
+}
+
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+impl<T> Inner<T> {
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.receivers.load(Ordering::Acquire) == 0 {
+            return Err(TrySendError::Disconnected(value));
+        }
+
+        let claimed = self
+            .len
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| match self.cap {
+                Some(cap) if len >= cap => None,
+                _ => Some(len + 1),
+            });
+        if claimed.is_err() {
+            return Err(TrySendError::Full(value));
+        }
+
+        self.queue.push(Box::new(Message {
+            link: DynSinglePtrLink::new(),
+            value,
+        }));
+        self.notify_receivers(false);
+        Ok(())
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.pop() {
+            return Ok(value);
+        }
+
+        if self.senders.load(Ordering::Acquire) != 0 {
+            return Err(TryRecvError::Empty);
+        }
+        // The last sender may have sent something just before it went away.
+        self.pop().ok_or(TryRecvError::Disconnected)
+    }
+
+    fn pop(&self) -> Option<T> {
+        // While a send is halfway through pushing, the queue can look empty even though
+        // later messages are in it. `len` still counts them, which is how `recv` tells.
+        let message = {
+            let _guard = self.pop_lock.lock();
+            unsafe { self.queue.pop_unsync() }
+        }?;
+
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        if self.cap.is_some() {
+            self.notify_senders(false);
+        }
+        Some(message.value)
+    }
+
+    fn notify_receivers(&self, disconnected: bool) {
+        self.recv_seq.fetch_add(1, Ordering::Release);
+        if disconnected {
+            wake_all(&self.recv_seq);
+        } else {
+            wake_one(&self.recv_seq);
+        }
+
+        for token in self.selectors.lock().iter() {
+            token.fetch_add(1, Ordering::Release);
+            wake_all(&**token);
+        }
+    }
+
+    fn notify_senders(&self, disconnected: bool) {
+        self.send_seq.fetch_add(1, Ordering::Release);
+        if disconnected {
+            wake_all(&self.send_seq);
+        } else {
+            wake_one(&self.send_seq);
+        }
+    }
+
+    /// Whether a receive would return straight away, with a message or a disconnect.
+    fn is_ready(&self) -> bool {
+        if self.senders.load(Ordering::Acquire) == 0 {
+            return true;
+        }
+        // Looking at the queue follows the node a pop would free, so keep receivers out.
+        let _guard = self.pop_lock.lock();
+        !self.queue.is_empty()
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        // Nobody else can touch the queue now, so it's consistent and this drains it.
+        while unsafe { self.queue.pop_unsync() }.is_some() {}
+        unsafe { drop(Box::from_raw(self.stub.as_ptr())) };
+    }
+}
@@ -0,0 +1,130 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{Receiver, RecvError, TryRecvError};
+use crate::sync::atomic::wait;
+
+/// Waits on several receivers at once, which need not carry the same message type.
+///
+/// ```ignore
+/// let mut sel = Select::new();
+/// let a = sel.recv(&rx1);
+/// let b = sel.recv(&rx2);
+/// match sel.ready() {
+///     i if i == a => handle_a(rx1.try_recv()),
+///     i if i == b => handle_b(rx2.try_recv()),
+///     _ => unreachable!(),
+/// }
+/// ```
+///
+/// Another receiver on the same channel may take the message between [`ready`] returning
+/// and the `try_recv`, so be prepared to find the channel empty and try again.
+///
+/// [`ready`]: Select::ready
+#[derive(Default)]
+pub struct Select<'a> {
+    receivers: Vec<&'a dyn Selectable>,
+    /// Where the next scan starts, so that a busy receiver can't starve the others.
+    next: usize,
+}
+
+impl<'a> Select<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a receiver, returning the index [`ready`](Select::ready) reports it by.
+    pub fn recv<T>(&mut self, receiver: &'a Receiver<T>) -> usize {
+        self.receivers.push(receiver);
+        self.receivers.len() - 1
+    }
+
+    /// The index of a receiver that has a message or is disconnected, if any.
+    pub fn try_ready(&mut self) -> Option<usize> {
+        let n = self.receivers.len();
+        let i = (0..n)
+            .map(|i| (self.next + i) % n)
+            .find(|&i| self.receivers[i].is_ready())?;
+        self.next = (i + 1) % n;
+        Some(i)
+    }
+
+    /// Block until one of the receivers has a message or is disconnected, returning its
+    /// index.
+    ///
+    /// # Panics
+    /// If no receivers have been added.
+    pub fn ready(&mut self) -> usize {
+        assert!(!self.receivers.is_empty(), "select with no receivers");
+
+        if let Some(i) = self.try_ready() {
+            return i;
+        }
+
+        let token = Arc::new(AtomicU32::new(0));
+        for receiver in &self.receivers {
+            receiver.register(&token);
+        }
+
+        let ready = loop {
+            let seq = token.load(Ordering::Acquire);
+            if let Some(i) = self.try_ready() {
+                break i;
+            }
+            wait(&token, seq);
+        };
+
+        for receiver in &self.receivers {
+            receiver.unregister(&token);
+        }
+        ready
+    }
+}
+
+/// Receive from whichever of `receivers` has a message first, returning its index along
+/// with the message, or an error if that receiver's channel is disconnected.
+///
+/// # Panics
+/// If `receivers` is empty.
+pub fn select<T>(receivers: &[&Receiver<T>]) -> (usize, Result<T, RecvError>) {
+    let mut sel = Select::new();
+    for &receiver in receivers {
+        sel.recv(receiver);
+    }
+
+    loop {
+        let i = sel.ready();
+        match receivers[i].try_recv() {
+            Ok(value) => return (i, Ok(value)),
+            Err(TryRecvError::Disconnected) => return (i, Err(RecvError)),
+            // Someone else got there first.
+            Err(TryRecvError::Empty) => {}
+        }
+    }
+}
+
+/// Type erased view of a receiver.
+trait Selectable {
+    fn is_ready(&self) -> bool;
+    /// Bump `token` and wake whoever waits on it whenever the receiver may have become
+    /// ready.
+    fn register(&self, token: &Arc<AtomicU32>);
+    fn unregister(&self, token: &Arc<AtomicU32>);
+}
+
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        self.inner.is_ready()
+    }
+
+    fn register(&self, token: &Arc<AtomicU32>) {
+        self.inner.selectors.lock().push(Arc::clone(token));
+    }
+
+    fn unregister(&self, token: &Arc<AtomicU32>) {
+        self.inner
+            .selectors
+            .lock()
+            .retain(|other| !Arc::ptr_eq(other, token));
+    }
+}
@@ -4,17 +4,40 @@ use core::{
 };
 
 use lock_api::GuardSend;
+use lockdep::{LockClass, LockKind};
 
 use super::atomic::{wait, wake_one};
 
 pub type Mutex<T> = lock_api::Mutex<RawMutex, T>;
+pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, RawMutex, T>;
+
+/// The lockdep class of mutexes made with `Mutex::new`.
+static CLASS: LockClass = LockClass::new("Mutex");
+
+/// Create a mutex in lockdep class `class`. Mutexes made with `Mutex::new` all share one
+/// class, so any that nest inside others should be given their own.
+pub const fn with_class<T>(value: T, class: &'static LockClass) -> Mutex<T> {
+    Mutex::const_new(RawMutex::with_class(class), value)
+}
 
 #[derive(Debug)]
 pub struct RawMutex {
     state: AtomicU32,
+    class: Option<&'static LockClass>,
 }
 
 impl RawMutex {
+    pub const fn with_class(class: &'static LockClass) -> Self {
+        Self {
+            class: Some(class),
+            ..<Self as lock_api::RawMutex>::INIT
+        }
+    }
+
+    fn class(&self) -> &'static LockClass {
+        self.class.unwrap_or(&CLASS)
+    }
+
     #[cold]
     fn lock_contended(&self) {
         let mut state = self.spin();
@@ -43,6 +66,16 @@ impl RawMutex {
         wake_one(&self.state);
     }
 
+    fn try_lock_uninstrumented(&self) -> bool {
+        self.state
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn key(&self) -> *const () {
+        (self as *const Self).cast()
+    }
+
     fn spin(&self) -> u32 {
         let mut spin = 100;
         loop {
@@ -61,24 +94,33 @@ unsafe impl lock_api::RawMutex for RawMutex {
 
     const INIT: Self = Self {
         state: AtomicU32::new(0),
+        class: None,
     };
 
     #[inline]
+    #[track_caller]
     fn try_lock(&self) -> bool {
-        self.state
-            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
+        let locked = self.try_lock_uninstrumented();
+        if locked {
+            lockdep::try_acquired(self.key(), self.class(), LockKind::Sleep);
+        }
+        locked
     }
 
     #[inline]
+    #[track_caller]
     fn lock(&self) {
-        if !self.try_lock() {
+        lockdep::might_sleep();
+        lockdep::acquire(self.key(), self.class(), LockKind::Sleep);
+
+        if !self.try_lock_uninstrumented() {
             self.lock_contended();
         }
     }
 
     #[inline]
     unsafe fn unlock(&self) {
+        lockdep::release(self.key());
         if self.state.swap(0, Ordering::Release) == 2 {
             self.wake();
         }
@@ -1,10 +1,15 @@
 use alloc::sync::Arc;
 use core::{
     cell::Cell,
+    future::Future,
+    pin::Pin,
     sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
 };
 
-use crate::thread::{self, park_if, Thread};
+use spin::mutex::SpinMutex;
+
+use crate::executor::block_on;
 
 pub struct Sender<T> {
     inner: Arc<Inner<T>>,
@@ -16,40 +21,66 @@ impl<T> Sender<T> {
     }
 }
 
+/// The receiving half of a oneshot channel. Either call [`Receiver::recv`] from a thread
+/// or await it from an async task.
 pub struct Receiver<T> {
     inner: Arc<Inner<T>>,
 }
 
 impl<T> Receiver<T> {
     pub fn recv(self) -> T {
-        unsafe { self.inner.recv() }
+        block_on(self)
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        unsafe { self.inner.poll_recv(cx) }
     }
 }
 
 pub fn oneshot<T>() -> (Sender<T>, Receiver<T>) {
-    todo!()
+    let inner = Arc::new(Inner {
+        done: AtomicBool::new(false),
+        value: Cell::new(None),
+        waker: SpinMutex::new(None),
+    });
+
+    let sender = Sender {
+        inner: Arc::clone(&inner),
+    };
+    (sender, Receiver { inner })
 }
 
 struct Inner<T> {
     done: AtomicBool,
     value: Cell<Option<T>>,
-    thread: Cell<Option<Thread>>,
+    waker: SpinMutex<Option<Waker>>,
 }
 
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
 impl<T> Inner<T> {
     pub unsafe fn send(&self, value: T) {
         self.value.set(Some(value));
         self.done.store(true, Ordering::Release);
-        if let Some(thread) = self.thread.take() {
-            thread.unpark();
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
         }
     }
 
-    pub unsafe fn recv(&self) -> T {
-        while !self.done.load(Ordering::Acquire) {
-            self.thread.set(Some(thread::current()));
-            park_if(|| !self.done.load(Ordering::Acquire));
+    pub unsafe fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<T> {
+        if !self.done.load(Ordering::Acquire) {
+            *self.waker.lock() = Some(cx.waker().clone());
+            // `send` sets `done` before taking the waker, so check again now that ours is
+            // in place.
+            if !self.done.load(Ordering::Acquire) {
+                return Poll::Pending;
+            }
         }
-        self.value.take().unwrap_unchecked()
+        Poll::Ready(self.value.take().expect("value already received"))
     }
 }
@@ -6,6 +6,7 @@ use core::{
 
 use hal::interrupts;
 use lock_api::{GuardSend, RawMutex};
+use lockdep::{LockClass, LockKind};
 use spin::mutex::SpinMutex;
 
 use crate::thread::{self, park, Thread};
@@ -16,6 +17,15 @@ const CONTENDED: u8 = 2;
 
 pub type IqMutex<T> = lock_api::Mutex<RawIqMutex, T>;
 
+/// The lockdep class of mutexes made with `IqMutex::new`.
+static CLASS: LockClass = LockClass::new("IqMutex");
+
+/// Create a mutex in lockdep class `class`. Mutexes made with `IqMutex::new` all share one
+/// class, so any that nest inside others should be given their own.
+pub const fn with_class<T>(value: T, class: &'static LockClass) -> IqMutex<T> {
+    IqMutex::const_new(RawIqMutex::with_class(class), value)
+}
+
 /// A mutex that manages it's own thread queue.
 ///
 /// Currently this is a bad first implementation that is slow and too fat, but it
@@ -24,9 +34,21 @@ pub type IqMutex<T> = lock_api::Mutex<RawIqMutex, T>;
 pub struct RawIqMutex {
     state: AtomicU8,
     queue: SpinMutex<VecDeque<Thread>>,
+    class: Option<&'static LockClass>,
 }
 
 impl RawIqMutex {
+    pub const fn with_class(class: &'static LockClass) -> Self {
+        Self {
+            class: Some(class),
+            ..Self::INIT
+        }
+    }
+
+    fn class(&self) -> &'static LockClass {
+        self.class.unwrap_or(&CLASS)
+    }
+
     #[cold]
     fn lock_contended(&self) {
         let mut state = self.spin();
@@ -65,6 +87,16 @@ impl RawIqMutex {
         }
     }
 
+    fn try_lock_uninstrumented(&self) -> bool {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn key(&self) -> *const () {
+        (self as *const Self).cast()
+    }
+
     fn spin(&self) -> u8 {
         let mut spin = 100;
         loop {
@@ -84,24 +116,33 @@ unsafe impl RawMutex for RawIqMutex {
     const INIT: Self = Self {
         state: AtomicU8::new(UNLOCKED),
         queue: SpinMutex::new(VecDeque::new()),
+        class: None,
     };
 
     #[inline]
+    #[track_caller]
     fn try_lock(&self) -> bool {
-        self.state
-            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
+        let locked = self.try_lock_uninstrumented();
+        if locked {
+            lockdep::try_acquired(self.key(), self.class(), LockKind::Sleep);
+        }
+        locked
     }
 
     #[inline]
+    #[track_caller]
     fn lock(&self) {
-        if !self.try_lock() {
+        lockdep::might_sleep();
+        lockdep::acquire(self.key(), self.class(), LockKind::Sleep);
+
+        if !self.try_lock_uninstrumented() {
             self.lock_contended();
         }
     }
 
     #[inline]
     unsafe fn unlock(&self) {
+        lockdep::release(self.key());
         if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
             self.wake();
         }
@@ -0,0 +1,349 @@
+use core::{
+    hint,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use lock_api::GuardSend;
+use lockdep::{LockClass, LockKind};
+
+use super::atomic::{wait, wake_all, wake_one};
+
+pub type RwLock<T> = lock_api::RwLock<RawRwLock, T>;
+pub type RwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawRwLock, T>;
+pub type RwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawRwLock, T>;
+
+/// The lockdep class of locks made with `RwLock::new`.
+static CLASS: LockClass = LockClass::new("RwLock");
+
+/// Create a reader-writer lock in lockdep class `class`. Locks made with `RwLock::new` all
+/// share one class, so any that nest inside others should be given their own.
+pub const fn with_class<T>(value: T, class: &'static LockClass) -> RwLock<T> {
+    RwLock::const_new(RawRwLock::with_class(class), value)
+}
+
+/// The low bits of the state count readers, with all of them set meaning write locked.
+const READ_LOCKED: u32 = 1;
+const MASK: u32 = (1 << 30) - 1;
+const WRITE_LOCKED: u32 = MASK;
+const MAX_READERS: u32 = MASK - 1;
+const READERS_WAITING: u32 = 1 << 30;
+const WRITERS_WAITING: u32 = 1 << 31;
+
+fn is_unlocked(state: u32) -> bool {
+    state & MASK == 0
+}
+
+fn is_write_locked(state: u32) -> bool {
+    state & MASK == WRITE_LOCKED
+}
+
+fn has_readers_waiting(state: u32) -> bool {
+    state & READERS_WAITING != 0
+}
+
+fn has_writers_waiting(state: u32) -> bool {
+    state & WRITERS_WAITING != 0
+}
+
+/// New readers queue up behind waiting writers, so that a steady stream of readers can't
+/// starve them.
+fn is_read_lockable(state: u32) -> bool {
+    state & MASK < MAX_READERS && !has_readers_waiting(state) && !has_writers_waiting(state)
+}
+
+/// A writer-preferring reader-writer lock.
+///
+/// Readers sleep on `state` itself. Writers sleep on `writer_notify`, which is bumped
+/// every time a writer is woken, so that a wakeup can't be lost between a writer checking
+/// the state and going to sleep.
+#[derive(Debug)]
+pub struct RawRwLock {
+    state: AtomicU32,
+    writer_notify: AtomicU32,
+    class: Option<&'static LockClass>,
+}
+
+impl RawRwLock {
+    pub const fn with_class(class: &'static LockClass) -> Self {
+        Self {
+            class: Some(class),
+            ..<Self as lock_api::RawRwLock>::INIT
+        }
+    }
+
+    fn class(&self) -> &'static LockClass {
+        self.class.unwrap_or(&CLASS)
+    }
+
+    #[cold]
+    fn read_contended(&self) {
+        let mut state = self.spin_read();
+
+        loop {
+            if is_read_lockable(state) {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state + READ_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(s) => {
+                        state = s;
+                        continue;
+                    }
+                }
+            }
+
+            assert!(
+                state & MASK != MAX_READERS,
+                "too many active read locks on RwLock"
+            );
+
+            if !has_readers_waiting(state) {
+                if let Err(s) = self.state.compare_exchange(
+                    state,
+                    state | READERS_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = s;
+                    continue;
+                }
+            }
+
+            wait(&self.state, state | READERS_WAITING);
+            state = self.spin_read();
+        }
+    }
+
+    #[cold]
+    fn write_contended(&self) {
+        let mut state = self.spin_write();
+        // Once we have slept, other writers may be asleep too, and we can't tell, so keep
+        // the flag set when we take the lock.
+        let mut other_writers_waiting = 0;
+
+        loop {
+            if is_unlocked(state) {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state | WRITE_LOCKED | other_writers_waiting,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(s) => {
+                        state = s;
+                        continue;
+                    }
+                }
+            }
+
+            if !has_writers_waiting(state) {
+                if let Err(s) = self.state.compare_exchange(
+                    state,
+                    state | WRITERS_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = s;
+                    continue;
+                }
+            }
+
+            other_writers_waiting = WRITERS_WAITING;
+
+            let seq = self.writer_notify.load(Ordering::Acquire);
+            state = self.state.load(Ordering::Relaxed);
+            if is_unlocked(state) || !has_writers_waiting(state) {
+                continue;
+            }
+
+            wait(&self.writer_notify, seq);
+            state = self.spin_write();
+        }
+    }
+
+    /// Wake up whoever is waiting, now that the lock is free: a writer if there is one,
+    /// otherwise all the readers.
+    #[cold]
+    fn wake_writer_or_readers(&self, mut state: u32) {
+        debug_assert!(is_unlocked(state));
+
+        if state == WRITERS_WAITING {
+            match self
+                .state
+                .compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    self.wake_writer();
+                    return;
+                }
+                Err(s) => state = s,
+            }
+        }
+
+        if state == READERS_WAITING + WRITERS_WAITING {
+            if self
+                .state
+                .compare_exchange(state, READERS_WAITING, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+            {
+                // Someone else took the lock, and will wake the others when they're done.
+                return;
+            }
+            if self.wake_writer() {
+                return;
+            }
+            // No writer was actually asleep, so fall through and wake the readers.
+            state = READERS_WAITING;
+        }
+
+        if state == READERS_WAITING
+            && self
+                .state
+                .compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            wake_all(&self.state);
+        }
+    }
+
+    fn wake_writer(&self) -> bool {
+        self.writer_notify.fetch_add(1, Ordering::Release);
+        wake_one(&self.writer_notify)
+    }
+
+    fn try_lock_shared_uninstrumented(&self) -> bool {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |s| {
+                is_read_lockable(s).then_some(s + READ_LOCKED)
+            })
+            .is_ok()
+    }
+
+    fn try_lock_exclusive_uninstrumented(&self) -> bool {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |s| {
+                is_unlocked(s).then_some(s + WRITE_LOCKED)
+            })
+            .is_ok()
+    }
+
+    fn key(&self) -> *const () {
+        (self as *const Self).cast()
+    }
+
+    fn spin_until(&self, f: impl Fn(u32) -> bool) -> u32 {
+        let mut spin = 100;
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if f(state) || spin == 0 {
+                return state;
+            }
+            hint::spin_loop();
+            spin -= 1;
+        }
+    }
+
+    fn spin_read(&self) -> u32 {
+        self.spin_until(|s| !is_write_locked(s) || has_readers_waiting(s) || has_writers_waiting(s))
+    }
+
+    fn spin_write(&self) -> u32 {
+        self.spin_until(|s| is_unlocked(s) || has_writers_waiting(s))
+    }
+}
+
+unsafe impl lock_api::RawRwLock for RawRwLock {
+    type GuardMarker = GuardSend;
+
+    const INIT: Self = Self {
+        state: AtomicU32::new(0),
+        writer_notify: AtomicU32::new(0),
+        class: None,
+    };
+
+    #[inline]
+    #[track_caller]
+    fn lock_shared(&self) {
+        lockdep::might_sleep();
+        lockdep::acquire(self.key(), self.class(), LockKind::Sleep);
+
+        let state = self.state.load(Ordering::Relaxed);
+        if !is_read_lockable(state)
+            || self
+                .state
+                .compare_exchange_weak(
+                    state,
+                    state + READ_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            self.read_contended();
+        }
+    }
+
+    #[inline]
+    #[track_caller]
+    fn try_lock_shared(&self) -> bool {
+        let locked = self.try_lock_shared_uninstrumented();
+        if locked {
+            lockdep::try_acquired(self.key(), self.class(), LockKind::Sleep);
+        }
+        locked
+    }
+
+    #[inline]
+    unsafe fn unlock_shared(&self) {
+        lockdep::release(self.key());
+
+        let state = self.state.fetch_sub(READ_LOCKED, Ordering::Release) - READ_LOCKED;
+        // Readers only wait while there is a writer to wait behind.
+        debug_assert!(!has_readers_waiting(state) || has_writers_waiting(state));
+
+        if is_unlocked(state) && has_writers_waiting(state) {
+            self.wake_writer_or_readers(state);
+        }
+    }
+
+    #[inline]
+    #[track_caller]
+    fn lock_exclusive(&self) {
+        lockdep::might_sleep();
+        lockdep::acquire(self.key(), self.class(), LockKind::Sleep);
+
+        if self
+            .state
+            .compare_exchange_weak(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.write_contended();
+        }
+    }
+
+    #[inline]
+    #[track_caller]
+    fn try_lock_exclusive(&self) -> bool {
+        let locked = self.try_lock_exclusive_uninstrumented();
+        if locked {
+            lockdep::try_acquired(self.key(), self.class(), LockKind::Sleep);
+        }
+        locked
+    }
+
+    #[inline]
+    unsafe fn unlock_exclusive(&self) {
+        lockdep::release(self.key());
+
+        let state = self.state.fetch_sub(WRITE_LOCKED, Ordering::Release) - WRITE_LOCKED;
+        debug_assert!(is_unlocked(state));
+
+        if has_writers_waiting(state) || has_readers_waiting(state) {
+            self.wake_writer_or_readers(state);
+        }
+    }
+}
@@ -8,6 +8,7 @@ use core::{
 };
 
 use hal::task::Context;
+use lockdep::HeldLocks;
 use skua::{mpsc_queue, Node};
 
 use crate::{scheduler::Scheduler, sync::event::OneShotEvent};
@@ -25,6 +26,8 @@ pub struct Header {
     pub name: Option<Cow<'static, str>>,
     pub id: NonZeroU64,
     pub interrupts_enabled: AtomicBool,
+    /// The locks this task holds, when lock validation is enabled.
+    pub held_locks: HeldLocks,
 }
 
 impl Header {
@@ -40,6 +43,7 @@ impl Header {
             name: None,
             id: new_id(),
             interrupts_enabled: AtomicBool::new(true),
+            held_locks: HeldLocks::new(),
         }
     }
 }
@@ -79,6 +83,9 @@ pub struct TaskVTable {
     pub drop_in_place: unsafe fn(NonNull<Header>),
     pub deallocate: unsafe fn(*mut u8),
     pub read_value_into: unsafe fn(NonNull<Header>, *mut u8),
+    /// Polls the task's future once. Only async tasks have one; threads are switched to
+    /// instead.
+    pub poll: Option<unsafe fn(NonNull<Header>)>,
 }
 
 impl Debug for TaskVTable {
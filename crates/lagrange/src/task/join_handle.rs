@@ -1,4 +1,9 @@
-use core::marker::PhantomData;
+use core::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
 use super::raw_task::RawTask;
 use crate::thread::Thread;
@@ -35,6 +40,17 @@ impl<T> JoinHandle<T> {
     }
 }
 
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        if !self.task().header().finished.register(cx.waker()) {
+            return Poll::Pending;
+        }
+        Poll::Ready(unsafe { self.task().take_value().expect("value not present") })
+    }
+}
+
 impl<T> Drop for JoinHandle<T> {
     fn drop(&mut self) {
         unsafe { self.task().detach() };
@@ -84,6 +84,23 @@ impl RawTask {
             .store(false, Ordering::Release);
     }
 
+    pub fn ptr_eq(&self, other: &RawTask) -> bool {
+        self.0 == other.0
+    }
+
+    /// Whether this is an async task, which is polled by the scheduler rather than
+    /// switched to.
+    pub fn is_async(&self) -> bool {
+        self.vtable().poll.is_some()
+    }
+
+    /// Poll an async task's future once. Does nothing for threads.
+    pub unsafe fn poll(&self) {
+        if let Some(poll) = self.vtable().poll {
+            (poll)(self.0);
+        }
+    }
+
     pub fn into_waker(self) -> Waker {
         waker_from_raw_task(self)
     }
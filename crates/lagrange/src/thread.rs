@@ -164,6 +164,7 @@ impl<F, R> FullTaskState<F, R> {
         drop_in_place: drop_in_place::<F, R>,
         deallocate: deallocate::<F, R>,
         read_value_into: read_value_into::<F, R>,
+        poll: None,
     };
 }
 
@@ -0,0 +1,102 @@
+//! Channels: pairs of endpoints that carry messages of bytes and handles between
+//! processes.
+
+use core::mem;
+
+use crate::{
+    syscall::{
+        syscall0, syscall1, syscall5, SYS_CHANNEL_CREATE, SYS_CHANNEL_READ, SYS_CHANNEL_WAIT,
+        SYS_CHANNEL_WRITE,
+    },
+    AnyHandle, Error, Handle, Object, Result,
+};
+
+/// The largest message body, in bytes.
+pub const MAX_MESSAGE_BYTES: usize = 65536;
+/// The most handles one message can carry.
+pub const MAX_MESSAGE_HANDLES: usize = 64;
+
+/// One end of a channel.
+#[derive(Debug)]
+pub enum Channel {}
+
+impl Object for Channel {
+    const KIND: &'static str = "channel";
+}
+
+impl Handle<Channel> {
+    /// Create a channel, returning its two ends.
+    pub fn create() -> Result<(Self, Self)> {
+        let raw = unsafe { syscall0(SYS_CHANNEL_CREATE)? };
+        unsafe {
+            Ok((
+                Self::from_raw(raw as u32),
+                Self::from_raw((raw >> 32) as u32),
+            ))
+        }
+    }
+
+    /// Send `bytes` and `handles` to the other end. The handles need
+    /// [`Rights::TRANSFER`](crate::Rights::TRANSFER). On failure they are handed back.
+    pub fn write<const N: usize>(
+        &self,
+        bytes: &[u8],
+        handles: [AnyHandle; N],
+    ) -> core::result::Result<(), (Error, [AnyHandle; N])> {
+        let raw = handles.each_ref().map(Handle::as_raw);
+        let result = unsafe {
+            syscall5(
+                SYS_CHANNEL_WRITE,
+                self.as_raw() as usize,
+                bytes.as_ptr() as usize,
+                bytes.len(),
+                raw.as_ptr() as usize,
+                N,
+            )
+        };
+        match result {
+            Ok(_) => {
+                // They belong to the message now.
+                mem::forget(handles);
+                Ok(())
+            }
+            Err(err) => Err((err, handles)),
+        }
+    }
+
+    /// Receive the next message into `bytes` and `handles`, which must be large enough
+    /// for all of it. Returns how many bytes and handles it had. Fails with
+    /// [`Error::WouldBlock`] if there is no message yet, and [`Error::PeerClosed`] if
+    /// there never will be.
+    pub fn read(
+        &self,
+        bytes: &mut [u8],
+        handles: &mut [Option<AnyHandle>],
+    ) -> Result<(usize, usize)> {
+        let mut raw = [0u32; MAX_MESSAGE_HANDLES];
+        let count = handles.len().min(MAX_MESSAGE_HANDLES);
+        let result = unsafe {
+            syscall5(
+                SYS_CHANNEL_READ,
+                self.as_raw() as usize,
+                bytes.as_mut_ptr() as usize,
+                bytes.len(),
+                raw.as_mut_ptr() as usize,
+                count,
+            )?
+        };
+
+        let (len, received) = (result & 0xffff_ffff, result >> 32);
+        for (slot, &raw) in handles.iter_mut().zip(&raw[..received]) {
+            *slot = Some(unsafe { Handle::from_raw(raw) });
+        }
+        Ok((len, received))
+    }
+
+    /// Sleep until there is a message to read, or fail with [`Error::PeerClosed`] once
+    /// there never will be.
+    pub fn wait(&self) -> Result<()> {
+        unsafe { syscall1(SYS_CHANNEL_WAIT, self.as_raw() as usize)? };
+        Ok(())
+    }
+}
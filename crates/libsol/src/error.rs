@@ -0,0 +1,8 @@
+//! Errors returned by the kernel.
+//!
+//! The kinds and their codes are shared with the kernel, in `sol-types`.
+
+/// Why a syscall failed.
+pub use sol_types::ErrorKind as Error;
+
+pub type Result<T> = core::result::Result<T, Error>;
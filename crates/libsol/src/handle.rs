@@ -0,0 +1,107 @@
+use core::{fmt, marker::PhantomData, mem};
+
+use bitflags::bitflags;
+
+use crate::{
+    syscall::{syscall1, syscall2, SYS_HANDLE_DROP, SYS_HANDLE_DUPLICATE},
+    Result,
+};
+
+bitflags! {
+    /// What may be done with an object through a handle.
+    pub struct Rights: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const MAP = 1 << 2;
+        /// The handle may be sent to another process.
+        const TRANSFER = 1 << 3;
+        /// The handle may be duplicated, with the same or fewer rights.
+        const DUPLICATE = 1 << 4;
+    }
+}
+
+/// A kind of kernel object a [`Handle`] can name.
+pub trait Object {
+    /// The kernel's name for this kind of object.
+    const KIND: &'static str;
+}
+
+/// Any kind of object, for handles whose kind isn't known, such as those received over a
+/// channel.
+#[derive(Debug)]
+pub enum Any {}
+
+impl Object for Any {
+    const KIND: &'static str = "any";
+}
+
+pub type AnyHandle = Handle<Any>;
+
+/// An owned handle to a kernel object of kind `T`, closed when dropped.
+///
+/// The kind is only a promise: the kernel checks it whenever the handle is used, and
+/// fails with [`Error::InvalidHandle`](crate::Error::InvalidHandle) if it doesn't hold.
+pub struct Handle<T: Object> {
+    raw: u32,
+    _kind: PhantomData<T>,
+}
+
+impl<T: Object> Handle<T> {
+    /// Take ownership of `raw`.
+    ///
+    /// # Safety
+    /// `raw` must be a handle that nothing else will close.
+    pub const unsafe fn from_raw(raw: u32) -> Self {
+        Self {
+            raw,
+            _kind: PhantomData,
+        }
+    }
+
+    pub fn as_raw(&self) -> u32 {
+        self.raw
+    }
+
+    /// Give up ownership of the handle without closing it.
+    pub fn into_raw(self) -> u32 {
+        let raw = self.raw;
+        mem::forget(self);
+        raw
+    }
+
+    /// Another handle to the same object, with `rights`, which must be a subset of this
+    /// handle's. This handle needs [`Rights::DUPLICATE`].
+    pub fn duplicate(&self, rights: Rights) -> Result<Self> {
+        let raw = unsafe {
+            syscall2(
+                SYS_HANDLE_DUPLICATE,
+                self.raw as usize,
+                rights.bits() as usize,
+            )?
+        };
+        Ok(unsafe { Self::from_raw(raw as u32) })
+    }
+
+    pub fn into_any(self) -> AnyHandle {
+        unsafe { Handle::from_raw(self.into_raw()) }
+    }
+
+    /// Treat the handle as naming a `U`.
+    pub fn cast<U: Object>(self) -> Handle<U> {
+        unsafe { Handle::from_raw(self.into_raw()) }
+    }
+}
+
+impl<T: Object> Drop for Handle<T> {
+    fn drop(&mut self) {
+        // Closing can only fail if the handle was already gone, which `from_raw` rules
+        // out.
+        let _ = unsafe { syscall1(SYS_HANDLE_DROP, self.raw as usize) };
+    }
+}
+
+impl<T: Object> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handle<{}>({:#x})", T::KIND, self.raw)
+    }
+}
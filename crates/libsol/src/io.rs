@@ -0,0 +1,67 @@
+//! I/O ports, for drivers.
+
+use core::arch::asm;
+
+use crate::{
+    syscall::{syscall1, SYS_IO_PORTS_ENABLE},
+    Handle, Object, Result,
+};
+
+/// A range of I/O ports.
+#[derive(Debug)]
+pub enum IoPorts {}
+
+impl Object for IoPorts {
+    const KIND: &'static str = "io_ports";
+}
+
+impl Handle<IoPorts> {
+    /// Let this process use the ports, for as long as it lives. The handle needs
+    /// [`Rights::READ`](crate::Rights::READ) and [`Rights::WRITE`](crate::Rights::WRITE).
+    pub fn enable(&self) -> Result<()> {
+        unsafe { syscall1(SYS_IO_PORTS_ENABLE, self.as_raw() as usize)? };
+        Ok(())
+    }
+}
+
+/// # Safety
+/// The port must be enabled, and reading it must not upset the device.
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// # Safety
+/// The port must be enabled, and writing it must not upset the device.
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+/// # Safety
+/// See [`inb`].
+pub unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// # Safety
+/// See [`outb`].
+pub unsafe fn outw(port: u16, value: u16) {
+    asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+}
+
+/// # Safety
+/// See [`inb`].
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// # Safety
+/// See [`outb`].
+pub unsafe fn outl(port: u16, value: u32) {
+    asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+}
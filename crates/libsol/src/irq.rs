@@ -0,0 +1,38 @@
+//! Device interrupts, for drivers.
+
+use crate::{
+    syscall::{syscall1, SYS_IRQ_ACK, SYS_IRQ_MSI_MESSAGE, SYS_IRQ_WAIT},
+    Handle, Object, Result,
+};
+
+/// An interrupt bound to this process.
+#[derive(Debug)]
+pub enum Irq {}
+
+impl Object for Irq {
+    const KIND: &'static str = "irq";
+}
+
+impl Handle<Irq> {
+    /// Sleep until the interrupt fires, returning how many times it has fired in all.
+    /// It stays masked until [acked](Handle::ack). The handle needs
+    /// [`Rights::READ`](crate::Rights::READ).
+    pub fn wait(&self) -> Result<u32> {
+        let count = unsafe { syscall1(SYS_IRQ_WAIT, self.as_raw() as usize)? };
+        Ok(count as u32)
+    }
+
+    /// Unmask the interrupt, once the device has been dealt with. The handle needs
+    /// [`Rights::WRITE`](crate::Rights::WRITE).
+    pub fn ack(&self) -> Result<()> {
+        unsafe { syscall1(SYS_IRQ_ACK, self.as_raw() as usize)? };
+        Ok(())
+    }
+
+    /// The address and data the device must write to raise a message signalled
+    /// interrupt. The handle needs [`Rights::READ`](crate::Rights::READ).
+    pub fn msi_message(&self) -> Result<(u32, u32)> {
+        let raw = unsafe { syscall1(SYS_IRQ_MSI_MESSAGE, self.as_raw() as usize)? };
+        Ok((raw as u32, (raw >> 32) as u32))
+    }
+}
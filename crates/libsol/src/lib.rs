@@ -0,0 +1,33 @@
+//! The userspace runtime for Sol.
+//!
+//! This wraps the raw syscalls in safe APIs: typed [handles](Handle) to kernel objects,
+//! [channels](channel), [futex based locks](sync) and the [submission and completion
+//! rings](ring). Binaries declare their entry point with [`entry!`], and are built for
+//! the target in `conf/x86_64-sol.json`, which links them with `conf/user.ld`:
+//!
+//! ```text
+//! cargo build -Z build-std=core,compiler_builtins \
+//!     -Z build-std-features=compiler-builtins-mem \
+//!     --target conf/x86_64-sol.json --bin <name>
+//! ```
+
+#![no_std]
+#![feature(naked_functions)]
+
+pub use self::{
+    error::{Error, Result},
+    handle::{AnyHandle, Handle, Object, Rights},
+};
+
+pub mod channel;
+pub mod error;
+mod handle;
+pub mod io;
+pub mod irq;
+pub mod log;
+pub mod phys_mem;
+pub mod process;
+pub mod ring;
+pub mod rt;
+pub mod sync;
+pub mod syscall;
@@ -0,0 +1,52 @@
+//! The kernel log.
+
+use core::fmt;
+
+use crate::{
+    syscall::{syscall3, SYS_LOG_WRITE},
+    Handle, Object, Result,
+};
+
+/// The longest message a single write may carry, in bytes.
+pub const MAX_LOG_BYTES: usize = 1024;
+
+/// The kernel log, which tags each line with a name chosen when it was handed out.
+#[derive(Debug)]
+pub enum Log {}
+
+impl Object for Log {
+    const KIND: &'static str = "log";
+}
+
+impl Handle<Log> {
+    /// Log `message`, which must be no longer than [`MAX_LOG_BYTES`]. The handle needs
+    /// [`Rights::WRITE`](crate::Rights::WRITE).
+    pub fn write(&self, message: &str) -> Result<()> {
+        unsafe {
+            syscall3(
+                SYS_LOG_WRITE,
+                self.as_raw() as usize,
+                message.as_ptr() as usize,
+                message.len(),
+            )?
+        };
+        Ok(())
+    }
+}
+
+/// Lets the log be used with `write!`. Longer messages are split across writes, which
+/// may split a line.
+impl fmt::Write for &Handle<Log> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut rest = s;
+        while !rest.is_empty() {
+            let mut end = rest.len().min(MAX_LOG_BYTES);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.write(&rest[..end]).map_err(|_| fmt::Error)?;
+            rest = &rest[end..];
+        }
+        Ok(())
+    }
+}
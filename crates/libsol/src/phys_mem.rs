@@ -0,0 +1,25 @@
+//! Physical memory, for drivers of memory mapped devices.
+
+use crate::{
+    syscall::{syscall1, SYS_PHYS_MEM_MAP},
+    Handle, Object, Result,
+};
+
+/// A range of device memory, such as a framebuffer.
+#[derive(Debug)]
+pub enum PhysMem {}
+
+impl Object for PhysMem {
+    const KIND: &'static str = "phys_mem";
+}
+
+impl Handle<PhysMem> {
+    /// Map the memory into this process, returning where. The handle needs
+    /// [`Rights::MAP`](crate::Rights::MAP), and the mapping is only writable if it also
+    /// has [`Rights::WRITE`](crate::Rights::WRITE). It stays mapped for as long as the
+    /// process lives.
+    pub fn map(&self) -> Result<*mut u8> {
+        let addr = unsafe { syscall1(SYS_PHYS_MEM_MAP, self.as_raw() as usize)? };
+        Ok(addr as *mut u8)
+    }
+}
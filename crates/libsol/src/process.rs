@@ -0,0 +1,81 @@
+//! Processes: spawning them, waiting for them, and ending them.
+
+use core::mem;
+
+/// How a process ended.
+pub use sol_types::ExitStatus;
+
+use crate::{
+    rt::Bootstrap,
+    syscall::{
+        syscall1, syscall3, SYS_PROCESS_EXIT, SYS_PROCESS_ID, SYS_PROCESS_KILL, SYS_PROCESS_SPAWN,
+        SYS_PROCESS_WAIT,
+    },
+    Error, Handle, Object, Result,
+};
+
+/// The largest executable [`spawn`](Handle::spawn) takes, in bytes.
+pub const MAX_IMAGE_BYTES: usize = 16 << 20;
+
+/// A running or exited process.
+#[derive(Debug)]
+pub enum Process {}
+
+impl Object for Process {
+    const KIND: &'static str = "process";
+}
+
+impl Handle<Process> {
+    /// Start the executable in `image` in a new process, passing it `bootstrap`, which
+    /// needs [`Rights::TRANSFER`](crate::Rights::TRANSFER). On failure the bootstrap
+    /// channel is handed back.
+    pub fn spawn(
+        image: &[u8],
+        bootstrap: Bootstrap,
+    ) -> core::result::Result<Self, (Error, Bootstrap)> {
+        let raw_bootstrap = bootstrap.as_ref().map_or(0, Handle::as_raw);
+        let result = unsafe {
+            syscall3(
+                SYS_PROCESS_SPAWN,
+                image.as_ptr() as usize,
+                image.len(),
+                raw_bootstrap as usize,
+            )
+        };
+        match result {
+            Ok(raw) => {
+                // It belongs to the new process now.
+                mem::forget(bootstrap);
+                Ok(unsafe { Self::from_raw(raw as u32) })
+            }
+            Err(err) => Err((err, bootstrap)),
+        }
+    }
+
+    /// Sleep until the process has exited, returning how it ended. The handle needs
+    /// [`Rights::READ`](crate::Rights::READ).
+    pub fn wait(&self) -> Result<ExitStatus> {
+        let raw = unsafe { syscall1(SYS_PROCESS_WAIT, self.as_raw() as usize)? };
+        ExitStatus::decode(raw).ok_or(Error::Unknown)
+    }
+
+    /// Terminate the process, unless it is already ending. The handle needs
+    /// [`Rights::WRITE`](crate::Rights::WRITE).
+    pub fn kill(&self) -> Result<()> {
+        unsafe { syscall1(SYS_PROCESS_KILL, self.as_raw() as usize)? };
+        Ok(())
+    }
+
+    /// The process's id, which is never reused. The handle needs
+    /// [`Rights::READ`](crate::Rights::READ).
+    pub fn id(&self) -> Result<u64> {
+        let id = unsafe { syscall1(SYS_PROCESS_ID, self.as_raw() as usize)? };
+        Ok(id as u64)
+    }
+}
+
+/// End the calling process with exit code `code`, along with all of its threads.
+pub fn exit(code: u32) -> ! {
+    let _ = unsafe { syscall1(SYS_PROCESS_EXIT, code as usize) };
+    unreachable!("process_exit returned")
+}
@@ -0,0 +1,173 @@
+//! The client side of the kernel's submission and completion rings.
+//!
+//! A [`Ring`] batches syscalls: [`Ring::push`] queues an entry, [`Ring::enter`] has the
+//! kernel run what is queued, and [`Ring::pop`] reaps the results. Every entry carries a
+//! `user_data` that comes back with its completion, to match the two up.
+
+use core::{
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    error::Error,
+    sync::futex_wait,
+    syscall::{syscall1, syscall3, SYS_RING_ENTER, SYS_RING_SETUP},
+    Result,
+};
+
+/// The most submission entries a ring may have.
+pub const MAX_ENTRIES: u32 = 4096;
+/// The most rings a process may set up.
+pub const MAX_RINGS: usize = 16;
+
+/// The start of a ring's memory, laid out as the kernel expects.
+#[repr(C)]
+#[derive(Debug)]
+struct RingHeader {
+    sq_head: AtomicU32,
+    sq_tail: AtomicU32,
+    cq_head: AtomicU32,
+    cq_tail: AtomicU32,
+    sq_entries: u32,
+    cq_entries: u32,
+    sq_offset: u32,
+    cq_offset: u32,
+}
+
+/// A submission entry: run syscall `opcode` with `args`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Sqe {
+    pub opcode: u32,
+    /// Reserved, must be zero.
+    pub flags: u32,
+    pub user_data: u64,
+    pub args: [u64; 6],
+}
+
+impl Sqe {
+    pub fn new(opcode: usize, args: [usize; 6], user_data: u64) -> Self {
+        Self {
+            opcode: opcode as u32,
+            flags: 0,
+            user_data,
+            args: args.map(|arg| arg as u64),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Cqe {
+    pub user_data: u64,
+    /// Encoded like a syscall return value, see [`Cqe::result`].
+    pub result: i64,
+}
+
+impl Cqe {
+    pub fn result(&self) -> Result<usize> {
+        Error::decode(self.result as usize)
+    }
+}
+
+/// A ring mapped into this process. The kernel keeps it for as long as the process lives.
+#[derive(Debug)]
+pub struct Ring {
+    header: NonNull<RingHeader>,
+    sq: NonNull<Sqe>,
+    cq: NonNull<Cqe>,
+    sq_entries: u32,
+    cq_entries: u32,
+}
+
+impl Ring {
+    /// Set up a ring with `entries` submission entries, which must be a power of two no
+    /// larger than [`MAX_ENTRIES`]. Fails with [`Error::Busy`] once the process has
+    /// [`MAX_RINGS`] rings.
+    pub fn new(entries: u32) -> Result<Self> {
+        let addr = unsafe { syscall1(SYS_RING_SETUP, entries as usize)? };
+        let header = NonNull::new(addr as *mut RingHeader).ok_or(Error::Fault)?;
+
+        let (sq_entries, cq_entries, sq_offset, cq_offset) = unsafe {
+            let header = header.as_ref();
+            (
+                header.sq_entries,
+                header.cq_entries,
+                header.sq_offset as usize,
+                header.cq_offset as usize,
+            )
+        };
+        let base = header.as_ptr().cast::<u8>();
+        Ok(Self {
+            header,
+            sq: unsafe { NonNull::new_unchecked(base.add(sq_offset).cast()) },
+            cq: unsafe { NonNull::new_unchecked(base.add(cq_offset).cast()) },
+            sq_entries,
+            cq_entries,
+        })
+    }
+
+    /// Queue `sqe`, handing it back if the submission queue is full.
+    pub fn push(&mut self, sqe: Sqe) -> core::result::Result<(), Sqe> {
+        let header = self.header();
+        let tail = header.sq_tail.load(Ordering::Relaxed);
+        let head = header.sq_head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= self.sq_entries {
+            return Err(sqe);
+        }
+
+        let index = (tail & (self.sq_entries - 1)) as usize;
+        unsafe { ptr::write_volatile(self.sq.as_ptr().add(index), sqe) };
+        header
+            .sq_tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Have the kernel run up to `to_submit` queued entries, then wait until at least
+    /// `min_complete` completions are ready. Returns how many entries were run.
+    pub fn enter(&mut self, to_submit: u32, min_complete: u32) -> Result<usize> {
+        unsafe {
+            syscall3(
+                SYS_RING_ENTER,
+                self.header.as_ptr() as usize,
+                to_submit as usize,
+                min_complete as usize,
+            )
+        }
+    }
+
+    /// Take the next completion, if there is one.
+    pub fn pop(&mut self) -> Option<Cqe> {
+        let header = self.header();
+        let head = header.cq_head.load(Ordering::Relaxed);
+        let tail = header.cq_tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let index = (head & (self.cq_entries - 1)) as usize;
+        let cqe = unsafe { ptr::read_volatile(self.cq.as_ptr().add(index)) };
+        header
+            .cq_head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(cqe)
+    }
+
+    /// Sleep until there is a completion to take, without entering the ring.
+    pub fn wait(&mut self) -> Result<()> {
+        let header = self.header();
+        loop {
+            let tail = header.cq_tail.load(Ordering::Acquire);
+            if tail != header.cq_head.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            futex_wait(&header.cq_tail, tail)?;
+        }
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { self.header.as_ref() }
+    }
+}
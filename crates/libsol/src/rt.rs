@@ -0,0 +1,61 @@
+//! Process startup.
+//!
+//! The kernel enters a process at `_start`, with a 16-byte aligned stack and the raw
+//! handle of the process's bootstrap channel in `rdi`, or zero if it was given none.
+//! `_start` calls the function named by [`entry!`], and the process exits with code zero
+//! once it returns, or [`PANIC_EXIT_CODE`] if it panics.
+
+use core::{arch::asm, panic::PanicInfo};
+
+use crate::{channel::Channel, process, Handle};
+
+/// The code a process exits with when it panics.
+pub const PANIC_EXIT_CODE: u32 = 101;
+
+/// The bootstrap channel a process is started with, if any.
+pub type Bootstrap = Option<Handle<Channel>>;
+
+/// Declare the function the process starts in, which takes the [`Bootstrap`] channel.
+///
+/// ```ignore
+/// libsol::entry!(main);
+///
+/// fn main(bootstrap: libsol::rt::Bootstrap) {}
+/// ```
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        fn __sol_main(bootstrap: $crate::rt::Bootstrap) {
+            let main: fn($crate::rt::Bootstrap) = $main;
+            main(bootstrap)
+        }
+    };
+}
+
+extern "Rust" {
+    fn __sol_main(bootstrap: Bootstrap);
+}
+
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn _start() -> ! {
+    asm!(
+        "xor ebp, ebp
+        call {start}
+        ud2",
+        start = sym start,
+        options(noreturn)
+    );
+}
+
+extern "C" fn start(bootstrap: u32) -> ! {
+    let bootstrap = (bootstrap != 0).then(|| unsafe { Handle::from_raw(bootstrap) });
+    unsafe { __sol_main(bootstrap) };
+    process::exit(0)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo<'_>) -> ! {
+    process::exit(PANIC_EXIT_CODE)
+}
@@ -0,0 +1,160 @@
+//! Locks built on futexes.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    syscall::{syscall4, SYS_FUTEX},
+    Error, Result,
+};
+
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+/// The futex may be mapped by more than one process.
+const FUTEX_SHARED: usize = 1 << 8;
+/// Every waiter matches.
+const BITSET_MATCH_ANY: usize = u32::MAX as usize;
+
+/// Sleep until woken, if `atomic` still holds `value`. May return spuriously.
+pub fn futex_wait(atomic: &AtomicU32, value: u32) -> Result<()> {
+    futex(atomic, FUTEX_WAIT, value as usize).map(drop)
+}
+
+/// Wake up to `count` tasks waiting on `atomic`, returning how many were woken.
+pub fn futex_wake(atomic: &AtomicU32, count: usize) -> Result<usize> {
+    futex(atomic, FUTEX_WAKE, count)
+}
+
+/// [`futex_wait`] on a futex in memory shared with other processes.
+pub fn futex_wait_shared(atomic: &AtomicU32, value: u32) -> Result<()> {
+    futex(atomic, FUTEX_WAIT | FUTEX_SHARED, value as usize).map(drop)
+}
+
+/// [`futex_wake`] on a futex in memory shared with other processes.
+pub fn futex_wake_shared(atomic: &AtomicU32, count: usize) -> Result<usize> {
+    futex(atomic, FUTEX_WAKE | FUTEX_SHARED, count)
+}
+
+fn futex(atomic: &AtomicU32, op: usize, value: usize) -> Result<usize> {
+    unsafe {
+        syscall4(
+            SYS_FUTEX,
+            atomic.as_ptr() as usize,
+            op,
+            value,
+            BITSET_MATCH_ANY,
+        )
+    }
+}
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// Locked, and someone may be asleep waiting for it.
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock that sleeps in the kernel while contended.
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_contended();
+        }
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    #[cold]
+    fn lock_contended(&self) {
+        // Once we have slept we can't know whether anyone else is waiting, so always take
+        // the lock as contended from here on.
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            match futex_wait(&self.state, CONTENDED) {
+                Ok(()) | Err(Error::WouldBlock) => {}
+                Err(err) => panic!("futex wait failed: {}", err),
+            }
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex_wake(&self.state, 1).expect("futex wake failed");
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.debug_struct("Mutex").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -0,0 +1,103 @@
+//! Raw syscalls.
+//!
+//! The number goes in `rax` and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8`
+//! and `r9`. The kernel returns the result in `rax` and clobbers `rcx` and `r11`. The
+//! numbers match the kernel's syscall table, and are never reused.
+
+use core::arch::asm;
+
+use crate::{Error, Result};
+
+pub const SYS_FUTEX: usize = 0;
+pub const SYS_RING_SETUP: usize = 1;
+pub const SYS_RING_ENTER: usize = 2;
+pub const SYS_HANDLE_DROP: usize = 3;
+pub const SYS_HANDLE_DUPLICATE: usize = 4;
+pub const SYS_CHANNEL_CREATE: usize = 5;
+pub const SYS_CHANNEL_WRITE: usize = 6;
+pub const SYS_CHANNEL_READ: usize = 7;
+pub const SYS_CHANNEL_WAIT: usize = 8;
+pub const SYS_IO_PORTS_ENABLE: usize = 9;
+pub const SYS_IRQ_WAIT: usize = 10;
+pub const SYS_IRQ_ACK: usize = 11;
+pub const SYS_IRQ_MSI_MESSAGE: usize = 12;
+pub const SYS_LOG_WRITE: usize = 13;
+pub const SYS_PHYS_MEM_MAP: usize = 14;
+pub const SYS_PROCESS_EXIT: usize = 15;
+pub const SYS_PROCESS_SPAWN: usize = 16;
+pub const SYS_PROCESS_WAIT: usize = 17;
+pub const SYS_PROCESS_KILL: usize = 18;
+pub const SYS_PROCESS_ID: usize = 19;
+
+/// Make syscall `number`, decoding the result.
+///
+/// # Safety
+/// The arguments must be valid for the syscall: in particular, any pointers must be
+/// valid for what the kernel will do with them.
+#[inline]
+pub unsafe fn syscall(number: usize, args: [usize; 6]) -> Result<usize> {
+    let ret: usize;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    Error::decode(ret)
+}
+
+/// # Safety
+/// See [`syscall`].
+#[inline]
+pub unsafe fn syscall0(number: usize) -> Result<usize> {
+    syscall(number, [0; 6])
+}
+
+/// # Safety
+/// See [`syscall`].
+#[inline]
+pub unsafe fn syscall1(number: usize, a: usize) -> Result<usize> {
+    syscall(number, [a, 0, 0, 0, 0, 0])
+}
+
+/// # Safety
+/// See [`syscall`].
+#[inline]
+pub unsafe fn syscall2(number: usize, a: usize, b: usize) -> Result<usize> {
+    syscall(number, [a, b, 0, 0, 0, 0])
+}
+
+/// # Safety
+/// See [`syscall`].
+#[inline]
+pub unsafe fn syscall3(number: usize, a: usize, b: usize, c: usize) -> Result<usize> {
+    syscall(number, [a, b, c, 0, 0, 0])
+}
+
+/// # Safety
+/// See [`syscall`].
+#[inline]
+pub unsafe fn syscall4(number: usize, a: usize, b: usize, c: usize, d: usize) -> Result<usize> {
+    syscall(number, [a, b, c, d, 0, 0])
+}
+
+/// # Safety
+/// See [`syscall`].
+#[inline]
+pub unsafe fn syscall5(
+    number: usize,
+    a: usize,
+    b: usize,
+    c: usize,
+    d: usize,
+    e: usize,
+) -> Result<usize> {
+    syscall(number, [a, b, c, d, e, 0])
+}
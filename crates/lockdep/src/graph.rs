@@ -0,0 +1,634 @@
+use core::{
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::{error, warn};
+use spin::{mutex::SpinMutex, Once};
+
+use crate::{
+    held::{Held, HeldLocks},
+    Hooks, LockClass, LockKind,
+};
+
+const MAX_CLASSES: usize = 1024;
+const MAX_EDGES: usize = 8192;
+
+/// Classes and edges are referred to by index, with zero meaning none, so that an empty
+/// graph is all zeroes and lives in `.bss`.
+type Index = u16;
+const NONE: Index = 0;
+
+static HOOKS: Once<&'static Hooks> = Once::new();
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static GRAPH: SpinMutex<Graph> = SpinMutex::new(Graph::new());
+
+#[derive(Clone, Copy)]
+struct Class {
+    /// The address of the [`LockClass`], zero if the slot has never been used.
+    key: usize,
+    name: &'static str,
+    first_edge: Index,
+}
+
+/// `from -> to`: `to` was acquired while `from` was held.
+#[derive(Clone, Copy)]
+struct Edge {
+    from: Index,
+    to: Index,
+    next: Index,
+    held_site: Option<&'static Location<'static>>,
+    acquire_site: Option<&'static Location<'static>>,
+}
+
+struct Graph {
+    classes: [Class; MAX_CLASSES],
+    edges: [Edge; MAX_EDGES],
+    /// Edges past this have never been used.
+    unused_edges: usize,
+    // Scratch space for searching the graph.
+    visited: [u32; MAX_CLASSES],
+    generation: u32,
+    parent: [Index; MAX_CLASSES],
+    stack: [Index; MAX_CLASSES],
+}
+
+enum AddEdgeError {
+    /// The new edge would close a cycle. The path it would close is left in `parent`.
+    Cycle,
+    Full,
+}
+
+impl Graph {
+    const fn new() -> Self {
+        const CLASS: Class = Class {
+            key: 0,
+            name: "",
+            first_edge: NONE,
+        };
+        const EDGE: Edge = Edge {
+            from: NONE,
+            to: NONE,
+            next: NONE,
+            held_site: None,
+            acquire_site: None,
+        };
+
+        Self {
+            classes: [CLASS; MAX_CLASSES],
+            edges: [EDGE; MAX_EDGES],
+            unused_edges: 1,
+            visited: [0; MAX_CLASSES],
+            generation: 0,
+            parent: [NONE; MAX_CLASSES],
+            stack: [NONE; MAX_CLASSES],
+        }
+    }
+
+    fn probe(key: usize) -> impl Iterator<Item = usize> {
+        let start = (key >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) % (MAX_CLASSES - 1);
+        (0..MAX_CLASSES - 1).map(move |i| 1 + (start + i) % (MAX_CLASSES - 1))
+    }
+
+    /// Look up `class`, registering it if this is the first time we've seen it. Classes
+    /// are statics, so they are never removed.
+    fn class(&mut self, class: &'static LockClass) -> Option<Index> {
+        let key = class.key();
+        let i = Self::probe(key).find(|&i| [0, key].contains(&self.classes[i].key))?;
+        if self.classes[i].key == 0 {
+            self.classes[i] = Class {
+                key,
+                name: class.name(),
+                first_edge: NONE,
+            };
+        }
+        Some(i as Index)
+    }
+
+    fn edges_from(&self, class: Index) -> impl Iterator<Item = Index> + '_ {
+        let mut edge = self.classes[class as usize].first_edge;
+        core::iter::from_fn(move || {
+            let current = edge;
+            (current != NONE).then(|| {
+                edge = self.edges[current as usize].next;
+                current
+            })
+        })
+    }
+
+    fn allocate_edge(&mut self) -> Option<Index> {
+        let edge = self.unused_edges;
+        if edge == MAX_EDGES {
+            return None;
+        }
+        self.unused_edges += 1;
+        Some(edge as Index)
+    }
+
+    fn add_edge(
+        &mut self,
+        from: Index,
+        to: Index,
+        held_site: &'static Location<'static>,
+        acquire_site: &'static Location<'static>,
+    ) -> Result<(), AddEdgeError> {
+        if self
+            .edges_from(from)
+            .any(|edge| self.edges[edge as usize].to == to)
+        {
+            return Ok(());
+        }
+
+        if self.reachable(to, from) {
+            return Err(AddEdgeError::Cycle);
+        }
+
+        let edge = self.allocate_edge().ok_or(AddEdgeError::Full)?;
+        self.edges[edge as usize] = Edge {
+            from,
+            to,
+            next: self.classes[from as usize].first_edge,
+            held_site: Some(held_site),
+            acquire_site: Some(acquire_site),
+        };
+        self.classes[from as usize].first_edge = edge;
+        Ok(())
+    }
+
+    /// Depth first search for a path from `from` to `to`. If there is one, following
+    /// `parent` back from `to` retraces it.
+    fn reachable(&mut self, from: Index, to: Index) -> bool {
+        self.generation = self.generation.wrapping_add(1);
+        if self.generation == 0 {
+            self.visited = [0; MAX_CLASSES];
+            self.generation = 1;
+        }
+
+        let generation = self.generation;
+        self.visited[from as usize] = generation;
+        self.parent[from as usize] = NONE;
+        self.stack[0] = from;
+        let mut len = 1;
+
+        while len > 0 {
+            len -= 1;
+            let class = self.stack[len];
+            if class == to {
+                return true;
+            }
+
+            let mut edge = self.classes[class as usize].first_edge;
+            while edge != NONE {
+                let Edge {
+                    to: next,
+                    next: sibling,
+                    ..
+                } = self.edges[edge as usize];
+                if self.visited[next as usize] != generation {
+                    self.visited[next as usize] = generation;
+                    self.parent[next as usize] = edge;
+                    self.stack[len] = next;
+                    len += 1;
+                }
+                edge = sibling;
+            }
+        }
+        false
+    }
+}
+
+pub fn init(hooks: &'static Hooks) {
+    HOOKS.call_once(|| hooks);
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Run `f` with the running task's lock stack, if validation is on and there is a task.
+fn with_held(f: impl FnOnce(&'static Hooks, &HeldLocks)) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let Some(&hooks) = HOOKS.get() else { return };
+
+    let mut f = Some(f);
+    (hooks.without_interrupts)(&mut || {
+        let Some(held) = (hooks.held)() else { return };
+        if let Some(f) = f.take() {
+            f(hooks, held);
+        }
+    });
+}
+
+/// Switch validation off, returning true if this call did so and so gets to report.
+fn disable() -> bool {
+    ENABLED.swap(false, Ordering::AcqRel)
+}
+
+pub fn acquire(
+    lock: *const (),
+    lock_class: &'static LockClass,
+    kind: LockKind,
+    site: &'static Location<'static>,
+    check: bool,
+) {
+    let lock = lock as usize;
+    let name = lock_class.name();
+
+    with_held(|_, held| {
+        let mut graph = GRAPH.lock();
+        let Some(class) = graph.class(lock_class) else {
+            if disable() {
+                warn!("lockdep: too many lock classes, turning off");
+            }
+            return;
+        };
+
+        if check {
+            for outer in held.iter() {
+                if outer.lock == lock {
+                    drop(graph);
+                    return report_recursive(held, name, lock, site);
+                }
+                if outer.class == class {
+                    continue;
+                }
+
+                match graph.add_edge(outer.class, class, outer.site, site) {
+                    Ok(()) => {}
+                    Err(AddEdgeError::Cycle) => {
+                        return report_cycle(&graph, held, outer.class, class, site);
+                    }
+                    Err(AddEdgeError::Full) => {
+                        if disable() {
+                            warn!("lockdep: too many lock dependencies, turning off");
+                        }
+                        return;
+                    }
+                }
+            }
+        }
+        drop(graph);
+
+        let pushed = held.push(Held {
+            lock,
+            class,
+            kind,
+            name,
+            site,
+        });
+        if !pushed && disable() {
+            warn!("lockdep: too many locks held at {}, turning off", site);
+        }
+    });
+}
+
+pub fn release(lock: *const ()) {
+    with_held(|_, held| {
+        held.remove(lock as usize);
+    });
+}
+
+pub fn might_sleep(site: &'static Location<'static>) {
+    with_held(|hooks, held| {
+        if !(hooks.interrupts_enabled)() {
+            if disable() {
+                error!("lockdep: sleeping at {} with interrupts disabled", site);
+                print_held(held);
+            }
+            return;
+        }
+
+        let spinlock = held.iter().find(|held| held.kind == LockKind::Spin);
+        if let Some(spinlock) = spinlock {
+            if disable() {
+                error!(
+                    "lockdep: sleeping at {} while holding spinlock {} ({:#x})",
+                    site, spinlock.name, spinlock.lock
+                );
+                print_held(held);
+            }
+        }
+    });
+}
+
+fn print_held(held: &HeldLocks) {
+    error!("  locks held:");
+    for (i, held) in held.iter().enumerate() {
+        error!(
+            "    #{}: {} ({:#x}), acquired at {}",
+            i, held.name, held.lock, held.site
+        );
+    }
+}
+
+fn report_recursive(
+    held: &HeldLocks,
+    name: &'static str,
+    lock: usize,
+    site: &'static Location<'static>,
+) {
+    if !disable() {
+        return;
+    }
+    error!(
+        "lockdep: recursive locking of {} ({:#x}) at {}",
+        name, lock, site
+    );
+    print_held(held);
+}
+
+/// `from` is held and the task wants `to`, but the graph already has a path from `to`
+/// back to `from`.
+fn report_cycle(
+    graph: &Graph,
+    held: &HeldLocks,
+    from: Index,
+    to: Index,
+    site: &'static Location<'static>,
+) {
+    if !disable() {
+        return;
+    }
+
+    let class = |index: Index| &graph.classes[index as usize];
+
+    error!("lockdep: possible circular locking dependency");
+    error!("  acquiring {} at {}", class(to).name, site);
+    print_held(held);
+    error!("  existing dependency chain, innermost first:");
+
+    let mut current = from;
+    while current != to {
+        let edge = &graph.edges[graph.parent[current as usize] as usize];
+        error!(
+            "    {} acquired at {}",
+            class(edge.to).name,
+            edge.acquire_site.unwrap()
+        );
+        error!(
+            "      while holding {}, acquired at {}",
+            class(edge.from).name,
+            edge.held_site.unwrap()
+        );
+        current = edge.from;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, MutexGuard, Once};
+
+    use super::*;
+    use crate::class;
+
+    static HELD: HeldLocks = HeldLocks::new();
+    static INTERRUPTS: AtomicBool = AtomicBool::new(true);
+    static HOOKS: Hooks = Hooks {
+        held: || Some(&HELD),
+        interrupts_enabled: || INTERRUPTS.load(Ordering::Relaxed),
+        without_interrupts: |f| f(),
+    };
+
+    /// Everything logged, one line per entry.
+    static LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    /// The graph and the lock stack are global, so the tests take turns.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    struct Logger;
+
+    impl log::Log for Logger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOG.lock().unwrap().push(format!("{}", record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Start from an empty graph with nothing held and validation on.
+    fn setup() -> MutexGuard<'static, ()> {
+        static LOGGER: Once = Once::new();
+        LOGGER.call_once(|| {
+            log::set_logger(&Logger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+
+        let guard = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+        *GRAPH.lock() = Graph::new();
+        HELD.clear();
+        INTERRUPTS.store(true, Ordering::Relaxed);
+        LOG.lock().unwrap().clear();
+        init(&HOOKS);
+        guard
+    }
+
+    fn enabled() -> bool {
+        ENABLED.load(Ordering::Acquire)
+    }
+
+    fn log() -> String {
+        LOG.lock().unwrap().join("\n")
+    }
+
+    /// A lock instance, standing in for a lock type.
+    struct Lock {
+        class: &'static LockClass,
+        kind: LockKind,
+    }
+
+    impl Lock {
+        const fn spin(class: &'static LockClass) -> Self {
+            Self {
+                class,
+                kind: LockKind::Spin,
+            }
+        }
+
+        const fn sleep(class: &'static LockClass) -> Self {
+            Self {
+                class,
+                kind: LockKind::Sleep,
+            }
+        }
+
+        fn addr(&self) -> *const () {
+            (self as *const Self).cast()
+        }
+
+        #[track_caller]
+        fn lock(&self) {
+            acquire(self.addr(), self.class, self.kind, Location::caller(), true);
+        }
+
+        #[track_caller]
+        fn try_lock(&self) {
+            acquire(
+                self.addr(),
+                self.class,
+                self.kind,
+                Location::caller(),
+                false,
+            );
+        }
+
+        fn unlock(&self) {
+            release(self.addr());
+        }
+    }
+
+    #[test]
+    fn consistent_order() {
+        let _guard = setup();
+        let a = Lock::spin(class!("a"));
+        let b = Lock::spin(class!("b"));
+
+        for _ in 0..2 {
+            a.lock();
+            b.lock();
+            b.unlock();
+            a.unlock();
+        }
+        assert!(enabled());
+    }
+
+    #[test]
+    fn inversion() {
+        let _guard = setup();
+        let a = Lock::spin(class!("a"));
+        let b = Lock::spin(class!("b"));
+
+        a.lock();
+        b.lock();
+        b.unlock();
+        a.unlock();
+        assert!(enabled());
+
+        b.lock();
+        a.lock();
+        assert!(!enabled());
+        assert!(log().contains("possible circular locking dependency"));
+    }
+
+    #[test]
+    fn inversion_between_instances_of_a_class() {
+        let _guard = setup();
+        let (class_a, class_b) = (class!("a"), class!("b"));
+        let (a1, a2) = (Lock::spin(class_a), Lock::spin(class_a));
+        let (b1, b2) = (Lock::spin(class_b), Lock::spin(class_b));
+
+        // The order is learnt from one pair of locks, and enforced on another.
+        a1.lock();
+        b1.lock();
+        b1.unlock();
+        a1.unlock();
+
+        b2.lock();
+        a2.lock();
+        assert!(!enabled());
+    }
+
+    #[test]
+    fn same_class_nests() {
+        let _guard = setup();
+        let class = class!("a");
+        let (a1, a2) = (Lock::spin(class), Lock::spin(class));
+
+        a1.lock();
+        a2.lock();
+        a2.unlock();
+        a1.unlock();
+        a2.lock();
+        a1.lock();
+        assert!(enabled());
+    }
+
+    #[test]
+    fn recursion() {
+        let _guard = setup();
+        let a = Lock::spin(class!("a"));
+
+        a.lock();
+        a.lock();
+        assert!(!enabled());
+        assert!(log().contains("recursive locking of a"));
+    }
+
+    #[test]
+    fn try_lock_adds_no_dependency() {
+        let _guard = setup();
+        let a = Lock::spin(class!("a"));
+        let b = Lock::spin(class!("b"));
+
+        a.lock();
+        b.try_lock();
+        b.unlock();
+        a.unlock();
+
+        b.lock();
+        a.lock();
+        assert!(enabled());
+    }
+
+    #[test]
+    fn cycle_report_shows_chain() {
+        let _guard = setup();
+        let a = Lock::sleep(class!("first"));
+        let b = Lock::sleep(class!("second"));
+        let c = Lock::sleep(class!("third"));
+
+        a.lock();
+        b.lock();
+        a.unlock();
+        c.lock();
+        c.unlock();
+        b.unlock();
+        assert!(enabled());
+
+        c.lock();
+        a.lock();
+        assert!(!enabled());
+
+        let log = log();
+        let chain = log.split("existing dependency chain").nth(1).unwrap();
+        assert!(log.contains("acquiring first"));
+        assert!(chain.contains("third acquired at"));
+        assert!(chain.contains("while holding second"));
+        assert!(chain.contains("second acquired at"));
+        assert!(chain.contains("while holding first"));
+        assert!(log.contains(file!()));
+    }
+
+    #[test]
+    fn sleep_with_interrupts_disabled() {
+        let _guard = setup();
+
+        INTERRUPTS.store(false, Ordering::Relaxed);
+        might_sleep(Location::caller());
+        assert!(!enabled());
+        assert!(log().contains("with interrupts disabled"));
+    }
+
+    #[test]
+    fn sleep_holding_spinlock() {
+        let _guard = setup();
+        let a = Lock::spin(class!("spin"));
+
+        a.lock();
+        might_sleep(Location::caller());
+        assert!(!enabled());
+        assert!(log().contains("while holding spinlock spin"));
+    }
+
+    #[test]
+    fn sleep_holding_sleeping_lock() {
+        let _guard = setup();
+        let a = Lock::sleep(class!("sleep"));
+
+        a.lock();
+        might_sleep(Location::caller());
+        a.unlock();
+        assert!(enabled());
+    }
+}
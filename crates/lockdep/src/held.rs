@@ -0,0 +1,90 @@
+use core::{
+    cell::{Cell, UnsafeCell},
+    fmt::Debug,
+    panic::Location,
+};
+
+use crate::LockKind;
+
+const MAX_HELD: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Held {
+    pub lock: usize,
+    pub class: u16,
+    pub kind: LockKind,
+    pub name: &'static str,
+    pub site: &'static Location<'static>,
+}
+
+/// The locks held by a task, innermost last.
+///
+/// Every task owns one of these. It is only ever touched by the task itself, or by
+/// interrupt handlers running on top of it, and always with interrupts disabled.
+pub struct HeldLocks {
+    entries: UnsafeCell<[Option<Held>; MAX_HELD]>,
+    depth: Cell<usize>,
+}
+
+unsafe impl Sync for HeldLocks {}
+unsafe impl Send for HeldLocks {}
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        Self {
+            entries: UnsafeCell::new([None; MAX_HELD]),
+            depth: Cell::new(0),
+        }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn entries(&self) -> &mut [Option<Held>; MAX_HELD] {
+        // Only the owning context touches the entries, and never reentrantly.
+        unsafe { &mut *self.entries.get() }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Held> + '_ {
+        self.entries()[..self.depth.get()].iter().flatten().copied()
+    }
+
+    /// Returns false if too many locks are held to keep track of them all.
+    pub(crate) fn push(&self, held: Held) -> bool {
+        let depth = self.depth.get();
+        let Some(slot) = self.entries().get_mut(depth) else {
+            return false;
+        };
+        *slot = Some(held);
+        self.depth.set(depth + 1);
+        true
+    }
+
+    #[cfg(test)]
+    pub(crate) fn clear(&self) {
+        self.depth.set(0);
+    }
+
+    pub(crate) fn remove(&self, lock: usize) -> Option<Held> {
+        let depth = self.depth.get();
+        let entries = &mut self.entries()[..depth];
+        let i = entries
+            .iter()
+            .rposition(|held| matches!(held, Some(held) if held.lock == lock))?;
+
+        let held = entries[i].take();
+        entries[i..].rotate_left(1);
+        self.depth.set(depth - 1);
+        held
+    }
+}
+
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for HeldLocks {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
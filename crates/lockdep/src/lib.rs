@@ -0,0 +1,145 @@
+//! Lock dependency validation.
+//!
+//! Lock types call into this crate as they are acquired and released. Every lock belongs
+//! to a [`LockClass`], a static declared with [`class!`] wherever the lock is created, and
+//! dependencies are recorded between classes rather than individual locks: whenever a task
+//! takes a lock of class `B` while holding one of class `A`, the dependency `A -> B` is
+//! added to a global graph. An ordering seen once between any two locks then applies to
+//! every pair of locks created in the same places. If adding an edge would close a cycle,
+//! some interleaving of the tasks involved can deadlock, and we report it there and then,
+//! before it ever does. We also report sleeping locks taken while holding a spinlock or
+//! with interrupts disabled.
+//!
+//! Nothing is recorded between two locks of the same class, as there is no telling their
+//! order apart. Taking the same lock twice is still reported.
+//!
+//! Validation is compiled in with the `enabled` feature. Without it, every entry point is
+//! an empty inline function and [`HeldLocks`] is zero-sized, so lock types can call in
+//! unconditionally.
+//!
+//! After the first report, validation switches itself off: the graph is suspect by then,
+//! and one report is far more readable than a flood of them.
+
+#![cfg_attr(not(test), no_std)]
+
+use core::panic::Location;
+
+#[cfg(feature = "enabled")]
+mod graph;
+#[cfg(feature = "enabled")]
+mod held;
+
+#[cfg(feature = "enabled")]
+pub use self::held::HeldLocks;
+
+/// Whether a lock spins or may put the task to sleep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    Spin,
+    Sleep,
+}
+
+/// A class of locks, which dependencies are recorded between. A class is identified by
+/// its address, so it must be a static: declare one with [`class!`].
+#[derive(Debug)]
+pub struct LockClass {
+    name: &'static str,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> Self {
+        Self { name }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[cfg(feature = "enabled")]
+    fn key(&'static self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+/// Declare a lock class, evaluating to a `&'static LockClass`. Each use is a class of its
+/// own, so locks created in different places are told apart.
+///
+/// ```ignore
+/// static QUEUE: SpinMutex<Queue> = SpinMutex::new(Queue::new(), lockdep::class!("QUEUE"));
+/// ```
+#[macro_export]
+macro_rules! class {
+    ($name:expr) => {{
+        static CLASS: $crate::LockClass = $crate::LockClass::new($name);
+        &CLASS
+    }};
+}
+
+/// What the embedding kernel provides.
+#[derive(Debug)]
+pub struct Hooks {
+    /// The lock stack of the running task, or `None` if there are no tasks yet.
+    pub held: fn() -> Option<&'static HeldLocks>,
+    pub interrupts_enabled: fn() -> bool,
+    /// Run a closure with interrupts disabled on this cpu.
+    pub without_interrupts: fn(&mut dyn FnMut()),
+}
+
+#[cfg(not(feature = "enabled"))]
+#[derive(Debug, Default)]
+pub struct HeldLocks;
+
+#[cfg(not(feature = "enabled"))]
+impl HeldLocks {
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+/// Start validating. Nothing is checked until this has been called.
+#[inline]
+pub fn init(hooks: &'static Hooks) {
+    #[cfg(feature = "enabled")]
+    graph::init(hooks);
+    #[cfg(not(feature = "enabled"))]
+    let _ = hooks;
+}
+
+/// Called before blocking on `lock`, of class `class`. Records the dependencies on every
+/// lock the task already holds, and reports if that closes a cycle.
+#[inline]
+#[track_caller]
+pub fn acquire(lock: *const (), class: &'static LockClass, kind: LockKind) {
+    #[cfg(feature = "enabled")]
+    graph::acquire(lock, class, kind, Location::caller(), true);
+    #[cfg(not(feature = "enabled"))]
+    let _ = (lock, class, kind, Location::caller());
+}
+
+/// Called after successfully try-locking `lock`. A try-lock can't deadlock, so no
+/// dependencies are recorded, but the lock is still held.
+#[inline]
+#[track_caller]
+pub fn try_acquired(lock: *const (), class: &'static LockClass, kind: LockKind) {
+    #[cfg(feature = "enabled")]
+    graph::acquire(lock, class, kind, Location::caller(), false);
+    #[cfg(not(feature = "enabled"))]
+    let _ = (lock, class, kind, Location::caller());
+}
+
+/// Called when `lock` is released. Locks need not be released in order.
+#[inline]
+pub fn release(lock: *const ()) {
+    #[cfg(feature = "enabled")]
+    graph::release(lock);
+    #[cfg(not(feature = "enabled"))]
+    let _ = lock;
+}
+
+/// Called from anything that may put the current task to sleep.
+#[inline]
+#[track_caller]
+pub fn might_sleep() {
+    #[cfg(feature = "enabled")]
+    graph::might_sleep(Location::caller());
+}
@@ -4,6 +4,7 @@ use core::{marker::PhantomData, mem, ops::Range};
 
 use controller_attributes::ControllerAttributes;
 use hal_core::volatile::Volatile;
+use sol_types::ErrorKind;
 
 pub mod controller_attributes;
 pub mod cqe;
@@ -20,6 +21,14 @@ pub enum NvmeError {
     VirtualMemoryError,
 }
 
+impl From<NvmeError> for ErrorKind {
+    fn from(value: NvmeError) -> Self {
+        match value {
+            NvmeError::VirtualMemoryError => ErrorKind::AllocError,
+        }
+    }
+}
+
 /// Provide access to virtual memory operations needed by the nvme driver.
 ///
 /// # Safety
@@ -0,0 +1,115 @@
+use core::fmt;
+
+/// Why an operation failed.
+///
+/// The discriminants are the error codes a syscall returns, as `-1 - code`. They are
+/// stable: new kinds get new codes, and a code is never reused.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ErrorKind {
+    /// The kernel ran out of memory.
+    AllocError = 0,
+    /// An internal kernel error.
+    Fault = 1,
+    /// An argument was malformed.
+    InvalidArgument = 2,
+    /// The operation doesn't exist, or isn't supported by this configuration.
+    Unsupported = 3,
+    /// The resource is fully committed or already taken.
+    Busy = 4,
+    /// The operation would have to wait. Try again later.
+    WouldBlock = 5,
+    /// The handle doesn't name a live object of the kind the operation needs.
+    InvalidHandle = 6,
+    /// The handle lacks the rights the operation needs.
+    AccessDenied = 7,
+    /// The other end of a channel is gone.
+    PeerClosed = 8,
+    /// A pointer doesn't point at memory the caller has mapped.
+    BadAddress = 9,
+    /// The operation didn't finish before its deadline.
+    TimedOut = 10,
+    /// A well-formed value lies outside what the operation accepts, such as an offset
+    /// past the end of an object.
+    OutOfRange = 11,
+    /// There is nothing by the given name.
+    NotFound = 12,
+    /// There is already something by the given name.
+    AlreadyExists = 13,
+    /// A device reported an error.
+    Io = 14,
+    /// A wait was cut short because the process is being terminated.
+    Interrupted = 15,
+    /// A code this side of the ABI doesn't know about, such as one added by a newer
+    /// kernel. The kernel never returns it.
+    Unknown = u32::MAX,
+}
+
+impl ErrorKind {
+    /// The error code, as returned by a syscall.
+    pub const fn code(self) -> u32 {
+        self as u32
+    }
+
+    pub const fn from_code(code: u32) -> Self {
+        match code {
+            0 => Self::AllocError,
+            1 => Self::Fault,
+            2 => Self::InvalidArgument,
+            3 => Self::Unsupported,
+            4 => Self::Busy,
+            5 => Self::WouldBlock,
+            6 => Self::InvalidHandle,
+            7 => Self::AccessDenied,
+            8 => Self::PeerClosed,
+            9 => Self::BadAddress,
+            10 => Self::TimedOut,
+            11 => Self::OutOfRange,
+            12 => Self::NotFound,
+            13 => Self::AlreadyExists,
+            14 => Self::Io,
+            15 => Self::Interrupted,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// The raw syscall return value for this error, `-1 - code`.
+    pub const fn encode(self) -> usize {
+        (-1 - self.code() as isize) as usize
+    }
+
+    /// Split a raw syscall return value into its result. Values that are negative as an
+    /// `isize` are errors.
+    pub const fn decode(raw: usize) -> Result<usize, Self> {
+        match raw as isize {
+            value if value >= 0 => Ok(raw),
+            value => Err(Self::from_code((-1 - value) as u32)),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ErrorKind::AllocError => "out of memory",
+            ErrorKind::Fault => "internal kernel error",
+            ErrorKind::InvalidArgument => "invalid argument",
+            ErrorKind::Unsupported => "not supported",
+            ErrorKind::Busy => "resource busy",
+            ErrorKind::WouldBlock => "operation would block",
+            ErrorKind::InvalidHandle => "invalid handle",
+            ErrorKind::AccessDenied => "access denied",
+            ErrorKind::PeerClosed => "peer closed",
+            ErrorKind::BadAddress => "bad address",
+            ErrorKind::TimedOut => "timed out",
+            ErrorKind::OutOfRange => "out of range",
+            ErrorKind::NotFound => "not found",
+            ErrorKind::AlreadyExists => "already exists",
+            ErrorKind::Io => "device error",
+            ErrorKind::Interrupted => "interrupted",
+            ErrorKind::Unknown => "unknown error",
+        };
+        f.write_str(message)
+    }
+}
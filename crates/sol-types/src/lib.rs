@@ -0,0 +1,11 @@
+//! Definitions shared by the kernel and userspace.
+//!
+//! Everything here is part of the syscall ABI, so values are only ever added: once a
+//! number is given out, it keeps its meaning.
+
+#![no_std]
+
+pub use crate::{error::ErrorKind, process::ExitStatus};
+
+mod error;
+mod process;
@@ -0,0 +1,52 @@
+use core::fmt;
+
+/// How a process ended.
+///
+/// A syscall returns it as a single value: the exit code in the low 32 bits for a process
+/// that exited by itself, or a reason above them for one that was ended.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExitStatus {
+    /// The process exited with this code.
+    Exited(u32),
+    /// The process was killed through a handle to it.
+    Killed,
+    /// A thread of the process raised an exception, such as a page fault, in ring 3.
+    Faulted,
+}
+
+const REASON_SHIFT: u32 = 32;
+const REASON_KILLED: usize = 1;
+const REASON_FAULTED: usize = 2;
+
+impl ExitStatus {
+    /// The raw syscall return value for this status.
+    pub const fn encode(self) -> usize {
+        match self {
+            Self::Exited(code) => code as usize,
+            Self::Killed => REASON_KILLED << REASON_SHIFT,
+            Self::Faulted => REASON_FAULTED << REASON_SHIFT,
+        }
+    }
+
+    /// The status a raw syscall return value encodes, or `None` for a reason this side of
+    /// the ABI doesn't know about.
+    pub const fn decode(raw: usize) -> Option<Self> {
+        match raw >> REASON_SHIFT {
+            0 => Some(Self::Exited(raw as u32)),
+            REASON_KILLED => Some(Self::Killed),
+            REASON_FAULTED => Some(Self::Faulted),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with code {}", code),
+            ExitStatus::Killed => f.write_str("killed"),
+            ExitStatus::Faulted => f.write_str("faulted"),
+        }
+    }
+}
@@ -1,10 +1,18 @@
-use core::ops::Range;
+use core::{error::Error, fmt, ops::Range};
 
 use crate::Frame;
 
 #[derive(Debug)]
 pub struct FrameAllocError;
 
+impl fmt::Display for FrameAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("out of physical frames")
+    }
+}
+
+impl Error for FrameAllocError {}
+
 /// # Safety
 /// Implementors of this trait need to adhere to very strict safety guidelines. The
 /// virtual memory system is one of the key parts of any OS, so bugs here can have
@@ -1,5 +1,12 @@
 #![no_std]
-#![feature(const_trait_impl, ptr_sub_ptr, const_option_ext, step_trait, const_try)]
+#![feature(
+    const_trait_impl,
+    ptr_sub_ptr,
+    const_option_ext,
+    step_trait,
+    const_try,
+    error_in_core
+)]
 
 pub use crate::{
     frame::Frame,
@@ -1,3 +1,5 @@
+use core::{error::Error, fmt};
+
 use crate::{
     frame_allocator::{FrameAllocError, FrameAllocator},
     Frame, Page,
@@ -9,6 +11,18 @@ pub enum PageTableError {
     FrameAllocError,
 }
 
+impl fmt::Display for PageTableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageTableError::FrameAllocError => {
+                f.write_str("out of physical frames for a page table")
+            }
+        }
+    }
+}
+
+impl Error for PageTableError {}
+
 impl From<FrameAllocError> for PageTableError {
     fn from(_: FrameAllocError) -> Self {
         Self::FrameAllocError
@@ -21,6 +35,21 @@ pub enum PageLookupError {
     MissingPageEntry(usize),
 }
 
+impl fmt::Display for PageLookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageLookupError::MissingPageTable(entry) => {
+                write!(f, "no page table under entry {:#x}", entry)
+            }
+            PageLookupError::MissingPageEntry(entry) => {
+                write!(f, "page not mapped, entry {:#x}", entry)
+            }
+        }
+    }
+}
+
+impl Error for PageLookupError {}
+
 /// A page table.
 ///
 /// # Safety
@@ -50,13 +79,12 @@ pub unsafe trait PageTable {
     where
         P: ?Sized + FrameAllocator;
 
-    /// Unmap the requested page, removing it from the page table.
+    /// Unmap the requested page, removing it from the page table. If the page was present,
+    /// the frame it was mapped to is returned so that the caller may release it.
     ///
     /// # Safety
     /// 1. The page must be valid and not used anywhere else.
-    // unsafe fn unmap<P>(&mut self, page: Page, phys: &P)
-    // where
-    //     P: ?Sized + FrameAllocator;
+    unsafe fn unmap(&mut self, page: Page) -> Result<Option<Frame>, PageLookupError>;
 
     /// Attempt to look up the frame that a given page is mapped to.
     fn lookup(&mut self, page: Page) -> Result<Frame, PageLookupError>;
@@ -20,6 +20,8 @@ const LIMINE_GIT_URL: &str = "https://github.com/limine-bootloader/limine.git";
 const KERNEL: &str = "target/x86_64-unknown-none/debug/kernel";
 const KERNEL_ISO: &str = "target/x86_64-unknown-none/debug/kernel.iso";
 const TARGET: &str = "x86_64-unknown-none";
+/// The target userspace programs are built for.
+const USER_TARGET: &str = "conf/x86_64-sol.json";
 
 #[derive(Debug)]
 struct Options {
@@ -48,6 +50,17 @@ impl Options {
         path
     }
 
+    fn init(&self) -> PathBuf {
+        let mut path = PathBuf::from("target/x86_64-sol");
+        if self.release {
+            path.push("release");
+        } else {
+            path.push("debug");
+        }
+        path.push("init");
+        path
+    }
+
     fn kernel_iso(&self) -> PathBuf {
         let mut path = PathBuf::from("target/x86_64-unknown-none");
         if self.release {
@@ -167,6 +180,25 @@ fn build(options: &Options) -> Result<(), Box<dyn Error>> {
     dbg!(&command);
     command.spawn()?.wait()?;
 
+    let mut command = Command::new("cargo");
+    command
+        .arg("+nightly")
+        .arg("build")
+        .arg("-Z")
+        .arg("build-std=core,compiler_builtins")
+        .arg("-Z")
+        .arg("build-std-features=compiler-builtins-mem")
+        .arg("--target")
+        .arg(USER_TARGET)
+        .arg("--bin")
+        .arg("init");
+
+    if options.release {
+        command.arg("--release");
+    }
+
+    command.spawn()?.wait()?;
+
     if !Path::new("target/limine").exists() {
         // git clone $LIMINE_GIT_URL --depth=1 --branch v3.0-branch-binary target/limine
         Command::new("git")
@@ -186,6 +218,7 @@ fn build(options: &Options) -> Result<(), Box<dyn Error>> {
 
     let iso_files = [
         options.kernel(),
+        options.init(),
         "conf/limine.cfg".into(),
         "target/limine/limine.sys".into(),
         "target/limine/limine-cd.bin".into(),